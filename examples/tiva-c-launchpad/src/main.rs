@@ -25,10 +25,10 @@ fn main() -> ! {
         p.UART0,
         porta
             .pa1
-            .into_af_push_pull::<hal::gpio::AF1>(&mut porta.control),
+            .into_alternate::<hal::gpio::AF1, hal::gpio::PushPull>(&mut porta.control),
         porta
             .pa0
-            .into_af_push_pull::<hal::gpio::AF1>(&mut porta.control),
+            .into_alternate::<hal::gpio::AF1, hal::gpio::PushPull>(&mut porta.control),
         (),
         (),
         115200_u32.bps(),
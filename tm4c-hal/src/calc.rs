@@ -0,0 +1,313 @@
+//! Clock divisor arithmetic shared by the drivers
+//!
+//! Plain functions over integers with no register access, so each formula
+//! lives in one place and can be checked on the host. Timer load and
+//! prescale calculations are in `timer::TickCalc`.
+
+/// Frequency of the TM4C123 PLL output before the system divider
+pub const PLL_400MHZ: u32 = 400_000_000;
+
+/// Frequency of the TM4C129 PLL's VCO as this crate configures it
+pub const VCO_480MHZ: u32 = 480_000_000;
+
+/// UART baud rate divisor for `baud` at `sysclk`, as the integer (UARTIBRD)
+/// and 6-bit fractional (UARTFBRD) parts of `sysclk / (16 * baud)`,
+/// rounded to the nearest 1/64
+pub fn uart_divisor(sysclk: u32, baud: u32) -> (u16, u8) {
+    // baud_int = 64 * (sys_clk / (16 * baud))
+    // baud_int = 4 * (sys_clk / baud)
+    // baud_int = ((8 * sys_clk) / baud) / 2, plus + 1 to round correctly
+    let baud_int = (((sysclk * 8) / baud) + 1) / 2;
+    ((baud_int / 64) as u16, (baud_int % 64) as u8)
+}
+
+/// A bus rate the divisor registers can't produce at the system clock given
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateError {
+    /// Faster than the largest rate the peripheral can run at
+    TooFast,
+    /// Slower than the largest divisor allows, or zero
+    TooSlow,
+}
+
+/// I2C master timer period (I2CMTPR.TPR, seven bits) for an SCL frequency
+/// of `scl` at `sysclk`. Each SCL period is 20 timer periods of `TPR + 1`
+/// clocks, so `scl` can be at most `sysclk / 20`.
+pub fn i2c_tpr(sysclk: u32, scl: u32) -> Result<u8, RateError> {
+    if scl == 0 {
+        return Err(RateError::TooSlow);
+    }
+    match u64::from(sysclk) / (2 * 10 * u64::from(scl)) {
+        0 => Err(RateError::TooFast),
+        periods if periods > 0x80 => Err(RateError::TooSlow),
+        periods => Ok((periods - 1) as u8),
+    }
+}
+
+/// SSI clock prescale divisor (SSICPSR.CPSDVSR) and serial clock rate
+/// (SSICR0.SCR) for a bit rate of about `bitrate` at `sysclk`, where
+/// `SSInClk = sysclk / (CPSDVSR * (1 + SCR))`. Uses the smallest even
+/// prescaler that gets SCR in range. The master can run at no more than
+/// `sysclk / 2`.
+pub fn ssi_divisors(sysclk: u32, bitrate: u32) -> Result<(u8, u8), RateError> {
+    if bitrate == 0 {
+        return Err(RateError::TooSlow);
+    }
+    let target_bitrate = sysclk / bitrate;
+    if target_bitrate < 2 {
+        return Err(RateError::TooFast);
+    }
+    // CPSDVSR in [2,254] and even, SCR in [0,255]
+    (2..=254u32)
+        .step_by(2)
+        .map(|cpsr| (cpsr, (target_bitrate / cpsr) - 1))
+        .find(|&(_, scr32)| scr32 < 255)
+        .map(|(cpsr, scr32)| (cpsr as u8, scr32 as u8))
+        .ok_or(RateError::TooSlow)
+}
+
+/// TM4C123 system clock from the PLL with RCC2.DIV400 set, where `div` is
+/// the 7-bit divisor formed by RCC2.SYSDIV2 and SYSDIV2LSB
+pub fn pll_sysclk_div400(div: u8) -> u32 {
+    PLL_400MHZ / (u32::from(div) + 1)
+}
+
+/// The 7-bit RCC2 divisor (SYSDIV2 shifted left by one, plus SYSDIV2LSB)
+/// that brings the 400 MHz PLL nearest to `sysclk`. The inverse of
+/// `pll_sysclk_div400`.
+pub fn pll_div400(sysclk: u32) -> u8 {
+    ((PLL_400MHZ + sysclk / 2) / sysclk - 1) as u8
+}
+
+/// TM4C129 PLL system divider (RSCLKCFG.PSYSDIV) that brings the VCO down
+/// to `sysclk`
+pub fn psysdiv(sysclk: u32) -> u16 {
+    (VCO_480MHZ / sysclk - 1) as u16
+}
+
+/// TM4C129 flash and EEPROM timing (MEMTIM0) for `sysclk`: the bank clock
+/// high time, whether the bank clock edge is aligned to the system clock,
+/// and the wait states. `None` above the 120 MHz maximum.
+///
+/// The rows are the datasheet's "MEMTIM0 Register Configuration versus
+/// Frequency" table, whose ranges are closed at the top: 16 MHz itself
+/// runs with no wait states, and 16 < f <= 40 MHz with one.
+pub fn memory_timing(sysclk: u32) -> Option<(u8, bool, u8)> {
+    match sysclk {
+        f if f <= 16_000_000 => Some((0, true, 0)),
+        f if f <= 40_000_000 => Some((2, false, 1)),
+        f if f <= 60_000_000 => Some((3, false, 2)),
+        f if f <= 80_000_000 => Some((4, false, 3)),
+        f if f <= 100_000_000 => Some((5, false, 4)),
+        f if f <= 120_000_000 => Some((6, false, 5)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// System clocks from the slowest either chip runs at to the fastest
+    const SYSCLKS: [u32; 9] = [
+        1_000_000,
+        4_000_000,
+        16_000_000,
+        25_000_000,
+        40_000_000,
+        50_000_000,
+        66_666_666,
+        80_000_000,
+        120_000_000,
+    ];
+
+    #[test]
+    fn uart_divisor_matches_the_datasheet_example() {
+        // 20 MHz at 115200 baud: BRD = 10.8507, IBRD 10, FBRD 54
+        assert_eq!(uart_divisor(20_000_000, 115_200), (10, 54));
+        // Whole divisors have no fraction
+        assert_eq!(uart_divisor(16_000_000, 1_000_000), (1, 0));
+        assert_eq!(uart_divisor(80_000_000, 9_600), (520, 53));
+    }
+
+    #[test]
+    fn i2c_tpr_for_the_standard_speeds() {
+        assert_eq!(i2c_tpr(16_000_000, 100_000), Ok(7));
+        assert_eq!(i2c_tpr(80_000_000, 400_000), Ok(9));
+        assert_eq!(i2c_tpr(120_000_000, 100_000), Ok(59));
+        assert_eq!(i2c_tpr(120_000_000, 1_000_000), Ok(5));
+    }
+
+    #[test]
+    fn i2c_tpr_rejects_what_seven_bits_cant_hold() {
+        // 599 used to be truncated to 87
+        assert_eq!(i2c_tpr(120_000_000, 10_000), Err(RateError::TooSlow));
+        assert_eq!(i2c_tpr(120_000_000, 0), Err(RateError::TooSlow));
+        // Faster than sysclk / 20 used to underflow
+        assert_eq!(i2c_tpr(16_000_000, 800_001), Err(RateError::TooFast));
+        assert_eq!(i2c_tpr(16_000_000, 800_000), Ok(0));
+    }
+
+    #[test]
+    fn i2c_tpr_switches_at_each_period_boundary() {
+        for &sysclk in SYSCLKS.iter() {
+            for periods in 1..=0x80u32 {
+                // The fastest SCL that still takes `periods` timer periods
+                let fastest = sysclk / (20 * periods);
+                if fastest == 0 || fastest <= sysclk / (20 * (periods + 1)) {
+                    continue;
+                }
+                assert_eq!(i2c_tpr(sysclk, fastest), Ok((periods - 1) as u8));
+                match i2c_tpr(sysclk, fastest + 1) {
+                    Ok(tpr) => assert!(u32::from(tpr) + 1 < periods),
+                    Err(e) => assert!(periods == 1 && e == RateError::TooFast),
+                }
+            }
+            // One period slower than seven bits allow
+            assert_eq!(
+                i2c_tpr(sysclk, sysclk / (20 * 0x81)),
+                Err(RateError::TooSlow)
+            );
+        }
+    }
+
+    #[test]
+    fn ssi_divisors_reject_out_of_range_rates() {
+        assert_eq!(ssi_divisors(16_000_000, 8_000_000), Ok((2, 0)));
+        // Faster than sysclk / 2 used to underflow
+        assert_eq!(ssi_divisors(16_000_000, 8_000_001), Err(RateError::TooFast));
+        assert_eq!(ssi_divisors(16_000_000, 0), Err(RateError::TooSlow));
+        // Below sysclk / (254 * 256) used to panic
+        assert_eq!(ssi_divisors(120_000_000, 1_000), Err(RateError::TooSlow));
+    }
+
+    /// Checks `ssi_divisors(sysclk, bitrate)` against the whole divisor
+    /// `sysclk / bitrate` it was aiming for
+    fn check_ssi_divisors(sysclk: u32, bitrate: u32) {
+        let target = sysclk / bitrate;
+        match ssi_divisors(sysclk, bitrate) {
+            Ok((cpsr, scr)) => {
+                let cpsr = u32::from(cpsr);
+                assert!(cpsr >= 2 && cpsr % 2 == 0 && cpsr <= 254);
+                // The divisor is the whole part of sysclk / bitrate, to
+                // within one prescaler step
+                let divisor = cpsr * (u32::from(scr) + 1);
+                assert!(divisor <= target && target - divisor < cpsr);
+                // and the prescaler is the smallest that fits SCR
+                assert!(cpsr == 2 || target / (cpsr - 2) >= 256);
+            }
+            Err(RateError::TooFast) => assert!(target < 2),
+            Err(RateError::TooSlow) => assert!(target >= 254 * 256),
+        }
+    }
+
+    #[test]
+    fn ssi_divisors_for_every_whole_divisor() {
+        // Every target divisor from the fastest rate to one past the
+        // slowest, so each prescaler and SCR edge is crossed
+        for target in 1..=254 * 256 + 1 {
+            check_ssi_divisors(target * 1_000, 1_000);
+            check_ssi_divisors(target * 1_000 + 999, 1_000);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn uart_divisor_is_within_half_a_step(
+            (sysclk, baud) in (1_000_000..=120_000_000u32)
+                .prop_flat_map(|sysclk| (Just(sysclk), 300..=sysclk / 16))
+        ) {
+            let (ibrd, fbrd) = uart_divisor(sysclk, baud);
+            prop_assert!(fbrd < 64);
+            // The divisor in 64ths, against the exact sysclk * 4 / baud
+            let divisor = u64::from(ibrd) * 64 + u64::from(fbrd);
+            let exact = u64::from(sysclk) * 8 / u64::from(baud);
+            prop_assert!((divisor * 2).max(exact) - (divisor * 2).min(exact) <= 1);
+        }
+
+        #[test]
+        fn i2c_tpr_never_exceeds_the_requested_rate_by_a_period(
+            // Up to twice the fastest rate, to take in the rejections
+            (sysclk, scl) in (1_000_000..=120_000_000u32)
+                .prop_flat_map(|sysclk| (Just(sysclk), 0..=sysclk / 10))
+        ) {
+            match i2c_tpr(sysclk, scl) {
+                Ok(tpr) => {
+                    prop_assert!(tpr <= 0x7F);
+                    // The rate it gives, and the next slower one, straddle scl
+                    let clocks = 20 * (u64::from(tpr) + 1);
+                    prop_assert!(clocks * u64::from(scl) <= u64::from(sysclk));
+                    prop_assert!((clocks + 20) * u64::from(scl) > u64::from(sysclk));
+                }
+                Err(RateError::TooFast) => {
+                    prop_assert!(u64::from(sysclk) < 20 * u64::from(scl));
+                }
+                Err(RateError::TooSlow) => {
+                    prop_assert!(scl == 0 || u64::from(sysclk) / (20 * u64::from(scl)) > 0x80);
+                }
+            }
+        }
+
+        #[test]
+        fn ssi_divisors_stay_in_their_fields(
+            (sysclk, bitrate) in (1_000_000..=120_000_000u32)
+                .prop_flat_map(|sysclk| (Just(sysclk), 1..=sysclk))
+        ) {
+            check_ssi_divisors(sysclk, bitrate);
+        }
+    }
+
+    #[test]
+    fn memory_timing_ranges_include_their_top() {
+        assert_eq!(memory_timing(16_000_000), Some((0, true, 0)));
+        assert_eq!(memory_timing(16_000_001), Some((2, false, 1)));
+        assert_eq!(memory_timing(25_000_000), Some((2, false, 1)));
+        assert_eq!(memory_timing(40_000_000), Some((2, false, 1)));
+        assert_eq!(memory_timing(40_000_001), Some((3, false, 2)));
+        assert_eq!(memory_timing(120_000_000), Some((6, false, 5)));
+        assert_eq!(memory_timing(120_000_001), None);
+    }
+
+    #[test]
+    fn pll_div400_round_trips_every_divisor() {
+        // SYSDIV2 of 2 (80 MHz) up to the largest the 7-bit field holds,
+        // asked for exactly or rounded to 10 kHz as the TM4C123's
+        // PllOutputFrequency values are
+        for div in 4..=127u8 {
+            let sysclk = pll_sysclk_div400(div);
+            assert_eq!(pll_div400(sysclk), div);
+            let rounded = (sysclk + 5_000) / 10_000 * 10_000;
+            assert_eq!(pll_div400(rounded), div, "{} Hz", rounded);
+        }
+    }
+
+    #[test]
+    fn pll_div400_for_each_pll_output_frequency() {
+        // The TM4C123's PllOutputFrequency values and the divisors they
+        // program; keep in step with the enum
+        let table = [
+            (80_000_000, 4),
+            (66_670_000, 5),
+            (50_000_000, 7),
+            (40_000_000, 9),
+            (33_330_000, 11),
+            (28_570_000, 13),
+            (25_000_000, 15),
+            (22_220_000, 17),
+            (20_000_000, 19),
+            (18_180_000, 21),
+            (16_670_000, 23),
+            (15_380_000, 25),
+            (14_290_000, 27),
+            (13_330_000, 29),
+            (12_500_000, 31),
+        ];
+        for &(frequency, div) in table.iter() {
+            assert_eq!(pll_div400(frequency), div, "{} Hz", frequency);
+            // What the hardware then runs at is within rounding of the name
+            let actual = pll_sysclk_div400(div);
+            assert!((i64::from(actual) - i64::from(frequency)).abs() <= 5_000);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Cortex-M4F core setup: turning the FPU on and choosing how its registers
+//! are saved across exceptions
+//!
+//! The FPU is off at reset, and the first floating-point instruction after
+//! reset takes a UsageFault. Call `enable_fpu` before any code that might use
+//! `f32`/`f64` runs. If you change the context policy, do it straight after
+//! enabling the FPU, before any floating-point instruction has executed.
+
+use cortex_m::peripheral::SCB;
+
+/// Full access for coprocessors 10 and 11 (the FPU) in CPACR
+const CPACR_FPU_FULL: u32 = 0b1111 << 20;
+
+/// Floating-point Context Control Register. Addressed directly as the
+/// `FPU` core peripheral only exists on hard-float targets.
+const FPCCR: *mut u32 = 0xE000_EF34 as *mut u32;
+/// Automatic state preservation enable
+const FPCCR_ASPEN: u32 = 1 << 31;
+/// Lazy state preservation enable
+const FPCCR_LSPEN: u32 = 1 << 30;
+
+/// How the FPU registers are saved when an exception is taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Nothing is saved. Interrupt latency is lowest, but an interrupt or
+    /// exception handler that uses floating point will silently corrupt the
+    /// floating-point registers of whatever code it interrupted. Only choose
+    /// this if no handler ever touches the FPU, including through library
+    /// code or compiler-generated moves.
+    NoPreserve,
+    /// Space for S0-S15 and FPSCR is reserved on the stack on entry, but the
+    /// registers are only written if the handler actually executes a
+    /// floating-point instruction. This is the reset behaviour and is safe
+    /// for handlers that use floating point, at the cost of a few extra
+    /// cycles the first time they do.
+    Lazy,
+    /// S0-S15 and FPSCR are stacked on every exception entry while a
+    /// floating-point context is active. Handlers may use floating point
+    /// freely and the worst-case latency no longer depends on what the
+    /// handler does, which some timing analyses prefer.
+    Full,
+}
+
+/// Gives privileged and unprivileged code full access to the FPU
+pub fn enable_fpu(scb: &mut SCB) {
+    unsafe { scb.cpacr.modify(|r| r | CPACR_FPU_FULL) };
+    // The new access rights don't apply until the write has completed and
+    // the pipeline has been refilled
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// True if `enable_fpu` (or something else) has given full access to the FPU.
+/// Code doing floating-point maths can `debug_assert!` on this.
+pub fn fpu_enabled() -> bool {
+    unsafe { (*SCB::ptr()).cpacr.read() & CPACR_FPU_FULL == CPACR_FPU_FULL }
+}
+
+/// Chooses how FPU registers are preserved across exceptions. See `Policy`
+/// for what each option means for handlers that use floating point.
+pub fn set_fpu_context_policy(_scb: &mut SCB, policy: Policy) {
+    let bits = match policy {
+        Policy::NoPreserve => 0,
+        Policy::Lazy => FPCCR_ASPEN | FPCCR_LSPEN,
+        Policy::Full => FPCCR_ASPEN,
+    };
+    unsafe {
+        let fpccr = core::ptr::read_volatile(FPCCR) & !(FPCCR_ASPEN | FPCCR_LSPEN);
+        core::ptr::write_volatile(FPCCR, fpccr | bits);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
@@ -0,0 +1,128 @@
+//! Debouncing for switch and relay inputs
+//!
+//! The GPIO blocks have no input filter, so `Debouncer` does it in software.
+//! Call `sample_pins` (or `sample`, for each pin) from a periodic timer or
+//! SysTick interrupt; each pin's filter is an integrator that has to see
+//! `press` more high samples than low ones before it reports high, and
+//! `release` more low samples than high ones before it reports low again.
+
+use embedded_hal::digital::InputPin;
+
+/// Which way a debounced input changed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    /// The input went high
+    Rising,
+    /// The input went low
+    Falling,
+}
+
+/// How many net samples a pin needs before its debounced level changes
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// Samples needed to go from low to high
+    pub press: u8,
+    /// Samples needed to go from high to low
+    pub release: u8,
+}
+
+const HIGH: u8 = 1 << 0;
+const ROSE: u8 = 1 << 1;
+const FELL: u8 = 1 << 2;
+
+#[derive(Clone, Copy)]
+struct PinState {
+    count: u8,
+    flags: u8,
+}
+
+/// Debounces up to `N` inputs. Every input starts out low.
+pub struct Debouncer<const N: usize> {
+    state: [PinState; N],
+    thresholds: [Thresholds; N],
+    on_edge: Option<fn(usize, Edge)>,
+}
+
+impl<const N: usize> Debouncer<N> {
+    /// Creates a debouncer with the same thresholds for every input
+    pub fn new(thresholds: Thresholds) -> Self {
+        Debouncer {
+            state: [PinState { count: 0, flags: 0 }; N],
+            thresholds: [thresholds; N],
+            on_edge: None,
+        }
+    }
+
+    /// Changes the thresholds for input `id`
+    pub fn set_thresholds(&mut self, id: usize, thresholds: Thresholds) {
+        self.thresholds[id] = thresholds;
+    }
+
+    /// Calls `callback` with the input number whenever a debounced input
+    /// changes. It runs inside `sample`, so usually in interrupt context.
+    pub fn set_callback(&mut self, callback: fn(usize, Edge)) {
+        self.on_edge = Some(callback);
+    }
+
+    /// Feeds one raw sample of input `id` through its filter
+    pub fn sample(&mut self, id: usize, high: bool) {
+        let thresholds = self.thresholds[id];
+        let pin = &mut self.state[id];
+        let was_high = pin.flags & HIGH != 0;
+        if high == was_high {
+            // Agreeing samples drain the count, so isolated glitches never
+            // add up to a change
+            pin.count = pin.count.saturating_sub(1);
+            return;
+        }
+        pin.count += 1;
+        let needed = if high {
+            thresholds.press
+        } else {
+            thresholds.release
+        };
+        if pin.count < needed {
+            return;
+        }
+        pin.count = 0;
+        let edge = if high {
+            pin.flags |= HIGH | ROSE;
+            Edge::Rising
+        } else {
+            pin.flags = (pin.flags & !HIGH) | FELL;
+            Edge::Falling
+        };
+        if let Some(callback) = self.on_edge {
+            callback(id, edge);
+        }
+    }
+
+    /// Samples every pin in `pins`, which are inputs `0..pins.len()`
+    pub fn sample_pins(&mut self, pins: &[&dyn InputPin]) {
+        for (id, pin) in pins.iter().enumerate() {
+            self.sample(id, pin.is_high());
+        }
+    }
+
+    /// The debounced level of input `id`
+    pub fn is_high(&self, id: usize) -> bool {
+        self.state[id].flags & HIGH != 0
+    }
+
+    /// True if input `id` has gone high since this was last called
+    pub fn rising_edge(&mut self, id: usize) -> bool {
+        self.take_flag(id, ROSE)
+    }
+
+    /// True if input `id` has gone low since this was last called
+    pub fn falling_edge(&mut self, id: usize) -> bool {
+        self.take_flag(id, FELL)
+    }
+
+    fn take_flag(&mut self, id: usize, flag: u8) -> bool {
+        let pin = &mut self.state[id];
+        let set = pin.flags & flag != 0;
+        pin.flags &= !flag;
+        set
+    }
+}
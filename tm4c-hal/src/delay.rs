@@ -13,6 +13,7 @@ pub struct Delay {
 impl Delay {
     /// Configures the system timer (SysTick) as a delay provider
     pub fn new(mut syst: SYST, clocks: &Clocks) -> Self {
+        clocks.assert_current();
         syst.set_clock_source(SystClkSource::Core);
 
         Delay {
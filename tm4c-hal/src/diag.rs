@@ -0,0 +1,309 @@
+//! Diagnostic snapshots of clocks, power, interrupts and driver errors
+//!
+//! Each chip crate's `diag::snapshot` reads the hardware into a `DiagReport`,
+//! which needs no heap and prints itself with `Display`. Drivers join in by
+//! implementing `Diagnose` and being passed to `snapshot`.
+//!
+//! Every critical section the HAL takes goes through `critical_section`.
+//! With the `cs-latency` feature each one is timed with the cycle counter,
+//! and `critical_section_report` gives the longest interrupts have been
+//! held off at each call site. The cycle counter must be running, which
+//! `MonoTimer::new` sees to.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::{self, CriticalSection};
+use cortex_m::peripheral::NVIC;
+
+/// Oscillator feeding the system clock (or the PLL in front of it)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    /// The main oscillator (external crystal)
+    MainOscillator,
+    /// The 16 MHz precision internal oscillator
+    PrecisionInternal,
+    /// The precision internal oscillator divided by 4
+    PrecisionInternalDiv4,
+    /// The ~30 kHz low-frequency internal oscillator
+    LowFrequencyInternal,
+    /// The 32.768 kHz hibernation oscillator
+    Hibernation,
+    /// A source field value this crate doesn't know about
+    Unknown(u8),
+}
+
+/// The system clock configuration, as read back from the clock registers
+#[derive(Debug, Clone, Copy)]
+pub struct ClockTree {
+    /// Where the clock comes from
+    pub source: ClockSource,
+    /// Frequency of `source`, in Hz
+    pub osc_hz: u32,
+    /// Whether the PLL sits between `source` and the system clock
+    pub pll: bool,
+    /// Divider between the oscillator (or PLL) and the system clock
+    pub divider: u32,
+    /// Resulting system clock, in Hz
+    pub sysclk_hz: u32,
+}
+
+/// Implemented by drivers that have sticky error flags or status worth
+/// including in a `DiagReport`
+pub trait Diagnose {
+    /// Writes this driver's status on a single line, without a newline
+    fn diagnose(&self, f: &mut fmt::Formatter) -> fmt::Result;
+}
+
+/// Number of NVIC enable/pending words that cover either chip family
+pub const NVIC_WORDS: usize = 5;
+
+/// Number of run-mode clock gating registers a `DiagReport` can hold
+pub const CLOCK_GATE_SLOTS: usize = 24;
+
+/// Everything `snapshot` found out
+pub struct DiagReport<'a> {
+    /// Clock configuration decoded from the registers
+    pub clocks: ClockTree,
+    /// System clock the HAL was frozen with, in Hz. If this differs from
+    /// `clocks.sysclk_hz`, something changed the clocks behind its back.
+    pub frozen_sysclk_hz: u32,
+    /// Raw reset cause register (RESC)
+    pub reset_cause: u32,
+    /// Non-zero run-mode clock gating registers, by name
+    pub run_clock_gates: [Option<(&'static str, u32)>; CLOCK_GATE_SLOTS],
+    /// NVIC interrupt set-enable words
+    pub nvic_enabled: [u32; NVIC_WORDS],
+    /// NVIC interrupt set-pending words
+    pub nvic_pending: [u32; NVIC_WORDS],
+    /// Drivers to ask for their status when printing
+    pub drivers: &'a [&'a dyn Diagnose],
+}
+
+impl<'a> DiagReport<'a> {
+    /// Builds a report from the chip-specific parts, reading the NVIC state
+    /// itself
+    pub fn new(
+        clocks: ClockTree,
+        frozen_sysclk_hz: u32,
+        reset_cause: u32,
+        run_clock_gates: [Option<(&'static str, u32)>; CLOCK_GATE_SLOTS],
+        drivers: &'a [&'a dyn Diagnose],
+    ) -> Self {
+        // This is safe as it's read only
+        let nvic = unsafe { &*NVIC::ptr() };
+        let mut nvic_enabled = [0; NVIC_WORDS];
+        let mut nvic_pending = [0; NVIC_WORDS];
+        for (i, (enabled, pending)) in nvic_enabled
+            .iter_mut()
+            .zip(nvic_pending.iter_mut())
+            .enumerate()
+        {
+            *enabled = nvic.iser[i].read();
+            *pending = nvic.ispr[i].read();
+        }
+        DiagReport {
+            clocks,
+            frozen_sysclk_hz,
+            reset_cause,
+            run_clock_gates,
+            nvic_enabled,
+            nvic_pending,
+            drivers,
+        }
+    }
+}
+
+/// Writes the numbers of the interrupts whose bits are set in `words`
+fn write_irqs(f: &mut fmt::Formatter, words: &[u32]) -> fmt::Result {
+    let mut any = false;
+    for (i, word) in words.iter().enumerate() {
+        for bit in 0..32 {
+            if word & (1 << bit) != 0 {
+                write!(f, " {}", i * 32 + bit)?;
+                any = true;
+            }
+        }
+    }
+    if !any {
+        write!(f, " none")?;
+    }
+    writeln!(f)
+}
+
+impl<'a> fmt::Display for DiagReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = &self.clocks;
+        write!(f, "clock: {:?} @ {} Hz", c.source, c.osc_hz)?;
+        if c.pll {
+            write!(f, " -> PLL")?;
+        }
+        writeln!(f, " / {} = {} Hz", c.divider, c.sysclk_hz)?;
+        if c.sysclk_hz != self.frozen_sysclk_hz {
+            writeln!(f, "  (HAL was frozen at {} Hz)", self.frozen_sysclk_hz)?;
+        }
+        writeln!(f, "reset cause: {:#010x}", self.reset_cause)?;
+        write!(f, "clocked:")?;
+        for (name, bits) in self.run_clock_gates.iter().flatten() {
+            write!(f, " {}={:#x}", name, bits)?;
+        }
+        writeln!(f)?;
+        write!(f, "irq enabled:")?;
+        write_irqs(f, &self.nvic_enabled)?;
+        write!(f, "irq pending:")?;
+        write_irqs(f, &self.nvic_pending)?;
+        for driver in self.drivers {
+            driver.diagnose(f)?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the names of the set bits in `value`, from a table of
+/// `(bit, name)` pairs
+pub fn write_flags(f: &mut fmt::Formatter, value: u32, names: &[(u8, &str)]) -> fmt::Result {
+    let mut any = false;
+    for &(bit, name) in names {
+        if value & (1 << bit) != 0 {
+            write!(f, " {}", name)?;
+            any = true;
+        }
+    }
+    if !any {
+        write!(f, " ok")?;
+    }
+    Ok(())
+}
+
+/// The places the HAL takes a critical section. GPIO interrupt masking and
+/// `gpio::PortDispatcher` don't appear, as they never mask interrupts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsSite {
+    /// `pps::SharedPps`, for as long as the discipline's arithmetic lasts
+    SharedPps,
+    /// `safe_state::register`, while it finds and fills a free slot
+    SafeStateRegister,
+    /// `rt_budget`, setting the handler or running it for a violation
+    RtBudget,
+    /// `trace`, setting the hook or running it for a record
+    Trace,
+}
+
+impl CsSite {
+    /// Every site, in `critical_section_report` order
+    pub const ALL: [CsSite; CS_SITES] = [
+        CsSite::SharedPps,
+        CsSite::SafeStateRegister,
+        CsSite::RtBudget,
+        CsSite::Trace,
+    ];
+
+    /// A short name for the site, such as `"pps::SharedPps"`
+    pub fn name(self) -> &'static str {
+        match self {
+            CsSite::SharedPps => "pps::SharedPps",
+            CsSite::SafeStateRegister => "safe_state::register",
+            CsSite::RtBudget => "rt_budget",
+            CsSite::Trace => "trace",
+        }
+    }
+}
+
+/// Number of `CsSite`s
+pub const CS_SITES: usize = 4;
+
+/// The longest critical section seen at one site
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CsReport {
+    /// Where it was taken
+    pub site: CsSite,
+    /// How long interrupts were held off, in system clock cycles. Zero if
+    /// the site hasn't been reached or `cs-latency` is off.
+    pub max_cycles: u32,
+}
+
+// Only used to initialise `CS_MAX`, where each copy is meant to be a fresh,
+// independent counter
+#[allow(clippy::declare_interior_mutable_const)]
+const CS_ZERO: AtomicU32 = AtomicU32::new(0);
+
+static CS_MAX: [AtomicU32; CS_SITES] = [CS_ZERO; CS_SITES];
+
+/// Runs `f` with interrupts disabled, like `interrupt::free`, timing it as
+/// `site` if the `cs-latency` feature is on
+#[inline]
+pub fn critical_section<F, R>(site: CsSite, f: F) -> R
+where
+    F: FnOnce(&CriticalSection) -> R,
+{
+    interrupt::free(|cs| {
+        #[cfg(feature = "cs-latency")]
+        let start = cortex_m::peripheral::DWT::get_cycle_count();
+        let result = f(cs);
+        #[cfg(feature = "cs-latency")]
+        record_critical_section(
+            site,
+            cortex_m::peripheral::DWT::get_cycle_count().wrapping_sub(start),
+        );
+        #[cfg(not(feature = "cs-latency"))]
+        let _ = site;
+        result
+    })
+}
+
+/// Notes a critical section of `cycles` at `site`, keeping the longest.
+/// `critical_section` calls this with interrupts still disabled, so the
+/// load and store can't be interleaved with another.
+#[cfg_attr(not(feature = "cs-latency"), allow(dead_code))]
+fn record_critical_section(site: CsSite, cycles: u32) {
+    let max = &CS_MAX[site as usize];
+    if cycles > max.load(Ordering::Relaxed) {
+        max.store(cycles, Ordering::Relaxed);
+    }
+}
+
+/// The longest critical section seen at each site since start-up or
+/// `reset_critical_section_report`
+pub fn critical_section_report() -> [CsReport; CS_SITES] {
+    let mut report = [CsReport {
+        site: CsSite::SharedPps,
+        max_cycles: 0,
+    }; CS_SITES];
+    for (entry, &site) in report.iter_mut().zip(CsSite::ALL.iter()) {
+        *entry = CsReport {
+            site,
+            max_cycles: CS_MAX[site as usize].load(Ordering::Relaxed),
+        };
+    }
+    report
+}
+
+/// Forgets the longest critical sections seen so far
+pub fn reset_critical_section_report() {
+    for max in CS_MAX.iter() {
+        max.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn critical_section_report_keeps_the_longest() {
+        reset_critical_section_report();
+        record_critical_section(CsSite::Trace, 40);
+        record_critical_section(CsSite::Trace, 25);
+        record_critical_section(CsSite::SharedPps, 300);
+        let report = critical_section_report();
+        for (entry, &site) in report.iter().zip(CsSite::ALL.iter()) {
+            assert_eq!(entry.site, site);
+        }
+        assert_eq!(report[CsSite::Trace as usize].max_cycles, 40);
+        assert_eq!(report[CsSite::SharedPps as usize].max_cycles, 300);
+        assert_eq!(report[CsSite::RtBudget as usize].max_cycles, 0);
+
+        reset_critical_section_report();
+        assert!(critical_section_report().iter().all(|e| e.max_cycles == 0));
+    }
+}
@@ -1,10 +1,18 @@
 //! Code for GPIO pins
 
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
 /// All unlocked pin modes implement this
 pub trait IsUnlocked {}
 
+/// The unlocked modes other than `AlternateFunction`, which are the ones
+/// `into_alternate` accepts. A pin already serving an alternate function
+/// has to be given back with `release_alternate_function` before it can be
+/// muxed to another, so moving a pin between peripherals is never an
+/// accident.
+pub trait NotMuxed: IsUnlocked {}
+
 /// All input modes implement this
 pub trait InputMode {}
 
@@ -31,6 +39,7 @@ where
     _mode: PhantomData<MODE>,
 }
 impl<MODE> IsUnlocked for Input<MODE> where MODE: InputMode {}
+impl<MODE> NotMuxed for Input<MODE> where MODE: InputMode {}
 
 /// Sub-mode of Input: Floating input (type state)
 pub struct Floating;
@@ -59,6 +68,7 @@ impl OpenDrainMode for PullUp {
 /// Tri-state
 pub struct Tristate;
 impl IsUnlocked for Tristate {}
+impl NotMuxed for Tristate {}
 
 /// Output mode (type state)
 pub struct Output<MODE>
@@ -68,6 +78,7 @@ where
     _mode: PhantomData<MODE>,
 }
 impl<MODE> IsUnlocked for Output<MODE> where MODE: OutputMode {}
+impl<MODE> NotMuxed for Output<MODE> where MODE: OutputMode {}
 
 /// AlternateFunction mode (type state for a GPIO pin)
 pub struct AlternateFunction<AF, MODE>
@@ -102,6 +113,115 @@ where
 }
 impl<ODM> OutputMode for OpenDrain<ODM> where ODM: OpenDrainMode {}
 
+/// Pad settings for a pin handed to an alternate function. Implemented by
+/// the modes `into_alternate` accepts.
+pub trait AlternateMode: OutputMode {
+    /// Is the output open-drain
+    fn open_drain() -> bool;
+    /// Is the weak pull-up enabled
+    fn pull_up() -> bool;
+    /// Is the weak pull-down enabled
+    fn pull_down() -> bool;
+}
+
+impl AlternateMode for PushPull {
+    fn open_drain() -> bool {
+        false
+    }
+    fn pull_up() -> bool {
+        false
+    }
+    fn pull_down() -> bool {
+        false
+    }
+}
+
+impl AlternateMode for PullUp {
+    fn open_drain() -> bool {
+        false
+    }
+    fn pull_up() -> bool {
+        true
+    }
+    fn pull_down() -> bool {
+        false
+    }
+}
+
+impl AlternateMode for PullDown {
+    fn open_drain() -> bool {
+        false
+    }
+    fn pull_up() -> bool {
+        false
+    }
+    fn pull_down() -> bool {
+        true
+    }
+}
+
+impl<ODM> AlternateMode for OpenDrain<ODM>
+where
+    ODM: OpenDrainMode,
+{
+    fn open_drain() -> bool {
+        true
+    }
+    fn pull_up() -> bool {
+        ODM::pup()
+    }
+    fn pull_down() -> bool {
+        false
+    }
+}
+
+/// A pin that drivers can conjure up, or hand back as a plain input,
+/// without knowing which pin it is. Every unlocked pin implements it, as do
+/// the `()` and `NoMiso` placeholders. Behind the drivers' `recover_pins`
+/// and `free_into_inputs`.
+pub trait RecoverablePin: Sized {
+    /// The pin as a floating input, or the placeholder itself
+    type Input;
+
+    /// Makes a handle to the pin out of thin air, like a PAC's `steal`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may use the pin afterwards, including any driver that
+    /// was holding it.
+    unsafe fn steal() -> Self;
+
+    /// Disconnects the pin from any peripheral and makes it a floating
+    /// input
+    fn into_input(self) -> Self::Input;
+}
+
+impl RecoverablePin for () {
+    type Input = ();
+
+    unsafe fn steal() -> Self {}
+
+    fn into_input(self) -> Self::Input {}
+}
+
+/// Switches a pin between its alternate function and plain GPIO at run
+/// time, for drivers that have to take a line away from their peripheral
+/// for part of a transaction. The pin's type doesn't change, so the driver
+/// must call `alternate` again before handing the pin back.
+pub trait Remux {
+    /// Disconnects the pin from the peripheral and makes it a GPIO input
+    fn gpio_input(&mut self);
+    /// Disconnects the pin from the peripheral and makes it a GPIO output,
+    /// driving `high` from the start
+    fn gpio_output(&mut self, high: bool);
+    /// Drives the pin while it is a GPIO output
+    fn set_level(&mut self, high: bool);
+    /// Reads the level on the pin
+    fn level(&self) -> bool;
+    /// Gives the pin back to its alternate function
+    fn alternate(&mut self);
+}
+
 /// Alternate function 1 (type state)
 pub struct AF1;
 impl AlternateFunctionChoice for AF1 {
@@ -203,4 +323,116 @@ pub enum InterruptMode {
     Disabled,
 }
 
+/// Called by `PortDispatcher` for a pin with a pending interrupt, with the
+/// context word it was registered with
+pub type PinCallback = fn(context: usize);
+
+struct Slot {
+    /// A `PinCallback` as a `usize`, or zero for none
+    callback: AtomicUsize,
+    context: AtomicUsize,
+}
+
+// Only used to initialise `PortDispatcher::slots`, where each copy is meant
+// to be a fresh, independent slot
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    callback: AtomicUsize::new(0),
+    context: AtomicUsize::new(0),
+};
+
+/// Eight GPIO lines driven and sampled together, bit `n` on line `n`, such
+/// as a port's `DataPort`. The lines start out as outputs.
+pub trait ParallelPort {
+    /// Drives `value` onto the lines in a single write
+    fn write(&mut self, value: u8);
+
+    /// Turns the lines into inputs, so another device can drive them
+    fn release(&mut self);
+
+    /// Samples the lines in a single read
+    fn sample(&self) -> u8;
+
+    /// Turns the lines back into outputs
+    fn drive(&mut self);
+}
+
+/// Shares one port's interrupt between handlers for its individual pins.
+///
+/// Put one in a `static`, `register` a callback for each pin as you enable
+/// its interrupt, and call the port module's `dispatch` from the port's
+/// interrupt handler. Pins `0..PINS` can be registered.
+///
+/// Registration happens in thread context and dispatch in the handler,
+/// without a critical section: none of the methods mask interrupts. To
+/// replace a registration, `register` empties the slot first, then stores
+/// the context word and last the callback. A handler that runs halfway
+/// through finds either the old pair, an empty slot (counted as spurious),
+/// or the new pair, never one pin's callback with another registration's
+/// context word.
+pub struct PortDispatcher<const PINS: usize> {
+    slots: [Slot; PINS],
+    spurious: AtomicU32,
+}
+
+impl<const PINS: usize> PortDispatcher<PINS> {
+    /// A dispatcher with no callbacks registered
+    pub const fn new() -> Self {
+        PortDispatcher {
+            slots: [EMPTY_SLOT; PINS],
+            spurious: AtomicU32::new(0),
+        }
+    }
+
+    /// Runs `callback(context)` whenever pin `bit` interrupts. Replaces any
+    /// callback already registered for that pin.
+    pub fn register(&self, bit: u8, callback: PinCallback, context: usize) {
+        let slot = &self.slots[usize::from(bit)];
+        slot.callback.store(0, Ordering::Release);
+        slot.context.store(context, Ordering::Release);
+        slot.callback.store(callback as usize, Ordering::Release);
+    }
+
+    /// Removes pin `bit`'s callback. Later interrupts from it count as
+    /// spurious.
+    pub fn unregister(&self, bit: u8) {
+        let slot = &self.slots[usize::from(bit)];
+        slot.callback.store(0, Ordering::Release);
+    }
+
+    /// Runs the callback of every pin set in `pending`, lowest pin first.
+    /// Pins without a callback add to `spurious_count`. The chip crates'
+    /// per-port `dispatch` functions call this with the port's masked
+    /// interrupt status.
+    pub fn run(&self, pending: u32) {
+        let mut pending = pending;
+        while pending != 0 {
+            let bit = pending.trailing_zeros() as usize;
+            pending &= pending - 1;
+            let callback = self
+                .slots
+                .get(bit)
+                .map_or(0, |slot| slot.callback.load(Ordering::Acquire));
+            if callback == 0 {
+                self.spurious.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            // Only ever stored from a `PinCallback` in `register`
+            let callback: PinCallback = unsafe { core::mem::transmute(callback) };
+            callback(self.slots[bit].context.load(Ordering::Acquire));
+        }
+    }
+
+    /// How many pending pin interrupts had no callback to run
+    pub fn spurious_count(&self) -> u32 {
+        self.spurious.load(Ordering::Relaxed)
+    }
+}
+
+impl<const PINS: usize> Default for PortDispatcher<PINS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // End of file
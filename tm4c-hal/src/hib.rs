@@ -0,0 +1,649 @@
+//! Hibernation module code that is generic to both the TM4C123 and TM4C129
+
+use crate::sysctl::ClockMeasurement;
+use crate::time::Hertz;
+
+/// Number of battery-backed 32-bit words in HIBDATA
+pub const DATA_WORDS: usize = 16;
+
+/// Number of battery-backed words `MonotonicClock` keeps its epoch in
+pub const MONOTONIC_WORDS: usize = 5;
+
+/// Marks the epoch words as written by `MonotonicClock`
+const MONOTONIC_MAGIC: u32 = 0x4d4f_4e4f;
+
+/// `MonotonicClock` corrects by at most 1 ms in every `SLEW_DIVISOR` ms of
+/// uptime, about 3%, which is more than the PIOSC can be out by
+pub const SLEW_DIVISOR: u64 = 32;
+
+/// A clock further than this behind the RTC at `sync` jumps forward rather
+/// than slewing, since catching up would take `SLEW_DIVISOR` times as long
+pub const STEP_MS: u64 = 1_000;
+
+/// RTC sub-second ticks `measure_sysclk` counts over (1/32 second)
+pub const MEASURE_TICKS: u16 = 1024;
+
+/// The RTC didn't move while a measurement waited for it, so it isn't
+/// running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RtcStopped;
+
+/// HIBRTCT value that leaves the RTC untrimmed
+pub const TRIM_DEFAULT: u16 = 0x7FFF;
+
+/// RTC ticks per trim period. The trim value replaces the 0x7FFF reload of
+/// the sub-second counter once every 64 seconds, so one count moves the
+/// clock by one tick in 64 * 32768.
+const TRIM_PERIOD_TICKS: i64 = 64 * 32_768;
+
+/// The requested trim is beyond what HIBRTCT can correct (about ±15600 ppm)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimOutOfRange;
+
+/// How long the HIB driver waits for a register write to complete before
+/// giving up. The first writes after CLK32EN wait for the 32.768 kHz
+/// oscillator to start, which takes up to about 1.5 s for a crystal.
+pub const WRITE_TIMEOUT_MS: u32 = 2_000;
+
+/// System clock cycles the HIB driver waits between polls of WRC
+pub const WRITE_POLL_CYCLES: u32 = 1_000;
+
+/// A HIB register write didn't complete within `WRITE_TIMEOUT_MS`, so the
+/// 32.768 kHz clock isn't running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteTimeout;
+
+/// Why setting the RTC trim failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrimError {
+    /// The trim is beyond what HIBRTCT can correct
+    OutOfRange,
+    /// The write to HIBRTCT didn't complete
+    WriteTimeout,
+}
+
+impl From<TrimOutOfRange> for TrimError {
+    fn from(_: TrimOutOfRange) -> Self {
+        TrimError::OutOfRange
+    }
+}
+
+impl From<WriteTimeout> for TrimError {
+    fn from(_: WriteTimeout) -> Self {
+        TrimError::WriteTimeout
+    }
+}
+
+/// How many polls of WRC, `WRITE_POLL_CYCLES` apart, cover
+/// `WRITE_TIMEOUT_MS` at `sysclk`. Each poll also costs a register read,
+/// so the real wait runs a little longer.
+pub fn write_polls(sysclk: Hertz) -> u32 {
+    let cycles = u64::from(sysclk.0) * u64::from(WRITE_TIMEOUT_MS) / 1_000;
+    let step = u64::from(WRITE_POLL_CYCLES);
+    ((cycles + step - 1) / step).max(1) as u32
+}
+
+/// The HIBRTCT value that compensates for an RTC running `ppm` parts per
+/// million fast (positive) or slow (negative), to the nearest count of
+/// about 0.48 ppm
+pub fn trim_for_ppm(ppm: i32) -> Result<u16, TrimOutOfRange> {
+    // Round to the nearest count, away from zero at the halfway point
+    let scaled = i64::from(ppm) * TRIM_PERIOD_TICKS;
+    let counts = (scaled + 500_000 * scaled.signum()) / 1_000_000;
+    let trim = i64::from(TRIM_DEFAULT) + counts;
+    if trim < 0 || trim > 0xFFFF {
+        Err(TrimOutOfRange)
+    } else {
+        Ok(trim as u16)
+    }
+}
+
+/// What the clock code needs from a chip's hibernation module
+pub trait Hibernation {
+    /// The RTC, in milliseconds
+    fn rtc_millis(&self) -> u64;
+
+    /// The RTC's seconds count (RTCC)
+    fn rtc_seconds(&self) -> u32;
+
+    /// The RTC's sub-second count (RTCSSC), in 32768ths of a second
+    fn rtc_subseconds(&self) -> u16;
+
+    /// Reads battery-backed word `index`
+    fn read_data(&self, index: usize) -> u32;
+
+    /// Writes battery-backed word `index`
+    fn write_data(&mut self, index: usize, value: u32) -> Result<(), WriteTimeout>;
+}
+
+/// Measures the system clock against the 32.768 kHz RTC, by counting
+/// `cycles` (the core cycle counter, usually) over `MEASURE_TICKS` RTC
+/// ticks. Polling the RTC costs a few cycles of uncertainty, so the result
+/// is good to well under 0.1%, plus whatever error the 32.768 kHz source has.
+///
+/// The end is wherever the sub-second count has moved at least
+/// `MEASURE_TICKS` on, and the clock is worked out from the ticks that
+/// actually went by, so a poll held up past a tick doesn't throw it off.
+/// Each wait gives up after `expected` cycles, about a second if the clock
+/// is what `Clocks` says, and reports `RtcStopped`.
+pub fn measure_sysclk<H, C>(
+    hib: &H,
+    mut cycles: C,
+    expected: Hertz,
+) -> Result<ClockMeasurement, RtcStopped>
+where
+    H: Hibernation + ?Sized,
+    C: FnMut() -> u32,
+{
+    let limit = expected.0;
+
+    // Start on the edge of a tick
+    let first = hib.rtc_subseconds();
+    let waiting = cycles();
+    let start_tick = loop {
+        let tick = hib.rtc_subseconds();
+        let now = cycles();
+        if tick != first {
+            break tick;
+        }
+        if now.wrapping_sub(waiting) > limit {
+            return Err(RtcStopped);
+        }
+    };
+    let start = cycles();
+
+    loop {
+        // The sub-second count wraps at 32768
+        let ticks = hib.rtc_subseconds().wrapping_sub(start_tick) & 0x7FFF;
+        let elapsed = cycles().wrapping_sub(start);
+        if ticks >= MEASURE_TICKS {
+            let measured = u64::from(elapsed) * 32_768 / u64::from(ticks);
+            return Ok(ClockMeasurement {
+                measured: Hertz(measured as u32),
+                expected,
+            });
+        }
+        if elapsed > limit {
+            return Err(RtcStopped);
+        }
+    }
+}
+
+/// Times `seconds` RTC seconds with `cycles` (the core cycle counter,
+/// usually, running at `frequency`) and returns how many ppm fast
+/// (positive) or slow (negative) the RTC runs relative to it, ready for
+/// `trim_for_ppm`.
+///
+/// The end is the first reading at least `seconds` on from the start, so a
+/// poll held up past it finishes late rather than waiting for the count to
+/// come round again. Waiting for the first second to start gives up after
+/// two seconds' worth of cycles, and the whole measurement after `seconds`
+/// plus two, with `RtcStopped`.
+pub fn measure_drift<H, C>(
+    hib: &H,
+    mut cycles: C,
+    frequency: Hertz,
+    seconds: u32,
+) -> Result<i32, RtcStopped>
+where
+    H: Hibernation + ?Sized,
+    C: FnMut() -> u32,
+{
+    let frequency = u64::from(frequency.0);
+
+    // Sum as we go, as the cycle counter wraps every minute or so
+    let mut last = cycles();
+    let mut elapsed = 0u64;
+    let mut tick = |elapsed: &mut u64| {
+        let now = cycles();
+        *elapsed += u64::from(now.wrapping_sub(last));
+        last = now;
+    };
+
+    // Start on the edge of a second
+    let first = hib.rtc_seconds();
+    let start = loop {
+        let second = hib.rtc_seconds();
+        tick(&mut elapsed);
+        if second != first {
+            break second;
+        }
+        if elapsed > 2 * frequency {
+            return Err(RtcStopped);
+        }
+    };
+
+    let limit = (u64::from(seconds) + 2) * frequency;
+    elapsed = 0;
+    let counted = loop {
+        let counted = hib.rtc_seconds().wrapping_sub(start);
+        tick(&mut elapsed);
+        if counted >= seconds {
+            break counted;
+        }
+        if elapsed > limit {
+            return Err(RtcStopped);
+        }
+    };
+
+    let expected = frequency * u64::from(counted);
+    // A fast RTC finishes its seconds in fewer cycles
+    let ppm = (expected as i64 - elapsed as i64) * 1_000_000 / elapsed.max(1) as i64;
+    Ok(ppm as i32)
+}
+
+/// Milliseconds that keep counting up across hibernation and reset, for as
+/// long as VBAT holds.
+///
+/// Between calls to `sync` the clock runs from the application's uptime
+/// counter, which is fast to read; on the TM4C123 that is
+/// `TickSource::millis`. Each method takes the counter's current value.
+/// `sync` compares the clock with the RTC and slews it towards the RTC,
+/// by at most 1 ms in `SLEW_DIVISOR`, so it never jumps and never runs
+/// backwards. Only when it has fallen more than `STEP_MS` behind does it
+/// step, and then only forwards.
+///
+/// The RTC offset and the highest value handed out so far live in
+/// battery-backed memory. If the RTC comes back behind where the clock got
+/// to (VBAT glitch, RTC rewritten), the offset is raised at start-up to
+/// carry on from there.
+pub struct MonotonicClock<H> {
+    hib: H,
+    slot: usize,
+    /// Added to the RTC to give the time the clock is steering for
+    offset: u64,
+    /// The most recent value returned
+    last: u64,
+    /// The clock value and uptime at the last `sync`
+    anchor: u64,
+    anchor_uptime: u64,
+    /// Correction still to be slewed in, in ms; positive runs the clock fast
+    pending: i64,
+}
+
+impl<H> MonotonicClock<H>
+where
+    H: Hibernation,
+{
+    /// Starts the clock, keeping its epoch in battery-backed words `slot` to
+    /// `slot + MONOTONIC_WORDS - 1`. If those words were not written by a
+    /// previous `save` the clock simply follows the RTC.
+    pub fn new(hib: H, slot: usize, uptime_ms: u64) -> Self {
+        assert!(slot + MONOTONIC_WORDS <= DATA_WORDS);
+        let words = [
+            hib.read_data(slot),
+            hib.read_data(slot + 1),
+            hib.read_data(slot + 2),
+            hib.read_data(slot + 3),
+        ];
+        let check = words.iter().fold(MONOTONIC_MAGIC, |acc, w| acc ^ w);
+        let (offset, mark) = if hib.read_data(slot + 4) == check {
+            (
+                u64::from(words[0]) | (u64::from(words[1]) << 32),
+                u64::from(words[2]) | (u64::from(words[3]) << 32),
+            )
+        } else {
+            (0, 0)
+        };
+
+        let rtc = hib.rtc_millis();
+        let offset = if rtc + offset < mark {
+            mark - rtc
+        } else {
+            offset
+        };
+
+        MonotonicClock {
+            hib,
+            slot,
+            offset,
+            last: rtc + offset,
+            anchor: rtc + offset,
+            anchor_uptime: uptime_ms,
+            pending: 0,
+        }
+    }
+
+    /// Milliseconds since the epoch
+    pub fn now(&mut self, uptime_ms: u64) -> u64 {
+        let now = self.project(uptime_ms);
+        if now > self.last {
+            self.last = now;
+        }
+        self.last
+    }
+
+    /// Compares the clock with the RTC and sets it slewing towards it. Call
+    /// this every few seconds.
+    pub fn sync(&mut self, uptime_ms: u64) {
+        self.anchor = self.now(uptime_ms);
+        self.anchor_uptime = self.anchor_uptime.max(uptime_ms);
+
+        let target = self.hib.rtc_millis() + self.offset;
+        if target > self.anchor + STEP_MS {
+            self.anchor = target;
+            self.last = target;
+            self.pending = 0;
+        } else {
+            self.pending = target as i64 - self.anchor as i64;
+        }
+    }
+
+    /// Writes the epoch to battery-backed memory. Call this before
+    /// hibernating and from time to time while running; it costs a few
+    /// hundred microseconds of HIB write cycles. A write that doesn't
+    /// complete stops the save there, and the half-written epoch fails its
+    /// check at the next start-up.
+    pub fn save(&mut self, uptime_ms: u64) -> Result<(), WriteTimeout> {
+        let mark = self.now(uptime_ms);
+        let words = [
+            self.offset as u32,
+            (self.offset >> 32) as u32,
+            mark as u32,
+            (mark >> 32) as u32,
+        ];
+        let check = words.iter().fold(MONOTONIC_MAGIC, |acc, w| acc ^ w);
+        for (i, word) in words.iter().enumerate() {
+            self.hib.write_data(self.slot + i, *word)?;
+        }
+        self.hib.write_data(self.slot + 4, check)
+    }
+
+    /// Releases the hibernation module
+    pub fn free(self) -> H {
+        self.hib
+    }
+
+    /// The clock at `uptime_ms`, before the never-backwards clamp
+    fn project(&self, uptime_ms: u64) -> u64 {
+        let elapsed = uptime_ms.saturating_sub(self.anchor_uptime);
+        let limit = (elapsed / SLEW_DIVISOR) as i64;
+        let applied = self.pending.max(-limit).min(limit);
+        // `applied` is no more than elapsed / SLEW_DIVISOR below zero, so
+        // this stays at or above the anchor
+        let now = (self.anchor + elapsed) as i64 + applied;
+        now as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct FakeHib {
+        rtc: u64,
+        data: [u32; DATA_WORDS],
+    }
+
+    impl Hibernation for FakeHib {
+        fn rtc_millis(&self) -> u64 {
+            self.rtc
+        }
+
+        fn rtc_seconds(&self) -> u32 {
+            (self.rtc / 1_000) as u32
+        }
+
+        fn rtc_subseconds(&self) -> u16 {
+            (self.rtc * 32_768 / 1_000) as u16 & 0x7FFF
+        }
+
+        fn read_data(&self, index: usize) -> u32 {
+            self.data[index]
+        }
+
+        fn write_data(&mut self, index: usize, value: u32) -> Result<(), WriteTimeout> {
+            self.data[index] = value;
+            Ok(())
+        }
+    }
+
+    /// An RTC running off a simulated system clock, which moves on by
+    /// `poll_cycles` each time the RTC is read and by 3 each time the cycle
+    /// counter is
+    struct RunningRtc {
+        cycle: Cell<u64>,
+        sysclk: u64,
+        poll_cycles: u64,
+        running: bool,
+        /// How fast the RTC runs, in ppm
+        rtc_ppm: i64,
+    }
+
+    impl RunningRtc {
+        fn new(sysclk: u32, start_tick: u64) -> Self {
+            RunningRtc {
+                cycle: Cell::new(start_tick * u64::from(sysclk) / 32_768),
+                sysclk: u64::from(sysclk),
+                poll_cycles: 5,
+                running: true,
+                rtc_ppm: 0,
+            }
+        }
+
+        /// RTC ticks so far, each RTC read having taken `poll_cycles`
+        fn rtc_ticks(&self) -> u64 {
+            self.cycle.set(self.cycle.get() + self.poll_cycles);
+            if !self.running {
+                return 0x1234;
+            }
+            let rate = 32_768 * (1_000_000 + self.rtc_ppm) as u128;
+            (u128::from(self.cycle.get()) * rate / (u128::from(self.sysclk) * 1_000_000)) as u64
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycle.set(self.cycle.get() + 3);
+            self.cycle.get() as u32
+        }
+    }
+
+    impl Hibernation for RunningRtc {
+        fn rtc_millis(&self) -> u64 {
+            0
+        }
+
+        fn rtc_seconds(&self) -> u32 {
+            (self.rtc_ticks() / 32_768) as u32
+        }
+
+        fn rtc_subseconds(&self) -> u16 {
+            self.rtc_ticks() as u16 & 0x7FFF
+        }
+
+        fn read_data(&self, _: usize) -> u32 {
+            0
+        }
+
+        fn write_data(&mut self, _: usize, _: u32) -> Result<(), WriteTimeout> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn measures_the_system_clock() {
+        let rtc = RunningRtc::new(80_000_000, 100);
+        let measurement = measure_sysclk(&rtc, || rtc.cycles(), Hertz(80_000_000)).unwrap();
+        assert!(measurement.within_ppm(100));
+        // A clock running 1% slow
+        let rtc = RunningRtc::new(79_200_000, 100);
+        let measurement = measure_sysclk(&rtc, || rtc.cycles(), Hertz(80_000_000)).unwrap();
+        assert!((measurement.deviation_ppm() + 10_000).abs() < 100);
+    }
+
+    #[test]
+    fn measures_across_the_sub_second_wrap() {
+        let rtc = RunningRtc::new(16_000_000, 0x7F00);
+        let measurement = measure_sysclk(&rtc, || rtc.cycles(), Hertz(16_000_000)).unwrap();
+        assert!(measurement.within_ppm(500));
+    }
+
+    #[test]
+    fn slow_polls_that_skip_ticks_still_finish() {
+        // Each RTC read takes about four ticks, so the exact end tick is
+        // never seen
+        let mut rtc = RunningRtc::new(80_000_000, 10);
+        rtc.poll_cycles = 10_000;
+        let measurement = measure_sysclk(&rtc, || rtc.cycles(), Hertz(80_000_000)).unwrap();
+        assert!(measurement.within_ppm(5_000));
+    }
+
+    #[test]
+    fn a_stopped_rtc_is_reported() {
+        let mut rtc = RunningRtc::new(16_000_000, 10);
+        rtc.running = false;
+        assert_eq!(
+            measure_sysclk(&rtc, || rtc.cycles(), Hertz(16_000_000)).err(),
+            Some(RtcStopped)
+        );
+    }
+
+    #[test]
+    fn measures_drift_either_way() {
+        for &ppm in [0i64, 100, -250].iter() {
+            let mut rtc = RunningRtc::new(1_000_000, 1_000);
+            rtc.rtc_ppm = ppm;
+            let drift = measure_drift(&rtc, || rtc.cycles(), Hertz(1_000_000), 4).unwrap();
+            assert!((i64::from(drift) - ppm).abs() <= 3, "{} for {}", drift, ppm);
+        }
+    }
+
+    #[test]
+    fn drift_measurement_survives_a_late_poll() {
+        // A poll held up for 1.5 s steps right over the last second
+        let mut rtc = RunningRtc::new(1_000_000, 1_000);
+        rtc.poll_cycles = 1_500_000;
+        assert!(measure_drift(&rtc, || rtc.cycles(), Hertz(1_000_000), 3).is_ok());
+    }
+
+    #[test]
+    fn drift_measurement_gives_up_on_a_stopped_rtc() {
+        let mut rtc = RunningRtc::new(1_000_000, 1_000);
+        rtc.running = false;
+        assert_eq!(
+            measure_drift(&rtc, || rtc.cycles(), Hertz(1_000_000), 4),
+            Err(RtcStopped)
+        );
+    }
+
+    #[test]
+    fn trim_counts_round_to_the_nearest() {
+        assert_eq!(trim_for_ppm(0), Ok(TRIM_DEFAULT));
+        // One count is 1 / (64 * 32768), about 0.477 ppm
+        assert_eq!(trim_for_ppm(1), Ok(0x8001));
+        assert_eq!(trim_for_ppm(-1), Ok(0x7FFD));
+        assert_eq!(trim_for_ppm(100), Ok(0x7FFF + 210));
+        assert_eq!(trim_for_ppm(-100), Ok(0x7FFF - 210));
+    }
+
+    #[test]
+    fn trim_stops_at_the_register_limits() {
+        assert_eq!(trim_for_ppm(15_625), Ok(0xFFFF));
+        assert_eq!(trim_for_ppm(15_626), Err(TrimOutOfRange));
+        assert_eq!(trim_for_ppm(-15_624), Ok(1));
+        assert_eq!(trim_for_ppm(-15_626), Err(TrimOutOfRange));
+        assert_eq!(trim_for_ppm(i32::min_value()), Err(TrimOutOfRange));
+    }
+
+    #[test]
+    fn write_polls_cover_a_crystal_starting() {
+        for &sysclk in [16_000_000, 80_000_000, 120_000_000].iter() {
+            let polls = write_polls(Hertz(sysclk));
+            let cycles = u64::from(polls) * u64::from(WRITE_POLL_CYCLES);
+            // Past the 1.5 s a 32.768 kHz crystal may take to start
+            assert!(cycles * 2 >= u64::from(sysclk) * 3, "{}", sysclk);
+            assert!(cycles - u64::from(WRITE_POLL_CYCLES) < u64::from(sysclk) * 2);
+        }
+        assert_eq!(write_polls(Hertz(80_000_000)), 160_000);
+        assert_eq!(write_polls(Hertz(1)), 1);
+    }
+
+    fn clock(rtc: u64) -> MonotonicClock<FakeHib> {
+        MonotonicClock::new(
+            FakeHib {
+                rtc,
+                data: [0; DATA_WORDS],
+            },
+            2,
+            0,
+        )
+    }
+
+    #[test]
+    fn runs_from_uptime_between_syncs() {
+        let mut clock = clock(5_000);
+        // The RTC isn't read until the next sync
+        clock.hib.rtc = 0;
+        assert_eq!(clock.now(250), 5_250);
+    }
+
+    #[test]
+    fn slews_forward_to_a_fast_rtc() {
+        let mut clock = clock(0);
+        clock.hib.rtc = 1_010;
+        clock.sync(1_000);
+        assert_eq!(clock.now(1_000), 1_000);
+        assert_eq!(clock.now(1_064), 1_066);
+        assert_eq!(clock.now(1_320), 1_330);
+        // Caught up; from here it runs at the uptime's rate
+        assert_eq!(clock.now(2_000), 2_010);
+    }
+
+    #[test]
+    fn slews_back_to_a_slow_rtc_without_going_backwards() {
+        let mut clock = clock(0);
+        clock.hib.rtc = 990;
+        clock.sync(1_000);
+        let mut previous = 0;
+        for uptime in 1_000..=1_320 {
+            let now = clock.now(uptime);
+            assert!(now >= previous);
+            previous = now;
+        }
+        assert_eq!(previous, 1_310);
+        assert_eq!(clock.now(2_000), 1_990);
+    }
+
+    #[test]
+    fn steps_forward_when_far_behind() {
+        let mut clock = clock(0);
+        clock.hib.rtc = 60_000;
+        clock.sync(1_000);
+        assert_eq!(clock.now(1_000), 60_000);
+        assert_eq!(clock.now(1_500), 60_500);
+    }
+
+    #[test]
+    fn never_steps_back_when_far_ahead() {
+        let mut clock = clock(10_000);
+        clock.hib.rtc = 0;
+        clock.sync(100);
+        assert_eq!(clock.now(100), 10_100);
+        assert_eq!(clock.now(420), 10_410);
+    }
+
+    #[test]
+    fn carries_on_from_the_saved_mark() {
+        let mut clock = clock(10_000);
+        clock.save(5_000).unwrap();
+        let mut hib = clock.free();
+        // The RTC came back from hibernation behind where the clock got to
+        hib.rtc = 2_000;
+        let mut clock = MonotonicClock::new(hib, 2, 0);
+        assert_eq!(clock.now(0), 15_000);
+        clock.sync(100);
+        assert_eq!(clock.now(100), 15_100);
+    }
+
+    #[test]
+    fn ignores_words_it_did_not_write() {
+        let mut hib = FakeHib {
+            rtc: 3_000,
+            data: [0; DATA_WORDS],
+        };
+        hib.data[2] = 0xFFFF_FFFF;
+        hib.data[6] = 0x1234;
+        let mut clock = MonotonicClock::new(hib, 2, 0);
+        assert_eq!(clock.now(0), 3_000);
+    }
+}
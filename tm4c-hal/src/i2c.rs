@@ -1,4 +1,12 @@
 //! Common I2C code for TM4C123 and TM4C129
+//!
+//! The master's transfer sequencing, `write`, `read` and `write_read`, is
+//! written against `regs::RegisterAccess` so that it can be tested on the
+//! host. The chip crates' drivers call it through `regs::Mmio`.
+
+use crate::poll::{poll_until, poll_until_deadline, TimeoutExpired};
+use crate::regs::RegisterAccess;
+use crate::time::{Hertz, Instant};
 
 /// I2C error
 #[derive(Debug)]
@@ -18,10 +26,116 @@ pub enum Error {
     /// I2C Timeout
     Timeout,
 
+    /// A slave held SCL low for longer than the clock-low timeout (see
+    /// `SclLowTimeout`). The transfer was aborted with a STOP.
+    ClockLowTimeout,
+
     #[doc(hidden)]
     _Extensible,
 }
 
+// FIXME these should be "closed" traits
+/// SCL pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait SclPin<I2C> {}
+
+/// SDA pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait SdaPin<I2C> {}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A register value `read_reg` and friends can move: `u8`, `u16` or `u32`.
+/// The trait is sealed, as the register helpers have room for at most four
+/// bytes.
+pub trait RegisterWidth: Copy + private::Sealed {
+    /// Size of the register in bytes
+    const BYTES: usize;
+
+    /// Decodes the register from `BYTES` bytes as sent by the device
+    fn from_bytes(bytes: &[u8], big_endian: bool) -> Self;
+
+    /// Encodes the register into `BYTES` bytes for sending to the device
+    fn to_bytes(self, bytes: &mut [u8], big_endian: bool);
+}
+
+macro_rules! register_width {
+    ($($T:ty),+) => {
+        $(
+            impl private::Sealed for $T {}
+
+            impl RegisterWidth for $T {
+                const BYTES: usize = core::mem::size_of::<$T>();
+
+                fn from_bytes(bytes: &[u8], big_endian: bool) -> Self {
+                    let mut raw = [0u8; core::mem::size_of::<$T>()];
+                    raw.copy_from_slice(bytes);
+                    if big_endian {
+                        <$T>::from_be_bytes(raw)
+                    } else {
+                        <$T>::from_le_bytes(raw)
+                    }
+                }
+
+                fn to_bytes(self, bytes: &mut [u8], big_endian: bool) {
+                    if big_endian {
+                        bytes.copy_from_slice(&self.to_be_bytes());
+                    } else {
+                        bytes.copy_from_slice(&self.to_le_bytes());
+                    }
+                }
+            }
+        )+
+    };
+}
+
+register_width!(u8, u16, u32);
+
+/// How long a slave may stretch SCL low before the master's clock-low
+/// timeout fires and the transfer fails with `Error::ClockLowTimeout`. The
+/// counter runs in units of 16 I2C clock periods.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SclLowTimeout {
+    cntl: u8,
+}
+
+impl SclLowTimeout {
+    /// The shortest timeout, 32 I2C clock periods
+    pub const MIN: SclLowTimeout = SclLowTimeout { cntl: 2 };
+
+    /// The longest timeout, 4080 I2C clock periods
+    pub const MAX: SclLowTimeout = SclLowTimeout { cntl: 0xFF };
+
+    /// What the drivers start with: 992 I2C clock periods, about 10 ms at
+    /// 100 kHz
+    pub const DEFAULT: SclLowTimeout = SclLowTimeout { cntl: 62 };
+
+    /// `periods` I2C clock periods, rounded down to a multiple of 16 and
+    /// clamped between `MIN` and `MAX`
+    pub fn periods(periods: u32) -> Self {
+        SclLowTimeout {
+            cntl: (periods >> 4).max(2).min(0xFF) as u8,
+        }
+    }
+
+    /// `ms` milliseconds with the bus running at `scl`, within the same
+    /// limits as `periods`
+    pub fn from_ms(ms: u32, scl: Hertz) -> Self {
+        let periods = u64::from(ms) * u64::from(scl.0) / 1_000;
+        Self::periods(periods.min(u64::from(u32::max_value())) as u32)
+    }
+
+    /// The timeout in I2C clock periods
+    pub fn as_periods(self) -> u32 {
+        u32::from(self.cntl) << 4
+    }
+
+    /// The value for the CNTL field of MCLKOCNT
+    pub fn cntl(self) -> u8 {
+        self.cntl
+    }
+}
+
 #[macro_export]
 /// Implements the traits for an I2C peripheral
 macro_rules! i2c_pins {
@@ -45,63 +159,306 @@ macro_rules! i2c_pins {
     }
 }
 
-#[macro_export]
-/// Spins until the controler is ready (mcs.busy is clear) and optionally on
-/// another field of the mcs register until it is clear or set (depending on op
-/// parameter).
-macro_rules! i2c_busy_wait {
-    ($i2c:expr $(, $field:ident, $op:ident)? ) => {{
-        // in 'release' builds, the time between setting the `run` bit and checking the `busy`
-        // bit is too short and the `busy` bit is not reliably set by the time you get there,
-        // it can take up to 8 clock cycles for the `run` to begin so this delay allows time
-        // for that hardware synchronization
-        delay(8);
-
-        // Allow 1,000 clock cycles before we timeout. At 100 kHz, this is 10 ms.
-        $i2c.mclkocnt
-            .write(|w| unsafe { w.cntl().bits((1_000 >> 4) as u8) });
-
-        let mcs = loop {
-            let mcs = $i2c.mcs.read();
-
-            if mcs.busy().bit_is_clear() {
-                break mcs;
-            }
-        };
-
-
-        if mcs.clkto().bit_is_set() {
-            return Err(Error::Timeout)
-        } else if mcs.arblst().bit_is_set() {
-            return Err(Error::Arbitration)
-        } else if mcs.error().bit_is_set() {
-            if mcs.adrack().bit_is_set() {
-                return Err(Error::AdrAck);
-            } else { // if mcs.datack().bit_is_set() {
-                return Err(Error::DataAck);
-            }
+/// How many times the I2C drivers poll the controller's status when no
+/// deadline is given, before giving up with `Error::Timeout`. At 120 MHz
+/// this is over 50 ms, several bytes even at 10 kHz.
+pub const BUSY_POLLS: u32 = 1_000_000;
+
+/// Why `I2c::reclock` left the bus speed as it was
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReclockError {
+    /// The speed can't be made at the new system clock
+    Rate(crate::calc::RateError),
+    /// A transfer was still running after `BUSY_POLLS` polls
+    Busy,
+}
+
+impl From<crate::calc::RateError> for ReclockError {
+    fn from(e: crate::calc::RateError) -> Self {
+        ReclockError::Rate(e)
+    }
+}
+
+/// Register offsets of the I2C master, the same on both families
+const MSA: usize = 0x000;
+const MCS: usize = 0x004;
+const MDR: usize = 0x008;
+const MICR: usize = 0x01C;
+
+/// MCS bits as read: the controller's status
+const MCS_BUSY: u32 = 1 << 0;
+const MCS_ERROR: u32 = 1 << 1;
+const MCS_ADRACK: u32 = 1 << 2;
+const MCS_ARBLST: u32 = 1 << 4;
+const MCS_BUSBSY: u32 = 1 << 6;
+const MCS_CLKTO: u32 = 1 << 7;
+
+/// MCS bits as written: the next command
+const CMD_RUN: u32 = 1 << 0;
+const CMD_START: u32 = 1 << 1;
+const CMD_STOP: u32 = 1 << 2;
+const CMD_ACK: u32 = 1 << 3;
+
+/// MICR.CLKIC, which clears the clock-low timeout
+const MICR_CLKIC: u32 = 1 << 1;
+
+fn poll<F: FnMut() -> bool>(deadline: Option<Instant>, condition: F) -> Result<(), TimeoutExpired> {
+    match deadline {
+        Some(d) => poll_until_deadline(d, condition),
+        None => poll_until(BUSY_POLLS, condition),
+    }
+}
+
+/// Turns the status left by a command into an error, if it failed. After a
+/// NAK the controller still owns the bus, so it is released with a STOP, or
+/// the next transfer would find it busy for ever. A transfer cut off by the
+/// clock-low timeout gets a STOP too, and the timeout is cleared, as the
+/// datasheet asks.
+fn check<R: RegisterAccess>(regs: &R, mcs: u32) -> Result<(), Error> {
+    if mcs & MCS_CLKTO != 0 {
+        regs.write(MCS, CMD_STOP);
+        regs.write(MICR, MICR_CLKIC);
+        Err(Error::ClockLowTimeout)
+    } else if mcs & MCS_ARBLST != 0 {
+        Err(Error::Arbitration)
+    } else if mcs & MCS_ERROR != 0 {
+        if mcs & MCS_BUSBSY != 0 {
+            regs.write(MCS, CMD_STOP);
         }
+        if mcs & MCS_ADRACK != 0 {
+            Err(Error::AdrAck)
+        } else {
+            Err(Error::DataAck)
+        }
+    } else {
+        Ok(())
+    }
+}
 
-        $( loop {
-            if mcs.clkto().bit_is_set() {
-                return Err(Error::Timeout)
-            } else if mcs.arblst().bit_is_set() {
-                return Err(Error::Arbitration)
-            } else if mcs.error().bit_is_set() {
-                if mcs.adrack().bit_is_set() {
-                    return Err(Error::AdrAck);
-                } else { // if mcs.datack().bit_is_set() {
-                    return Err(Error::DataAck);
-                }
-            } else if mcs.$field().$op() {
-                break;
-            } else {
-                // try again
+/// Spins until the controller has finished the last command. Gives up with
+/// `Error::Timeout` once `deadline` has passed, or after `BUSY_POLLS` polls
+/// without one, and with `Error::ClockLowTimeout` if the hardware clock-low
+/// timeout fires.
+///
+/// With `bus_idle` set this is the wait before a START instead: it waits
+/// until neither the controller nor another master is using the bus. The
+/// error bits then still describe the last transaction, which reported
+/// them already, so they are ignored; otherwise one NAK would fail every
+/// transfer after it.
+pub fn wait<R: RegisterAccess>(
+    regs: &R,
+    deadline: Option<Instant>,
+    bus_idle: bool,
+) -> Result<(), Error> {
+    if bus_idle {
+        return poll(deadline, || regs.read(MCS) & (MCS_BUSY | MCS_BUSBSY) == 0)
+            .map_err(|_| Error::Timeout);
+    }
+
+    // The controller can take up to 8 clock cycles to set BUSY after a
+    // command is written, so don't look before then
+    regs.settle(8);
+
+    // A slave stretching SCL keeps the controller busy, so stop waiting as
+    // soon as the clock-low timeout fires too
+    let mut mcs = regs.read(MCS);
+    let polled = poll(deadline, || {
+        mcs = regs.read(MCS);
+        mcs & (MCS_BUSY | MCS_CLKTO) != MCS_BUSY
+    });
+    if polled.is_err() {
+        // Leave the bus idle for whoever comes next
+        regs.write(MCS, CMD_STOP);
+        return Err(Error::Timeout);
+    }
+    regs.trace_read(MCS);
+    check(regs, mcs)
+}
+
+/// Writes `bytes` to the device at `addr`, in one transaction ending in a
+/// STOP. `bytes` must not be empty.
+pub fn write<R: RegisterAccess>(
+    regs: &R,
+    addr: u8,
+    bytes: &[u8],
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    // Slave address, with the Receive bit clear, and the first byte
+    regs.write(MSA, u32::from(addr) << 1);
+    regs.write(MDR, u32::from(bytes[0]));
+
+    wait(regs, deadline, true)?;
+
+    // START + RUN, with STOP too if it is the only byte
+    let last = bytes.len() - 1;
+    let stop = if last == 0 { CMD_STOP } else { 0 };
+    regs.write(MCS, CMD_START | CMD_RUN | stop);
+
+    for (i, byte) in bytes[1..].iter().enumerate() {
+        wait(regs, deadline, false)?;
+        regs.write(MDR, u32::from(*byte));
+        // RUN to continue the burst, with STOP on the last byte
+        regs.write(MCS, CMD_RUN | if i + 1 == last { CMD_STOP } else { 0 });
+    }
+
+    wait(regs, deadline, false)
+}
+
+/// Fills `buffer`, which must not be empty, from the slave address already
+/// in MSA with the Receive bit set. The first command carries a START, which
+/// is a repeated START if the controller still holds the bus from a write.
+fn receive<R: RegisterAccess>(
+    regs: &R,
+    buffer: &mut [u8],
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    let last = buffer.len() - 1;
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        // START on the first byte, then ACK every byte but the last, which
+        // gets a NAK and a STOP instead
+        let mut command = CMD_RUN;
+        if i == 0 {
+            command |= CMD_START;
+        }
+        command |= if i == last { CMD_STOP } else { CMD_ACK };
+        regs.write(MCS, command);
+        wait(regs, deadline, false)?;
+        *byte = regs.read(MDR) as u8;
+    }
+    Ok(())
+}
+
+/// Fills `buffer` from the device at `addr`, in one transaction ending in a
+/// STOP. `buffer` must not be empty.
+pub fn read<R: RegisterAccess>(
+    regs: &R,
+    addr: u8,
+    buffer: &mut [u8],
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    // Slave address, with the Receive bit set
+    regs.write(MSA, (u32::from(addr) << 1) | 1);
+
+    wait(regs, deadline, true)?;
+
+    receive(regs, buffer, deadline)
+}
+
+/// Checks whether a device answers at `addr`, by reading one byte from it.
+/// Only a NAK of the address means nothing is there; any other failure is a
+/// bus fault and is passed up. The bus is released either way.
+pub fn probe<R: RegisterAccess>(
+    regs: &R,
+    addr: u8,
+    deadline: Option<Instant>,
+) -> Result<bool, Error> {
+    match read(regs, addr, &mut [0], deadline) {
+        Ok(()) => Ok(true),
+        Err(Error::AdrAck) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes `bytes` to the device at `addr`, then fills `buffer` from it
+/// after a repeated START. Neither may be empty.
+pub fn write_read<R: RegisterAccess>(
+    regs: &R,
+    addr: u8,
+    bytes: &[u8],
+    buffer: &mut [u8],
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    // Slave address, with the Receive bit clear, and the first byte
+    regs.write(MSA, u32::from(addr) << 1);
+    regs.write(MDR, u32::from(bytes[0]));
+
+    wait(regs, deadline, true)?;
+    regs.write(MCS, CMD_START | CMD_RUN);
+    wait(regs, deadline, false)?;
+
+    for byte in bytes[1..].iter() {
+        regs.write(MDR, u32::from(*byte));
+        regs.write(MCS, CMD_RUN);
+        wait(regs, deadline, false)?;
+    }
+
+    // Slave address again, with the Receive bit set, for the repeated START
+    regs.write(MSA, (u32::from(addr) << 1) | 1);
+
+    receive(regs, buffer, deadline)
+}
+
+/// A write moved along by `poll` instead of waited on, from the drivers'
+/// `write_nb`. Each `poll` that finds the controller idle hands it the next
+/// byte, so a cooperative scheduler can run other work in between.
+pub struct WriteNb<'a, R> {
+    regs: R,
+    addr: u8,
+    bytes: &'a [u8],
+    /// Bytes handed to the controller so far
+    sent: usize,
+}
+
+impl<'a, R: RegisterAccess> WriteNb<'a, R> {
+    /// A write of `bytes`, which must not be empty, to the device at
+    /// `addr`. Nothing goes on the bus until the first `poll`.
+    pub fn new(regs: R, addr: u8, bytes: &'a [u8]) -> Self {
+        assert!(!bytes.is_empty());
+        WriteNb {
+            regs,
+            addr,
+            bytes,
+            sent: 0,
+        }
+    }
+
+    /// Moves the write on if the controller is ready for it. `Ok` once the
+    /// last byte has gone and the STOP with it. After an error the bus has
+    /// been released, as for the blocking calls.
+    pub fn poll(&mut self) -> nb::Result<(), Error> {
+        let regs = &self.regs;
+        let mcs = regs.read(MCS);
+        if self.sent == 0 {
+            // Wait for the bus as well as the controller before starting
+            if mcs & (MCS_BUSY | MCS_BUSBSY) != 0 {
+                return Err(nb::Error::WouldBlock);
             }
-        };)?
+            regs.write(MSA, u32::from(self.addr) << 1);
+            regs.write(MDR, u32::from(self.bytes[0]));
+            let stop = if self.bytes.len() == 1 { CMD_STOP } else { 0 };
+            regs.write(MCS, CMD_START | CMD_RUN | stop);
+        } else {
+            if mcs & (MCS_BUSY | MCS_CLKTO) == MCS_BUSY {
+                return Err(nb::Error::WouldBlock);
+            }
+            regs.trace_read(MCS);
+            check(regs, mcs).map_err(nb::Error::Other)?;
+            if self.sent == self.bytes.len() {
+                return Ok(());
+            }
+            regs.write(MDR, u32::from(self.bytes[self.sent]));
+            let stop = if self.sent + 1 == self.bytes.len() {
+                CMD_STOP
+            } else {
+                0
+            };
+            regs.write(MCS, CMD_RUN | stop);
+        }
+        self.sent += 1;
+        // BUSY takes up to 8 cycles to show, so the next poll mustn't look
+        // before then
+        regs.settle(8);
+        Err(nb::Error::WouldBlock)
+    }
 
-        Ok(())
-    }};
+    /// Gives up on the write, with a STOP if it had started, leaving the
+    /// controller idle. Use this when a deadline passes before `poll`
+    /// returns `Ok`.
+    pub fn abort(self) {
+        if self.sent > 0 {
+            self.regs.write(MCS, CMD_STOP);
+        }
+    }
 }
 
 #[macro_export]
@@ -111,6 +468,11 @@ macro_rules! i2c_hal {
         $(
             impl<SCL, SDA> I2c<$I2CX, (SCL, SDA)> {
                 /// Configures the I2C peripheral to work in master mode
+                ///
+                /// # Panics
+                ///
+                /// If `freq` is faster than a twentieth of the system clock,
+                /// or too slow for the seven-bit timer period.
                 pub fn $i2cX<F>(
                     i2c: $I2CX,
                     pins: (SCL, SDA),
@@ -122,240 +484,734 @@ macro_rules! i2c_hal {
                     SCL: SclPin<$I2CX>,
                     SDA: SdaPin<$I2CX>,
                 {
+                    clocks.assert_current();
+
                     sysctl::control_power(
                         pc, sysctl::Domain::$powerDomain,
                         sysctl::RunMode::Run, sysctl::PowerState::On);
-                    sysctl::reset(pc, sysctl::Domain::$powerDomain);
+                    sysctl::reset(pc, sysctl::Domain::$powerDomain)
+                        .expect("I2C didn't come out of reset");
 
                     // set Master Function Enable, and clear other bits.
                     i2c.mcr.write(|w| w.mfe().set_bit());
+                    $crate::trace_access!(i2c, mcr, Write);
 
                     // Write TimerPeriod configuration and clear other bits.
                     let freq = freq.into().0;
-                    let tpr = ((clocks.sysclk.0/(2*10*freq))-1) as u8;
+                    let tpr = $crate::calc::i2c_tpr(clocks.sysclk.0, freq)
+                        .expect("I2C bus speed out of range for the system clock");
 
                     i2c.mtpr.write(|w| unsafe {w.tpr().bits(tpr)});
+                    $crate::trace_access!(i2c, mtpr, Write);
+
+                    let cntl = $crate::i2c::SclLowTimeout::DEFAULT.cntl();
+                    i2c.mclkocnt.write(|w| unsafe { w.cntl().bits(cntl) });
+                    $crate::trace_access!(i2c, mclkocnt, Write);
 
                     I2c { i2c, pins }
                 }
 
-                /// Releases the I2C peripheral and associated pins
-                pub fn free(self) -> ($I2CX, (SCL, SDA)) {
-                    (self.i2c, self.pins)
+                /// Sets the bus speed again, for `freq` at the system clock
+                /// in `clocks`, such as after `sysctl::reconfigure`. Waits
+                /// for any transfer in progress to finish first. If `freq`
+                /// can't be made at this clock, or the transfer doesn't
+                /// finish, the speed is left as it was.
+                pub fn reclock<F>(
+                    &mut self,
+                    freq: F,
+                    clocks: &Clocks,
+                ) -> Result<(), $crate::i2c::ReclockError> where F: Into<Hertz> {
+                    clocks.assert_current();
+
+                    let tpr = $crate::calc::i2c_tpr(clocks.sysclk.0, freq.into().0)?;
+
+                    $crate::poll::poll_until($crate::i2c::BUSY_POLLS, || {
+                        self.i2c.mcs.read().busy().bit_is_clear()
+                    })
+                    .map_err(|_| $crate::i2c::ReclockError::Busy)?;
+
+                    self.i2c.mtpr.write(|w| unsafe {w.tpr().bits(tpr)});
+                    $crate::trace_access!(self.i2c, mtpr, Write);
+                    Ok(())
                 }
-            }
 
-            impl<PINS> Write for I2c<$I2CX, PINS> {
-                type Error = Error;
+                /// Sets how long a slave may hold SCL low before a transfer
+                /// gives up with `Error::ClockLowTimeout`. The default is
+                /// `SclLowTimeout::DEFAULT`.
+                pub fn set_clock_low_timeout(&mut self, timeout: $crate::i2c::SclLowTimeout) {
+                    self.i2c.mclkocnt.write(|w| unsafe { w.cntl().bits(timeout.cntl()) });
+                    $crate::trace_access!(self.i2c, mclkocnt, Write);
+                }
 
-                fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
-                    // Write Slave address and clear Receive bit
-                    self.i2c.msa.write(|w| unsafe {
-                        w.sa().bits(addr)
-                    });
+                /// True if the clock-low timeout has fired since it was
+                /// last cleared. The blocking transfers clear it themselves.
+                pub fn clock_low_timed_out(&self) -> bool {
+                    self.i2c.mris.read().clkris().bit_is_set()
+                }
 
-                    // Put first byte in data register
-                    self.i2c.mdr.write(|w| unsafe {
-                        w.data().bits(bytes[0])
-                    });
+                /// Clears the clock-low timeout flag. An interrupt handler
+                /// should do this, and send a STOP, when it fires.
+                pub fn clear_clock_low_timeout(&mut self) {
+                    self.i2c.micr.write(|w| w.clkic().set_bit());
+                    $crate::trace_access!(self.i2c, micr, Write);
+                }
 
-                    let sz = bytes.len();
+                /// Raises the I2C interrupt when the clock-low timeout fires
+                pub fn listen_clock_low_timeout(&mut self) {
+                    self.i2c.mimr.modify(|_, w| w.clkim().set_bit());
+                    $crate::trace_access!(self.i2c, mimr, Write);
+                }
 
-                    i2c_busy_wait!(self.i2c, busbsy, bit_is_clear)?;
+                /// Stops raising the I2C interrupt for the clock-low timeout
+                pub fn unlisten_clock_low_timeout(&mut self) {
+                    self.i2c.mimr.modify(|_, w| w.clkim().clear_bit());
+                    $crate::trace_access!(self.i2c, mimr, Write);
+                }
 
-                    // Send START + RUN
-                    // If single byte transfer, set STOP
-                    self.i2c.mcs.write(|w| {
-                        if sz == 1 {
-                            w.stop().set_bit();
-                        }
-                        w.start().set_bit()
-                            .run().set_bit()
+                /// Releases the I2C peripheral and associated pins. The master
+                /// is disabled once any transfer in progress has finished.
+                pub fn free(self) -> ($I2CX, (SCL, SDA)) {
+                    // A wedged transfer is abandoned rather than waited on
+                    // for ever
+                    let _ = $crate::poll::poll_until($crate::i2c::BUSY_POLLS, || {
+                        self.i2c.mcs.read().busy().bit_is_clear()
                     });
+                    self.i2c.mcr.modify(|_, w| w.mfe().clear_bit());
+                    $crate::trace_access!(self.i2c, mcr, Write);
+                    (self.i2c, self.pins)
+                }
 
-                    for (i,byte) in (&bytes[1..]).iter().enumerate() {
-                        i2c_busy_wait!(self.i2c)?;
-
-                        // Put next byte in data register
-                        self.i2c.mdr.write(|w| unsafe {
-                            w.data().bits(*byte)
-                        });
-
-                        // Send RUN command (Burst continue)
-                        // Set STOP on last byte
-                        self.i2c.mcs.write(|w| {
-                            if (i+1) == (sz-1) {
-                                w.stop().set_bit();
-                            }
-                            w.run().set_bit()
-                        });
-                    }
+                /// Like `free`, but hands the pins back as floating inputs,
+                /// disconnected from the I2C module, so whatever uses them
+                /// next starts from a known state
+                pub fn free_into_inputs(self) -> ($I2CX, (SCL::Input, SDA::Input))
+                where
+                    SCL: $crate::gpio::RecoverablePin,
+                    SDA: $crate::gpio::RecoverablePin,
+                {
+                    let (i2c, (scl, sda)) = self.free();
+                    (i2c, (scl.into_input(), sda.into_input()))
+                }
 
-                    i2c_busy_wait!(self.i2c)?;
+                /// Makes this driver's pins out of thin air, for recovery
+                /// code, such as a fault handler, that can't get at the
+                /// driver to `free` it. The pins keep whatever mode they
+                /// were in; `into_input` them before use. The I2C module
+                /// itself isn't touched, so steal it from the PAC if you need
+                /// it.
+                ///
+                /// # Safety
+                ///
+                /// The driver that owned the pins, and anything else holding
+                /// them, must never run again. A transaction it was in the
+                /// middle of is simply cut off, which can leave a slave
+                /// holding SDA low until it is clocked out.
+                pub unsafe fn recover_pins() -> (SCL, SDA)
+                where
+                    SCL: $crate::gpio::RecoverablePin,
+                    SDA: $crate::gpio::RecoverablePin,
+                {
+                    (SCL::steal(), SDA::steal())
+                }
 
-                    Ok(())
+                /// Like `free`, but also stops the I2C module's run-mode clock.
+                /// The next constructor call turns it back on.
+                pub fn power_down(self, pc: &sysctl::PowerControl) -> ($I2CX, (SCL, SDA)) {
+                    let parts = self.free();
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::Off);
+                    parts
                 }
             }
 
-            impl<PINS> Read for I2c<$I2CX, PINS> {
-                type Error = Error;
+            impl<PINS> I2c<$I2CX, PINS> {
+                /// The NVIC interrupt this I2C module raises
+                pub const INTERRUPT: Interrupt = Interrupt::$I2CX;
 
-                fn read(
+                $crate::nvic_control!("this I2C module's interrupt");
+            }
+
+            impl<PINS> I2c<$I2CX, PINS> {
+                /// Like `Write::write`, but gives up with `Error::Timeout`,
+                /// leaving the bus idle, if it has not finished by `deadline`
+                pub fn write_with_deadline(
+                    &mut self,
+                    addr: u8,
+                    bytes: &[u8],
+                    deadline: $crate::time::Instant,
+                ) -> Result<(), Error> {
+                    self.write_until(addr, bytes, Some(deadline))
+                }
+
+                /// Like `Read::read`, but gives up with `Error::Timeout`,
+                /// leaving the bus idle, if it has not finished by `deadline`
+                pub fn read_with_deadline(
                     &mut self,
                     addr: u8,
                     buffer: &mut [u8],
+                    deadline: $crate::time::Instant,
                 ) -> Result<(), Error> {
+                    self.read_until(addr, buffer, Some(deadline))
+                }
 
-                    // Write Slave address and set Receive bit
-                    self.i2c.msa.write(|w| unsafe {
-                        w.sa().bits(addr)
-                            .rs().set_bit()
-                    });
+                /// Like `WriteRead::write_read`, but gives up with
+                /// `Error::Timeout`, leaving the bus idle, if it has not
+                /// finished by `deadline`
+                pub fn write_read_with_deadline(
+                    &mut self,
+                    addr: u8,
+                    bytes: &[u8],
+                    buffer: &mut [u8],
+                    deadline: $crate::time::Instant,
+                ) -> Result<(), Error> {
+                    self.write_read_until(addr, bytes, buffer, Some(deadline))
+                }
 
-                    i2c_busy_wait!(self.i2c, busbsy, bit_is_clear)?;
+                /// Starts a write of `bytes` to `addr` and returns it
+                /// without waiting; drive it with `WriteNb::poll`, or end it
+                /// early with `WriteNb::abort`
+                pub fn write_nb<'a>(
+                    &'a mut self,
+                    addr: u8,
+                    bytes: &'a [u8],
+                ) -> $crate::i2c::WriteNb<'a, $crate::regs::Mmio> {
+                    $crate::i2c::WriteNb::new(self.regs(), addr, bytes)
+                }
 
-                    let recv_sz = buffer.len();
+                /// Reads register `reg` of the device at `addr`, most
+                /// significant byte first. `R` is `u8`, `u16` or `u32`.
+                pub fn read_reg<R: $crate::i2c::RegisterWidth>(
+                    &mut self,
+                    addr: u8,
+                    reg: u8,
+                ) -> Result<R, Error> {
+                    self.read_reg_ordered(addr, reg, true)
+                }
 
-                    if recv_sz == 1 {
-                        // Single receive
-                        self.i2c.mcs.write(|w| {
-                            w.run().set_bit()
-                                .start().set_bit()
-                                .stop().set_bit()
-                        });
+                /// Like `read_reg`, for devices that send the least
+                /// significant byte first
+                pub fn read_reg_le<R: $crate::i2c::RegisterWidth>(
+                    &mut self,
+                    addr: u8,
+                    reg: u8,
+                ) -> Result<R, Error> {
+                    self.read_reg_ordered(addr, reg, false)
+                }
 
-                        i2c_busy_wait!(self.i2c)?;
-                        buffer[0] = self.i2c.mdr.read().data().bits();
-                    } else {
-                        self.i2c.mcs.write(|w| {
-                            w.start().set_bit()
-                                .run().set_bit()
-                                .ack().set_bit()
-                        });
-
-                        i2c_busy_wait!(self.i2c)?;
-                        buffer[0] = self.i2c.mdr.read().data().bits();
-
-                        for byte in &mut buffer[1..recv_sz-1] {
-                            self.i2c.mcs.write(|w| {
-                                w.run().set_bit()
-                                    .ack().set_bit()
-                            });
-                            i2c_busy_wait!(self.i2c)?;
-                            *byte = self.i2c.mdr.read().data().bits();
+                /// Writes `value` to register `reg` of the device at `addr`,
+                /// most significant byte first
+                pub fn write_reg<R: $crate::i2c::RegisterWidth>(
+                    &mut self,
+                    addr: u8,
+                    reg: u8,
+                    value: R,
+                ) -> Result<(), Error> {
+                    self.write_reg_ordered(addr, reg, value, true)
+                }
+
+                /// Like `write_reg`, for devices that expect the least
+                /// significant byte first
+                pub fn write_reg_le<R: $crate::i2c::RegisterWidth>(
+                    &mut self,
+                    addr: u8,
+                    reg: u8,
+                    value: R,
+                ) -> Result<(), Error> {
+                    self.write_reg_ordered(addr, reg, value, false)
+                }
+
+                /// Reads register `reg`, passes it through `f` and writes
+                /// back the result, most significant byte first. This is two
+                /// transactions, so it isn't atomic as far as the device is
+                /// concerned.
+                pub fn modify_reg<R, F>(&mut self, addr: u8, reg: u8, f: F) -> Result<(), Error>
+                where
+                    R: $crate::i2c::RegisterWidth,
+                    F: FnOnce(R) -> R,
+                {
+                    let value = self.read_reg_ordered(addr, reg, true)?;
+                    self.write_reg_ordered(addr, reg, f(value), true)
+                }
+
+                /// Like `modify_reg`, for least significant byte first devices
+                pub fn modify_reg_le<R, F>(&mut self, addr: u8, reg: u8, f: F) -> Result<(), Error>
+                where
+                    R: $crate::i2c::RegisterWidth,
+                    F: FnOnce(R) -> R,
+                {
+                    let value = self.read_reg_ordered(addr, reg, false)?;
+                    self.write_reg_ordered(addr, reg, f(value), false)
+                }
+
+                /// Fills `buffer` from consecutive registers starting at
+                /// `start`, for devices that step the register pointer after
+                /// each byte read
+                pub fn read_regs(
+                    &mut self,
+                    addr: u8,
+                    start: u8,
+                    buffer: &mut [u8],
+                ) -> Result<(), Error> {
+                    self.write_read_until(addr, &[start], buffer, None)
+                }
+
+                fn read_reg_ordered<R: $crate::i2c::RegisterWidth>(
+                    &mut self,
+                    addr: u8,
+                    reg: u8,
+                    big_endian: bool,
+                ) -> Result<R, Error> {
+                    let mut raw = [0u8; 4];
+                    let raw = &mut raw[..R::BYTES];
+                    self.write_read_until(addr, &[reg], raw, None)?;
+                    Ok(R::from_bytes(raw, big_endian))
+                }
+
+                fn write_reg_ordered<R: $crate::i2c::RegisterWidth>(
+                    &mut self,
+                    addr: u8,
+                    reg: u8,
+                    value: R,
+                    big_endian: bool,
+                ) -> Result<(), Error> {
+                    // Register address, then up to four bytes of value
+                    let mut raw = [0u8; 5];
+                    raw[0] = reg;
+                    value.to_bytes(&mut raw[1..=R::BYTES], big_endian);
+                    self.write_until(addr, &raw[..=R::BYTES], None)
+                }
+
+                /// Checks whether a device answers at `addr`; see
+                /// `i2c::probe`. `Ok(false)` means the address was NAKed,
+                /// while arbitration loss, timeouts and the like are errors.
+                pub fn probe(&mut self, addr: u8) -> Result<bool, Error> {
+                    $crate::assert_can_block!(concat!(stringify!($I2CX), " probe"));
+                    $crate::i2c::probe(&self.regs(), addr, None)
+                }
+
+                /// Probes every non-reserved address (0x08 to 0x77). Bit
+                /// `addr % 64` of word `addr / 64` is set for each address
+                /// that answered. A bus fault stops the scan and is returned.
+                pub fn scan(&mut self) -> Result<[u64; 2], Error> {
+                    let mut found = [0u64; 2];
+                    for addr in 0x08..=0x77u8 {
+                        if self.probe(addr)? {
+                            found[usize::from(addr / 64)] |= 1 << (addr % 64);
                         }
-                        self.i2c.mcs.write(|w| {
-                            w.run().set_bit()
-                                .stop().set_bit()
-                        });
+                    }
+                    Ok(found)
+                }
 
-                        i2c_busy_wait!(self.i2c)?;
-                        buffer[recv_sz-1] = self.i2c.mdr.read().data().bits();
+                fn regs(&self) -> $crate::regs::Mmio {
+                    // The driver owns the peripheral
+                    unsafe {
+                        $crate::regs::Mmio::new(&*self.i2c, cfg!(feature = "trace-registers"))
                     }
+                }
 
-                    Ok(())
+                fn write_until(
+                    &mut self,
+                    addr: u8,
+                    bytes: &[u8],
+                    deadline: Option<$crate::time::Instant>,
+                ) -> Result<(), Error> {
+                    $crate::assert_can_block!(concat!(stringify!($I2CX), " write"));
+                    $crate::rt_budget_scope!(concat!(stringify!($I2CX), " write"));
+                    $crate::i2c::write(&self.regs(), addr, bytes, deadline)
                 }
-            }
 
-            impl<PINS> WriteRead for I2c<$I2CX, PINS> {
-                type Error = Error;
+                fn read_until(
+                    &mut self,
+                    addr: u8,
+                    buffer: &mut [u8],
+                    deadline: Option<$crate::time::Instant>,
+                ) -> Result<(), Error> {
+                    $crate::assert_can_block!(concat!(stringify!($I2CX), " read"));
+                    $crate::rt_budget_scope!(concat!(stringify!($I2CX), " read"));
+                    $crate::i2c::read(&self.regs(), addr, buffer, deadline)
+                }
 
-                fn write_read(
+                fn write_read_until(
                     &mut self,
                     addr: u8,
                     bytes: &[u8],
                     buffer: &mut [u8],
+                    deadline: Option<$crate::time::Instant>,
                 ) -> Result<(), Error> {
-
-                    let write_len = bytes.len();
+                    $crate::assert_can_block!(concat!(stringify!($I2CX), " write_read"));
+                    $crate::rt_budget_scope!(concat!(stringify!($I2CX), " write_read"));
 
                     if buffer.len() == 0 {
-                       return self.write(addr, bytes);
+                        return $crate::i2c::write(&self.regs(), addr, bytes, deadline);
                     }
 
                     if bytes.len() == 0 {
-                        return self.read(addr, buffer);
+                        return $crate::i2c::read(&self.regs(), addr, buffer, deadline);
                     }
 
-                    // Write Slave address and clear Receive bit
-                    self.i2c.msa.write(|w| unsafe {
-                        w.sa().bits(addr)
-                    });
+                    $crate::i2c::write_read(&self.regs(), addr, bytes, buffer, deadline)
+                }
+            }
 
-                    // send first byte
-                    self.i2c.mdr.write(|w| unsafe {
-                        w.data().bits(bytes[0])
-                    });
+            impl<PINS> $crate::diag::Diagnose for I2c<$I2CX, PINS> {
+                fn diagnose(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                    write!(f, "{}:", stringify!($I2CX))?;
+                    $crate::diag::write_flags(
+                        f,
+                        self.i2c.mcs.read().bits(),
+                        &[(1, "error"), (2, "adrack"), (3, "datack"), (4, "arblst"),
+                          (6, "busbsy"), (7, "clkto")],
+                    )
+                }
+            }
 
-                    i2c_busy_wait!(self.i2c, busbsy, bit_is_clear)?;
-                    self.i2c.mcs.write(|w| {
-                        w.start().set_bit()
-                            .run().set_bit()
-                    });
+            impl<PINS> Write for I2c<$I2CX, PINS> {
+                type Error = Error;
 
-                    i2c_busy_wait!(self.i2c)?;
-                    for byte in (&bytes[1..write_len]).iter() {
-                        self.i2c.mdr.write(|w| unsafe {
-                            w.data().bits(*byte)
-                        });
+                fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+                    self.write_until(addr, bytes, None)
+                }
+            }
 
-                        self.i2c.mcs.write(|w| {
-                            w.run().set_bit()
-                        });
+            impl<PINS> Read for I2c<$I2CX, PINS> {
+                type Error = Error;
 
-                        i2c_busy_wait!(self.i2c)?;
-                    }
+                fn read(
+                    &mut self,
+                    addr: u8,
+                    buffer: &mut [u8],
+                ) -> Result<(), Error> {
+                    self.read_until(addr, buffer, None)
+                }
+            }
 
-                    // Write Slave address and set Receive bit
-                    self.i2c.msa.write(|w| unsafe {
-                        w.sa().bits(addr)
-                            .rs().set_bit()
-                    });
+            impl<PINS> WriteRead for I2c<$I2CX, PINS> {
+                type Error = Error;
 
-                    let recv_sz = buffer.len();
+                fn write_read(
+                    &mut self,
+                    addr: u8,
+                    bytes: &[u8],
+                    buffer: &mut [u8],
+                ) -> Result<(), Error> {
+                    self.write_read_until(addr, bytes, buffer, None)
+                }
+            }
+        )+
+    }
+}
 
-                    if recv_sz == 1 {
-                        // emit Repeated START and STOP for single receive
-                        self.i2c.mcs.write(|w| {
-                            w.run().set_bit()
-                                .start().set_bit()
-                                .stop().set_bit()
-                        });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regs::fake::Fake;
+    use std::vec::Vec;
+
+    /// A slave on the other end of the bus, which answers each RUN command
+    /// straight away
+    #[derive(Default)]
+    struct Slave {
+        /// Which byte of the transfer, counting the address as 0, gets a NAK
+        nak_at: Option<usize>,
+        /// Which byte the slave stretches SCL on until the timeout fires
+        stretch_at: Option<usize>,
+        /// Bytes clocked so far in this transaction
+        position: usize,
+        /// Whether the controller still holds the bus
+        holding: bool,
+        /// Polls left before BUSY clears
+        busy_polls: u32,
+        /// What the slave sends when read
+        data: Vec<u8>,
+    }
 
-                        i2c_busy_wait!(self.i2c)?;
-                        buffer[0] = self.i2c.mdr.read().data().bits();
-                    } else {
-                        // emit Repeated START
-                        self.i2c.mcs.write(|w| {
-                            w.run().set_bit()
-                                .start().set_bit()
-                                .ack().set_bit()
-                        });
-
-                        i2c_busy_wait!(self.i2c)?;
-                        buffer[0] = self.i2c.mdr.read().data().bits();
-
-                        for byte in &mut buffer[1..recv_sz-1] {
-                            self.i2c.mcs.write(|w| {
-                                w.run().set_bit()
-                                    .ack().set_bit()
-                            });
-                            i2c_busy_wait!(self.i2c)?;
-                            *byte = self.i2c.mdr.read().data().bits();
-                        }
+    fn on_write(slave: &mut Slave, regs: &mut [u32; 16], offset: usize, value: u32) {
+        regs[offset / 4] = value;
+        if offset != MCS {
+            return;
+        }
+        let mut status = 0;
+        if value & CMD_RUN == 0 {
+            // A bare STOP releases the bus
+            if value & CMD_STOP != 0 {
+                slave.holding = false;
+            }
+        } else {
+            // START puts the address on the wire as byte 0, then every RUN
+            // moves one data byte
+            if value & CMD_START != 0 {
+                slave.position = 0;
+                if slave.nak_at == Some(0) {
+                    status = MCS_ERROR | MCS_ADRACK;
+                }
+            }
+            slave.position += 1;
+            let position = slave.position;
+            if status == 0 {
+                if slave.stretch_at == Some(position) {
+                    status = MCS_CLKTO;
+                } else if slave.nak_at == Some(position) {
+                    status = MCS_ERROR | (1 << 3);
+                } else if regs[MSA / 4] & 1 != 0 {
+                    regs[MDR / 4] = u32::from(slave.data[position - 1]);
+                }
+            }
+            slave.holding = value & CMD_STOP == 0 || status & MCS_CLKTO != 0;
+            slave.busy_polls = 2;
+        }
+        regs[MCS / 4] = status;
+    }
 
-                        self.i2c.mcs.write(|w| {
-                            w.run().set_bit()
-                                .stop().set_bit()
-                        });
+    fn on_read(slave: &mut Slave, regs: &mut [u32; 16], offset: usize) {
+        if offset != MCS {
+            return;
+        }
+        let mut status = regs[MCS / 4] & !(MCS_BUSY | MCS_BUSBSY);
+        if slave.busy_polls > 0 {
+            slave.busy_polls -= 1;
+            status |= MCS_BUSY;
+        }
+        if slave.holding {
+            status |= MCS_BUSBSY;
+        }
+        regs[MCS / 4] = status;
+    }
 
-                        i2c_busy_wait!(self.i2c)?;
-                        buffer[recv_sz-1] = self.i2c.mdr.read().data().bits();
-                    }
+    fn bus(slave: Slave) -> Fake<Slave> {
+        Fake::new(slave, on_write, on_read)
+    }
 
-                    Ok(())
-                }
+    #[test]
+    fn write_sends_start_run_then_stop_on_the_last_byte() {
+        let fake = bus(Slave::default());
+        write(&fake, 0x50, &[1, 2, 3], None).unwrap();
+        assert_eq!(fake.writes_to(MSA), [0x50 << 1]);
+        assert_eq!(fake.writes_to(MDR), [1, 2, 3]);
+        assert_eq!(
+            fake.writes_to(MCS),
+            [CMD_START | CMD_RUN, CMD_RUN, CMD_RUN | CMD_STOP]
+        );
+        assert!(!fake.state.borrow().holding);
+    }
+
+    #[test]
+    fn single_byte_write_stops_straight_away() {
+        let fake = bus(Slave::default());
+        write(&fake, 0x50, &[7], None).unwrap();
+        assert_eq!(fake.writes_to(MCS), [CMD_START | CMD_RUN | CMD_STOP]);
+    }
+
+    #[test]
+    fn read_acks_all_but_the_last_byte() {
+        let fake = bus(Slave {
+            data: vec![0xAA, 0xBB, 0xCC],
+            ..Slave::default()
+        });
+        let mut buffer = [0; 3];
+        read(&fake, 0x21, &mut buffer, None).unwrap();
+        assert_eq!(buffer, [0xAA, 0xBB, 0xCC]);
+        assert_eq!(fake.writes_to(MSA), [(0x21 << 1) | 1]);
+        assert_eq!(
+            fake.writes_to(MCS),
+            [
+                CMD_START | CMD_RUN | CMD_ACK,
+                CMD_RUN | CMD_ACK,
+                CMD_RUN | CMD_STOP
+            ]
+        );
+    }
+
+    #[test]
+    fn write_read_uses_a_repeated_start() {
+        let fake = bus(Slave {
+            data: vec![0x42],
+            ..Slave::default()
+        });
+        let mut buffer = [0; 1];
+        write_read(&fake, 0x68, &[0x75], &mut buffer, None).unwrap();
+        assert_eq!(buffer, [0x42]);
+        assert_eq!(fake.writes_to(MSA), [0x68 << 1, (0x68 << 1) | 1]);
+        assert_eq!(
+            fake.writes_to(MCS),
+            [CMD_START | CMD_RUN, CMD_START | CMD_RUN | CMD_STOP]
+        );
+    }
+
+    #[test]
+    fn nak_mid_transfer_releases_the_bus() {
+        // The slave NAKs the third data byte of four
+        let fake = bus(Slave {
+            nak_at: Some(3),
+            ..Slave::default()
+        });
+        match write(&fake, 0x50, &[1, 2, 3, 4], None) {
+            Err(Error::DataAck) => {}
+            other => panic!("expected DataAck, got {:?}", other),
+        }
+        // Without the STOP the controller would still hold the bus...
+        assert_eq!(*fake.writes_to(MCS).last().unwrap(), CMD_STOP);
+        assert!(!fake.state.borrow().holding);
+
+        // ...and the next transfer would wait for BUSBSY until it timed out
+        fake.state.borrow_mut().nak_at = None;
+        write(&fake, 0x50, &[1, 2], None).unwrap();
+    }
+
+    #[test]
+    fn address_nak_is_reported_as_such() {
+        let fake = bus(Slave {
+            nak_at: Some(0),
+            ..Slave::default()
+        });
+        match read(&fake, 0x10, &mut [0; 2], None) {
+            Err(Error::AdrAck) => {}
+            other => panic!("expected AdrAck, got {:?}", other),
+        }
+        assert!(!fake.state.borrow().holding);
+    }
+
+    #[test]
+    fn probe_tells_absent_from_broken() {
+        let fake = bus(Slave {
+            data: vec![0xAA],
+            ..Slave::default()
+        });
+        assert_eq!(probe(&fake, 0x3C, None).ok(), Some(true));
+
+        let fake = bus(Slave {
+            nak_at: Some(0),
+            ..Slave::default()
+        });
+        assert_eq!(probe(&fake, 0x3D, None).ok(), Some(false));
+        assert!(!fake.state.borrow().holding);
+        // The NAK left in MCS isn't taken for the next transfer's
+        fake.state.borrow_mut().nak_at = None;
+        fake.state.borrow_mut().data = vec![0xAA];
+        assert_eq!(probe(&fake, 0x3C, None).ok(), Some(true));
+
+        // A slave stuck holding SCL isn't "absent"
+        let fake = bus(Slave {
+            stretch_at: Some(1),
+            ..Slave::default()
+        });
+        match probe(&fake, 0x3C, None) {
+            Err(Error::ClockLowTimeout) => {}
+            other => panic!("expected ClockLowTimeout, got {:?}", other),
+        }
+        assert!(!fake.state.borrow().holding);
+    }
+
+    #[test]
+    fn clock_low_timeout_aborts_and_clears() {
+        let fake = bus(Slave {
+            stretch_at: Some(2),
+            ..Slave::default()
+        });
+        match write(&fake, 0x50, &[1, 2, 3], None) {
+            Err(Error::ClockLowTimeout) => {}
+            other => panic!("expected ClockLowTimeout, got {:?}", other),
+        }
+        assert_eq!(*fake.writes_to(MCS).last().unwrap(), CMD_STOP);
+        assert_eq!(fake.writes_to(MICR), [MICR_CLKIC]);
+        assert!(!fake.state.borrow().holding);
+    }
+
+    /// Polls `transfer` to completion, counting the polls
+    fn run(transfer: &mut WriteNb<&Fake<Slave>>) -> (Result<(), Error>, usize) {
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            match transfer.poll() {
+                Ok(()) => return (Ok(()), polls),
+                Err(nb::Error::Other(e)) => return (Err(e), polls),
+                Err(nb::Error::WouldBlock) => assert!(polls < 100),
             }
-        )+
+        }
+    }
+
+    #[test]
+    fn write_nb_matches_the_blocking_write() {
+        let fake = bus(Slave::default());
+        let mut transfer = WriteNb::new(&fake, 0x50, &[1, 2, 3]);
+        let (result, polls) = run(&mut transfer);
+        result.unwrap();
+        // One poll per byte, and the controller busy twice after each
+        assert_eq!(polls, 1 + 3 * 3);
+        assert_eq!(fake.writes_to(MDR), [1, 2, 3]);
+        assert_eq!(
+            fake.writes_to(MCS),
+            [CMD_START | CMD_RUN, CMD_RUN, CMD_RUN | CMD_STOP]
+        );
+        assert!(!fake.state.borrow().holding);
+    }
+
+    #[test]
+    fn write_nb_waits_for_the_bus_before_starting() {
+        let fake = bus(Slave::default());
+        fake.state.borrow_mut().holding = true;
+        let mut transfer = WriteNb::new(&fake, 0x50, &[1]);
+        assert!(transfer.poll().is_err());
+        assert!(fake.writes_to(MCS).is_empty());
+        fake.state.borrow_mut().holding = false;
+        run(&mut transfer).0.unwrap();
+    }
+
+    #[test]
+    fn write_nb_releases_the_bus_after_a_nak() {
+        let fake = bus(Slave {
+            nak_at: Some(2),
+            ..Slave::default()
+        });
+        let mut transfer = WriteNb::new(&fake, 0x50, &[1, 2, 3]);
+        match run(&mut transfer).0 {
+            Err(Error::DataAck) => {}
+            other => panic!("expected DataAck, got {:?}", other),
+        }
+        assert!(!fake.state.borrow().holding);
+    }
+
+    #[test]
+    fn aborted_write_nb_sends_stop() {
+        let fake = bus(Slave::default());
+        let mut transfer = WriteNb::new(&fake, 0x50, &[1, 2, 3]);
+        assert!(transfer.poll().is_err());
+        transfer.abort();
+        assert_eq!(*fake.writes_to(MCS).last().unwrap(), CMD_STOP);
+        assert!(!fake.state.borrow().holding);
+    }
+
+    #[test]
+    fn a_bus_that_never_frees_times_out() {
+        let fake = bus(Slave::default());
+        fake.state.borrow_mut().holding = true;
+        match write(&fake, 0x50, &[1], None) {
+            Err(Error::Timeout) => {}
+            other => panic!("expected Timeout, got {:?}", other),
+        }
+        // Nothing was started
+        assert!(fake.writes_to(MCS).iter().all(|&c| c & CMD_RUN == 0));
+    }
+
+    fn round_trip<R: RegisterWidth + PartialEq + core::fmt::Debug>(
+        value: R,
+        big_endian: bool,
+        wire: &[u8],
+    ) {
+        assert_eq!(R::BYTES, wire.len());
+        let mut bytes = [0u8; 4];
+        value.to_bytes(&mut bytes[..R::BYTES], big_endian);
+        assert_eq!(&bytes[..R::BYTES], wire);
+        assert_eq!(R::from_bytes(wire, big_endian), value);
+    }
+
+    #[test]
+    fn register_widths_in_both_byte_orders() {
+        round_trip(0xA5u8, true, &[0xA5]);
+        round_trip(0xA5u8, false, &[0xA5]);
+        round_trip(0x1234u16, true, &[0x12, 0x34]);
+        round_trip(0x1234u16, false, &[0x34, 0x12]);
+        round_trip(0x1234_5678u32, true, &[0x12, 0x34, 0x56, 0x78]);
+        round_trip(0x1234_5678u32, false, &[0x78, 0x56, 0x34, 0x12]);
     }
 }
@@ -0,0 +1,87 @@
+//! Which driver calls are safe from interrupt context
+//!
+//! The rule across the drivers is:
+//!
+//! * The `nb` methods (the embedded-hal `Read`, `Write` and `FullDuplex`
+//!   impls) return `WouldBlock` instead of waiting, so they can run from a
+//!   handler, as can the GPIO pin methods, which use atomic bit-band
+//!   accesses.
+//! * Calls that wait on the hardware until they are done block for as long
+//!   as the transfer takes. That is the I2C transfers and register
+//!   helpers, the SPI `transfer_with_deadline`, `send_idle_clocks` and
+//!   three-wire calls, `Serial::write_all` and the clock setup. Keep them
+//!   out of plain interrupt handlers, and never make them with interrupts
+//!   masked. A handler should hand the work to a task or thread mode
+//!   instead.
+//!
+//! The calls meant for handlers also come as `*_from_isr` methods (such as
+//! `Serial::write_from_isr` and `Spi::send_from_isr`), which take the
+//! `IsrContext` that `with_isr_context` hands out. Writing a handler's body
+//! inside `with_isr_context` and against those methods makes the split
+//! explicit: the variants are guaranteed never to wait, whatever the `nb`
+//! impls they stand for come to do.
+//!
+//! Blocking in a handler is fine in itself (an RTIC task is a handler), but
+//! not with interrupts masked by PRIMASK (`interrupt::free`) or BASEPRI (an
+//! RTIC resource lock), which holds up every interrupt that should have
+//! preempted. With the chip crate's `isr-check` feature, the blocking calls
+//! check for that in debug builds and panic if they find it.
+
+use cortex_m::peripheral::SCB;
+use cortex_m::register::{basepri, primask};
+
+/// The VECTACTIVE field of the ICSR, the same number the IPSR holds
+const ICSR_VECTACTIVE: u32 = 0x1FF;
+
+/// Handed to the closure given to `with_isr_context`, and taken by the
+/// `*_from_isr` driver methods. It can't be made any other way, or kept
+/// beyond the closure.
+pub struct IsrContext {
+    _private: (),
+}
+
+/// Runs `f`, the body of an interrupt handler, with an `IsrContext` for the
+/// `*_from_isr` driver methods
+pub fn with_isr_context<R, F>(f: F) -> R
+where
+    F: FnOnce(&IsrContext) -> R,
+{
+    f(&IsrContext { _private: () })
+}
+
+/// The number of the exception being handled, or `None` in thread mode.
+/// Interrupts are numbered from 16, so IRQ `n` is `16 + n`.
+pub fn active_exception() -> Option<u16> {
+    // Read only, so this is safe
+    let vector = unsafe { (*SCB::ptr()).icsr.read() } & ICSR_VECTACTIVE;
+    if vector == 0 {
+        None
+    } else {
+        Some(vector as u16)
+    }
+}
+
+/// True if running in an interrupt or exception handler
+pub fn in_handler() -> bool {
+    active_exception().is_some()
+}
+
+/// True if interrupts are masked, by PRIMASK or by a non-zero BASEPRI, so
+/// that waiting here would hold up interrupts that ought to run
+pub fn interrupts_masked() -> bool {
+    primask::read().is_active() || basepri::read() != 0
+}
+
+#[macro_export]
+/// Panics in debug builds if called with interrupts masked, when the
+/// `isr-check` feature is enabled in the crate expanding it. `$call` names
+/// the blocking call in the message.
+macro_rules! assert_can_block {
+    ($call:expr) => {
+        #[cfg(feature = "isr-check")]
+        debug_assert!(
+            !$crate::isr::interrupts_masked(),
+            concat!($call, " blocks, so must not be called with interrupts masked")
+        );
+    };
+}
@@ -1,16 +1,114 @@
 //! Generic implementation code for both TM4C123 and TM4C129.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![deny(missing_docs, warnings)]
 #![allow(deprecated)]
 
 pub mod bb;
+pub mod calc;
+pub mod cpu;
+pub mod debounce;
 pub mod delay;
+pub mod diag;
 pub mod gpio;
+pub mod hib;
 pub mod i2c;
+pub mod isr;
+pub mod nmi;
+pub mod parallel;
+pub mod poll;
+pub mod post;
+pub mod pps;
+pub mod regs;
+pub mod rt_budget;
+#[cfg(feature = "panic-safe-state")]
+pub mod safe_state;
 pub mod serial;
+#[cfg(feature = "soft-uart")]
+pub mod soft_uart;
+pub mod spi;
 pub mod sysctl;
+pub mod tick;
 pub mod time;
+pub mod timer;
+pub mod trace;
+
+///! An internal macro giving a driver with an `INTERRUPT` constant its
+///! `enable_interrupt` and `disable_interrupt` methods. `$what` names the
+///! interrupt in their docs, as in "this UART's interrupt".
+#[macro_export]
+macro_rules! nvic_control {
+    ($what:literal) => {
+        #[doc = concat!("Sets the priority of ", $what, " and unmasks it. Only the top three")]
+        /// bits of `priority` are implemented.
+        ///
+        /// # Safety
+        ///
+        /// Unmasking an interrupt can break mask-based critical sections;
+        /// see `NVIC::unmask`.
+        pub unsafe fn enable_interrupt(
+            &mut self,
+            nvic: &mut cortex_m::peripheral::NVIC,
+            priority: u8,
+        ) {
+            nvic.set_priority(Self::INTERRUPT, priority);
+            cortex_m::peripheral::NVIC::unmask(Self::INTERRUPT);
+        }
+
+        #[doc = concat!("Masks ", $what, " in the NVIC")]
+        pub fn disable_interrupt(&mut self) {
+            cortex_m::peripheral::NVIC::mask(Self::INTERRUPT);
+        }
+    };
+}
+
+///! An internal macro declaring a clock frequency enum along with its
+///! `From<_> for Hertz` conversion, an `ALL` list of its variants and a
+///! `TryFrom<Hertz>` that snaps to the nearest one. Each variant is written
+///! `Variant (= discriminant)? => hertz,`, so the list can't fall out of
+///! step with the enum.
+#[macro_export]
+macro_rules! frequency_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $Name:ident {
+            $(
+                $(#[$vmeta:meta])*
+                $Variant:ident $(= $disc:expr)? => $hz:expr,
+            )+
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $Name {
+            $(
+                $(#[$vmeta])*
+                $Variant $(= $disc)?,
+            )+
+        }
+
+        impl From<$Name> for $crate::time::Hertz {
+            fn from(f: $Name) -> Self {
+                $crate::time::Hertz(match f {
+                    $($Name::$Variant => $hz,)+
+                })
+            }
+        }
+
+        impl $Name {
+            /// Every variant, in declaration order
+            pub const ALL: &'static [$Name] = &[$($Name::$Variant,)+];
+        }
+
+        /// Snaps to the nearest supported frequency, if it is within 1%
+        impl core::convert::TryFrom<$crate::time::Hertz> for $Name {
+            type Error = $crate::sysctl::UnsupportedFrequency;
+
+            fn try_from(f: $crate::time::Hertz) -> Result<Self, Self::Error> {
+                $crate::sysctl::nearest_frequency($Name::ALL, f)
+            }
+        }
+    };
+}
 
 ///! An internal macro to implement the GPIO functionality for each port
 #[macro_export]
@@ -47,7 +145,25 @@ macro_rules! gpio_macro {
                     sysctl::control_power(
                         pc, sysctl::Domain::$iopd,
                         sysctl::RunMode::Run, sysctl::PowerState::On);
-                    sysctl::reset(pc, sysctl::Domain::$iopd);
+                    sysctl::reset(pc, sysctl::Domain::$iopd)
+                        .expect("GPIO port didn't come out of reset");
+
+                    // Some pins come out of reset muxed to a peripheral;
+                    // forget that so only our own conversions count. GPIOCR
+                    // doesn't protect PCTL, so the commit-protected pins
+                    // (JTAG/SWD, NMI) are skipped by hand; they keep their
+                    // function until they are unlocked.
+                    #[cfg(feature = "pin-conflict-check")]
+                    {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        let cr = p.cr.read().bits();
+                        let locked = (0..8)
+                            .filter(|i| cr & (1 << i) == 0)
+                            .fold(0u32, |mask, i| mask | (0xF << (i * 4)));
+                        unsafe {
+                            p.pctl.modify(|r, w| w.bits(r.bits() & locked));
+                        }
+                    }
 
                     Parts {
                         control: GpioControl { _0: () },
@@ -58,6 +174,41 @@ macro_rules! gpio_macro {
                 }
             }
 
+            /// Runs `dispatcher`'s callbacks for every pin of this port with
+            /// a pending interrupt. Call it from the port's interrupt
+            /// handler. The pending flags are cleared before the callbacks
+            /// run, so an edge that arrives while they run interrupts again
+            /// rather than being lost.
+            pub fn dispatch<const PINS: usize>(dispatcher: &$crate::gpio::PortDispatcher<PINS>) {
+                let p = unsafe { &*$GPIOX::ptr() };
+                let pending = p.mis.read().bits();
+                p.icr.write(|w| unsafe { w.bits(pending) });
+                dispatcher.run(pending);
+            }
+
+            /// Panics if pin `i` is already muxed to an alternate function
+            /// other than `af`. The last PCTL write would otherwise silently
+            /// win over whoever set up the pin before.
+            #[cfg(feature = "pin-conflict-check")]
+            fn check_pctl(i: u8, af: u32) {
+                let p = unsafe { &*$GPIOX::ptr() };
+                let current = (p.pctl.read().bits() >> (i * 4)) & 0xF;
+                assert!(
+                    current == 0 || current == af,
+                    "pin is already muxed to alternate function {}", current
+                );
+            }
+
+            /// Clears pin `i`'s PCTL nibble, so it can be muxed afresh
+            #[cfg(feature = "pin-conflict-check")]
+            fn clear_pctl(i: u8) {
+                let p = unsafe { &*$GPIOX::ptr() };
+                let mask = 0xF << (i * 4);
+                unsafe {
+                    p.pctl.modify(|r, w| w.bits(r.bits() & !mask));
+                }
+            }
+
             /// Partially erased pin
             pub struct $PXx<MODE> {
                 i: u8,
@@ -185,6 +336,56 @@ macro_rules! gpio_macro {
                 }
             }
 
+            /// All eight pins of the port as push-pull outputs, written and
+            /// read as a byte in one DATA access rather than bit by bit
+            pub struct DataPort {
+                _0: (),
+            }
+
+            impl DataPort {
+                /// Takes the port's eight pins, in any order. They are given
+                /// back if they aren't eight different pins, which is always
+                /// the case on ports with fewer than eight.
+                pub fn new(
+                    pins: [$PXx<Output<PushPull>>; 8],
+                ) -> Result<Self, [$PXx<Output<PushPull>>; 8]> {
+                    let mask = pins.iter().fold(0u32, |mask, pin| mask | (1 << pin.i));
+                    if mask == 0xFF {
+                        Ok(DataPort { _0: () })
+                    } else {
+                        Err(pins)
+                    }
+                }
+
+                /// Gives the pins back, pin `n` at index `n`
+                pub fn free(self) -> [$PXx<Output<PushPull>>; 8] {
+                    let pin = |i| $PXx { i, _mode: PhantomData };
+                    [pin(0), pin(1), pin(2), pin(3), pin(4), pin(5), pin(6), pin(7)]
+                }
+            }
+
+            impl $crate::gpio::ParallelPort for DataPort {
+                fn write(&mut self, value: u8) {
+                    let p = unsafe { &*$GPIOX::ptr() };
+                    p.data.write(|w| unsafe { w.bits(u32::from(value)) });
+                }
+
+                fn release(&mut self) {
+                    let p = unsafe { &*$GPIOX::ptr() };
+                    p.dir.modify(|r, w| unsafe { w.bits(r.bits() & !0xFF) });
+                }
+
+                fn sample(&self) -> u8 {
+                    let p = unsafe { &*$GPIOX::ptr() };
+                    p.data.read().bits() as u8
+                }
+
+                fn drive(&mut self) {
+                    let p = unsafe { &*$GPIOX::ptr() };
+                    p.dir.modify(|r, w| unsafe { w.bits(r.bits() | 0xFF) });
+                }
+            }
+
             $(
                 /// Pin
                 pub struct $PXi<MODE> {
@@ -192,88 +393,72 @@ macro_rules! gpio_macro {
                 }
 
                 impl<MODE> $PXi<MODE> where MODE: IsUnlocked {
-                    /// Configures the pin to serve as alternate function 1 through 15.
-                    /// Disables open-drain to make the output a push-pull.
-                    pub fn into_af_push_pull<AF>(
+                    /// Configures the pin to serve as alternate function `AF`
+                    /// (`AF1` through `AF15`), with the pad set up as `MODE`:
+                    /// `PushPull`, `PullUp`, `PullDown` or `OpenDrain<_>`.
+                    /// Whether the function makes sense on this pin is checked
+                    /// where the pin is used, by the peripheral pin traits.
+                    /// A pin already serving an alternate function must be
+                    /// given back with `release_alternate_function` first.
+                    pub fn into_alternate<AF, AM>(
                         self,
                         _gpio_control: &mut GpioControl,
-                    ) -> $PXi<AlternateFunction<AF, PushPull>> where AF: AlternateFunctionChoice {
+                    ) -> $PXi<AlternateFunction<AF, AM>> where MODE: NotMuxed, AF: AlternateFunctionChoice, AM: AlternateMode {
                         let p = unsafe { &*$GPIOX::ptr() };
                         let mask = 0xF << ($i * 4);
                         let bits = AF::number() << ($i * 4);
+                        #[cfg(feature = "pin-conflict-check")]
+                        check_pctl($i, AF::number());
                         unsafe {
                             p.pctl.modify(|r, w| w.bits((r.bits() & !mask) | bits));
                         }
                         unsafe { bb::change_bit(&p.afsel, $i, true); }
                         unsafe { bb::change_bit(&p.dir, $i, false); }
-                        unsafe { bb::change_bit(&p.odr, $i, false); }
-                        unsafe { bb::change_bit(&p.pur, $i, false); }
-                        unsafe { bb::change_bit(&p.pdr, $i, false); }
+                        unsafe { bb::change_bit(&p.odr, $i, AM::open_drain()); }
+                        unsafe { bb::change_bit(&p.pur, $i, AM::pull_up()); }
+                        unsafe { bb::change_bit(&p.pdr, $i, AM::pull_down()); }
                         unsafe { bb::change_bit(&p.den, $i, true); }
                         $PXi { _mode: PhantomData }
                     }
 
+                    /// Configures the pin to serve as alternate function 1 through 15.
+                    /// Disables open-drain to make the output a push-pull.
+                    #[deprecated(note = "use `into_alternate::<AF, PushPull>`")]
+                    pub fn into_af_push_pull<AF>(
+                        self,
+                        gpio_control: &mut GpioControl,
+                    ) -> $PXi<AlternateFunction<AF, PushPull>> where MODE: NotMuxed, AF: AlternateFunctionChoice {
+                        self.into_alternate(gpio_control)
+                    }
+
                     /// Configures the pin to serve as alternate function 1 through 15 with
                     /// a weak pull-up resistor.
+                    #[deprecated(note = "use `into_alternate::<AF, PullUp>`")]
                     pub fn into_af_pull_up<AF>(
                         self,
-                        _gpio_control: &mut GpioControl,
-                    ) -> $PXi<AlternateFunction<AF, PullUp>> where AF: AlternateFunctionChoice {
-                        let p = unsafe { &*$GPIOX::ptr() };
-                        let mask = 0xF << ($i * 4);
-                        let bits = AF::number() << ($i * 4);
-                        unsafe {
-                            p.pctl.modify(|r, w| w.bits((r.bits() & !mask) | bits));
-                        }
-                        unsafe { bb::change_bit(&p.afsel, $i, true); }
-                        unsafe { bb::change_bit(&p.dir, $i, false); }
-                        unsafe { bb::change_bit(&p.odr, $i, false); }
-                        unsafe { bb::change_bit(&p.pur, $i, true); }
-                        unsafe { bb::change_bit(&p.pdr, $i, false); }
-                        unsafe { bb::change_bit(&p.den, $i, true); }
-                        $PXi { _mode: PhantomData }
+                        gpio_control: &mut GpioControl,
+                    ) -> $PXi<AlternateFunction<AF, PullUp>> where MODE: NotMuxed, AF: AlternateFunctionChoice {
+                        self.into_alternate(gpio_control)
                     }
 
                     /// Configures the pin to serve as alternate function 1 through 15 with
                     /// a weak pull-down resistor.
+                    #[deprecated(note = "use `into_alternate::<AF, PullDown>`")]
                     pub fn into_af_pull_down<AF>(
                         self,
-                        _gpio_control: &mut GpioControl,
-                    ) -> $PXi<AlternateFunction<AF, PullDown>> where AF: AlternateFunctionChoice {
-                        let p = unsafe { &*$GPIOX::ptr() };
-                        let mask = 0xF << ($i * 4);
-                        let bits = AF::number() << ($i * 4);
-                        unsafe {
-                            p.pctl.modify(|r, w| w.bits((r.bits() & !mask) | bits));
-                        }
-                        unsafe { bb::change_bit(&p.afsel, $i, true); }
-                        unsafe { bb::change_bit(&p.dir, $i, false); }
-                        unsafe { bb::change_bit(&p.odr, $i, false); }
-                        unsafe { bb::change_bit(&p.pur, $i, false); }
-                        unsafe { bb::change_bit(&p.pdr, $i, true); }
-                        unsafe { bb::change_bit(&p.den, $i, true); }
-                        $PXi { _mode: PhantomData }
+                        gpio_control: &mut GpioControl,
+                    ) -> $PXi<AlternateFunction<AF, PullDown>> where MODE: NotMuxed, AF: AlternateFunctionChoice {
+                        self.into_alternate(gpio_control)
                     }
 
                     /// Configures the pin to serve as alternate function 1 through 15.
                     /// Enables open-drain (useful for I2C SDA, for example).
+                    #[deprecated(note = "use `into_alternate::<AF, OpenDrain<ODM>>`")]
                     pub fn into_af_open_drain<AF, ODM>(
                         self,
-                        _gpio_control: &mut GpioControl,
-                    ) -> $PXi<AlternateFunction<AF, OpenDrain<ODM>>> where AF: AlternateFunctionChoice, ODM: OpenDrainMode {
-                        let p = unsafe { &*$GPIOX::ptr() };
-                        let mask = 0xF << ($i * 4);
-                        let bits = AF::number() << ($i * 4);
-                        unsafe {
-                            p.pctl.modify(|r, w| w.bits((r.bits() & !mask) | bits));
-                        }
-                        unsafe { bb::change_bit(&p.afsel, $i, true); }
-                        unsafe { bb::change_bit(&p.dir, $i, false); }
-                        unsafe { bb::change_bit(&p.odr, $i, true); }
-                        unsafe { bb::change_bit(&p.pur, $i, ODM::pup()); }
-                        unsafe { bb::change_bit(&p.pdr, $i, false); }
-                        unsafe { bb::change_bit(&p.den, $i, true); }
-                        $PXi { _mode: PhantomData }
+                        gpio_control: &mut GpioControl,
+                    ) -> $PXi<AlternateFunction<AF, OpenDrain<ODM>>> where MODE: NotMuxed, AF: AlternateFunctionChoice, ODM: OpenDrainMode {
+                        self.into_alternate(gpio_control)
                     }
 
                     /// Configures the pin to operate as a floating input pin
@@ -282,6 +467,8 @@ macro_rules! gpio_macro {
                     ) -> $PXi<Input<Floating>> {
                         let p = unsafe { &*$GPIOX::ptr() };
                         unsafe { bb::change_bit(&p.afsel, $i, false); }
+                        #[cfg(feature = "pin-conflict-check")]
+                        clear_pctl($i);
                         unsafe { bb::change_bit(&p.dir, $i, false); }
                         unsafe { bb::change_bit(&p.odr, $i, false); }
                         unsafe { bb::change_bit(&p.pur, $i, false); }
@@ -296,6 +483,8 @@ macro_rules! gpio_macro {
                     ) -> $PXi<Input<PullDown>> {
                         let p = unsafe { &*$GPIOX::ptr() };
                         unsafe { bb::change_bit(&p.afsel, $i, false); }
+                        #[cfg(feature = "pin-conflict-check")]
+                        clear_pctl($i);
                         unsafe { bb::change_bit(&p.dir, $i, false); }
                         unsafe { bb::change_bit(&p.odr, $i, false); }
                         unsafe { bb::change_bit(&p.pur, $i, false); }
@@ -310,6 +499,8 @@ macro_rules! gpio_macro {
                     ) -> $PXi<Input<PullUp>> {
                         let p = unsafe { &*$GPIOX::ptr() };
                         unsafe { bb::change_bit(&p.afsel, $i, false); }
+                        #[cfg(feature = "pin-conflict-check")]
+                        clear_pctl($i);
                         unsafe { bb::change_bit(&p.dir, $i, false); }
                         unsafe { bb::change_bit(&p.odr, $i, false); }
                         unsafe { bb::change_bit(&p.pur, $i, true); }
@@ -324,6 +515,8 @@ macro_rules! gpio_macro {
                     ) -> $PXi<Output<OpenDrain<ODM>>> where ODM: OpenDrainMode {
                         let p = unsafe { &*$GPIOX::ptr() };
                         unsafe { bb::change_bit(&p.afsel, $i, false); }
+                        #[cfg(feature = "pin-conflict-check")]
+                        clear_pctl($i);
                         unsafe { bb::change_bit(&p.dir, $i, true); }
                         unsafe { bb::change_bit(&p.odr, $i, true); }
                         unsafe { bb::change_bit(&p.pur, $i, ODM::pup()); }
@@ -338,6 +531,8 @@ macro_rules! gpio_macro {
                     ) -> $PXi<Output<PushPull>> {
                         let p = unsafe { &*$GPIOX::ptr() };
                         unsafe { bb::change_bit(&p.afsel, $i, false); }
+                        #[cfg(feature = "pin-conflict-check")]
+                        clear_pctl($i);
                         unsafe { bb::change_bit(&p.dir, $i, true); }
                         unsafe { bb::change_bit(&p.odr, $i, false); }
                         unsafe { bb::change_bit(&p.pur, $i, false); }
@@ -353,6 +548,8 @@ macro_rules! gpio_macro {
                         let p = unsafe { &*$GPIOX::ptr() };
                         unsafe { bb::change_bit(&p.den, $i, false); }
                         unsafe { bb::change_bit(&p.afsel, $i, false); }
+                        #[cfg(feature = "pin-conflict-check")]
+                        clear_pctl($i);
                         unsafe { bb::change_bit(&p.dir, $i, false); }
                         unsafe { bb::change_bit(&p.odr, $i, false); }
                         unsafe { bb::change_bit(&p.pur, $i, false); }
@@ -375,6 +572,53 @@ macro_rules! gpio_macro {
                     }
                 }
 
+                impl<MODE> $crate::gpio::RecoverablePin for $PXi<MODE> where MODE: IsUnlocked {
+                    type Input = $PXi<Input<Floating>>;
+
+                    unsafe fn steal() -> Self {
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    fn into_input(self) -> Self::Input {
+                        self.into_floating_input()
+                    }
+                }
+
+                impl<AF, MODE> $crate::gpio::Remux for $PXi<AlternateFunction<AF, MODE>>
+                where
+                    AF: AlternateFunctionChoice,
+                    MODE: OutputMode,
+                {
+                    fn gpio_input(&mut self) {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        unsafe { bb::change_bit(&p.dir, $i, false); }
+                        unsafe { bb::change_bit(&p.afsel, $i, false); }
+                    }
+
+                    fn gpio_output(&mut self, high: bool) {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        unsafe { bb::change_bit(&p.data, $i, high); }
+                        unsafe { bb::change_bit(&p.dir, $i, true); }
+                        unsafe { bb::change_bit(&p.afsel, $i, false); }
+                    }
+
+                    fn set_level(&mut self, high: bool) {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        unsafe { bb::change_bit(&p.data, $i, high); }
+                    }
+
+                    fn level(&self) -> bool {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        bb::read_bit(&p.data, $i)
+                    }
+
+                    fn alternate(&mut self) {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        unsafe { bb::change_bit(&p.afsel, $i, true); }
+                        unsafe { bb::change_bit(&p.dir, $i, false); }
+                    }
+                }
+
                 impl<MODE> StatefulOutputPin for $PXi<Output<MODE>> where MODE: OutputMode {
                     fn is_set_high(&self) -> bool {
                         let p = unsafe { &*$GPIOX::ptr() };
@@ -496,6 +740,85 @@ macro_rules! gpio_macro {
                     }
                 }
 
+                impl<AF, MODE> $PXi<AlternateFunction<AF, MODE>>
+                where
+                    AF: AlternateFunctionChoice,
+                    MODE: OutputMode,
+                {
+                    /// Temporarily disconnects the pin from its peripheral and
+                    /// arms a falling-edge interrupt on it, so that activity on
+                    /// the line can wake the chip while the peripheral is
+                    /// switched off. Pull and drive settings are kept. Call
+                    /// `exit_wake_mode` to hand the pin back.
+                    pub fn enter_wake_mode(&mut self) {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        // IM &= ~$i;
+                        unsafe { bb::change_bit(&p.im, $i, false); }
+                        // IS &= ~$i;
+                        unsafe { bb::change_bit(&p.is, $i, false); }
+                        // IBE &= ~$i;
+                        unsafe { bb::change_bit(&p.ibe, $i, false); }
+                        // IEV &= ~$i;
+                        unsafe { bb::change_bit(&p.iev, $i, false); }
+                        // Throw away any edge latched while the peripheral owned the pin
+                        unsafe { bb::change_bit(&p.icr, $i, true); }
+                        // DIR is already clear, so this leaves a plain input
+                        unsafe { bb::change_bit(&p.afsel, $i, false); }
+                        // IM |= $i;
+                        unsafe { bb::change_bit(&p.im, $i, true); }
+                    }
+
+                    /// Disarms the wake interrupt and reconnects the pin to its
+                    /// peripheral. Returns true if a falling edge was latched
+                    /// since `enter_wake_mode`.
+                    pub fn exit_wake_mode(&mut self) -> bool {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        // IM &= ~$i;
+                        unsafe { bb::change_bit(&p.im, $i, false); }
+                        let woken = bb::read_bit(&p.ris, $i);
+                        unsafe { bb::change_bit(&p.icr, $i, true); }
+                        unsafe { bb::change_bit(&p.afsel, $i, true); }
+                        woken
+                    }
+
+                    /// Temporarily disconnects the pin from its peripheral, so
+                    /// that its level can be read with `line_is_low`. Unlike
+                    /// `enter_wake_mode` this leaves the pin's interrupt
+                    /// settings alone, so edges on the line don't raise the
+                    /// port interrupt. Call `exit_sample_mode` to hand the pin
+                    /// back.
+                    pub fn enter_sample_mode(&mut self) {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        // DIR is already clear, so this leaves a plain input
+                        unsafe { bb::change_bit(&p.afsel, $i, false); }
+                    }
+
+                    /// Reconnects the pin to its peripheral after
+                    /// `enter_sample_mode`
+                    pub fn exit_sample_mode(&mut self) {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        unsafe { bb::change_bit(&p.afsel, $i, true); }
+                    }
+
+                    /// Reads the level on the pin while it is in wake or
+                    /// sample mode
+                    pub fn line_is_low(&self) -> bool {
+                        let p = unsafe { &*$GPIOX::ptr() };
+                        !bb::read_bit(&p.data, $i)
+                    }
+
+                    /// Disconnects the pin from its peripheral and leaves it
+                    /// tri-stated, with its PCTL nibble cleared under
+                    /// `pin-conflict-check`. This is the only way to mux a pin
+                    /// that a driver has handed back to a different function.
+                    pub fn release_alternate_function(
+                        self,
+                        _gpio_control: &mut GpioControl,
+                    ) -> $PXi<Tristate> {
+                        self.into_tri_state()
+                    }
+                }
+
                 impl $PXi<Locked> {
                     /// Unlock a GPIO so that it can be used. This is required
                     /// on 'special' GPIOs that the manufacturer doesn't want
@@ -505,6 +828,9 @@ macro_rules! gpio_macro {
                         p.lock.write(|w| w.lock().key());
                         p.cr.modify(|_, w| unsafe { w.bits(1 << $i) });
                         p.lock.write(|w| w.lock().unlocked());
+                        // Whatever the pin was committed to is ours to change now
+                        #[cfg(feature = "pin-conflict-check")]
+                        clear_pctl($i);
                         unsafe { bb::change_bit(&p.den, $i, false); }
                         unsafe { bb::change_bit(&p.afsel, $i, false); }
                         unsafe { bb::change_bit(&p.dir, $i, false); }
@@ -545,26 +871,26 @@ macro_rules! uart_hal_macro {
                     CTS: CtsPin<$UARTX>,
                     RTS: RtsPin<$UARTX>,
                 {
+                    clocks.assert_current();
+
                     // Enable UART peripheral clocks
                     sysctl::control_power(
                         pc, sysctl::Domain::$powerDomain,
                         sysctl::RunMode::Run, sysctl::PowerState::On);
-                    sysctl::reset(pc, sysctl::Domain::$powerDomain);
+                    sysctl::reset(pc, sysctl::Domain::$powerDomain)
+                        .expect("UART didn't come out of reset");
 
                     // Reset UART
                     uart.ctl.reset();
 
                     // Calculate baud rate dividers
-                    // baud_int = 64 * (sys_clk / (16 * baud))
-                    // baud_int = 4 * (sys_clk / baud)
-                    // baud_int = ((8 * sys_clk) / baud) / 2, plus + 1 to round correctly
-                    let baud_int: u32 = (((clocks.sysclk.0 * 8) / baud_rate.0) + 1) / 2;
+                    let (ibrd, fbrd) = $crate::calc::uart_divisor(clocks.sysclk.0, baud_rate.0);
 
                     // Set baud rate
                     uart.ibrd.write(|w|
-                        unsafe { w.divint().bits((baud_int / 64) as u16) });
+                        unsafe { w.divint().bits(ibrd) });
                     uart.fbrd.write(|w|
-                        unsafe { w.divfrac().bits((baud_int % 64) as u8) });
+                        unsafe { w.divfrac().bits(fbrd) });
 
                     // Set data bits / parity / stop bits / enable fifo
                     uart.lcrh.write(|w| w.wlen()._8().fen().bit(true));
@@ -576,24 +902,26 @@ macro_rules! uart_hal_macro {
                     // Enable uart
                     uart.ctl.modify(|_, w| w.rxe().bit(true).txe().bit(true).uarten().bit(true));
 
-                    Serial { uart, tx_pin, rx_pin, rts_pin, cts_pin, nl_mode }
+                    Serial { uart, tx_pin, rx_pin, rts_pin, cts_pin, nl_mode, stats: Stats::default() }
                 }
 
                 /// Change the current baud rate for the UART. We need the
                 /// `clocks` object in order to calculate the magic baud rate
                 /// register values.
                 pub fn change_baud_rate(&mut self, baud_rate: Bps, clocks: &Clocks) {
+                    clocks.assert_current();
+
                     // Stop UART
                     self.uart.ctl.modify(|_, w| w.uarten().bit(false));
 
                     // Calculate baud rate dividers
-                    let baud_int: u32 = (((clocks.sysclk.0 * 8) / baud_rate.0) + 1) / 2;
+                    let (ibrd, fbrd) = $crate::calc::uart_divisor(clocks.sysclk.0, baud_rate.0);
 
                     // Set baud rate
                     self.uart.ibrd.write(|w|
-                        unsafe { w.divint().bits((baud_int / 64) as u16) });
+                        unsafe { w.divint().bits(ibrd) });
                     self.uart.fbrd.write(|w|
-                        unsafe { w.divfrac().bits((baud_int % 64) as u8) });
+                        unsafe { w.divfrac().bits(fbrd) });
 
                     // Set data bits / parity / stop bits / enable fifo
                     // If you don't write to this register, the baud rate change doesn't take effect
@@ -613,11 +941,13 @@ macro_rules! uart_hal_macro {
                             pin: self.tx_pin,
                             nl_mode: self.nl_mode,
                             flow_pin: self.rts_pin,
+                            stats: self.stats,
                         },
                         Rx {
                             _uart: PhantomData,
                             pin: self.rx_pin,
                             flow_pin: self.cts_pin,
+                            stats: self.stats,
                         },
                     )
                 }
@@ -627,6 +957,8 @@ macro_rules! uart_hal_macro {
                 where
                     I: AsRef<[u8]>,
                 {
+                    $crate::assert_can_block!(concat!(stringify!($UARTX), " write_all"));
+                    $crate::rt_budget_scope!(concat!(stringify!($UARTX), " write_all"));
                     for octet in data.as_ref().iter() {
                         block!(self.write(*octet)).unwrap(); // E = Void
                     }
@@ -641,35 +973,208 @@ macro_rules! uart_hal_macro {
                         tx_pin: tx.pin,
                         rts_pin: tx.flow_pin,
                         cts_pin: rx.flow_pin,
+                        stats: Stats::merge(rx.stats, tx.stats),
                     }
                 }
 
-                /// Releases the UART peripheral and associated pins
+                /// Releases the UART peripheral and associated pins. Anything
+                /// still in the transmit FIFO is sent first, then the UART is
+                /// disabled so it stops driving the TX pin.
                 pub fn free(self) -> ($UARTX, TX, RX, RTS, CTS) {
+                    while self.uart.fr.read().busy().bit() {}
+                    self.uart.ctl.modify(|_, w| w.uarten().bit(false));
                     (self.uart, self.tx_pin, self.rx_pin, self.rts_pin, self.cts_pin)
                 }
+
+                /// Like `free`, but hands the pins back as floating inputs,
+                /// disconnected from the UART, so whatever uses them next
+                /// starts from a known state
+                pub fn free_into_inputs(self) -> ($UARTX, TX::Input, RX::Input, RTS::Input, CTS::Input)
+                where
+                    TX: $crate::gpio::RecoverablePin,
+                    RX: $crate::gpio::RecoverablePin,
+                    RTS: $crate::gpio::RecoverablePin,
+                    CTS: $crate::gpio::RecoverablePin,
+                {
+                    let (uart, tx, rx, rts, cts) = self.free();
+                    (uart, tx.into_input(), rx.into_input(), rts.into_input(), cts.into_input())
+                }
+
+                /// Makes this driver's pins out of thin air, for recovery
+                /// code, such as a fault handler, that can't get at the
+                /// driver to `free` it. The pins keep whatever mode they
+                /// were in; `into_input` them before use. The UART itself
+                /// isn't touched, so steal it from the PAC if you need it.
+                ///
+                /// # Safety
+                ///
+                /// The driver that owned the pins (or its `Tx` and `Rx`
+                /// halves), and anything else holding them, must never run
+                /// again.
+                pub unsafe fn recover_pins() -> (TX, RX, RTS, CTS)
+                where
+                    TX: $crate::gpio::RecoverablePin,
+                    RX: $crate::gpio::RecoverablePin,
+                    RTS: $crate::gpio::RecoverablePin,
+                    CTS: $crate::gpio::RecoverablePin,
+                {
+                    (TX::steal(), RX::steal(), RTS::steal(), CTS::steal())
+                }
+
+                /// Like `free`, but also stops the UART's run-mode clock. The
+                /// next constructor call turns it back on.
+                pub fn power_down(self, pc: &sysctl::PowerControl) -> ($UARTX, TX, RX, RTS, CTS) {
+                    let parts = self.free();
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::Off);
+                    parts
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> Serial<$UARTX, TX, RX, RTS, CTS> where RX: RxPin<$UARTX> {
+                /// Gets the UART ready for deep sleep. Waits for the transmitter
+                /// to drain, switches the UART off and turns the RX pin into a
+                /// falling-edge interrupt source, so a start bit on the line
+                /// can wake the chip.
+                ///
+                /// The GPIO port of the RX pin must stay clocked in deep sleep
+                /// (see `sysctl::control_power` with `RunMode::DeepSleep`) and
+                /// its interrupt must be enabled in the NVIC. Sleep with
+                /// interrupts masked (e.g. `wfi` inside `interrupt::free`) so
+                /// that the edge wakes the core without the port handler
+                /// clearing it before `resume_from_sleep` can see it.
+                ///
+                /// The character whose start bit woke us is lost: the UART is
+                /// off while it arrives, and the tail of it may come out as
+                /// a framing error once the UART is back on.
+                pub fn prepare_for_sleep(&mut self) {
+                    while self.uart.fr.read().busy().bit() {}
+                    self.uart.ctl.modify(|_, w| w.uarten().bit(false));
+                    self.rx_pin.enter_wake_mode();
+                }
+
+                /// Undoes `prepare_for_sleep`: gives the RX pin back to the UART
+                /// and switches the UART on again. Returns true if activity on
+                /// the RX line was seen while asleep.
+                pub fn resume_from_sleep(&mut self) -> bool {
+                    let woken = self.rx_pin.exit_wake_mode();
+                    self.uart.ctl.modify(|_, w| w.uarten().bit(true));
+                    woken
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> Serial<$UARTX, TX, RX, RTS, CTS> {
+                /// The NVIC interrupt this UART raises
+                pub const INTERRUPT: Interrupt = Interrupt::$UARTX;
+
+                $crate::nvic_control!("this UART's interrupt");
+            }
+
+            impl<TX, RX, RTS, CTS> Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn regs(&self) -> $crate::regs::Mmio {
+                    // The driver owns the peripheral
+                    unsafe { $crate::regs::Mmio::new(&*self.uart, false) }
+                }
+
+                /// The underlying UART peripheral, for registers this driver
+                /// doesn't cover
+                pub fn inner(&self) -> &$UARTX {
+                    &self.uart
+                }
+
+                /// Mutable access to the underlying UART peripheral. Leave
+                /// the registers the driver relies on (enable, clocking, frame
+                /// format) as you found them, or the driver will misbehave.
+                pub fn inner_mut(&mut self) -> &mut $UARTX {
+                    &mut self.uart
+                }
+
+                /// Error and flow counters since the UART was set up or the
+                /// counters were last reset
+                pub fn stats(&self) -> Stats {
+                    self.stats
+                }
+
+                /// Zeroes the counters `stats` returns
+                pub fn reset_stats(&mut self) {
+                    self.stats = Stats::default();
+                }
+
+                /// Waits for a byte until `deadline`. A byte that arrives
+                /// with an error flag is dropped and the error returned
+                /// instead; `Error::Timeout` means nothing arrived in time.
+                pub fn read_with_deadline(
+                    &mut self,
+                    deadline: $crate::time::Instant,
+                ) -> Result<u8, $crate::serial::Error> {
+                    loop {
+                        if let Some(dr) = $crate::serial::take_rx(&self.regs(), &mut self.stats) {
+                            return match $crate::serial::Error::from_dr(dr) {
+                                Some(e) => Err(e),
+                                None => Ok(dr as u8),
+                            };
+                        }
+                        if deadline.has_passed() {
+                            return Err($crate::serial::Error::Timeout);
+                        }
+                    }
+                }
             }
 
             impl<TX, RTS> Tx<$UARTX, TX, RTS> {
+                fn regs(&self) -> $crate::regs::Mmio {
+                    // The transmit half owns the peripheral
+                    unsafe { $crate::regs::Mmio::new(&*self.uart, false) }
+                }
+
                 /// Write a complete string to the UART.
                 pub fn write_all<I: ?Sized>(&mut self, data: &I)
                 where
                     I: AsRef<[u8]>,
                 {
+                    $crate::assert_can_block!(concat!(stringify!($UARTX), " write_all"));
+                    $crate::rt_budget_scope!(concat!(stringify!($UARTX), " write_all"));
                     for octet in data.as_ref().iter() {
                         block!(self.write(*octet)).unwrap(); // E = Void
                     }
                 }
+
+                /// Transmit counters since the UART was set up or the
+                /// counters were last reset. Only `tx_stalls` is kept up to
+                /// date here; the receive counters are in the `Rx` half.
+                pub fn stats(&self) -> Stats {
+                    self.stats
+                }
+
+                /// Zeroes the counters `stats` returns
+                pub fn reset_stats(&mut self) {
+                    self.stats = Stats::default();
+                }
+            }
+
+            impl<RX, CTS> Rx<$UARTX, RX, CTS> {
+                /// Receive counters since the UART was set up or the
+                /// counters were last reset. `tx_stalls` is kept by the `Tx`
+                /// half instead.
+                pub fn stats(&self) -> Stats {
+                    self.stats
+                }
+
+                /// Zeroes the counters `stats` returns
+                pub fn reset_stats(&mut self) {
+                    self.stats = Stats::default();
+                }
             }
 
             impl<TX, RX, RTS, CTS> serial::Read<u8> for Serial<$UARTX, TX, RX, RTS, CTS> {
                 type Error = Void;
 
                 fn read(&mut self) -> nb::Result<u8, Self::Error> {
-                    if self.uart.fr.read().rxfe().bit() {
-                        return Err(nb::Error::WouldBlock);
+                    match $crate::serial::take_rx(&self.regs(), &mut self.stats) {
+                        Some(dr) => Ok(dr as u8),
+                        None => Err(nb::Error::WouldBlock),
                     }
-                    Ok(self.uart.dr.read().data().bits())
                 }
             }
 
@@ -678,11 +1183,68 @@ macro_rules! uart_hal_macro {
 
                 fn read(&mut self) -> nb::Result<u8, Self::Error> {
                     // We're only doing RX operations here so this is safe.
-                    let p = unsafe { &*$UARTX::ptr() };
-                    if p.fr.read().rxfe().bit() {
-                        return Err(nb::Error::WouldBlock);
+                    let regs = unsafe { $crate::regs::Mmio::new($UARTX::ptr(), false) };
+                    match $crate::serial::take_rx(&regs, &mut self.stats) {
+                        Some(dr) => Ok(dr as u8),
+                        None => Err(nb::Error::WouldBlock),
                     }
-                    Ok(p.dr.read().data().bits())
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> Serial<$UARTX, TX, RX, RTS, CTS> {
+                /// `serial::Read::read`, for interrupt handlers; see `isr`
+                pub fn read_from_isr(
+                    &mut self,
+                    _isr: &$crate::isr::IsrContext,
+                ) -> nb::Result<u8, Void> {
+                    serial::Read::read(self)
+                }
+
+                /// `serial::Write::write`, for interrupt handlers; see `isr`
+                pub fn write_from_isr(
+                    &mut self,
+                    _isr: &$crate::isr::IsrContext,
+                    byte: u8,
+                ) -> nb::Result<(), Void> {
+                    serial::Write::write(self, byte)
+                }
+            }
+
+            impl<RX, CTS> Rx<$UARTX, RX, CTS> {
+                /// `serial::Read::read`, for interrupt handlers; see `isr`
+                pub fn read_from_isr(
+                    &mut self,
+                    _isr: &$crate::isr::IsrContext,
+                ) -> nb::Result<u8, Void> {
+                    serial::Read::read(self)
+                }
+            }
+
+            impl<TX, RTS> Tx<$UARTX, TX, RTS> {
+                /// `serial::Write::write`, for interrupt handlers; see `isr`
+                pub fn write_from_isr(
+                    &mut self,
+                    _isr: &$crate::isr::IsrContext,
+                    byte: u8,
+                ) -> nb::Result<(), Void> {
+                    serial::Write::write(self, byte)
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> crate::hal::blocking::serial::Write<u8> for Serial<$UARTX, TX, RX, RTS, CTS> {
+                type Error = Void;
+
+                fn bwrite_all(&mut self, buffer: &[u8]) -> Result<(), Void> {
+                    self.write_all(buffer);
+                    Ok(())
+                }
+
+                /// Waits until the last character has left the shift
+                /// register, not just the FIFO
+                fn bflush(&mut self) -> Result<(), Void> {
+                    $crate::rt_budget_scope!(concat!(stringify!($UARTX), " flush"));
+                    while self.uart.fr.read().busy().bit() {}
+                    Ok(())
                 }
             }
 
@@ -690,17 +1252,34 @@ macro_rules! uart_hal_macro {
                 type Error = Void;
 
                 fn flush(&mut self) -> nb::Result<(), Void> {
-                    if self.uart.fr.read().txff().bit() {
+                    if $crate::serial::tx_full(&self.regs()) {
                         return Err(nb::Error::WouldBlock);
                     }
                     Ok(())
                 }
 
                 fn write(&mut self, byte: u8) -> nb::Result<(), Void> {
-                    if self.uart.fr.read().txff().bit() {
-                        return Err(nb::Error::WouldBlock);
+                    if $crate::serial::put_tx(&self.regs(), &mut self.stats, byte) {
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
                     }
-                    self.uart.dr.write(|w| unsafe { w.data().bits(byte) });
+                }
+            }
+
+            impl<TX, RTS> crate::hal::blocking::serial::Write<u8> for Tx<$UARTX, TX, RTS> {
+                type Error = Void;
+
+                fn bwrite_all(&mut self, buffer: &[u8]) -> Result<(), Void> {
+                    self.write_all(buffer);
+                    Ok(())
+                }
+
+                /// Waits until the last character has left the shift
+                /// register, not just the FIFO
+                fn bflush(&mut self) -> Result<(), Void> {
+                    $crate::rt_budget_scope!(concat!(stringify!($UARTX), " flush"));
+                    while self.uart.fr.read().busy().bit() {}
                     Ok(())
                 }
             }
@@ -709,18 +1288,61 @@ macro_rules! uart_hal_macro {
                 type Error = Void;
 
                 fn flush(&mut self) -> nb::Result<(), Void> {
-                    if self.uart.fr.read().txff().bit() {
+                    if $crate::serial::tx_full(&self.regs()) {
                         return Err(nb::Error::WouldBlock);
                     }
                     Ok(())
                 }
 
                 fn write(&mut self, byte: u8) -> nb::Result<(), Void> {
-                    if self.uart.fr.read().txff().bit() {
-                        return Err(nb::Error::WouldBlock);
+                    if $crate::serial::put_tx(&self.regs(), &mut self.stats, byte) {
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
                     }
-                    self.uart.dr.write(|w| unsafe { w.data().bits(byte) });
-                    Ok(())
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::post::SelfTest for Serial<$UARTX, TX, RX, RTS, CTS> {
+                /// Sends a couple of patterns through the UART's internal
+                /// loopback. Anything waiting in the receive FIFO is lost.
+                fn self_test(&mut self) -> bool {
+                    while self.uart.fr.read().busy().bit() {}
+                    while !self.uart.fr.read().rxfe().bit() {
+                        let _ = self.uart.dr.read();
+                    }
+                    self.uart.ctl.modify(|_, w| w.lbe().set_bit());
+
+                    let mut ok = true;
+                    for &pattern in &[0x55u8, 0xAA] {
+                        self.uart.dr.write(|w| unsafe { w.data().bits(pattern) });
+                        // Once the transmitter is idle the byte has looped round
+                        while self.uart.fr.read().busy().bit() {}
+                        if self.uart.fr.read().rxfe().bit() {
+                            ok = false;
+                            break;
+                        }
+                        let dr = self.uart.dr.read();
+                        // Bits 11:8 are the OE, BE, PE and FE flags
+                        if dr.data().bits() != pattern || dr.bits() & 0xF00 != 0 {
+                            ok = false;
+                        }
+                    }
+
+                    self.uart.ctl.modify(|_, w| w.lbe().clear_bit());
+                    ok
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::diag::Diagnose for Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn diagnose(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    write!(f, "{}:", stringify!($UARTX))?;
+                    // Receive status: FE, PE, BE, OE
+                    $crate::diag::write_flags(
+                        f,
+                        self.uart.rsr.read().bits(),
+                        &[(0, "framing"), (1, "parity"), (2, "break"), (3, "overrun")],
+                    )
                 }
             }
 
@@ -805,7 +1427,27 @@ macro_rules! uart_pin_macro {
             unsafe impl <T> RxPin<$UARTn> for $($rxgpio)::*<AlternateFunction<$rxaf, T>>
             where
                 T: OutputMode,
-            {}
+            {
+                fn enter_wake_mode(&mut self) {
+                    $($rxgpio)::*::enter_wake_mode(self);
+                }
+
+                fn exit_wake_mode(&mut self) -> bool {
+                    $($rxgpio)::*::exit_wake_mode(self)
+                }
+
+                fn enter_sample_mode(&mut self) {
+                    $($rxgpio)::*::enter_sample_mode(self);
+                }
+
+                fn exit_sample_mode(&mut self) {
+                    $($rxgpio)::*::exit_sample_mode(self);
+                }
+
+                fn line_is_low(&self) -> bool {
+                    $($rxgpio)::*::line_is_low(self)
+                }
+            }
         )*
 
         $(
@@ -0,0 +1,64 @@
+//! Non-maskable interrupt causes and a safe-state hook
+//!
+//! Register a handler with `set_handler` early in `main`, before anything
+//! that can raise an NMI is enabled, then call the chip crate's
+//! `nmi::handle` from the application's `NMI` exception handler. The
+//! handler is told what caused the NMI and decides whether to carry on or
+//! reset.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m::peripheral::SCB;
+
+/// Why the NMI fired. More than one cause can be set at once.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct NmiCause {
+    /// The NMI pin was asserted, or software pended the NMI
+    pub external: bool,
+    /// A power supply fault (TM4C129 only)
+    pub power: bool,
+    /// Watchdog 0 timed out
+    pub watchdog0: bool,
+    /// Watchdog 1 timed out
+    pub watchdog1: bool,
+    /// A hibernation tamper event (TM4C129 only)
+    pub tamper: bool,
+    /// The main oscillator failed (TM4C129 only)
+    pub mosc_fail: bool,
+}
+
+/// What to do once the handler has run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Return from the NMI
+    Continue,
+    /// Reset the chip
+    Reset,
+}
+
+/// The handler, stored as a `usize` so the NMI can read it without a
+/// critical section (which couldn't keep the NMI out anyway). Zero means
+/// none is set.
+static HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the function `dispatch` calls. Do this before enabling any NMI
+/// source.
+pub fn set_handler(handler: fn(NmiCause) -> Action) {
+    HANDLER.store(handler as usize, Ordering::SeqCst);
+}
+
+/// Passes `cause` to the handler and carries out its decision. Without a
+/// handler the chip is reset, as there is no safe way to carry on from an
+/// NMI nobody planned for.
+pub fn dispatch(cause: NmiCause) {
+    let handler = HANDLER.load(Ordering::SeqCst);
+    let action = if handler == 0 {
+        Action::Reset
+    } else {
+        // Only ever stored from a `fn(NmiCause) -> Action` in `set_handler`
+        let handler: fn(NmiCause) -> Action = unsafe { core::mem::transmute(handler) };
+        handler(cause)
+    };
+    if action == Action::Reset {
+        SCB::sys_reset();
+    }
+}
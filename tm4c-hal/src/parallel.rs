@@ -0,0 +1,350 @@
+//! An 8080-style parallel bus, as used by ILI9341 and similar displays in
+//! their 8-bit parallel mode
+//!
+//! The data lines are a whole GPIO port (`gpio::ParallelPort`, normally a
+//! port's `DataPort`), so each byte goes out in one register write. WR is
+//! held low and then high for half the minimum cycle each, in whole system
+//! clock cycles, rounded up.
+
+use crate::gpio::ParallelPort;
+use crate::sysctl::Clocks;
+use crate::time::NanoSeconds;
+use embedded_hal::digital::OutputPin;
+
+/// System clock cycles in half of `cycle`, rounded up, so that a low and a
+/// high phase of this length each make up at least the whole cycle
+pub fn half_cycle(cycle: NanoSeconds, sysclk: u32) -> u32 {
+    let cycles = (u64::from(cycle.0) * u64::from(sysclk) + 1_999_999_999) / 2_000_000_000;
+    cycles as u32
+}
+
+/// An 8080 bus master: the data port plus the WR, DC (data/command, also
+/// called RS) and CS strobes, and optionally RD
+pub struct ParallelBus8080<P, WR, DC, CS, RD> {
+    port: P,
+    wr: WR,
+    dc: DC,
+    cs: CS,
+    rd: RD,
+    write_half: u32,
+    read_half: u32,
+}
+
+impl<P, WR, DC, CS> ParallelBus8080<P, WR, DC, CS, ()>
+where
+    P: ParallelPort,
+    WR: OutputPin,
+    DC: OutputPin,
+    CS: OutputPin,
+{
+    /// A write-only bus whose write cycle is at least `write_cycle` long
+    /// (66 ns for the ILI9341) at the system clock in `clocks`. WR and CS
+    /// are set high, which is idle.
+    pub fn new(
+        port: P,
+        mut wr: WR,
+        dc: DC,
+        mut cs: CS,
+        write_cycle: NanoSeconds,
+        clocks: &Clocks,
+    ) -> Self {
+        wr.set_high();
+        cs.set_high();
+        ParallelBus8080 {
+            port,
+            wr,
+            dc,
+            cs,
+            rd: (),
+            write_half: half_cycle(write_cycle, clocks.sysclk.0),
+            read_half: 0,
+        }
+    }
+
+    /// Adds the RD strobe, for `read_data`, with a read cycle of at least
+    /// `read_cycle`. RD is set high, which is idle.
+    pub fn with_read<RD: OutputPin>(
+        self,
+        mut rd: RD,
+        read_cycle: NanoSeconds,
+        clocks: &Clocks,
+    ) -> ParallelBus8080<P, WR, DC, CS, RD> {
+        rd.set_high();
+        ParallelBus8080 {
+            port: self.port,
+            wr: self.wr,
+            dc: self.dc,
+            cs: self.cs,
+            rd,
+            write_half: self.write_half,
+            read_half: half_cycle(read_cycle, clocks.sysclk.0),
+        }
+    }
+}
+
+impl<P, WR, DC, CS, RD> ParallelBus8080<P, WR, DC, CS, RD>
+where
+    P: ParallelPort,
+    WR: OutputPin,
+    DC: OutputPin,
+    CS: OutputPin,
+{
+    /// Sends `command` with DC low
+    pub fn write_command(&mut self, command: u8) {
+        self.write(false, Some(command).into_iter());
+    }
+
+    /// Sends `data` with DC high
+    pub fn write_data(&mut self, data: &[u8]) {
+        self.write(true, data.iter().cloned());
+    }
+
+    /// Gives back the port and the pins
+    pub fn free(self) -> (P, WR, DC, CS, RD) {
+        (self.port, self.wr, self.dc, self.cs, self.rd)
+    }
+
+    /// Clocks out `bytes` in one CS-low transaction. The display latches
+    /// each byte on the rising edge of WR.
+    fn write<I: Iterator<Item = u8>>(&mut self, data: bool, bytes: I) {
+        if data {
+            self.dc.set_high();
+        } else {
+            self.dc.set_low();
+        }
+        self.cs.set_low();
+        for byte in bytes {
+            self.port.write(byte);
+            self.wr.set_low();
+            wait(self.write_half);
+            self.wr.set_high();
+            wait(self.write_half);
+        }
+        self.cs.set_high();
+    }
+}
+
+impl<P, WR, DC, CS, RD> ParallelBus8080<P, WR, DC, CS, RD>
+where
+    P: ParallelPort,
+    WR: OutputPin,
+    DC: OutputPin,
+    CS: OutputPin,
+    RD: OutputPin,
+{
+    /// Fills `buffer` from the display with DC high. The data lines are
+    /// released while RD is pulsed and driven again afterwards.
+    pub fn read_data(&mut self, buffer: &mut [u8]) {
+        self.dc.set_high();
+        self.cs.set_low();
+        self.port.release();
+        for byte in buffer.iter_mut() {
+            self.rd.set_low();
+            wait(self.read_half);
+            // The display holds the data until shortly after RD rises
+            *byte = self.port.sample();
+            self.rd.set_high();
+            wait(self.read_half);
+        }
+        self.port.drive();
+        self.cs.set_high();
+    }
+}
+
+fn wait(cycles: u32) {
+    if cycles > 0 {
+        cortex_m::asm::delay(cycles);
+    }
+}
+
+#[cfg(feature = "display-interface")]
+impl<P, WR, DC, CS, RD> display_interface::WriteOnlyDataCommand
+    for ParallelBus8080<P, WR, DC, CS, RD>
+where
+    P: ParallelPort,
+    WR: OutputPin,
+    DC: OutputPin,
+    CS: OutputPin,
+{
+    fn send_commands(
+        &mut self,
+        commands: display_interface::DataFormat<'_>,
+    ) -> Result<(), display_interface::DisplayError> {
+        match commands {
+            display_interface::DataFormat::U8(bytes) => {
+                self.write(false, bytes.iter().cloned());
+                Ok(())
+            }
+            _ => Err(display_interface::DisplayError::DataFormatNotImplemented),
+        }
+    }
+
+    fn send_data(
+        &mut self,
+        data: display_interface::DataFormat<'_>,
+    ) -> Result<(), display_interface::DisplayError> {
+        use display_interface::DataFormat;
+
+        match data {
+            DataFormat::U8(bytes) => self.write(true, bytes.iter().cloned()),
+            DataFormat::U8Iter(bytes) => self.write(true, bytes),
+            DataFormat::U16BE(words) => {
+                self.write(true, words.iter().flat_map(|w| w.to_be_bytes()))
+            }
+            DataFormat::U16LE(words) => {
+                self.write(true, words.iter().flat_map(|w| w.to_le_bytes()))
+            }
+            DataFormat::U16BEIter(words) => self.write(true, words.flat_map(u16::to_be_bytes)),
+            DataFormat::U16LEIter(words) => self.write(true, words.flat_map(u16::to_le_bytes)),
+            _ => return Err(display_interface::DisplayError::DataFormatNotImplemented),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::Hertz;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Event {
+        Port(u8),
+        Released,
+        Driven,
+        Pin(&'static str, bool),
+    }
+
+    type Log = Rc<RefCell<Vec<Event>>>;
+
+    struct Port {
+        log: Log,
+        input: u8,
+    }
+
+    impl ParallelPort for Port {
+        fn write(&mut self, value: u8) {
+            self.log.borrow_mut().push(Event::Port(value));
+        }
+
+        fn release(&mut self) {
+            self.log.borrow_mut().push(Event::Released);
+        }
+
+        fn sample(&self) -> u8 {
+            self.input
+        }
+
+        fn drive(&mut self) {
+            self.log.borrow_mut().push(Event::Driven);
+        }
+    }
+
+    struct Pin(&'static str, Log);
+
+    impl OutputPin for Pin {
+        fn set_low(&mut self) {
+            self.1.borrow_mut().push(Event::Pin(self.0, false));
+        }
+
+        fn set_high(&mut self) {
+            self.1.borrow_mut().push(Event::Pin(self.0, true));
+        }
+    }
+
+    fn bus(log: &Log) -> ParallelBus8080<Port, Pin, Pin, Pin, ()> {
+        let port = Port {
+            log: log.clone(),
+            input: 0x5A,
+        };
+        let clocks = Clocks::new(Hertz(16_000_000), Hertz(16_000_000));
+        // A zero cycle skips the delays, which can't run on the host
+        let bus = ParallelBus8080::new(
+            port,
+            Pin("wr", log.clone()),
+            Pin("dc", log.clone()),
+            Pin("cs", log.clone()),
+            NanoSeconds(0),
+            &clocks,
+        );
+        log.borrow_mut().clear();
+        bus
+    }
+
+    #[test]
+    fn half_cycles_round_up() {
+        // The ILI9341's 66 ns write cycle
+        assert_eq!(half_cycle(NanoSeconds(66), 80_000_000), 3);
+        assert_eq!(half_cycle(NanoSeconds(66), 120_000_000), 4);
+        assert_eq!(half_cycle(NanoSeconds(66), 16_000_000), 1);
+        // Exactly two cycles a half at 80 MHz
+        assert_eq!(half_cycle(NanoSeconds(50), 80_000_000), 2);
+        assert_eq!(half_cycle(NanoSeconds(0), 120_000_000), 0);
+    }
+
+    #[test]
+    fn command_goes_out_with_dc_low_and_wr_pulsed() {
+        let log = Log::default();
+        let mut bus = bus(&log);
+        bus.write_command(0x2C);
+        assert_eq!(
+            *log.borrow(),
+            [
+                Event::Pin("dc", false),
+                Event::Pin("cs", false),
+                Event::Port(0x2C),
+                Event::Pin("wr", false),
+                Event::Pin("wr", true),
+                Event::Pin("cs", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn data_bytes_each_get_a_wr_pulse() {
+        let log = Log::default();
+        let mut bus = bus(&log);
+        bus.write_data(&[1, 2]);
+        let log = log.borrow();
+        assert_eq!(log[0], Event::Pin("dc", true));
+        assert_eq!(
+            log[2..8],
+            [
+                Event::Port(1),
+                Event::Pin("wr", false),
+                Event::Pin("wr", true),
+                Event::Port(2),
+                Event::Pin("wr", false),
+                Event::Pin("wr", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_releases_the_port_around_rd_pulses() {
+        let log = Log::default();
+        let clocks = Clocks::new(Hertz(16_000_000), Hertz(16_000_000));
+        let mut bus = bus(&log).with_read(Pin("rd", log.clone()), NanoSeconds(0), &clocks);
+        log.borrow_mut().clear();
+        let mut buffer = [0; 2];
+        bus.read_data(&mut buffer);
+        assert_eq!(buffer, [0x5A, 0x5A]);
+        assert_eq!(
+            *log.borrow(),
+            [
+                Event::Pin("dc", true),
+                Event::Pin("cs", false),
+                Event::Released,
+                Event::Pin("rd", false),
+                Event::Pin("rd", true),
+                Event::Pin("rd", false),
+                Event::Pin("rd", true),
+                Event::Driven,
+                Event::Pin("cs", true),
+            ]
+        );
+    }
+}
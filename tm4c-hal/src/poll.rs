@@ -0,0 +1,43 @@
+//! Bounded waits on hardware status bits
+//!
+//! A bare `while !ready() {}` hangs for good if the hardware never gets
+//! there, because of a missing crystal or a wedged bus, say. These helpers
+//! give up instead, so the driver can report the failure.
+
+use crate::time::Instant;
+
+/// The condition didn't come true in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeoutExpired;
+
+/// Calls `condition` until it returns true, giving up after `budget`
+/// calls. Each call costs at least one peripheral register read, so size
+/// budgets from the slowest clock the wait can run at, with plenty of
+/// margin.
+pub fn poll_until<F>(budget: u32, mut condition: F) -> Result<(), TimeoutExpired>
+where
+    F: FnMut() -> bool,
+{
+    for _ in 0..budget {
+        if condition() {
+            return Ok(());
+        }
+    }
+    Err(TimeoutExpired)
+}
+
+/// Calls `condition` until it returns true, giving up once `deadline` has
+/// passed. Needs the cycle counter running, which `MonoTimer::new` sees to.
+pub fn poll_until_deadline<F>(deadline: Instant, mut condition: F) -> Result<(), TimeoutExpired>
+where
+    F: FnMut() -> bool,
+{
+    loop {
+        if condition() {
+            return Ok(());
+        }
+        if deadline.has_passed() {
+            return Err(TimeoutExpired);
+        }
+    }
+}
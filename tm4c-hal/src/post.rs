@@ -0,0 +1,169 @@
+//! Power-on self test
+//!
+//! Drivers that can check themselves (usually with an internal loopback
+//! mode) implement `SelfTest`. A `PostConfig` lists them and says which are
+//! switched on; `run` goes through those, carries on past failures, and
+//! sums the outcome up in a `PostReport` small enough to print or keep in
+//! battery-backed memory.
+
+/// Most checks a `PostConfig` can hold, one per bit of `PostReport`
+pub const MAX_CHECKS: usize = 32;
+
+/// A driver that can check its own hardware
+pub trait SelfTest {
+    /// Runs the check, returning true if it passed. The driver is left
+    /// configured as it was before.
+    fn self_test(&mut self) -> bool;
+}
+
+/// More than `MAX_CHECKS` checks were given to `PostConfig::new`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TooManyChecks;
+
+/// The checks `run` should go through. Check `n` is the `n`th in the list
+/// given to `new`, and all of them start enabled.
+pub struct PostConfig<'a, 'b> {
+    checks: &'a mut [&'b mut dyn SelfTest],
+    enabled: u32,
+}
+
+impl<'a, 'b> PostConfig<'a, 'b> {
+    /// Every check in `checks`, of which there may be up to `MAX_CHECKS`
+    pub fn new(checks: &'a mut [&'b mut dyn SelfTest]) -> Result<Self, TooManyChecks> {
+        if checks.len() > MAX_CHECKS {
+            return Err(TooManyChecks);
+        }
+        let enabled = (1u64 << checks.len()) as u32;
+        Ok(PostConfig {
+            checks,
+            enabled: enabled.wrapping_sub(1),
+        })
+    }
+
+    /// Switches check `n` on or off. Numbers past the end of the list are
+    /// ignored.
+    pub fn enable(mut self, n: usize, on: bool) -> Self {
+        if n < self.checks.len() {
+            if on {
+                self.enabled |= 1 << n;
+            } else {
+                self.enabled &= !(1 << n);
+            }
+        }
+        self
+    }
+
+    /// Runs only the checks whose bits are set in `mask`
+    pub fn only(mut self, mask: u32) -> Self {
+        self.enabled &= mask;
+        self
+    }
+}
+
+/// Outcome of `run`. Bit `n` refers to the `n`th check in the list.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PostReport {
+    /// Checks that were run
+    pub ran: u32,
+    /// Checks that failed
+    pub failed: u32,
+}
+
+impl PostReport {
+    /// True if every check that ran passed
+    pub fn passed(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+/// Runs the checks `config` has enabled and reports which failed
+pub fn run(config: PostConfig) -> PostReport {
+    let mut report = PostReport::default();
+    for (i, check) in config.checks.iter_mut().enumerate() {
+        if config.enabled & (1 << i) == 0 {
+            continue;
+        }
+        report.ran |= 1 << i;
+        if !check.self_test() {
+            report.failed |= 1 << i;
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    struct Check {
+        pass: bool,
+        runs: u32,
+    }
+
+    impl SelfTest for Check {
+        fn self_test(&mut self) -> bool {
+            self.runs += 1;
+            self.pass
+        }
+    }
+
+    fn check(pass: bool) -> Check {
+        Check { pass, runs: 0 }
+    }
+
+    #[test]
+    fn failures_are_collected_not_returned_early() {
+        let (mut a, mut b, mut c) = (check(false), check(true), check(false));
+        let mut checks: [&mut dyn SelfTest; 3] = [&mut a, &mut b, &mut c];
+        let report = run(PostConfig::new(&mut checks).unwrap());
+        assert_eq!(
+            report,
+            PostReport {
+                ran: 0b111,
+                failed: 0b101
+            }
+        );
+        assert!(!report.passed());
+        assert_eq!(c.runs, 1);
+    }
+
+    #[test]
+    fn disabled_checks_are_skipped() {
+        let (mut a, mut b, mut c) = (check(false), check(true), check(true));
+        let mut checks: [&mut dyn SelfTest; 3] = [&mut a, &mut b, &mut c];
+        let config = PostConfig::new(&mut checks).unwrap().enable(0, false);
+        let report = run(config);
+        assert_eq!(
+            report,
+            PostReport {
+                ran: 0b110,
+                failed: 0
+            }
+        );
+        assert!(report.passed());
+        assert_eq!(a.runs, 0);
+    }
+
+    #[test]
+    fn only_runs_the_masked_checks() {
+        let (mut a, mut b) = (check(true), check(true));
+        let mut checks: [&mut dyn SelfTest; 2] = [&mut a, &mut b];
+        let config = PostConfig::new(&mut checks)
+            .unwrap()
+            .only(0b10)
+            .enable(7, true);
+        assert_eq!(run(config).ran, 0b10);
+    }
+
+    #[test]
+    fn thirty_two_checks_fit_and_no_more() {
+        let mut all: Vec<Check> = (0..33).map(|_| check(true)).collect();
+        let mut refs: Vec<&mut dyn SelfTest> =
+            all.iter_mut().map(|c| c as &mut dyn SelfTest).collect();
+        assert_eq!(PostConfig::new(&mut refs).err(), Some(TooManyChecks));
+        let report = run(PostConfig::new(&mut refs[..32]).unwrap());
+        assert_eq!(report.ran, u32::max_value());
+        assert!(report.passed());
+    }
+}
@@ -0,0 +1,467 @@
+//! GPS pulse-per-second discipline for the cycle counter
+//!
+//! `PpsDiscipline` measures the system clock against a GPS receiver's PPS
+//! output and provides a `now()` that counts true nanoseconds. Call `on_pps`
+//! from the interrupt handler for the pin the PPS signal is wired to, with
+//! the `Instant` of the edge. Reading `MonoTimer::now()` first thing in the
+//! handler works, at the cost of some interrupt-latency jitter.
+//!
+//! The cycle counter is extended to 64 bits internally, which only works if
+//! `on_pps`, `now` or `quality` is called at least once every 2^32 cycles
+//! (53 seconds at 80 MHz). While PPS is present that happens by itself; in
+//! holdover, make sure something calls `now` often enough.
+//!
+//! Every method takes `&mut self`, as both the edges and the readings move
+//! the 64-bit count and the anchor along, so `on_pps` in the handler and
+//! `now` in thread code can't both own a `PpsDiscipline`. Put it in a
+//! `SharedPps` instead, which can live in a `static` and runs each call in a
+//! short critical section.
+
+use crate::diag::{critical_section, CsSite};
+use crate::time::{Hertz, Instant, MonoTimer};
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+
+/// Number of one-second intervals the frequency estimate averages over
+const WINDOW: usize = 8;
+/// How far an interval may be from a whole number of seconds before the
+/// edge is rejected as an outlier
+const TOLERANCE_PPM: u64 = 1_000;
+/// The most the clock is slewed by per second, i.e. 500 ppm
+const MAX_SLEW_NS: u64 = 500_000;
+/// Consecutive outliers after which the PPS phase is reacquired
+const REACQUIRE_AFTER: u8 = 3;
+/// How far behind the newest cycle count a timestamp may be and still be
+/// taken as being in the past rather than a wrap of the counter
+const MAX_BEHIND: u32 = 1 << 24;
+const NS_PER_S: u64 = 1_000_000_000;
+
+/// How much `now()` can currently be trusted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    /// No PPS edge has been seen yet; `now()` runs at the nominal clock
+    /// frequency
+    NoSignal,
+    /// Edges are arriving, but the frequency window isn't full yet
+    Acquiring,
+    /// Edges are arriving and the frequency estimate is complete
+    Locked,
+    /// PPS has stopped. `now()` carries on at the last measured frequency.
+    Holdover,
+}
+
+/// Disciplines the cycle counter to a PPS signal. See the module
+/// documentation.
+pub struct PpsDiscipline {
+    timer: MonoTimer,
+    nominal: u64,
+    last_raw: u32,
+    ticks: u64,
+    // now() = ns_anchor + (ticks - tick_anchor) * advance / rate, for up to
+    // one second after tick_anchor
+    tick_anchor: u64,
+    ns_anchor: u64,
+    advance: u64,
+    rate: u64,
+    last_output: u64,
+    // Ticks and disciplined time of the last accepted edge
+    last_edge: Option<(u64, u64)>,
+    samples: [u32; WINDOW],
+    sample_count: usize,
+    next_sample: usize,
+    rejected_in_row: u8,
+    outliers: u32,
+}
+
+impl PpsDiscipline {
+    /// Starts an undisciplined clock at zero, running at `timer`'s nominal
+    /// frequency
+    pub fn new(timer: MonoTimer) -> Self {
+        Self::new_at(timer, timer.now().ticks())
+    }
+
+    fn new_at(timer: MonoTimer, raw: u32) -> Self {
+        let nominal = u64::from(timer.frequency().0);
+        PpsDiscipline {
+            timer,
+            nominal,
+            last_raw: raw,
+            ticks: 0,
+            tick_anchor: 0,
+            ns_anchor: 0,
+            advance: NS_PER_S,
+            rate: nominal,
+            last_output: 0,
+            last_edge: None,
+            samples: [0; WINDOW],
+            sample_count: 0,
+            next_sample: 0,
+            rejected_in_row: 0,
+            outliers: 0,
+        }
+    }
+
+    /// Disciplined nanoseconds since `new`. Never goes backwards.
+    ///
+    /// The first PPS edge moves the clock forward to the next whole second,
+    /// so from then on PPS edges fall on whole seconds. After that,
+    /// differences between the clock and PPS are slewed out at no more than
+    /// 500 ppm, never stepped.
+    pub fn now(&mut self) -> u64 {
+        self.now_at(self.timer.now().ticks())
+    }
+
+    fn now_at(&mut self, raw: u32) -> u64 {
+        let t = self.extend(raw);
+        let ns = self.time_at(t).max(self.last_output);
+        self.last_output = ns;
+        ns
+    }
+
+    /// Feeds in a PPS edge, timestamped as close to the edge as possible.
+    /// Edges that aren't a whole number of seconds after the previous one,
+    /// such as glitches or double triggers, are counted as outliers and
+    /// otherwise ignored. Missed edges are fine. After three outliers in a
+    /// row the phase is reacquired from the latest edge, which moves the
+    /// clock forward to the next whole second as the first edge did.
+    pub fn on_pps(&mut self, edge: Instant) {
+        self.on_edge(edge.ticks())
+    }
+
+    fn on_edge(&mut self, raw: u32) {
+        let t = self.extend(raw);
+        let (prev_t, prev_ns) = match self.last_edge {
+            Some(prev) => prev,
+            None => return self.acquire(t),
+        };
+
+        let interval = t.saturating_sub(prev_t);
+        let seconds = (interval + self.rate / 2) / self.rate;
+        let expected = seconds * self.rate;
+        let error = if interval > expected {
+            interval - expected
+        } else {
+            expected - interval
+        };
+        if seconds == 0 || error > expected * TOLERANCE_PPM / 1_000_000 {
+            self.outliers = self.outliers.wrapping_add(1);
+            self.rejected_in_row += 1;
+            if self.rejected_in_row >= REACQUIRE_AFTER {
+                self.acquire(t);
+            }
+            return;
+        }
+        self.rejected_in_row = 0;
+
+        self.samples[self.next_sample] = (interval / seconds) as u32;
+        self.next_sample = (self.next_sample + 1) % WINDOW;
+        self.sample_count = (self.sample_count + 1).min(WINDOW);
+        let sum: u64 = self.samples[..self.sample_count]
+            .iter()
+            .map(|&s| u64::from(s))
+            .sum();
+        let count = self.sample_count as u64;
+
+        // Re-anchor at this edge using the old rate, so now() stays
+        // continuous, then switch to the new one
+        let estimated = self.time_at(t);
+        self.rate = (sum + count / 2) / count;
+        let edge_ns = prev_ns + seconds * NS_PER_S;
+        self.last_edge = Some((t, edge_ns));
+
+        // Run the next second fast or slow enough to land on the next edge
+        let target = edge_ns + NS_PER_S;
+        let advance = if target > estimated {
+            target - estimated
+        } else {
+            0
+        };
+        self.tick_anchor = t;
+        self.ns_anchor = estimated;
+        self.advance = advance
+            .max(NS_PER_S - MAX_SLEW_NS)
+            .min(NS_PER_S + MAX_SLEW_NS);
+    }
+
+    /// How much `now()` can currently be trusted. Becomes `Holdover` once
+    /// two seconds pass without a good edge.
+    pub fn quality(&mut self) -> Quality {
+        self.quality_at(self.timer.now().ticks())
+    }
+
+    fn quality_at(&mut self, raw: u32) -> Quality {
+        let edge_t = match self.last_edge {
+            Some((edge_t, _)) => edge_t,
+            None => return Quality::NoSignal,
+        };
+        let t = self.extend(raw);
+        if t.saturating_sub(edge_t) > 2 * self.rate + self.rate * TOLERANCE_PPM / 1_000_000 {
+            Quality::Holdover
+        } else if self.sample_count < WINDOW {
+            Quality::Acquiring
+        } else {
+            Quality::Locked
+        }
+    }
+
+    /// The measured system clock frequency, averaged over the last few good
+    /// PPS intervals
+    pub fn measured_frequency(&self) -> Option<Hertz> {
+        if self.sample_count == 0 {
+            None
+        } else {
+            Some(Hertz(self.rate as u32))
+        }
+    }
+
+    /// How far the system clock is from its nominal frequency, in parts per
+    /// billion. Positive means it runs fast.
+    pub fn error_ppb(&self) -> Option<i32> {
+        if self.sample_count == 0 {
+            return None;
+        }
+        let count = self.sample_count as i64;
+        let sum: i64 = self.samples[..self.sample_count]
+            .iter()
+            .map(|&s| i64::from(s))
+            .sum();
+        let nominal = self.nominal as i64 * count;
+        Some(((sum - nominal) * 1_000_000_000 / nominal) as i32)
+    }
+
+    /// The cycles counted in each of the recent good PPS intervals, in no
+    /// particular order
+    pub fn samples(&self) -> &[u32] {
+        &self.samples[..self.sample_count]
+    }
+
+    /// How many PPS edges have been rejected as outliers
+    pub fn outliers(&self) -> u32 {
+        self.outliers
+    }
+
+    /// Takes the edge at `t` as a whole second, moving the clock forward to
+    /// make it so
+    fn acquire(&mut self, t: u64) {
+        let estimated = self.time_at(t).max(self.last_output);
+        let edge_ns = (estimated + NS_PER_S - 1) / NS_PER_S * NS_PER_S;
+        self.tick_anchor = t;
+        self.ns_anchor = edge_ns;
+        self.advance = NS_PER_S;
+        self.last_edge = Some((t, edge_ns));
+        self.rejected_in_row = 0;
+    }
+
+    /// Extends a cycle count to 64 bits. Counts a little older than the
+    /// newest one seen, such as a captured edge, are handled too.
+    fn extend(&mut self, raw: u32) -> u64 {
+        let behind = self.last_raw.wrapping_sub(raw);
+        if behind != 0 && behind < MAX_BEHIND {
+            return self.ticks.saturating_sub(u64::from(behind));
+        }
+        self.ticks += u64::from(raw.wrapping_sub(self.last_raw));
+        self.last_raw = raw;
+        self.ticks
+    }
+
+    /// The disciplined time at tick `t`, moving the anchor along in whole
+    /// seconds so the arithmetic can't overflow
+    fn time_at(&mut self, t: u64) -> u64 {
+        let elapsed = t.saturating_sub(self.tick_anchor);
+        if elapsed >= self.rate {
+            // The slewed second is over; carry on at the measured rate
+            self.tick_anchor += self.rate;
+            self.ns_anchor += self.advance;
+            self.advance = NS_PER_S;
+            let whole = (t - self.tick_anchor) / self.rate;
+            self.tick_anchor += whole * self.rate;
+            self.ns_anchor += whole * NS_PER_S;
+        }
+        self.ns_anchor + t.saturating_sub(self.tick_anchor) * self.advance / self.rate
+    }
+}
+
+/// A `PpsDiscipline` shared between the PPS interrupt handler and thread
+/// code. Each call takes a critical section for as long as the arithmetic
+/// lasts, a few hundred cycles, reported as `diag::CsSite::SharedPps`.
+pub struct SharedPps {
+    inner: Mutex<RefCell<Option<PpsDiscipline>>>,
+}
+
+impl SharedPps {
+    /// An empty slot, for a `static`
+    pub const fn new() -> Self {
+        SharedPps {
+            inner: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Puts `pps` in, replacing whatever was there
+    pub fn init(&self, pps: PpsDiscipline) {
+        critical_section(CsSite::SharedPps, |cs| {
+            *self.inner.borrow(cs).borrow_mut() = Some(pps)
+        });
+    }
+
+    /// `PpsDiscipline::on_pps`, for the interrupt handler. Does nothing
+    /// before `init`.
+    pub fn on_pps(&self, edge: Instant) {
+        self.with(|pps| pps.on_pps(edge));
+    }
+
+    /// `PpsDiscipline::now`, or `None` before `init`
+    pub fn now(&self) -> Option<u64> {
+        self.with(PpsDiscipline::now)
+    }
+
+    /// `PpsDiscipline::quality`, or `None` before `init`
+    pub fn quality(&self) -> Option<Quality> {
+        self.with(PpsDiscipline::quality)
+    }
+
+    /// Runs `f` on the discipline inside a critical section, for the other
+    /// methods
+    pub fn with<T, F>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut PpsDiscipline) -> T,
+    {
+        critical_section(CsSite::SharedPps, |cs| {
+            self.inner.borrow(cs).borrow_mut().as_mut().map(f)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MHZ_80: u32 = 80_000_000;
+
+    /// A discipline for a nominal 80 MHz clock, started at cycle `start`
+    fn pps(start: u32) -> PpsDiscipline {
+        PpsDiscipline::new_at(MonoTimer::with_frequency(Hertz(MHZ_80)), start)
+    }
+
+    /// Edges `seconds` whole seconds apart from `start`, for a clock that
+    /// really runs at `frequency`
+    fn edge(frequency: u32, start: u32, seconds: u32) -> u32 {
+        start.wrapping_add((u64::from(frequency) * u64::from(seconds)) as u32)
+    }
+
+    #[test]
+    fn runs_at_the_nominal_rate_before_any_edge() {
+        let mut pps = pps(0);
+        assert_eq!(pps.quality_at(0), Quality::NoSignal);
+        assert_eq!(pps.now_at(40_000_000), 500_000_000);
+        assert_eq!(pps.now_at(80_000_000), NS_PER_S);
+        assert_eq!(pps.measured_frequency(), None);
+    }
+
+    #[test]
+    fn the_first_edge_moves_to_the_next_whole_second() {
+        let mut pps = pps(0);
+        assert_eq!(pps.now_at(20_000_000), 250_000_000);
+        pps.on_edge(40_000_000);
+        assert_eq!(pps.now_at(40_000_000), NS_PER_S);
+        assert_eq!(pps.now_at(60_000_000), NS_PER_S + 250_000_000);
+        assert_eq!(pps.quality_at(60_000_000), Quality::Acquiring);
+    }
+
+    #[test]
+    fn locks_to_a_clock_running_fast() {
+        // 100 ppm fast: 80.008 MHz
+        let actual = 80_008_000;
+        let start = 1_000;
+        let mut pps = pps(start);
+        for second in 0..=WINDOW as u32 + 2 {
+            pps.on_edge(edge(actual, start, second));
+        }
+        let last = edge(actual, start, WINDOW as u32 + 2);
+        assert_eq!(pps.quality_at(last), Quality::Locked);
+        assert_eq!(pps.measured_frequency(), Some(Hertz(actual)));
+        assert_eq!(pps.error_ppb(), Some(100_000));
+        // Edges land on whole seconds, and half way to the next one is
+        // half a second on
+        let at_edge = pps.now_at(last);
+        assert_eq!(at_edge % NS_PER_S, 0);
+        let half = pps.now_at(last.wrapping_add(actual / 2));
+        assert!((half as i64 - (at_edge + NS_PER_S / 2) as i64).abs() < 10);
+    }
+
+    #[test]
+    fn phase_errors_are_slewed_not_stepped() {
+        let mut pps = pps(0);
+        pps.on_edge(0);
+        pps.on_edge(80_000_000);
+        // The next edge comes 200 us late, well inside the tolerance
+        let late = 160_000_000 + 16_000;
+        pps.on_edge(late);
+        // At most 500 us a second of correction, and never backwards
+        let mut previous = pps.now_at(late);
+        for step in 1..=80u32 {
+            let now = pps.now_at(late + step * 1_000_000);
+            let delta = now - previous;
+            assert!(delta >= 12_500_000 - 6_250 && delta <= 12_500_000 + 6_250);
+            previous = now;
+        }
+    }
+
+    #[test]
+    fn outliers_are_ignored_until_three_in_a_row() {
+        let mut pps = pps(0);
+        pps.on_edge(0);
+        pps.on_edge(80_000_000);
+        let rate = pps.measured_frequency();
+        // A glitch half way through the second
+        pps.on_edge(120_000_000);
+        assert_eq!(pps.outliers(), 1);
+        assert_eq!(pps.measured_frequency(), rate);
+        pps.on_edge(160_000_000);
+        assert_eq!(pps.outliers(), 1);
+
+        // Three glitches in a row reacquire from the last of them
+        for &t in [200_000_000u32, 210_000_000, 220_000_000].iter() {
+            pps.on_edge(t);
+        }
+        assert_eq!(pps.outliers(), 4);
+        assert_eq!(pps.now_at(220_000_000) % NS_PER_S, 0);
+    }
+
+    #[test]
+    fn follows_the_counter_across_its_wrap() {
+        let start = u32::max_value() - 100_000_000;
+        let mut pps = pps(start);
+        // The first edge falls on zero, which is already a whole second
+        for second in 0..4 {
+            pps.on_edge(edge(MHZ_80, start, second));
+        }
+        let last = edge(MHZ_80, start, 3);
+        assert!(last < start);
+        assert_eq!(pps.now_at(last), 3 * NS_PER_S);
+        assert_eq!(pps.error_ppb(), Some(0));
+    }
+
+    #[test]
+    fn a_captured_edge_older_than_the_last_reading_is_in_the_past() {
+        let mut pps = pps(0);
+        pps.on_edge(0);
+        // Thread code reads the clock just after the edge...
+        let read = pps.now_at(80_000_400);
+        // ...before the handler gets to report it
+        pps.on_edge(80_000_000);
+        assert!(pps.now_at(80_000_800) >= read);
+        assert_eq!(pps.outliers(), 0);
+        assert_eq!(pps.samples(), &[80_000_000]);
+    }
+
+    #[test]
+    fn holdover_after_two_quiet_seconds() {
+        let mut pps = pps(0);
+        pps.on_edge(0);
+        pps.on_edge(80_000_000);
+        assert_eq!(pps.quality_at(200_000_000), Quality::Acquiring);
+        assert_eq!(pps.quality_at(250_000_000), Quality::Holdover);
+        // Time carries on at the measured rate
+        assert_eq!(pps.now_at(320_000_000), 4 * NS_PER_S);
+    }
+}
@@ -0,0 +1,171 @@
+//! Word access to a peripheral's registers by offset
+//!
+//! The sequencing logic of the I2C master and the UART FIFO handling is written
+//! against `RegisterAccess` rather than the PAC's register blocks, so that
+//! the same code runs on the chip through `Mmio` and on the host against a
+//! scripted fake peripheral in the unit tests. The trait is sealed: `Mmio`
+//! is the only implementation outside the tests.
+
+use core::ptr::{read_volatile, write_volatile};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Reads and writes 32-bit registers at byte offsets from a peripheral's base
+pub trait RegisterAccess: private::Sealed {
+    /// Reads the register `offset` bytes from the base
+    fn read(&self, offset: usize) -> u32;
+
+    /// Writes `value` to the register `offset` bytes from the base
+    fn write(&self, offset: usize, value: u32);
+
+    /// Reports a read of the register at `offset` to the trace hook, where
+    /// the driver wants one recorded. Writes are always reported.
+    fn trace_read(&self, offset: usize);
+
+    /// Waits `cycles` system clock cycles, for the hardware to catch up
+    /// with a write
+    fn settle(&self, cycles: u32);
+}
+
+/// The registers of a memory-mapped peripheral
+pub struct Mmio {
+    base: usize,
+    trace: bool,
+}
+
+impl Mmio {
+    /// The peripheral whose register block starts at `base`. With `trace`
+    /// set, accesses are passed to `trace::record`; the drivers set it from
+    /// the chip crate's `trace-registers` feature.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the address of a peripheral's register block, and the
+    /// caller must own that peripheral.
+    pub unsafe fn new<T>(base: *const T, trace: bool) -> Self {
+        Mmio {
+            base: base as usize,
+            trace,
+        }
+    }
+
+    fn record(&self, offset: usize, access: crate::trace::Access) {
+        if self.trace {
+            crate::trace::record(crate::trace::Record {
+                base: self.base as u32,
+                offset: offset as u16,
+                value: self.read(offset),
+                access,
+            });
+        }
+    }
+}
+
+impl private::Sealed for Mmio {}
+
+impl RegisterAccess for Mmio {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+        self.record(offset, crate::trace::Access::Write);
+    }
+
+    fn trace_read(&self, offset: usize) {
+        self.record(offset, crate::trace::Access::Read);
+    }
+
+    fn settle(&self, cycles: u32) {
+        cortex_m::asm::delay(cycles);
+    }
+}
+
+impl<T: RegisterAccess> private::Sealed for &T {}
+
+impl<T: RegisterAccess> RegisterAccess for &T {
+    fn read(&self, offset: usize) -> u32 {
+        (**self).read(offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        (**self).write(offset, value)
+    }
+
+    fn trace_read(&self, offset: usize) {
+        (**self).trace_read(offset)
+    }
+
+    fn settle(&self, cycles: u32) {
+        (**self).settle(cycles)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    //! A peripheral for host tests, whose behaviour is a closure over its
+    //! register file
+
+    use super::{private, RegisterAccess};
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    /// Sixteen registers, every write logged, and `on_write` run after each
+    /// write to update the register file the way the hardware would
+    pub struct Fake<S> {
+        pub regs: RefCell<[u32; 16]>,
+        pub writes: RefCell<Vec<(usize, u32)>>,
+        pub state: RefCell<S>,
+        on_write: fn(&mut S, &mut [u32; 16], usize, u32),
+        on_read: fn(&mut S, &mut [u32; 16], usize),
+    }
+
+    impl<S> Fake<S> {
+        pub fn new(
+            state: S,
+            on_write: fn(&mut S, &mut [u32; 16], usize, u32),
+            on_read: fn(&mut S, &mut [u32; 16], usize),
+        ) -> Self {
+            Fake {
+                regs: RefCell::new([0; 16]),
+                writes: RefCell::new(Vec::new()),
+                state: RefCell::new(state),
+                on_write,
+                on_read,
+            }
+        }
+
+        /// The values written to the register at `offset`, in order
+        pub fn writes_to(&self, offset: usize) -> Vec<u32> {
+            self.writes
+                .borrow()
+                .iter()
+                .filter(|w| w.0 == offset)
+                .map(|w| w.1)
+                .collect()
+        }
+    }
+
+    impl<S> private::Sealed for Fake<S> {}
+
+    impl<S> RegisterAccess for Fake<S> {
+        fn read(&self, offset: usize) -> u32 {
+            let mut regs = self.regs.borrow_mut();
+            (self.on_read)(&mut self.state.borrow_mut(), &mut regs, offset);
+            regs[offset / 4]
+        }
+
+        fn write(&self, offset: usize, value: u32) {
+            self.writes.borrow_mut().push((offset, value));
+            let mut regs = self.regs.borrow_mut();
+            (self.on_write)(&mut self.state.borrow_mut(), &mut regs, offset, value);
+        }
+
+        fn trace_read(&self, _offset: usize) {}
+
+        fn settle(&self, _cycles: u32) {}
+    }
+}
@@ -0,0 +1,113 @@
+//! Catching driver calls that block for longer than a deadline allows
+//!
+//! With the chip crate's `rt-budget` feature enabled, the blocking driver
+//! calls (I2C transfers, SPI transfers, UART `write_all` and `bflush`) time
+//! themselves with the cycle counter. Any call that takes longer than the
+//! budget set with `set_max_block_cycles` or `set_max_block_us` is counted
+//! and passed to the handler set with `set_handler`, which might log it,
+//! panic, or do nothing. Without the feature the `rt_budget_scope!` calls
+//! expand to nothing.
+//!
+//! The cycle counter must be running, which `MonoTimer::new` sees to.
+
+use crate::diag::{critical_section, CsSite};
+use crate::sysctl::Clocks;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::DWT;
+
+/// A call that went over budget
+#[derive(Debug, Clone, Copy)]
+pub struct Violation {
+    /// Which call it was, such as `"I2C0 write"`
+    pub call: &'static str,
+    /// How long it took, in system clock cycles
+    pub cycles: u32,
+    /// The budget in force, in system clock cycles
+    pub budget: u32,
+}
+
+/// The budget in cycles, or zero for none
+static BUDGET: AtomicU32 = AtomicU32::new(0);
+static VIOLATIONS: AtomicU32 = AtomicU32::new(0);
+static HANDLER: Mutex<Cell<Option<fn(&Violation)>>> = Mutex::new(Cell::new(None));
+
+/// Sets the longest a single call may block for, in system clock cycles.
+/// Zero turns the check off, which is the default.
+pub fn set_max_block_cycles(cycles: u32) {
+    BUDGET.store(cycles, Ordering::Relaxed);
+}
+
+/// Sets the longest a single call may block for, in microseconds at the
+/// current system clock
+pub fn set_max_block_us(us: u32, clocks: &Clocks) {
+    clocks.assert_current();
+    let cycles = u64::from(us) * u64::from(clocks.sysclk.0) / 1_000_000;
+    set_max_block_cycles(cycles.min(u64::from(u32::max_value())) as u32);
+}
+
+/// Sets the function told about each call over budget. It runs in the
+/// context of the call, with interrupts disabled, and its run time counts
+/// towards `diag::CsSite::RtBudget`.
+pub fn set_handler(handler: fn(&Violation)) {
+    critical_section(CsSite::RtBudget, |cs| HANDLER.borrow(cs).set(Some(handler)));
+}
+
+/// Stops reporting violations; they are still counted
+pub fn clear_handler() {
+    critical_section(CsSite::RtBudget, |cs| HANDLER.borrow(cs).set(None));
+}
+
+/// How many calls have gone over budget
+pub fn violations() -> u32 {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+
+/// Times one call, from `enter` until it is dropped
+pub struct Scope {
+    call: &'static str,
+    start: u32,
+}
+
+/// Starts timing a call. The drivers use this through `rt_budget_scope!`.
+pub fn enter(call: &'static str) -> Scope {
+    Scope {
+        call,
+        start: DWT::get_cycle_count(),
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let budget = BUDGET.load(Ordering::Relaxed);
+        if budget == 0 {
+            return;
+        }
+        let cycles = DWT::get_cycle_count().wrapping_sub(self.start);
+        if cycles <= budget {
+            return;
+        }
+        VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        let violation = Violation {
+            call: self.call,
+            cycles,
+            budget,
+        };
+        critical_section(CsSite::RtBudget, |cs| {
+            if let Some(handler) = HANDLER.borrow(cs).get() {
+                handler(&violation);
+            }
+        });
+    }
+}
+
+#[macro_export]
+/// Times the rest of the enclosing block as call `$call`, if the
+/// `rt-budget` feature is enabled in the crate expanding it
+macro_rules! rt_budget_scope {
+    ($call:expr) => {
+        #[cfg(feature = "rt-budget")]
+        let _rt_budget = $crate::rt_budget::enter($call);
+    };
+}
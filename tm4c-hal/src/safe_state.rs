@@ -0,0 +1,85 @@
+//! Parking dangerous outputs when the firmware panics
+//!
+//! A panic leaves every peripheral exactly as it was, so a PWM output
+//! driving a motor or heater keeps going at its last duty cycle. Drivers for
+//! such outputs can `register` a hook that forces them to a safe level, and
+//! the application's panic handler calls `panic_prelude` before halting or
+//! resetting:
+//!
+//! ```rust,ignore
+//! #[panic_handler]
+//! fn panic(_info: &core::panic::PanicInfo) -> ! {
+//!     tm4c123x_hal::safe_state::panic_prelude();
+//!     loop {
+//!         cortex_m::asm::bkpt();
+//!     }
+//! }
+//! ```
+//!
+//! The hooks live in a fixed table of `MAX_HOOKS` entries, so registering
+//! never allocates and a full table is reported rather than grown.
+
+use crate::diag::{critical_section, CsSite};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m::interrupt;
+
+/// Forces one output to its safe state. It gets the context word it was
+/// registered with, runs with interrupts disabled, and mustn't panic.
+pub type SafeStateHook = fn(context: usize);
+
+/// Number of hooks that can be registered at once
+pub const MAX_HOOKS: usize = 8;
+
+/// A registration, for passing to `unregister`
+#[derive(Debug, PartialEq)]
+pub struct HookId(usize);
+
+struct Slot {
+    /// A `SafeStateHook` as a `usize`, or zero for a free slot
+    hook: AtomicUsize,
+    context: AtomicUsize,
+}
+
+// Only used to initialise `HOOKS`, where each copy is meant to be a fresh,
+// independent slot
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    hook: AtomicUsize::new(0),
+    context: AtomicUsize::new(0),
+};
+
+static HOOKS: [Slot; MAX_HOOKS] = [EMPTY_SLOT; MAX_HOOKS];
+
+/// Arranges for `hook(context)` to run from `panic_prelude`. Returns `None`
+/// if all `MAX_HOOKS` slots are taken. Holds interrupts off while it scans
+/// the slots (`diag::CsSite::SafeStateRegister`); `unregister` doesn't.
+pub fn register(hook: SafeStateHook, context: usize) -> Option<HookId> {
+    critical_section(CsSite::SafeStateRegister, |_| {
+        let index = HOOKS
+            .iter()
+            .position(|slot| slot.hook.load(Ordering::Relaxed) == 0)?;
+        HOOKS[index].context.store(context, Ordering::Relaxed);
+        HOOKS[index].hook.store(hook as usize, Ordering::Relaxed);
+        Some(HookId(index))
+    })
+}
+
+/// Removes a hook, for when the output it protects is released or turned
+/// over to other code
+pub fn unregister(id: HookId) {
+    HOOKS[id.0].hook.store(0, Ordering::Relaxed);
+}
+
+/// Disables interrupts and runs every registered hook. Call this first
+/// thing in the panic handler. Interrupts are left disabled.
+pub fn panic_prelude() {
+    interrupt::disable();
+    for slot in HOOKS.iter() {
+        let hook = slot.hook.load(Ordering::Relaxed);
+        if hook != 0 {
+            // Only ever stored from a `SafeStateHook` in `register`
+            let hook: SafeStateHook = unsafe { core::mem::transmute(hook) };
+            hook(slot.context.load(Ordering::Relaxed));
+        }
+    }
+}
@@ -1,10 +1,46 @@
 //! Serial code that is generic to both the TM4C123 and TM4C129, such as the pin traits.
+//!
+//! The FIFO handling behind the drivers' `read` and `write`, `take_rx` and
+//! `put_tx`, is written against `regs::RegisterAccess` so that it can be
+//! tested on the host.
+
+use crate::regs::RegisterAccess;
+use crate::time::{Bps, MonoTimer};
+
+/// Register offsets of the UART, the same on both families
+const DR: usize = 0x000;
+const FR: usize = 0x018;
+
+/// UARTFR bits
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+const FR_RXFF: u32 = 1 << 6;
 
 /// TX pin - DO NOT IMPLEMENT THIS TRAIT
 pub unsafe trait TxPin<UART> {}
 
 /// RX pin - DO NOT IMPLEMENT THIS TRAIT
-pub unsafe trait RxPin<UART> {}
+pub unsafe trait RxPin<UART> {
+    /// Hands the pin to the GPIO block as a falling-edge wake source, if a
+    /// valid pin is given (not `()`).
+    fn enter_wake_mode(&mut self);
+
+    /// Hands the pin back to the UART. Returns true if a falling edge was
+    /// seen while it was acting as a wake source.
+    fn exit_wake_mode(&mut self) -> bool;
+
+    /// Hands the pin to the GPIO block as a plain input, with its interrupt
+    /// left as it was, if a valid pin is given (not `()`)
+    fn enter_sample_mode(&mut self);
+
+    /// Hands the pin back to the UART after `enter_sample_mode`
+    fn exit_sample_mode(&mut self);
+
+    /// Returns true if the line is low. Only meaningful between
+    /// `enter_wake_mode` and `exit_wake_mode`, or `enter_sample_mode` and
+    /// `exit_sample_mode`.
+    fn line_is_low(&self) -> bool;
+}
 
 /// CTS pin - DO NOT IMPLEMENT THIS TRAIT
 pub unsafe trait CtsPin<UART> {
@@ -44,7 +80,27 @@ pub unsafe trait RtsPin<UART> {
 
 unsafe impl<U> TxPin<U> for () {}
 
-unsafe impl<U> RxPin<U> for () {}
+unsafe impl<U> RxPin<U> for () {
+    fn enter_wake_mode(&mut self) {
+        // Do nothing
+    }
+
+    fn exit_wake_mode(&mut self) -> bool {
+        false
+    }
+
+    fn enter_sample_mode(&mut self) {
+        // Do nothing
+    }
+
+    fn exit_sample_mode(&mut self) {
+        // Do nothing
+    }
+
+    fn line_is_low(&self) -> bool {
+        false
+    }
+}
 
 unsafe impl<U> CtsPin<U> for () {
     fn enable(&mut self, _uart: &mut U) {
@@ -86,3 +142,333 @@ pub enum NewlineMode {
     /// Emit an extra CR before every LF
     SwapLFtoCRLF,
 }
+
+/// Errors from the UART calls that report them, such as
+/// `Serial::read_with_deadline`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The receive FIFO overflowed and bytes before this one were lost
+    Overrun,
+    /// A byte arrived with a missing stop bit
+    Framing,
+    /// A byte arrived with a parity error
+    Parity,
+    /// The line was held low for longer than a character
+    Break,
+    /// The deadline passed first
+    Timeout,
+}
+
+impl Error {
+    /// The error flagged in a UARTDR value, if any. A break also shows up
+    /// as a framing error, so it is checked first.
+    pub fn from_dr(dr: u32) -> Option<Error> {
+        if dr & (1 << 10) != 0 {
+            Some(Error::Break)
+        } else if dr & (1 << 8) != 0 {
+            Some(Error::Framing)
+        } else if dr & (1 << 9) != 0 {
+            Some(Error::Parity)
+        } else if dr & (1 << 11) != 0 {
+            Some(Error::Overrun)
+        } else {
+            None
+        }
+    }
+}
+
+/// Link health counters kept by the UART driver, from `Serial::stats`.
+/// The counters wrap rather than saturate.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Bytes received after the receive FIFO had overflowed, i.e. bytes
+    /// lost before this one
+    pub overruns: u32,
+    /// Bytes received with a missing stop bit
+    pub framing_errors: u32,
+    /// Bytes received with a parity error
+    pub parity_errors: u32,
+    /// Break conditions received
+    pub breaks: u32,
+    /// Reads that found the receive FIFO full, a sign the reader is close
+    /// to falling behind
+    pub rx_fifo_full: u32,
+    /// Bytes that had to wait for room in the transmit FIFO
+    pub tx_stalls: u32,
+    tx_waiting: bool,
+}
+
+impl Stats {
+    /// Counts the error flags of a byte read from UARTDR, and whether the
+    /// receive FIFO was full (`UARTFR.RXFF`) just before it was read
+    pub fn record_rx(&mut self, dr: u32, fifo_full: bool) {
+        // FE, PE, BE and OE sit above the data byte
+        let count = |counter: &mut u32, bit: u32| {
+            if dr & (1 << bit) != 0 {
+                *counter = counter.wrapping_add(1);
+            }
+        };
+        count(&mut self.framing_errors, 8);
+        count(&mut self.parity_errors, 9);
+        count(&mut self.breaks, 10);
+        count(&mut self.overruns, 11);
+        if fifo_full {
+            self.rx_fifo_full = self.rx_fifo_full.wrapping_add(1);
+        }
+    }
+
+    /// Records whether a write found the transmit FIFO full. A byte that
+    /// has to be retried is only counted once.
+    pub fn record_tx(&mut self, fifo_full: bool) {
+        if fifo_full && !self.tx_waiting {
+            self.tx_stalls = self.tx_stalls.wrapping_add(1);
+        }
+        self.tx_waiting = fifo_full;
+    }
+
+    /// The receive counters of `rx` with the transmit counters of `tx`
+    pub fn merge(rx: Stats, tx: Stats) -> Stats {
+        Stats {
+            tx_stalls: tx.tx_stalls,
+            tx_waiting: tx.tx_waiting,
+            ..rx
+        }
+    }
+}
+
+/// Takes a byte from the receive FIFO, if there is one, and counts its
+/// error flags in `stats`. Returns the whole UARTDR value, with the flags
+/// above the data byte.
+pub fn take_rx<R: RegisterAccess>(regs: &R, stats: &mut Stats) -> Option<u32> {
+    let fr = regs.read(FR);
+    if fr & FR_RXFE != 0 {
+        return None;
+    }
+    let dr = regs.read(DR);
+    stats.record_rx(dr, fr & FR_RXFF != 0);
+    Some(dr)
+}
+
+/// Puts `byte` in the transmit FIFO if there is room for it, and records in
+/// `stats` if there wasn't. Returns whether it went in.
+pub fn put_tx<R: RegisterAccess>(regs: &R, stats: &mut Stats, byte: u8) -> bool {
+    let full = tx_full(regs);
+    stats.record_tx(full);
+    if !full {
+        regs.write(DR, u32::from(byte));
+    }
+    !full
+}
+
+/// Whether the transmit FIFO is full
+pub fn tx_full<R: RegisterAccess>(regs: &R) -> bool {
+    regs.read(FR) & FR_TXFF != 0
+}
+
+/// Baud rates `autobaud` will snap to, slowest first
+pub const STANDARD_BAUDS: [u32; 12] = [
+    1_200, 2_400, 4_800, 9_600, 14_400, 19_200, 38_400, 57_600, 115_200, 230_400, 460_800, 921_600,
+];
+
+/// Number of low pulses `autobaud` measures before deciding
+const AUTOBAUD_PULSES: u8 = 16;
+
+/// Auto-baud detection error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutobaudError {
+    /// No characters arrived before the timeout
+    Timeout,
+    /// The measured rate, in bits per second, isn't within 5% of any of
+    /// `STANDARD_BAUDS`
+    NonStandard(u32),
+}
+
+/// Returns the entry of `STANDARD_BAUDS` within 5% of `measured`, if any
+pub fn nearest_standard_baud(measured: u32) -> Option<Bps> {
+    STANDARD_BAUDS
+        .iter()
+        .find(|&&baud| {
+            let tolerance = baud / 20;
+            measured >= baud - tolerance && measured <= baud + tolerance
+        })
+        .map(|&baud| Bps(baud))
+}
+
+/// A deadline counted in 64 bits, for timeouts longer than the cycle
+/// counter's wrap. It must be checked at least once per wrap.
+struct LongDeadline {
+    last: u32,
+    elapsed: u64,
+    cycles: u64,
+}
+
+impl LongDeadline {
+    fn new(now: u32, cycles: u64) -> Self {
+        LongDeadline {
+            last: now,
+            elapsed: 0,
+            cycles,
+        }
+    }
+
+    /// Counts the time since the last check, given the cycle counter
+    fn has_passed(&mut self, now: u32) -> bool {
+        self.elapsed += u64::from(now.wrapping_sub(self.last));
+        self.last = now;
+        self.elapsed >= self.cycles
+    }
+}
+
+/// Works out the baud rate the other end is using by timing the shortest
+/// low pulse on the RX line, which is one bit long as long as the traffic
+/// includes a character with an isolated zero bit (`U`, 0x55, is ideal).
+///
+/// The pin is taken from the UART for the measurement and handed back
+/// afterwards, so it can go straight into the `Serial` constructor. Its
+/// interrupt isn't armed, so the port's handler doesn't see the edges. The
+/// line is polled against the cycle counter, which is plenty at the usual
+/// rates; at 80 MHz a bit at 921600 baud is still 87 cycles long.
+/// `timeout_us` may be longer than the cycle counter takes to wrap, which
+/// suits waiting for someone to start typing.
+pub fn autobaud<UART, RX>(
+    rx: &mut RX,
+    timer: MonoTimer,
+    timeout_us: u32,
+) -> Result<Bps, AutobaudError>
+where
+    RX: RxPin<UART>,
+{
+    let cycles = u64::from(timer.frequency().0) * u64::from(timeout_us) / 1_000_000;
+    let mut deadline = LongDeadline::new(timer.now().ticks(), cycles);
+    let mut shortest = u32::max_value();
+    let mut pulses = 0;
+
+    rx.enter_sample_mode();
+    'measure: while pulses < AUTOBAUD_PULSES {
+        // Start from an idle (high) line, so the first pulse isn't cut short
+        while rx.line_is_low() {
+            if deadline.has_passed(timer.now().ticks()) {
+                break 'measure;
+            }
+        }
+        while !rx.line_is_low() {
+            if deadline.has_passed(timer.now().ticks()) {
+                break 'measure;
+            }
+        }
+        let start = timer.now();
+        while rx.line_is_low() {
+            if deadline.has_passed(timer.now().ticks()) {
+                break 'measure;
+            }
+        }
+        shortest = shortest.min(start.elapsed());
+        pulses += 1;
+    }
+    rx.exit_sample_mode();
+
+    if pulses == 0 {
+        return Err(AutobaudError::Timeout);
+    }
+    let measured = timer.frequency().0 / shortest.max(1);
+    nearest_standard_baud(measured).ok_or(AutobaudError::NonStandard(measured))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regs::fake::Fake;
+    use std::collections::VecDeque;
+
+    /// The receive FIFO's contents, as UARTDR values, and how many more
+    /// bytes the transmit FIFO will take
+    struct Uart {
+        rx: VecDeque<u32>,
+        tx_room: usize,
+    }
+
+    fn on_write(uart: &mut Uart, _regs: &mut [u32; 16], offset: usize, _value: u32) {
+        if offset == DR {
+            uart.tx_room -= 1;
+        }
+    }
+
+    fn on_read(uart: &mut Uart, regs: &mut [u32; 16], offset: usize) {
+        match offset {
+            FR => {
+                let mut fr = 0;
+                if uart.rx.is_empty() {
+                    fr |= FR_RXFE;
+                }
+                if uart.rx.len() >= 16 {
+                    fr |= FR_RXFF;
+                }
+                if uart.tx_room == 0 {
+                    fr |= FR_TXFF;
+                }
+                regs[FR / 4] = fr;
+            }
+            DR => regs[DR / 4] = uart.rx.pop_front().unwrap(),
+            _ => {}
+        }
+    }
+
+    fn uart(rx: &[u32], tx_room: usize) -> Fake<Uart> {
+        Fake::new(
+            Uart {
+                rx: rx.iter().cloned().collect(),
+                tx_room,
+            },
+            on_write,
+            on_read,
+        )
+    }
+
+    #[test]
+    fn take_rx_counts_error_flags() {
+        // A clean byte, then one with a framing error and an overrun
+        let fake = uart(&[0x41, 0x942], 0);
+        let mut stats = Stats::default();
+        assert_eq!(take_rx(&fake, &mut stats), Some(0x41));
+        assert_eq!(take_rx(&fake, &mut stats), Some(0x942));
+        assert_eq!(take_rx(&fake, &mut stats), None);
+        assert_eq!(stats.framing_errors, 1);
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.parity_errors, 0);
+    }
+
+    #[test]
+    fn long_deadline_survives_counter_wraps() {
+        // 60 s at 120 MHz is 7.2e9 cycles, past one wrap of the counter
+        let mut deadline = LongDeadline::new(0xF000_0000, 7_200_000_000);
+        for step in 1..=6u32 {
+            let now = 0xF000_0000u32.wrapping_add(step.wrapping_mul(1_000_000_000));
+            assert!(!deadline.has_passed(now));
+        }
+        assert!(deadline.has_passed(0xF000_0000u32.wrapping_add(3_000_000_000)));
+    }
+
+    #[test]
+    fn error_flags_decode() {
+        assert_eq!(Error::from_dr(0x41), None);
+        assert_eq!(Error::from_dr(0x100), Some(Error::Framing));
+        assert_eq!(Error::from_dr(0x200), Some(Error::Parity));
+        assert_eq!(Error::from_dr(0x500), Some(Error::Break));
+        assert_eq!(Error::from_dr(0x800), Some(Error::Overrun));
+    }
+
+    #[test]
+    fn full_fifo_counts_one_stall_per_byte() {
+        let fake = uart(&[], 1);
+        let mut stats = Stats::default();
+        assert!(put_tx(&fake, &mut stats, b'a'));
+        // Retried three times, but it is still one byte held up
+        assert!(!put_tx(&fake, &mut stats, b'b'));
+        assert!(!put_tx(&fake, &mut stats, b'b'));
+        assert!(!put_tx(&fake, &mut stats, b'b'));
+        fake.state.borrow_mut().tx_room = 1;
+        assert!(put_tx(&fake, &mut stats, b'b'));
+        assert_eq!(stats.tx_stalls, 1);
+        assert_eq!(fake.writes_to(DR), [u32::from(b'a'), u32::from(b'b')]);
+    }
+}
@@ -0,0 +1,210 @@
+//! A software UART on two GPIO pins, for a slow extra console
+//!
+//! `SoftUart` does all its work in `tick`, which must be called from a
+//! periodic timer interrupt at `tick_rate()`, i.e. three times the baud
+//! rate. Each tick moves the transmitter on by a third of a bit and samples
+//! the receive pin once; a start bit is confirmed one tick after it is seen
+//! and each bit after that is sampled three ticks later, close to its
+//! middle.
+//!
+//! Costs and limits:
+//!
+//! * Each tick takes roughly 50 to 100 CPU cycles, so at 38400 baud
+//!   (115200 ticks per second) about 10% of an 80 MHz CPU goes on the
+//!   software UART whether or not anything is being sent.
+//! * Sampling a third of a bit at a time places the samples within a sixth
+//!   of a bit of the true middle. The far end's baud rate may therefore be
+//!   out by about 3% before the last bits of a byte are misread. Timer
+//!   interrupt latency eats into that margin, so give the timer a high
+//!   priority.
+//! * The baud rate may be at most `sysclk / (3 * 600)`, about 44000 baud at
+//!   80 MHz, which `new` checks.
+//!
+//! The driver is shared between thread code (`Read`/`Write`) and the timer
+//! interrupt (`tick`), so keep it in a `Mutex<RefCell<..>>` and only hold the
+//! lock for one non-blocking call at a time.
+
+use crate::{
+    sysctl::Clocks,
+    time::{Bps, Hertz},
+};
+use core::convert::Infallible;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::serial;
+
+/// Timer ticks per bit
+const OVERSAMPLE: u32 = 3;
+/// CPU cycles each tick is allowed, which sets the highest baud rate
+const MIN_CYCLES_PER_TICK: u32 = 600;
+/// Start bit, eight data bits and a stop bit
+const FRAME_BITS: u8 = 10;
+
+/// Software UART receive error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// A byte arrived before the previous one was read, and was dropped
+    Overrun,
+    /// The stop bit was low, so the byte was dropped
+    Framing,
+}
+
+/// A UART bit-banged on `TX` and `RX`. See the module documentation.
+pub struct SoftUart<TX, RX> {
+    tx_pin: TX,
+    rx_pin: RX,
+    tick_rate: Hertz,
+    tx_pending: Option<u8>,
+    tx_frame: u16,
+    tx_ticks: u8,
+    rx_bit: u8,
+    rx_wait: u8,
+    rx_shift: u8,
+    rx_byte: Option<u8>,
+    rx_error: Option<Error>,
+}
+
+impl<TX, RX> SoftUart<TX, RX>
+where
+    TX: OutputPin,
+    RX: InputPin,
+{
+    /// Sets up an 8N1 UART on the given pins. `tx_pin` is driven high (idle)
+    /// straight away. Panics if `baud_rate` is too high for the system
+    /// clock.
+    pub fn new(mut tx_pin: TX, rx_pin: RX, baud_rate: Bps, clocks: &Clocks) -> Self {
+        clocks.assert_current();
+        let tick_rate = baud_rate.0 * OVERSAMPLE;
+        assert!(
+            tick_rate != 0 && tick_rate <= clocks.sysclk.0 / MIN_CYCLES_PER_TICK,
+            "baud rate too high for a software UART at this system clock"
+        );
+        tx_pin.set_high();
+        SoftUart {
+            tx_pin,
+            rx_pin,
+            tick_rate: Hertz(tick_rate),
+            tx_pending: None,
+            tx_frame: 0,
+            tx_ticks: 0,
+            rx_bit: 0,
+            rx_wait: 0,
+            rx_shift: 0,
+            rx_byte: None,
+            rx_error: None,
+        }
+    }
+
+    /// How often `tick` must be called
+    pub fn tick_rate(&self) -> Hertz {
+        self.tick_rate
+    }
+
+    /// Moves both directions on by one tick. Call this from the timer
+    /// interrupt, `tick_rate()` times a second.
+    pub fn tick(&mut self) {
+        self.tick_tx();
+        self.tick_rx();
+    }
+
+    /// Releases the pins
+    pub fn free(self) -> (TX, RX) {
+        (self.tx_pin, self.rx_pin)
+    }
+
+    fn tick_tx(&mut self) {
+        if self.tx_ticks == 0 {
+            match self.tx_pending.take() {
+                // Start bit low, data LSB first, stop bit high
+                Some(byte) => self.tx_frame = (u16::from(byte) << 1) | (1 << 9),
+                None => return,
+            }
+            self.tx_ticks = FRAME_BITS * OVERSAMPLE as u8;
+        }
+        if u32::from(self.tx_ticks) % OVERSAMPLE == 0 {
+            if self.tx_frame & 1 != 0 {
+                self.tx_pin.set_high();
+            } else {
+                self.tx_pin.set_low();
+            }
+            self.tx_frame >>= 1;
+        }
+        self.tx_ticks -= 1;
+    }
+
+    fn tick_rx(&mut self) {
+        if self.rx_bit == 0 {
+            if self.rx_pin.is_low() {
+                // Possible start bit; check it again in the middle
+                self.rx_bit = 1;
+                self.rx_wait = 1;
+            }
+            return;
+        }
+        self.rx_wait -= 1;
+        if self.rx_wait != 0 {
+            return;
+        }
+        let high = self.rx_pin.is_high();
+        match self.rx_bit {
+            1 if high => {
+                // Glitch rather than a start bit
+                self.rx_bit = 0;
+                return;
+            }
+            1 => self.rx_shift = 0,
+            2..=9 => self.rx_shift |= u8::from(high) << (self.rx_bit - 2),
+            _ => {
+                if !high {
+                    self.rx_error = Some(Error::Framing);
+                } else if self.rx_byte.replace(self.rx_shift).is_some() {
+                    self.rx_error = Some(Error::Overrun);
+                }
+                // Back to idle in the middle of the stop bit, ready for the
+                // next start bit
+                self.rx_bit = 0;
+                return;
+            }
+        }
+        self.rx_bit += 1;
+        self.rx_wait = OVERSAMPLE as u8;
+    }
+}
+
+impl<TX, RX> serial::Read<u8> for SoftUart<TX, RX>
+where
+    TX: OutputPin,
+    RX: InputPin,
+{
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        if let Some(error) = self.rx_error.take() {
+            return Err(nb::Error::Other(error));
+        }
+        self.rx_byte.take().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl<TX, RX> serial::Write<u8> for SoftUart<TX, RX>
+where
+    TX: OutputPin,
+    RX: InputPin,
+{
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        if self.tx_pending.is_some() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.tx_pending = Some(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        if self.tx_pending.is_some() || self.tx_ticks != 0 {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! Common SPI code for TM4C123 and TM4C129
+
+/// SPI error
+#[derive(Debug)]
+pub enum Error {
+    /// The transfer did not finish before its deadline
+    Timeout,
+
+    #[doc(hidden)]
+    _Extensible,
+}
+
+// FIXME these should be "closed" traits
+/// SCK pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait SckPin<SPI> {}
+
+/// MISO pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait MisoPin<SPI> {}
+
+/// MOSI pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait MosiPin<SPI> {}
+
+/// Stands in for the MISO pin of a bus that doesn't have one, such as a
+/// write-only display or a 3-wire device (see `Spi::into_three_wire`).
+/// Reads return whatever the unconnected receive line gives.
+pub struct NoMiso;
+
+unsafe impl<SPI> MisoPin<SPI> for NoMiso {}
+
+impl crate::gpio::RecoverablePin for NoMiso {
+    type Input = NoMiso;
+
+    unsafe fn steal() -> Self {
+        NoMiso
+    }
+
+    fn into_input(self) -> Self::Input {
+        self
+    }
+}
+
+#[macro_export]
+/// Spins until the given field of the SSI status register matches `$op`
+macro_rules! spi_busy_wait {
+    ($spi:expr, $flag:ident, $op:ident) => {
+        loop {
+            let sr = $spi.sr.read();
+            if sr.$flag().$op() {
+                break;
+            }
+        }
+    };
+}
+
+#[macro_export]
+/// Implements embedded-hal for an TM4C SSI peripheral
+macro_rules! spi_hal {
+    ($($SPIX:ident: ($powerDomain:ident, $spiX:ident),)+) => {
+        $(
+            impl<SCK, MISO, MOSI> Spi<$SPIX, (SCK, MISO, MOSI)> {
+                /// Configures the SPI peripheral to operate in full duplex master mode
+                ///
+                /// # Panics
+                ///
+                /// If `freq` is faster than half the system clock, or slower
+                /// than the largest divisor allows.
+                pub fn $spiX<F>(
+                    spi: $SPIX,
+                    pins: (SCK, MISO, MOSI),
+                    mode: Mode,
+                    freq: F,
+                    clocks: &Clocks,
+                    pc: &sysctl::PowerControl,
+                ) -> Self
+                where
+                    F: Into<Hertz>,
+                    SCK: SckPin<$SPIX>,
+                    MISO: MisoPin<$SPIX>,
+                    MOSI: MosiPin<$SPIX>,
+                {
+                    clocks.assert_current();
+
+                    // power up
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::On);
+                    sysctl::reset(pc, sysctl::Domain::$powerDomain)
+                        .expect("SSI didn't come out of reset");
+
+                    // write 0 (reset value) for master operation.
+                    spi.cr1.write(|w| w);
+
+                    // SSICC Clock setup
+                    // set to reset value (0 = use system clock)
+                    spi.cc.write(|w| w);
+
+                    // Use Moto/SPI & 8bits data size
+                    // SSInClk = SysClk / (CPSDVSR * (1 + SCR))
+                    let (cpsr, scr) = $crate::calc::ssi_divisors(clocks.sysclk.0, freq.into().0)
+                        .expect("SPI bit rate out of range for the system clock");
+
+                    spi.cpsr.write(|w| unsafe {
+                        w.cpsdvsr().bits(cpsr)
+                    });
+
+                    spi.cr0.modify(|_,w| unsafe {
+                        w.spo().bit(mode.polarity == Polarity::IdleHigh)
+                            .sph().bit(mode.phase == Phase::CaptureOnSecondTransition)
+                            // FRF = 0 (Freescale SPI), DSS = 0x7 (8-bit data)
+                            .frf().bits(0)
+                            .dss().bits(0x7)
+                            .scr().bits(scr)
+                    });
+
+                    // Enable peripheral
+                    spi.cr1.write(|w| w.sse().set_bit());
+
+                    Spi { spi, pins, frame_gap: NanoSeconds(0), frame_gap_cycles: 0 }
+                }
+
+                /// Releases the SPI peripheral and associated pins. The SSI is
+                /// disabled once the current frame has gone out.
+                pub fn free(self) -> ($SPIX, (SCK, MISO, MOSI)) {
+                    spi_busy_wait!(self.spi, bsy, bit_is_clear);
+                    self.spi.cr1.modify(|_, w| w.sse().clear_bit());
+                    (self.spi, self.pins)
+                }
+
+                /// Like `free`, but hands the pins back as floating inputs,
+                /// disconnected from the SSI, so whatever uses them next
+                /// starts from a known state
+                pub fn free_into_inputs(self) -> ($SPIX, (SCK::Input, MISO::Input, MOSI::Input))
+                where
+                    SCK: $crate::gpio::RecoverablePin,
+                    MISO: $crate::gpio::RecoverablePin,
+                    MOSI: $crate::gpio::RecoverablePin,
+                {
+                    let (spi, (sck, miso, mosi)) = self.free();
+                    (spi, (sck.into_input(), miso.into_input(), mosi.into_input()))
+                }
+
+                /// Makes this driver's pins out of thin air, for recovery
+                /// code, such as a fault handler, that can't get at the
+                /// driver to `free` it. The pins keep whatever mode they
+                /// were in; `into_input` them before use. The SSI itself
+                /// isn't touched, so steal it from the PAC if you need it.
+                ///
+                /// # Safety
+                ///
+                /// The driver that owned the pins, and anything else holding
+                /// them, must never run again. A transfer it was in the
+                /// middle of is simply cut off, so a device on the bus may
+                /// need resetting.
+                pub unsafe fn recover_pins() -> (SCK, MISO, MOSI)
+                where
+                    SCK: $crate::gpio::RecoverablePin,
+                    MISO: $crate::gpio::RecoverablePin,
+                    MOSI: $crate::gpio::RecoverablePin,
+                {
+                    (SCK::steal(), MISO::steal(), MOSI::steal())
+                }
+
+                /// Like `free`, but also stops the SSI module's run-mode clock.
+                /// The next constructor call turns it back on.
+                pub fn power_down(self, pc: &sysctl::PowerControl) -> ($SPIX, (SCK, MISO, MOSI)) {
+                    let parts = self.free();
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::Off);
+                    parts
+                }
+
+                /// Change the clock frequency of the SPI device. If `freq`
+                /// can't be made at this clock the rate is left as it was.
+                pub fn reclock<F>(
+                    &mut self,
+                    freq: F,
+                    clocks: &Clocks,
+                ) -> Result<(), $crate::calc::RateError> where F: Into<Hertz> {
+                    clocks.assert_current();
+
+                    // SSInClk = SysClk / (CPSDVSR * (1 + SCR))
+                    let (cpsr, scr) = $crate::calc::ssi_divisors(clocks.sysclk.0, freq.into().0)?;
+
+                    // Disable peripheral
+                    self.spi.cr1.modify(|_, w| w.sse().clear_bit());
+
+                    self.spi.cpsr.write(|w| unsafe { w.cpsdvsr().bits(cpsr) });
+                    self.spi.cr0.modify(|_,w| unsafe { w.scr().bits(scr) });
+
+                    // Keep the inter-frame gap the same length of time
+                    self.frame_gap_cycles = self.frame_gap.cycles(clocks);
+
+                    // Enable peripheral again
+                    self.spi.cr1.modify(|_, w| w.sse().set_bit());
+                    Ok(())
+                }
+            }
+
+            impl<SCK, MOSI> Spi<$SPIX, (SCK, NoMiso, MOSI)>
+            where
+                SCK: $crate::gpio::Remux,
+                MOSI: $crate::gpio::Remux,
+            {
+                /// Turns this into a driver for a 3-wire bus, where the device
+                /// answers on MOSI. Writes still go through the SSI. The SSI
+                /// can't let go of MOSI in the middle of a frame, so reads
+                /// switch SCK and MOSI over to GPIO and clock the bits in by
+                /// software, at no more than the configured rate and in the
+                /// same SPI mode.
+                pub fn into_three_wire(self) -> ThreeWire<$SPIX, SCK, MOSI> {
+                    ThreeWire { spi: self }
+                }
+            }
+
+            impl<SCK, MOSI> ThreeWire<$SPIX, SCK, MOSI>
+            where
+                SCK: $crate::gpio::Remux,
+                MOSI: $crate::gpio::Remux,
+            {
+                /// Sends `words` to the device
+                pub fn write(&mut self, words: &[u8]) {
+                    $crate::assert_can_block!(concat!(stringify!($SPIX), " three-wire write"));
+                    $crate::rt_budget_scope!(concat!(stringify!($SPIX), " three-wire write"));
+                    for &word in words {
+                        // `send` waits for the frame to go out, so nothing
+                        // can pile up in the receive FIFO
+                        let _ = nb::block!(FullDuplex::send(&mut self.spi, word));
+                        let _ = self.spi.spi.dr.read();
+                    }
+                }
+
+                /// Fills `words` with bytes clocked in from the device. SCK
+                /// and MOSI are plain GPIO until this returns; an interrupt
+                /// meanwhile only stretches the clock, which the device won't
+                /// mind.
+                pub fn read(&mut self, words: &mut [u8]) {
+                    $crate::assert_can_block!(concat!(stringify!($SPIX), " three-wire read"));
+                    $crate::rt_budget_scope!(concat!(stringify!($SPIX), " three-wire read"));
+                    let spi = &self.spi.spi;
+                    spi_busy_wait!(spi, bsy, bit_is_clear);
+                    let cr0 = spi.cr0.read();
+                    let idle_high = cr0.spo().bit();
+                    let sample_on_second = cr0.sph().bit();
+                    // SSInClk = SysClk / (CPSDVSR * (1 + SCR))
+                    let half_bit = u32::from(spi.cpsr.read().cpsdvsr().bits())
+                        * (u32::from(cr0.scr().bits()) + 1)
+                        / 2;
+
+                    let (sck, _, mosi) = &mut self.spi.pins;
+                    mosi.gpio_input();
+                    sck.gpio_output(idle_high);
+                    for word in words.iter_mut() {
+                        let mut value = 0u8;
+                        for _ in 0..8 {
+                            cortex_m::asm::delay(half_bit);
+                            sck.set_level(!idle_high);
+                            if !sample_on_second {
+                                value = (value << 1) | mosi.level() as u8;
+                            }
+                            cortex_m::asm::delay(half_bit);
+                            sck.set_level(idle_high);
+                            if sample_on_second {
+                                value = (value << 1) | mosi.level() as u8;
+                            }
+                        }
+                        *word = value;
+                        if self.spi.frame_gap_cycles != 0 {
+                            cortex_m::asm::delay(self.spi.frame_gap_cycles);
+                        }
+                    }
+                    sck.alternate();
+                    mosi.alternate();
+                }
+
+                /// Goes back to the full-duplex driver
+                pub fn free(self) -> Spi<$SPIX, (SCK, NoMiso, MOSI)> {
+                    self.spi
+                }
+            }
+
+            impl<PINS> Spi<$SPIX, PINS> {
+                /// The underlying SSI peripheral, for registers this driver
+                /// doesn't cover
+                pub fn inner(&self) -> &$SPIX {
+                    &self.spi
+                }
+
+                /// Mutable access to the underlying SSI peripheral. Leave
+                /// the registers the driver relies on (enable, clocking, frame
+                /// format) as you found them, or the driver will misbehave.
+                pub fn inner_mut(&mut self) -> &mut $SPIX {
+                    &mut self.spi
+                }
+
+                /// Leaves at least `gap` between the end of one frame and the
+                /// start of the next, for slaves that need time to latch or
+                /// recover. Zero (the default) sends frames as fast as the
+                /// FIFO allows. `reclock` rescales the gap for the new clocks.
+                pub fn set_inter_frame_delay(&mut self, gap: NanoSeconds, clocks: &Clocks) {
+                    clocks.assert_current();
+                    self.frame_gap = gap;
+                    self.frame_gap_cycles = gap.cycles(clocks);
+                }
+
+                /// Like `Transfer::transfer`, but gives up with `Error::Timeout`
+                /// if it has not finished by `deadline`. On timeout the
+                /// transmitter is left to drain and the receive FIFO is emptied,
+                /// so the next transfer starts clean.
+                pub fn transfer_with_deadline<'w>(
+                    &mut self,
+                    words: &'w mut [u8],
+                    deadline: $crate::time::Instant,
+                ) -> Result<&'w [u8], Error> {
+                    $crate::assert_can_block!(concat!(stringify!($SPIX), " transfer"));
+                    $crate::rt_budget_scope!(concat!(stringify!($SPIX), " transfer"));
+                    for word in words.iter_mut() {
+                        while self.spi.sr.read().tnf().bit_is_clear() {
+                            if deadline.has_passed() {
+                                return Err(self.abandon());
+                            }
+                        }
+                        self.spi.dr.write(|w| unsafe { w.data().bits((*word).into()) });
+                        while self.spi.sr.read().rne().bit_is_clear() {
+                            if deadline.has_passed() {
+                                return Err(self.abandon());
+                            }
+                        }
+                        *word = self.spi.dr.read().data().bits() as u8;
+                        if self.frame_gap_cycles != 0 {
+                            cortex_m::asm::delay(self.frame_gap_cycles);
+                        }
+                    }
+                    Ok(words)
+                }
+
+                /// Clocks out at least `cycles` SCK cycles with MOSI held high,
+                /// by sending 0xFF bytes and discarding whatever comes back.
+                /// Chip select is up to the caller; SD cards, for example, want
+                /// 74 or more of these with CS released before they are spoken
+                /// to.
+                pub fn send_idle_clocks(&mut self, cycles: u32) {
+                    $crate::assert_can_block!(concat!(stringify!($SPIX), " idle clocks"));
+                    $crate::rt_budget_scope!(concat!(stringify!($SPIX), " idle clocks"));
+                    for _ in 0..(cycles + 7) / 8 {
+                        spi_busy_wait!(self.spi, tnf, bit_is_set);
+                        self.spi.dr.write(|w| unsafe { w.data().bits(0xFF) });
+                        spi_busy_wait!(self.spi, rne, bit_is_set);
+                        let _ = self.spi.dr.read();
+                    }
+                    spi_busy_wait!(self.spi, bsy, bit_is_clear);
+                }
+
+                fn abandon(&mut self) -> Error {
+                    spi_busy_wait!(self.spi, bsy, bit_is_clear);
+                    while self.spi.sr.read().rne().bit_is_set() {
+                        let _ = self.spi.dr.read();
+                    }
+                    Error::Timeout
+                }
+            }
+
+            impl<PINS> Spi<$SPIX, PINS> {
+                /// The NVIC interrupt this SSI module raises
+                pub const INTERRUPT: Interrupt = Interrupt::$SPIX;
+
+                $crate::nvic_control!("this SSI module's interrupt");
+            }
+
+            impl<PINS> Spi<$SPIX, PINS> {
+                /// `FullDuplex::read`, for interrupt handlers; see `isr`
+                pub fn read_from_isr(
+                    &mut self,
+                    _isr: &$crate::isr::IsrContext,
+                ) -> nb::Result<u8, Error> {
+                    FullDuplex::read(self)
+                }
+
+                /// Queues `byte` if the transmit FIFO has room, for
+                /// interrupt handlers; see `isr`. Unlike `FullDuplex::send`
+                /// this doesn't wait for the frame to go out, so there is
+                /// no inter-frame gap.
+                pub fn send_from_isr(
+                    &mut self,
+                    _isr: &$crate::isr::IsrContext,
+                    byte: u8,
+                ) -> nb::Result<(), Error> {
+                    if self.spi.sr.read().tnf().bit_is_clear() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    self.spi.dr.write(|w| unsafe { w.data().bits(byte.into()) });
+                    Ok(())
+                }
+            }
+
+            impl<PINS> FullDuplex<u8> for Spi<$SPIX, PINS> {
+                type Error = Error;
+
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    // Receive FIFO Not Empty
+                    if self.spi.sr.read().rne().bit_is_clear() {
+                        Err(nb::Error::WouldBlock)
+                    } else {
+                        let r = self.spi.dr.read().data().bits() as u8;
+                        Ok(r)
+                    }
+                }
+
+                fn send(&mut self, byte: u8) -> nb::Result<(), Error> {
+                    // Transmit FIFO Not Full
+                    if self.spi.sr.read().tnf().bit_is_clear() {
+                        Err(nb::Error::WouldBlock)
+                    } else {
+                        self.spi.dr.write(|w| unsafe {
+                            w.data().bits(byte.into())
+                        });
+                        spi_busy_wait!(self.spi, bsy, bit_is_clear);
+                        if self.frame_gap_cycles != 0 {
+                            cortex_m::asm::delay(self.frame_gap_cycles);
+                        }
+                        Ok(())
+                    }
+                }
+            }
+
+            impl<PINS> $crate::post::SelfTest for Spi<$SPIX, PINS> {
+                /// Sends a couple of patterns through the SSI's internal
+                /// loopback. Nothing is driven onto the pins meanwhile.
+                fn self_test(&mut self) -> bool {
+                    // The control registers may only change while SSE is clear
+                    self.spi.cr1.modify(|_, w| w.sse().clear_bit());
+                    self.spi.cr1.modify(|_, w| w.lbm().set_bit());
+                    self.spi.cr1.modify(|_, w| w.sse().set_bit());
+
+                    while self.spi.sr.read().rne().bit_is_set() {
+                        let _ = self.spi.dr.read();
+                    }
+                    let mut ok = true;
+                    for &pattern in &[0x55u8, 0xAA] {
+                        self.spi.dr.write(|w| unsafe { w.data().bits(pattern.into()) });
+                        spi_busy_wait!(self.spi, bsy, bit_is_clear);
+                        if self.spi.sr.read().rne().bit_is_clear()
+                            || self.spi.dr.read().data().bits() as u8 != pattern
+                        {
+                            ok = false;
+                        }
+                    }
+
+                    self.spi.cr1.modify(|_, w| w.sse().clear_bit());
+                    self.spi.cr1.modify(|_, w| w.lbm().clear_bit());
+                    self.spi.cr1.modify(|_, w| w.sse().set_bit());
+                    ok
+                }
+            }
+
+            impl<PINS> crate::hal::blocking::spi::transfer::Default<u8> for Spi<$SPIX, PINS> {}
+
+            impl<PINS> crate::hal::blocking::spi::write::Default<u8> for Spi<$SPIX, PINS> {}
+        )+
+    }
+}
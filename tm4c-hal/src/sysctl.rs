@@ -1,6 +1,7 @@
 //! Code for the System Control module.
 
 use crate::time::Hertz;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 /// Frozen clock frequencies
 ///
@@ -11,8 +12,14 @@ pub struct Clocks {
     pub osc: Hertz,
     /// System clock speed
     pub sysclk: Hertz,
+    /// Which clock configuration this describes; zero if unchecked
+    epoch: u32,
 }
 
+/// Bumped every time the clocks are configured, so a `Clocks` from an
+/// earlier configuration can be told apart from the current one
+static CLOCK_EPOCH: AtomicU32 = AtomicU32::new(0);
+
 #[derive(Copy, Clone)]
 /// Select in which mode the peripheral should be affected
 pub enum RunMode {
@@ -34,6 +41,47 @@ pub enum PowerState {
 }
 
 impl Clocks {
+    /// A `Clocks` that doesn't come from `freeze`, for tests and for code
+    /// that runs before the clocks are set up. Nothing checks it against
+    /// the hardware.
+    pub const fn new(osc: Hertz, sysclk: Hertz) -> Self {
+        Clocks {
+            osc,
+            sysclk,
+            epoch: 0,
+        }
+    }
+
+    /// A `Clocks` describing a configuration that has just been applied.
+    /// Every earlier `Clocks` from this function stops being current. Only
+    /// the chip crates' clock setup should call this.
+    pub fn configured(osc: Hertz, sysclk: Hertz) -> Self {
+        let epoch = CLOCK_EPOCH.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        Clocks { osc, sysclk, epoch }
+    }
+
+    /// False if the clocks have been configured again since this `Clocks`
+    /// was made, so the frequencies in it may no longer be true. A `Clocks`
+    /// from `new` is always considered current.
+    pub fn is_current(&self) -> bool {
+        self.epoch == 0 || self.epoch == CLOCK_EPOCH.load(Ordering::Relaxed)
+    }
+
+    /// Panics if this `Clocks` is out of date (see `is_current`). The
+    /// drivers call this when given a `Clocks`, in debug builds or with the
+    /// `strict-clocks` feature, so that a stale value shows up as a panic
+    /// rather than as a wrong baud rate. Release builds otherwise skip the
+    /// check.
+    #[track_caller]
+    pub fn assert_current(&self) {
+        if cfg!(any(debug_assertions, feature = "strict-clocks")) {
+            assert!(
+                self.is_current(),
+                "stale Clocks: the clocks were reconfigured after this value was made"
+            );
+        }
+    }
+
     /// Returns the frequency of the oscillator.
     pub fn osc(self) -> Hertz {
         self.osc
@@ -45,6 +93,272 @@ impl Clocks {
     }
 }
 
+/// The clocks the chip will run at in deep sleep, as set up by the chip
+/// crate's `DeepSleepClockSetup::apply`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeepSleepClocks {
+    /// Deep-sleep oscillator frequency
+    pub osc: Hertz,
+    /// System clock in deep sleep, after the divider
+    pub sysclk: Hertz,
+}
+
+/// The system clock as measured against a reference clock, for checking the
+/// clock setup did what `Clocks` says it did
+#[derive(Clone, Copy)]
+pub struct ClockMeasurement {
+    /// The measured system clock
+    pub measured: Hertz,
+    /// The system clock `Clocks` claims
+    pub expected: Hertz,
+}
+
+impl ClockMeasurement {
+    /// How far `measured` is from `expected`, in parts per million
+    pub fn deviation_ppm(&self) -> i32 {
+        let diff = i64::from(self.measured.0) - i64::from(self.expected.0);
+        (diff * 1_000_000 / i64::from(self.expected.0.max(1))) as i32
+    }
+
+    /// True if `measured` is within `ppm` parts per million of `expected`
+    pub fn within_ppm(&self, ppm: u32) -> bool {
+        self.deviation_ppm().abs() as u32 <= ppm
+    }
+}
+
+/// A clock source that didn't come up while the clock setup waited for it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockError {
+    /// The main oscillator never reported it was running: the crystal is
+    /// missing, or isn't the frequency configured
+    MainOscillator,
+    /// The PLL never locked
+    PllLock,
+    /// The chip crate can't set up this oscillator. Nothing was changed.
+    Unsupported,
+}
+
+/// How many times the clock setup polls for an oscillator or the PLL to be
+/// ready before giving up. That is at least a quarter of a second at the
+/// 16 MHz the chip runs at meanwhile, far longer than either should take.
+pub const CLOCK_READY_POLLS: u32 = 1_000_000;
+
+/// A peripheral didn't report ready (its PRx bit) after being reset, most
+/// likely because its run-mode clock is off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResetTimeout;
+
+/// How many times `reset` polls a peripheral's ready bit before giving up.
+/// A clocked module is ready within a few system clocks.
+pub const RESET_POLLS: u32 = 10_000;
+
+/// What caused the resets recorded in the RESC register since it was last
+/// cleared. More than one can be set, as the register accumulates until
+/// `clear_reset_cause`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ResetCause {
+    /// The RST pin was pulled low
+    pub external: bool,
+    /// Power-on reset
+    pub power_on: bool,
+    /// Brown-out reset
+    pub brown_out: bool,
+    /// Watchdog timer 0 timed out
+    pub watchdog0: bool,
+    /// Software reset, through `SCB::sys_reset` or the debugger
+    pub software: bool,
+    /// Watchdog timer 1 timed out
+    pub watchdog1: bool,
+    /// Woken from hibernation. Always false on TM4C123, which records
+    /// hibernation wake-ups in the hibernation module instead.
+    pub hibernation: bool,
+    /// Reset from the HSSR register. Always false on TM4C123.
+    pub hssr: bool,
+    /// The main oscillator failed
+    pub mosc_failure: bool,
+}
+
+impl ResetCause {
+    /// Decodes a RESC value. The bit layout is shared by both chip
+    /// families; TM4C123 leaves the 129-only bits clear.
+    pub fn from_bits(bits: u32) -> ResetCause {
+        let bit = |n: u32| bits & (1 << n) != 0;
+        ResetCause {
+            external: bit(0),
+            power_on: bit(1),
+            brown_out: bit(2),
+            watchdog0: bit(3),
+            software: bit(4),
+            watchdog1: bit(5),
+            hibernation: bit(6),
+            hssr: bit(12),
+            mosc_failure: bit(16),
+        }
+    }
+
+    /// True if either watchdog caused a reset
+    pub fn watchdog(&self) -> bool {
+        self.watchdog0 || self.watchdog1
+    }
+}
+
+/// The peripheral isn't on this part, so its clock gate bits are reserved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeripheralNotPresent;
+
+/// A frequency that isn't within 1% of any of the supported values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnsupportedFrequency(pub Hertz);
+
+/// Picks the entry of `options` nearest to `target`, as long as it is within
+/// 1%. Used by the `TryFrom<Hertz>` impls for the clock enums.
+pub fn nearest_frequency<T>(options: &[T], target: Hertz) -> Result<T, UnsupportedFrequency>
+where
+    T: Copy + Into<Hertz>,
+{
+    let distance = |option: &T| {
+        let hz: Hertz = (*option).into();
+        (i64::from(hz.0) - i64::from(target.0)).abs()
+    };
+    options
+        .iter()
+        .min_by_key(|option| distance(option))
+        .filter(|option| distance(option) * 100 <= i64::from(target.0))
+        .copied()
+        .ok_or(UnsupportedFrequency(target))
+}
+
+/// Declarative idle-current settings, applied by each chip crate's
+/// `sysctl::low_power::apply`.
+pub mod low_power {
+    use core::fmt;
+
+    /// What the SRAM does while the chip is in deep sleep
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum SramPower {
+        /// Fully powered (reset value)
+        Active,
+        /// Standby, with a short wake-up delay
+        Standby,
+        /// Lowest retention power, with the longest wake-up delay
+        LowPower,
+    }
+
+    impl SramPower {
+        /// The SRAMPM field encoding
+        pub fn bits(self) -> u32 {
+            match self {
+                SramPower::Active => 0x0,
+                SramPower::Standby => 0x1,
+                SramPower::LowPower => 0x3,
+            }
+        }
+    }
+
+    /// Idle-current options. Start from `Builder::new()`, which changes
+    /// nothing, and switch on what you want.
+    pub struct Builder<'a, D> {
+        /// Leave the precision internal oscillator running
+        pub keep_piosc: bool,
+        /// SRAM power mode in deep sleep
+        pub sram_retention: SramPower,
+        /// Put the flash into its low-power mode in deep sleep
+        pub flash_low_power: bool,
+        /// GPIO ports to clock-gate in every mode, because nothing uses them
+        pub gate_unused_gpio: &'a [D],
+    }
+
+    impl<'a, D> Builder<'a, D> {
+        /// Options that leave everything as it is
+        pub fn new() -> Self {
+            Builder {
+                keep_piosc: true,
+                sram_retention: SramPower::Active,
+                flash_low_power: false,
+                gate_unused_gpio: &[],
+            }
+        }
+
+        /// Sets `keep_piosc`
+        pub fn keep_piosc(mut self, keep: bool) -> Self {
+            self.keep_piosc = keep;
+            self
+        }
+
+        /// Sets `sram_retention`
+        pub fn sram_retention(mut self, mode: SramPower) -> Self {
+            self.sram_retention = mode;
+            self
+        }
+
+        /// Sets `flash_low_power`
+        pub fn flash_low_power(mut self, low_power: bool) -> Self {
+            self.flash_low_power = low_power;
+            self
+        }
+
+        /// Sets `gate_unused_gpio`
+        pub fn gate_unused_gpio(mut self, ports: &'a [D]) -> Self {
+            self.gate_unused_gpio = ports;
+            self
+        }
+    }
+
+    /// `gate_unused_gpio` listed a domain that isn't a GPIO port. Nothing
+    /// was changed.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct NotGpio<D>(pub D);
+
+    impl<'a, D> Default for Builder<'a, D> {
+        fn default() -> Self {
+            Builder::new()
+        }
+    }
+
+    /// What `apply` changed. Its `Display` impl spells this out, one change
+    /// per line, for logging.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Report {
+        /// The sleep-mode clock gating registers were put into effect
+        pub auto_clock_gating: bool,
+        /// The precision internal oscillator was switched off
+        pub piosc_off: bool,
+        /// The deep-sleep SRAM power mode was set to this
+        pub sram_retention: Option<SramPower>,
+        /// The flash was set to low-power mode in deep sleep
+        pub flash_low_power: bool,
+        /// Number of GPIO ports that were clock-gated
+        pub gated_gpio: usize,
+        /// `keep_piosc` was false but the PIOSC was left on, because the
+        /// system clock runs from it
+        pub piosc_in_use: bool,
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if self.auto_clock_gating {
+                writeln!(f, "sleep clock gating registers enabled")?;
+            }
+            if self.piosc_off {
+                writeln!(f, "PIOSC switched off")?;
+            }
+            if self.piosc_in_use {
+                writeln!(f, "PIOSC kept on: it drives the system clock")?;
+            }
+            if let Some(mode) = self.sram_retention {
+                writeln!(f, "deep-sleep SRAM power mode: {:?}", mode)?;
+            }
+            if self.flash_low_power {
+                writeln!(f, "deep-sleep flash low-power mode enabled")?;
+            }
+            if self.gated_gpio != 0 {
+                writeln!(f, "{} unused GPIO ports clock-gated", self.gated_gpio)?;
+            }
+            Ok(())
+        }
+    }
+}
+
 /// This module is all about identifying the physical chip we're running on.
 pub mod chip_id {
 
@@ -169,3 +483,76 @@ pub mod chip_id {
         pub part_no: PartNo,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryFrom;
+
+    crate::frequency_enum! {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum Test {
+            /// 4 MHz
+            _4mhz => 4_000_000,
+            /// 4.096 MHz, 2.4% from its neighbour
+            _4_09mhz = 3 => 4_096_000,
+            /// 25 MHz
+            _25mhz => 25_000_000,
+        }
+    }
+
+    #[test]
+    fn reconfiguring_retires_the_old_clocks() {
+        let unchecked = Clocks::new(Hertz(16_000_000), Hertz(16_000_000));
+        let first = Clocks::configured(Hertz(16_000_000), Hertz(80_000_000));
+        assert!(first.is_current());
+        let second = Clocks::configured(Hertz(16_000_000), Hertz(50_000_000));
+        assert!(!first.is_current());
+        assert!(second.is_current());
+        // `new` is never checked
+        assert!(unchecked.is_current());
+    }
+
+    #[test]
+    fn all_lists_every_variant_in_order() {
+        assert_eq!(Test::ALL, &[Test::_4mhz, Test::_4_09mhz, Test::_25mhz]);
+        assert_eq!(Test::_4_09mhz as u8, 3);
+        assert_eq!(Test::_25mhz as u8, 4);
+        for &f in Test::ALL {
+            assert_eq!(Test::try_from(Hertz::from(f)), Ok(f));
+        }
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_within_one_percent() {
+        assert_eq!(Test::try_from(Hertz(4_040_000)), Ok(Test::_4mhz));
+        assert_eq!(Test::try_from(Hertz(4_060_000)), Ok(Test::_4_09mhz));
+        // Within 1% of the target, either side
+        assert_eq!(Test::try_from(Hertz(25_250_000)), Ok(Test::_25mhz));
+        assert_eq!(Test::try_from(Hertz(24_760_000)), Ok(Test::_25mhz));
+    }
+
+    #[test]
+    fn rejects_anything_further_off() {
+        for &hz in [
+            0,
+            3_950_000,
+            4_150_000,
+            24_700_000,
+            25_300_000,
+            u32::max_value(),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                Test::try_from(Hertz(hz)),
+                Err(UnsupportedFrequency(Hertz(hz)))
+            );
+        }
+        let none: [Test; 0] = [];
+        assert_eq!(
+            nearest_frequency(&none, Hertz(4_000_000)),
+            Err(UnsupportedFrequency(Hertz(4_000_000)))
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Millisecond time from a periodic tick, without drift
+//!
+//! A timer can rarely hit 1 ms exactly: the period is a whole number of
+//! clock cycles, and at an awkward system clock 1 ms isn't. Counting each
+//! tick as 1 ms then drifts by the difference, which adds up to seconds
+//! over weeks. `TickAccumulator` instead adds up the exact length of each
+//! tick as whole milliseconds plus a remainder in clock cycles, so the
+//! error never exceeds one clock cycle however long it runs.
+
+/// Counts ticks of a known length in system clock cycles and turns them
+/// into milliseconds
+#[derive(Debug, Clone, Copy)]
+pub struct TickAccumulator {
+    ticks: u64,
+    millis: u64,
+    /// Sub-millisecond time carried over, in units of 1/1000 clock cycle
+    remainder: u32,
+    /// Whole milliseconds per tick
+    whole: u64,
+    /// Leftover per tick, in the same units as `remainder`
+    fraction: u32,
+    sysclk: u32,
+}
+
+impl TickAccumulator {
+    /// An accumulator for ticks `cycles_per_tick` system clock cycles long,
+    /// at a system clock of `sysclk` Hz
+    pub fn new(cycles_per_tick: u64, sysclk: u32) -> Self {
+        // ms per tick = cycles_per_tick * 1000 / sysclk
+        let scaled = cycles_per_tick * 1_000;
+        TickAccumulator {
+            ticks: 0,
+            millis: 0,
+            remainder: 0,
+            whole: scaled / u64::from(sysclk),
+            fraction: (scaled % u64::from(sysclk)) as u32,
+            sysclk,
+        }
+    }
+
+    /// Counts one tick
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        self.millis += self.whole;
+        // Both are below sysclk, so this can't overflow for any sysclk under
+        // 2 GHz
+        self.remainder += self.fraction;
+        if self.remainder >= self.sysclk {
+            self.remainder -= self.sysclk;
+            self.millis += 1;
+        }
+    }
+
+    /// Ticks counted so far
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Time elapsed over those ticks, in whole milliseconds
+    pub fn millis(&self) -> u64 {
+        self.millis
+    }
+}
+
+/// Fires every `period` milliseconds, on a schedule fixed when it was made.
+/// A late check doesn't push back the ones after it.
+#[derive(Debug, Clone, Copy)]
+pub struct Every {
+    next: u64,
+    period: u64,
+}
+
+impl Every {
+    /// Fires first at `now + period`. Panics if `period` is zero, which
+    /// would be due on every check.
+    pub fn new(now: u64, period: u64) -> Self {
+        assert!(period > 0, "Every needs a period of at least 1 ms");
+        Every {
+            next: now + period,
+            period,
+        }
+    }
+
+    /// True if the next deadline has passed at time `now`, in which case
+    /// the one after it is scheduled. If several have passed, each call
+    /// returns true once until it has caught up.
+    pub fn due(&mut self, now: u64) -> bool {
+        if now >= self.next {
+            self.next += self.period;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Milliseconds in `ticks` ticks of `cycles` cycles, rounded down
+    fn exact_millis(ticks: u64, cycles: u64, sysclk: u32) -> u64 {
+        (u128::from(ticks) * u128::from(cycles) * 1_000 / u128::from(sysclk)) as u64
+    }
+
+    #[test]
+    fn whole_millisecond_ticks_count_exactly() {
+        let mut acc = TickAccumulator::new(16_000, 16_000_000);
+        for _ in 0..1_000 {
+            acc.tick();
+        }
+        assert_eq!(acc.ticks(), 1_000);
+        assert_eq!(acc.millis(), 1_000);
+    }
+
+    #[test]
+    fn awkward_ticks_never_drift() {
+        // 66.67 MHz can't make 1 ms: the nearest period is 66 667 cycles,
+        // about 5 ppm long, which must show up rather than being rounded away
+        let (cycles, sysclk) = (66_667, 66_666_666);
+        let mut acc = TickAccumulator::new(cycles, sysclk);
+        for n in 1..=2_000_000u64 {
+            acc.tick();
+            if n % 99_991 == 0 {
+                assert_eq!(acc.millis(), exact_millis(n, cycles, sysclk));
+            }
+        }
+        assert_eq!(acc.millis(), exact_millis(2_000_000, cycles, sysclk));
+        assert_eq!(acc.millis(), 2_000_010);
+    }
+
+    #[test]
+    fn long_ticks_carry_whole_milliseconds() {
+        // 2.5 ms ticks at 80 MHz
+        let mut acc = TickAccumulator::new(200_000, 80_000_000);
+        acc.tick();
+        assert_eq!(acc.millis(), 2);
+        acc.tick();
+        assert_eq!(acc.millis(), 5);
+    }
+
+    #[test]
+    fn every_fires_on_a_fixed_schedule() {
+        let mut every = Every::new(100, 10);
+        assert!(!every.due(109));
+        assert!(every.due(110));
+        assert!(!every.due(110));
+        // A late check doesn't move the next deadline
+        assert!(every.due(125));
+        assert!(every.due(130));
+        assert!(!every.due(139));
+    }
+
+    #[test]
+    fn every_catches_up_one_at_a_time() {
+        let mut every = Every::new(0, 10);
+        let fired = (0..5).filter(|_| every.due(35)).count();
+        assert_eq!(fired, 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn every_refuses_a_zero_period() {
+        Every::new(0, 0);
+    }
+}
@@ -1,23 +1,36 @@
 //! Time units
 
+use crate::sysctl::Clocks;
 use cortex_m::peripheral::DWT;
 
 /// Bits per second
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Bps(pub u32);
 
 /// Hertz
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Hertz(pub u32);
 
 /// KiloHertz
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct KiloHertz(pub u32);
 
 /// MegaHertz
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct MegaHertz(pub u32);
 
+/// NanoSeconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NanoSeconds(pub u32);
+
+impl NanoSeconds {
+    /// Number of `clocks.sysclk` cycles in this duration, rounded up
+    pub fn cycles(self, clocks: &Clocks) -> u32 {
+        let cycles = (u64::from(self.0) * u64::from(clocks.sysclk.0) + 999_999_999) / 1_000_000_000;
+        cycles as u32
+    }
+}
+
 /// Extension trait that adds convenience methods to the `u32` type
 pub trait U32Ext {
     /// Wrap in `Bps`
@@ -31,6 +44,9 @@ pub trait U32Ext {
 
     /// Wrap in `MegaHertz`
     fn mhz(self) -> MegaHertz;
+
+    /// Wrap in `NanoSeconds`
+    fn ns(self) -> NanoSeconds;
 }
 
 impl U32Ext for u32 {
@@ -49,24 +65,57 @@ impl U32Ext for u32 {
     fn mhz(self) -> MegaHertz {
         MegaHertz(self)
     }
+
+    fn ns(self) -> NanoSeconds {
+        NanoSeconds(self)
+    }
 }
 
-impl Into<Hertz> for KiloHertz {
-    fn into(self) -> Hertz {
+impl KiloHertz {
+    /// Converts to `Hertz`; unlike `into`, this works in const contexts
+    pub const fn to_hz(self) -> Hertz {
         Hertz(self.0 * 1_000)
     }
 }
 
-impl Into<Hertz> for MegaHertz {
-    fn into(self) -> Hertz {
+impl MegaHertz {
+    /// Converts to `Hertz`; unlike `into`, this works in const contexts
+    pub const fn to_hz(self) -> Hertz {
         Hertz(self.0 * 1_000_000)
     }
 }
 
-impl Into<KiloHertz> for MegaHertz {
-    fn into(self) -> KiloHertz {
-        KiloHertz(self.0 * 1_000)
+impl From<KiloHertz> for Hertz {
+    fn from(f: KiloHertz) -> Self {
+        Hertz(f.0 * 1_000)
+    }
+}
+
+impl From<MegaHertz> for Hertz {
+    fn from(f: MegaHertz) -> Self {
+        Hertz(f.0 * 1_000_000)
+    }
+}
+
+impl From<MegaHertz> for KiloHertz {
+    fn from(f: MegaHertz) -> Self {
+        KiloHertz(f.0 * 1_000)
+    }
+}
+
+/// The deadline is 2^31 or more cycle counter ticks away, too far for
+/// `Instant::has_passed` to tell apart from one in the past
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadlineTooFar;
+
+/// Cycles in `us` microseconds at `frequency`, if an `Instant` can be that
+/// far ahead
+fn us_to_ticks(frequency: Hertz, us: u32) -> Result<u32, DeadlineTooFar> {
+    let ticks = u64::from(frequency.0) * u64::from(us) / 1_000_000;
+    if ticks >= 1 << 31 {
+        return Err(DeadlineTooFar);
     }
+    Ok(ticks as u32)
 }
 
 /// A monotonic nondecreasing timer
@@ -76,6 +125,36 @@ pub struct MonoTimer {
 }
 
 impl MonoTimer {
+    /// Creates a `MonoTimer` from the core cycle counter
+    pub fn new(mut dwt: DWT, clocks: Clocks) -> Self {
+        clocks.assert_current();
+        dwt.enable_cycle_counter();
+
+        // now the CYCCNT counter can't be stopped or reset
+        drop(dwt);
+
+        MonoTimer {
+            frequency: clocks.sysclk,
+        }
+    }
+
+    /// Returns an `Instant` `us` microseconds from now, for use as a
+    /// deadline. `Instant::has_passed` can only see 2^31 cycles ahead
+    /// (about 17 seconds at 120 MHz), so anything further is refused.
+    pub fn deadline_us(self, us: u32) -> Result<Instant, DeadlineTooFar> {
+        let ticks = us_to_ticks(self.frequency, us)?;
+        Ok(Instant {
+            now: DWT::get_cycle_count().wrapping_add(ticks),
+        })
+    }
+
+    /// A timer that claims to run at `frequency`, for host tests of the code
+    /// that uses one
+    #[cfg(test)]
+    pub(crate) fn with_frequency(frequency: Hertz) -> Self {
+        MonoTimer { frequency }
+    }
+
     /// Returns the frequency at which the monotonic timer is operating at
     pub fn frequency(self) -> Hertz {
         self.frequency
@@ -96,8 +175,49 @@ pub struct Instant {
 }
 
 impl Instant {
+    /// The cycle counter value this `Instant` stands for
+    pub fn ticks(self) -> u32 {
+        self.now
+    }
+
     /// Ticks elapsed since the `Instant` was created
     pub fn elapsed(self) -> u32 {
         DWT::get_cycle_count().wrapping_sub(self.now)
     }
+
+    /// Returns true once the cycle counter has reached this `Instant`. Only
+    /// meaningful for instants less than 2^31 ticks away (about 26 seconds
+    /// at 80 MHz).
+    pub fn has_passed(self) -> bool {
+        DWT::get_cycle_count().wrapping_sub(self.now) as i32 >= 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_ticks_do_not_overflow() {
+        // 60 s at 120 MHz used to wrap u32 to 2.9 s
+        assert_eq!(
+            us_to_ticks(Hertz(120_000_000), 60_000_000),
+            Err(DeadlineTooFar)
+        );
+        assert_eq!(
+            us_to_ticks(Hertz(120_000_000), 17_000_000),
+            Ok(2_040_000_000)
+        );
+        assert_eq!(
+            us_to_ticks(Hertz(120_000_000), 17_900_000),
+            Err(DeadlineTooFar)
+        );
+    }
+
+    #[test]
+    fn deadline_ticks_keep_fractional_megahertz() {
+        // 66.67 MHz used to count as 66 cycles per microsecond
+        assert_eq!(us_to_ticks(Hertz(66_666_667), 1_000), Ok(66_666));
+        assert_eq!(us_to_ticks(Hertz(16_000_000), 0), Ok(0));
+    }
 }
@@ -0,0 +1,428 @@
+//! Timer tick calculations that are generic to both the TM4C123 and TM4C129
+//!
+//! `TickCalc` turns a period or frequency into the load and prescale values a
+//! General-Purpose Timer needs, and back again. Everything rounds to the
+//! nearest tick; if the result doesn't fit the timer, a `RangeError` says
+//! which way it fell out, and `saturate` gives the nearest ticks that do.
+//!
+//! `spread_table` works out the period sequence for a spread-spectrum PWM
+//! output.
+
+use crate::time::{Hertz, NanoSeconds};
+use core::time::Duration;
+
+/// Which timer configuration the ticks are for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    /// A 16-bit timer half counting input clocks directly, with the
+    /// prescaler left at zero (PWM outputs)
+    Bits16,
+    /// A 16-bit timer half, with its 8-bit prescaler acting as a divider
+    /// (periodic and one-shot count-down modes)
+    Split16,
+    /// A 16/32-bit timer with its halves concatenated
+    Bits32,
+    /// A 32/64-bit wide timer with its halves concatenated
+    Bits64,
+}
+
+/// Values to program into a timer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ticks {
+    /// Timer clocks per period. The interval load register takes one less
+    /// than this, as the count includes zero.
+    pub load: u64,
+    /// Prescaler value. The timer clock is the input clock divided by
+    /// `prescale + 1`. Always zero unless the width is `Split16`.
+    pub prescale: u8,
+}
+
+impl Ticks {
+    /// Input clock cycles per period
+    pub fn cycles(self) -> u64 {
+        self.load * (u64::from(self.prescale) + 1)
+    }
+}
+
+/// The requested period can't be reached with the chosen timer width
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeError {
+    /// Shorter than one clock cycle
+    TooShort,
+    /// Longer than the timer can count
+    TooLong,
+}
+
+/// Converts between periods and timer ticks for one clock and timer width
+#[derive(Clone, Copy)]
+pub struct TickCalc {
+    clock: Hertz,
+    width: Width,
+}
+
+impl TickCalc {
+    /// Calculator for a timer of the given width, running from `clock`
+    /// (usually `clocks.sysclk`)
+    pub fn for_clock(clock: Hertz, width: Width) -> Self {
+        TickCalc { clock, width }
+    }
+
+    /// Splits a number of input clock cycles into load and prescale values
+    pub fn cycles_to_ticks(self, cycles: u64) -> Result<Ticks, RangeError> {
+        if cycles == 0 {
+            return Err(RangeError::TooShort);
+        }
+        match self.width {
+            Width::Split16 => {
+                // Use the smallest prescaler that brings the load into range,
+                // which keeps the resolution as fine as possible
+                let divider = (cycles + 0xFFFF) >> 16;
+                if divider > 256 {
+                    return Err(RangeError::TooLong);
+                }
+                let load = (cycles + divider / 2) / divider;
+                Ok(Ticks {
+                    load,
+                    prescale: (divider - 1) as u8,
+                })
+            }
+            Width::Bits16 if cycles > 0x1_0000 => Err(RangeError::TooLong),
+            Width::Bits32 if cycles > 0x1_0000_0000 => Err(RangeError::TooLong),
+            Width::Bits16 | Width::Bits32 | Width::Bits64 => Ok(Ticks {
+                load: cycles,
+                prescale: 0,
+            }),
+        }
+    }
+
+    /// The shortest and longest ticks the timer can hold
+    pub fn range(self) -> (Ticks, Ticks) {
+        let longest = match self.width {
+            Width::Bits16 => Ticks {
+                load: 0x1_0000,
+                prescale: 0,
+            },
+            Width::Split16 => Ticks {
+                load: 0x1_0000,
+                prescale: 0xFF,
+            },
+            Width::Bits32 => Ticks {
+                load: 0x1_0000_0000,
+                prescale: 0,
+            },
+            Width::Bits64 => Ticks {
+                load: u64::max_value(),
+                prescale: 0,
+            },
+        };
+        let shortest = Ticks {
+            load: 1,
+            prescale: 0,
+        };
+        (shortest, longest)
+    }
+
+    /// The ticks a conversion gave, or if it fell out of range, the end of
+    /// the range it fell off
+    pub fn saturate(self, ticks: Result<Ticks, RangeError>) -> Ticks {
+        let (shortest, longest) = self.range();
+        match ticks {
+            Ok(ticks) => ticks,
+            Err(RangeError::TooShort) => shortest,
+            Err(RangeError::TooLong) => longest,
+        }
+    }
+
+    /// Ticks for a timer that should expire every `period`
+    pub fn period_to_ticks(self, period: NanoSeconds) -> Result<Ticks, RangeError> {
+        self.duration_to_ticks(Duration::from_nanos(u64::from(period.0)))
+    }
+
+    /// Ticks for a timer that should expire every `period`. Unlike
+    /// `period_to_ticks` this reaches past 4.29 s, for the 32- and 64-bit
+    /// widths.
+    pub fn duration_to_ticks(self, period: Duration) -> Result<Ticks, RangeError> {
+        let clock = u64::from(self.clock.0);
+        // Below 2^30 * 2^32, so this fits in a u64
+        let product = u64::from(period.subsec_nanos()) * clock;
+        let fraction = product / 1_000_000_000 + u64::from(product % 1_000_000_000 >= 500_000_000);
+        let cycles = period
+            .as_secs()
+            .checked_mul(clock)
+            .and_then(|whole| whole.checked_add(fraction))
+            .ok_or(RangeError::TooLong)?;
+        self.cycles_to_ticks(cycles)
+    }
+
+    /// Ticks for a timer that should expire at `frequency`
+    pub fn frequency_to_ticks(self, frequency: Hertz) -> Result<Ticks, RangeError> {
+        if frequency.0 == 0 {
+            return Err(RangeError::TooLong);
+        }
+        let clock = u64::from(self.clock.0);
+        let frequency = u64::from(frequency.0);
+        self.cycles_to_ticks((clock + frequency / 2) / frequency)
+    }
+
+    /// The period `ticks` actually gives, to the nearest nanosecond
+    pub fn actual_period(self, ticks: Ticks) -> Result<NanoSeconds, RangeError> {
+        let clock = u64::from(self.clock.0);
+        let ns = ticks
+            .cycles()
+            .checked_mul(1_000_000_000)
+            .and_then(|product| product.checked_add(clock / 2))
+            .ok_or(RangeError::TooLong)?
+            / clock;
+        if ns > u64::from(u32::max_value()) {
+            Err(RangeError::TooLong)
+        } else {
+            Ok(NanoSeconds(ns as u32))
+        }
+    }
+
+    /// The period `ticks` actually gives, to the nearest nanosecond, however
+    /// long it is
+    pub fn actual_duration(self, ticks: Ticks) -> Duration {
+        let clock = u64::from(self.clock.0);
+        let cycles = ticks.cycles();
+        // The remainder is below 2^32, so this fits in a u64
+        let nanos = ((cycles % clock) * 1_000_000_000 + clock / 2) / clock;
+        // `Duration::new` carries a rounded-up 10^9 ns into the seconds
+        Duration::new(cycles / clock, nanos as u32)
+    }
+
+    /// The frequency `ticks` actually gives, to the nearest hertz
+    pub fn actual_frequency(self, ticks: Ticks) -> Hertz {
+        let cycles = ticks.cycles().max(1);
+        Hertz(((u64::from(self.clock.0) + cycles / 2) / cycles) as u32)
+    }
+}
+
+/// Length of the pseudo-random period sequence in a `spread_table` (a 5-bit
+/// maximal LFSR)
+pub const SPREAD_STEPS: usize = 31;
+
+/// The (period, match) pairs a spread-spectrum PWM output hops between: the
+/// nominal `period` moved by up to `depth_permille` tenths of a percent
+/// either way, in pseudo-random order, with the match value rescaled so the
+/// `duty` cycle stays the same fraction of each period. Every pair must fit
+/// a timer that counts up to `max_period`, or this fails with the way the
+/// first one that doesn't fell out.
+pub fn spread_table(
+    period: u32,
+    duty: u32,
+    depth_permille: u16,
+    max_period: u32,
+) -> Result<[(u32, u32); SPREAD_STEPS], RangeError> {
+    let duty = duty.min(period);
+    let mut table = [(period, period - duty); SPREAD_STEPS];
+    let mut lfsr: u8 = 1;
+    for entry in table.iter_mut() {
+        // x^5 + x^3 + 1 visits 1..=31 once each before repeating
+        let bit = ((lfsr >> 4) ^ (lfsr >> 2)) & 1;
+        lfsr = ((lfsr << 1) | bit) & 0x1F;
+        // Centre on 16 to get a step of -15..=15
+        let step = i64::from(lfsr) - 16;
+        let deviation = i64::from(period) * i64::from(depth_permille) * step / (1000 * 15);
+        let hop = i64::from(period) + deviation;
+        if hop < 1 {
+            return Err(RangeError::TooShort);
+        }
+        if hop > i64::from(max_period) {
+            return Err(RangeError::TooLong);
+        }
+        let hop = hop as u32;
+        let hop_duty = (u64::from(duty) * u64::from(hop) / u64::from(period.max(1))) as u32;
+        *entry = (hop, hop - hop_duty);
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MHZ_120: Hertz = Hertz(120_000_000);
+    const MHZ_16: Hertz = Hertz(16_000_000);
+
+    fn ticks(load: u64, prescale: u8) -> Result<Ticks, RangeError> {
+        Ok(Ticks { load, prescale })
+    }
+
+    #[test]
+    fn one_hertz_at_120_mhz() {
+        let calc = TickCalc::for_clock(MHZ_120, Width::Bits32);
+        assert_eq!(calc.frequency_to_ticks(Hertz(1)), ticks(120_000_000, 0));
+        assert_eq!(
+            calc.actual_period(calc.frequency_to_ticks(Hertz(1)).unwrap()),
+            Ok(NanoSeconds(1_000_000_000))
+        );
+        // Too slow for a 16-bit half, even with the prescaler
+        let calc = TickCalc::for_clock(MHZ_120, Width::Split16);
+        assert_eq!(calc.frequency_to_ticks(Hertz(1)), Err(RangeError::TooLong));
+    }
+
+    #[test]
+    fn ten_mhz_at_16_mhz_rounds_to_the_nearest_tick() {
+        let calc = TickCalc::for_clock(MHZ_16, Width::Bits32);
+        // 1.6 cycles rounds up to 2, which is 8 MHz
+        let ten = calc.frequency_to_ticks(Hertz(10_000_000)).unwrap();
+        assert_eq!(
+            ten,
+            Ticks {
+                load: 2,
+                prescale: 0
+            }
+        );
+        assert_eq!(calc.actual_frequency(ten), Hertz(8_000_000));
+        assert_eq!(calc.actual_period(ten), Ok(NanoSeconds(125)));
+        // Faster than half the clock rounds to no cycles at all
+        assert_eq!(
+            calc.frequency_to_ticks(Hertz(40_000_000)),
+            Err(RangeError::TooShort)
+        );
+        assert_eq!(calc.frequency_to_ticks(Hertz(0)), Err(RangeError::TooLong));
+    }
+
+    #[test]
+    fn periods_round_to_the_nearest_tick() {
+        let calc = TickCalc::for_clock(MHZ_16, Width::Bits32);
+        // 0.496 and 0.512 cycles
+        assert_eq!(
+            calc.period_to_ticks(NanoSeconds(31)),
+            Err(RangeError::TooShort)
+        );
+        assert_eq!(calc.period_to_ticks(NanoSeconds(32)), ticks(1, 0));
+        assert_eq!(calc.period_to_ticks(NanoSeconds(1_000)), ticks(16, 0));
+    }
+
+    #[test]
+    fn split16_uses_the_smallest_prescaler() {
+        let calc = TickCalc::for_clock(MHZ_16, Width::Split16);
+        assert_eq!(calc.cycles_to_ticks(0x1_0000), ticks(0x1_0000, 0));
+        assert_eq!(calc.cycles_to_ticks(0x1_0001), ticks(0x8001, 1));
+        // 1 ms at 16 MHz
+        assert_eq!(calc.frequency_to_ticks(Hertz(1_000)), ticks(16_000, 0));
+        // 1 s needs a divider of 245
+        let second = calc.frequency_to_ticks(Hertz(1)).unwrap();
+        assert_eq!(
+            second,
+            Ticks {
+                load: 65_306,
+                prescale: 244
+            }
+        );
+        assert_eq!(calc.actual_frequency(second), Hertz(1));
+    }
+
+    #[test]
+    fn every_width_stops_at_its_limit() {
+        let limits = [
+            (Width::Bits16, 0x1_0000),
+            (Width::Split16, 0x100_0000),
+            (Width::Bits32, 0x1_0000_0000),
+        ];
+        for &(width, limit) in limits.iter() {
+            let calc = TickCalc::for_clock(MHZ_120, width);
+            let (shortest, longest) = calc.range();
+            assert_eq!(shortest.cycles(), 1);
+            assert_eq!(longest.cycles(), limit);
+            assert_eq!(calc.cycles_to_ticks(limit).map(Ticks::cycles), Ok(limit));
+            assert_eq!(calc.cycles_to_ticks(limit + 1), Err(RangeError::TooLong));
+            assert_eq!(calc.cycles_to_ticks(0), Err(RangeError::TooShort));
+        }
+        let calc = TickCalc::for_clock(MHZ_120, Width::Bits64);
+        assert_eq!(
+            calc.cycles_to_ticks(u64::max_value()),
+            ticks(u64::max_value(), 0)
+        );
+    }
+
+    #[test]
+    fn saturate_clamps_to_the_range() {
+        let calc = TickCalc::for_clock(MHZ_120, Width::Bits32);
+        let slow = calc.frequency_to_ticks(Hertz(1)).unwrap();
+        assert_eq!(calc.saturate(Ok(slow)), slow);
+        assert_eq!(calc.saturate(Err(RangeError::TooShort)).cycles(), 1);
+        assert_eq!(
+            calc.saturate(calc.frequency_to_ticks(Hertz(0))).cycles(),
+            0x1_0000_0000
+        );
+    }
+
+    #[test]
+    fn long_durations_reach_the_64_bit_width() {
+        let minute = Duration::from_secs(60);
+        let calc = TickCalc::for_clock(MHZ_120, Width::Bits64);
+        let ticks = calc.duration_to_ticks(minute).unwrap();
+        assert_eq!(ticks.load, 7_200_000_000);
+        assert_eq!(calc.actual_duration(ticks), minute);
+        // The same minute is too long for 32 bits, and for NanoSeconds
+        let calc = TickCalc::for_clock(MHZ_120, Width::Bits32);
+        assert_eq!(calc.duration_to_ticks(minute), Err(RangeError::TooLong));
+        assert_eq!(calc.actual_period(ticks), Err(RangeError::TooLong));
+        // Seconds times the clock overflowing is too long, not a panic
+        let calc = TickCalc::for_clock(MHZ_120, Width::Bits64);
+        assert_eq!(
+            calc.duration_to_ticks(Duration::from_secs(u64::max_value())),
+            Err(RangeError::TooLong)
+        );
+    }
+
+    #[test]
+    fn duration_fractions_round_to_the_nearest_tick() {
+        let calc = TickCalc::for_clock(MHZ_16, Width::Bits64);
+        let period = Duration::new(5, 31);
+        assert_eq!(calc.duration_to_ticks(period), ticks(80_000_000, 0));
+        let period = Duration::new(5, 32);
+        let ticks = calc.duration_to_ticks(period).unwrap();
+        assert_eq!(ticks.load, 80_000_001);
+        assert_eq!(calc.actual_duration(ticks), Duration::new(5, 63));
+    }
+
+    #[test]
+    fn spread_table_visits_every_step_once() {
+        let table = spread_table(10_000, 2_500, 150, 0xFFFF).unwrap();
+        let mut periods: [u32; SPREAD_STEPS] = [0; SPREAD_STEPS];
+        for (period, entry) in periods.iter_mut().zip(table.iter()) {
+            *period = entry.0;
+        }
+        periods.sort();
+        // Steps of 1% of the period from -15% to +15%, each once
+        for (i, period) in periods.iter().enumerate() {
+            assert_eq!(*period, 10_000 - 1_500 + 100 * i as u32);
+        }
+    }
+
+    #[test]
+    fn spread_table_keeps_the_duty_cycle() {
+        let table = spread_table(40_000, 10_000, 20, 0xFFFF).unwrap();
+        for &(period, compare) in table.iter() {
+            assert!(period >= 39_200 && period <= 40_800);
+            let duty = period - compare;
+            // A quarter, to within the rounding of one cycle
+            assert!((i64::from(duty) * 4 - i64::from(period)).abs() <= 4);
+        }
+    }
+
+    #[test]
+    fn spread_table_without_depth_is_flat() {
+        let table = spread_table(1_000, 300, 0, 0xFFFF).unwrap();
+        assert!(table.iter().all(|&entry| entry == (1_000, 700)));
+    }
+
+    #[test]
+    fn spread_table_rejects_periods_the_timer_cant_hold() {
+        // 65,000 + 2% is past the 16-bit limit
+        assert_eq!(
+            spread_table(65_000, 100, 20, 0xFFFF),
+            Err(RangeError::TooLong)
+        );
+        assert!(spread_table(65_000, 100, 20, 0xFF_FFFF).is_ok());
+        // -100% would stop the timer altogether
+        assert_eq!(
+            spread_table(1_000, 100, 1_000, 0xFFFF),
+            Err(RangeError::TooShort)
+        );
+    }
+}
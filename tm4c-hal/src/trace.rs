@@ -0,0 +1,75 @@
+//! Register access tracing
+//!
+//! With the chip crate's `trace-registers` feature enabled, the I2C driver
+//! and the clock setup in `sysctl` report each register access they make to
+//! a hook set with `set_hook`, which can log it over RTT, ITM or a spare
+//! UART. Without the feature the `trace_access!` calls expand to nothing.
+
+use crate::diag::{critical_section, CsSite};
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+
+/// Whether a register was read or written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// The driver read the register
+    Read,
+    /// The driver wrote (or modified) the register
+    Write,
+}
+
+/// One register access
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    /// Base address of the peripheral
+    pub base: u32,
+    /// Offset of the register from `base`
+    pub offset: u16,
+    /// What the register read back as straight after the access. For
+    /// command/status registers such as the I2C MCS this is the status, not
+    /// the command that was written.
+    pub value: u32,
+    /// Read or write
+    pub access: Access,
+}
+
+static HOOK: Mutex<Cell<Option<fn(&Record)>>> = Mutex::new(Cell::new(None));
+
+/// Sets the function that receives each `Record`. It is called with
+/// interrupts disabled, so it should be quick; its run time counts towards
+/// `diag::CsSite::Trace`.
+pub fn set_hook(hook: fn(&Record)) {
+    critical_section(CsSite::Trace, |cs| HOOK.borrow(cs).set(Some(hook)));
+}
+
+/// Stops tracing
+pub fn clear_hook() {
+    critical_section(CsSite::Trace, |cs| HOOK.borrow(cs).set(None));
+}
+
+/// Passes `record` to the hook, if one is set
+pub fn record(record: Record) {
+    critical_section(CsSite::Trace, |cs| {
+        if let Some(hook) = HOOK.borrow(cs).get() {
+            hook(&record);
+        }
+    });
+}
+
+#[macro_export]
+/// Reports an access to `$periph.$reg` to the trace hook, if the
+/// `trace-registers` feature is enabled in the crate expanding it
+macro_rules! trace_access {
+    ($periph:expr, $reg:ident, $access:ident) => {
+        #[cfg(feature = "trace-registers")]
+        {
+            let base = &*$periph as *const _ as u32;
+            $crate::trace::record($crate::trace::Record {
+                base,
+                offset: (&$periph.$reg as *const _ as u32 - base) as u16,
+                value: $periph.$reg.read().bits(),
+                access: $crate::trace::Access::$access,
+            });
+        }
+    };
+}
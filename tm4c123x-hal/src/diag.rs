@@ -0,0 +1,113 @@
+//! Diagnostic snapshots
+//!
+//! See `tm4c_hal::diag` for the report format.
+
+pub use tm4c_hal::diag::*;
+
+use crate::sysctl::Clocks;
+
+/// Crystal frequencies for the RCC.XTAL values 0x06 to 0x1A
+const XTAL_HZ: [u32; 21] = [
+    4_000_000, 4_096_000, 4_915_200, 5_000_000, 5_120_000, 6_000_000, 6_144_000, 7_372_800,
+    8_000_000, 8_192_000, 10_000_000, 12_000_000, 12_288_000, 13_560_000, 14_318_180, 16_000_000,
+    16_384_000, 18_000_000, 20_000_000, 24_000_000, 25_000_000,
+];
+
+/// Reads the clock configuration back out of RCC and RCC2
+pub fn clock_tree() -> ClockTree {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    let rcc = p.rcc.read().bits();
+    let rcc2 = p.rcc2.read().bits();
+    // RCC2.USERCC2 makes RCC2's fields override their RCC equivalents
+    let use_rcc2 = rcc2 & (1 << 31) != 0;
+
+    let oscsrc = if use_rcc2 {
+        (rcc2 >> 4) & 0x7
+    } else {
+        (rcc >> 4) & 0x3
+    };
+    let (source, osc_hz) = match oscsrc {
+        0 => {
+            let xtal = ((rcc >> 6) & 0x1F) as usize;
+            let hz = xtal
+                .checked_sub(6)
+                .and_then(|i| XTAL_HZ.get(i))
+                .cloned()
+                .unwrap_or(0);
+            (ClockSource::MainOscillator, hz)
+        }
+        1 => (ClockSource::PrecisionInternal, 16_000_000),
+        2 => (ClockSource::PrecisionInternalDiv4, 4_000_000),
+        3 => (ClockSource::LowFrequencyInternal, 30_000),
+        7 => (ClockSource::Hibernation, 32_768),
+        n => (ClockSource::Unknown(n as u8), 0),
+    };
+
+    // BYPASS / BYPASS2
+    let bypass = if use_rcc2 { rcc2 } else { rcc } & (1 << 11) != 0;
+    let sysdiv = if use_rcc2 {
+        ((rcc2 >> 23) & 0x3F) + 1
+    } else {
+        ((rcc >> 23) & 0xF) + 1
+    };
+    let (pll, divider, input_hz) = if !bypass {
+        if use_rcc2 && rcc2 & (1 << 30) != 0 {
+            // DIV400: SYSDIV2 and SYSDIV2LSB together divide 400 MHz
+            (true, ((rcc2 >> 22) & 0x7F) + 1, 400_000_000)
+        } else {
+            (true, sysdiv, 200_000_000)
+        }
+    } else if rcc & (1 << 22) != 0 {
+        // USESYSDIV
+        (false, sysdiv, osc_hz)
+    } else {
+        (false, 1, osc_hz)
+    };
+
+    ClockTree {
+        source,
+        osc_hz,
+        pll,
+        divider,
+        sysclk_hz: input_hz / divider,
+    }
+}
+
+/// Takes a snapshot of the chip's state. `clocks` is what the HAL was frozen
+/// with, to compare against what the registers now say; `drivers` are asked
+/// for their status when the report is printed.
+pub fn snapshot<'a>(clocks: &Clocks, drivers: &'a [&'a dyn Diagnose]) -> DiagReport<'a> {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    let gates = [
+        ("wd", p.rcgcwd.read().bits()),
+        ("timer", p.rcgctimer.read().bits()),
+        ("gpio", p.rcgcgpio.read().bits()),
+        ("dma", p.rcgcdma.read().bits()),
+        ("hib", p.rcgchib.read().bits()),
+        ("uart", p.rcgcuart.read().bits()),
+        ("ssi", p.rcgcssi.read().bits()),
+        ("i2c", p.rcgci2c.read().bits()),
+        ("usb", p.rcgcusb.read().bits()),
+        ("can", p.rcgccan.read().bits()),
+        ("adc", p.rcgcadc.read().bits()),
+        ("acmp", p.rcgcacmp.read().bits()),
+        ("pwm", p.rcgcpwm.read().bits()),
+        ("eeprom", p.rcgceeprom.read().bits()),
+        ("wtimer", p.rcgcwtimer.read().bits()),
+    ];
+    let mut run_clock_gates = [None; CLOCK_GATE_SLOTS];
+    for (slot, &(name, bits)) in run_clock_gates.iter_mut().zip(gates.iter()) {
+        if bits != 0 {
+            *slot = Some((name, bits));
+        }
+    }
+    DiagReport::new(
+        clock_tree(),
+        clocks.sysclk.0,
+        p.resc.read().bits(),
+        run_clock_gates,
+        drivers,
+    )
+}
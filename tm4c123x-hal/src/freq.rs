@@ -0,0 +1,307 @@
+//! Frequency measurement by counting edges over a gate time
+//!
+//! Timing single periods of a fast signal is at the mercy of a few clocks of
+//! jitter. Counting its edges over a fixed gate time instead gives a result
+//! good to one count, so the resolution is simply one over the gate time.
+//!
+//! `FrequencyCounter` takes over a 16/32-bit timer in split mode: timer A
+//! counts rising edges on the even CCP pin, and timer B ticks once a
+//! millisecond. Every tick, `poll` adds up the edges since the last one,
+//! and once the gate time has gone by it hands back a `Reading`. Call
+//! `poll` from the timer B interrupt (see `listen`) or in a loop; `measure`
+//! does the latter for you. The input must stay below a quarter of the
+//! system clock for the edges to be seen.
+//!
+//! The gate is timed with the core cycle counter rather than by counting
+//! ticks, since the tick flag can't say how many ticks went by while `poll`
+//! was held up. A late `poll` stretches the gate a little, and the reading
+//! divides by the time that really passed. `poll` must still run at least
+//! once every 2^23 edges (about 0.4 s at 20 MHz) for the edge count to
+//! stay right.
+
+use crate::{
+    pwm::{EvenPin, Timer},
+    sysctl::Clocks,
+    time::{Hertz, MonoTimer},
+};
+use tm4c_hal::timer::{TickCalc, Width};
+
+/// Shortest gate time, in milliseconds
+pub const MIN_GATE_MS: u32 = 1;
+
+/// Longest gate time, in milliseconds
+pub const MAX_GATE_MS: u32 = 10_000;
+
+/// The edge counter, with its prescaler, is 24 bits wide
+const COUNT_MASK: u32 = 0x00FF_FFFF;
+
+/// Once the count gets this far it is wound back to zero, well before the
+/// counter could reach its match value and stop
+const REWIND_AT: u32 = 0x0080_0000;
+
+/// Whether a `FrequencyCounter` stops after one reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Take one reading, then stop until `start` is called again
+    SingleShot,
+    /// Start the next gate as soon as one ends
+    Continuous,
+}
+
+/// The result of one gate time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reading {
+    /// Not a single edge arrived during the gate
+    NoSignal,
+    /// The input frequency, which is within `resolution` of the truth,
+    /// give or take the accuracy of the system clock
+    Frequency {
+        /// Edges counted divided by the time they were counted over
+        frequency: Hertz,
+        /// The ±1 count uncertainty, as a frequency
+        resolution: Hertz,
+    },
+}
+
+/// Counts the edges on a timer's even CCP pin over a gate time. See the
+/// module documentation.
+pub struct FrequencyCounter<T> {
+    timer: T,
+    mono: MonoTimer,
+    mode: Mode,
+    gate_ms: u32,
+    /// Cycle counter value at the last `poll`
+    last_cycle: u32,
+    /// Cycles since the gate opened
+    elapsed: u64,
+    last: u32,
+    edges: u32,
+    running: bool,
+}
+
+macro_rules! frequency_counter {
+    ($($TIMER:ident: ($INT:ident),)+) => {
+        $(
+            impl Timer<tm4c123x::$TIMER> {
+                /// Turns this timer into a `FrequencyCounter` measuring the
+                /// signal on `pin` over `gate_ms` milliseconds, which must be
+                /// between `MIN_GATE_MS` and `MAX_GATE_MS`, timed by `mono`.
+                /// The first gate starts straight away.
+                #[allow(unused_unsafe)]
+                pub fn into_frequency_counter<P: EvenPin<Self>>(
+                    self,
+                    _pin: P,
+                    gate_ms: u32,
+                    mode: Mode,
+                    clocks: &Clocks,
+                    mono: MonoTimer,
+                ) -> FrequencyCounter<tm4c123x::$TIMER> {
+                    assert!(
+                        gate_ms >= MIN_GATE_MS && gate_ms <= MAX_GATE_MS,
+                        "gate time out of range"
+                    );
+                    clocks.assert_current();
+                    assert_eq!(
+                        mono.frequency(), clocks.sysclk,
+                        "MonoTimer is from another clock setup"
+                    );
+                    let timer = self.timer;
+
+                    // Timer A: count rising edges upwards, with the prescaler
+                    // as the top eight bits of the count
+                    timer.tamr.write(|w| {
+                        w.tacdir().set_bit();
+                        w.tacmr().clear_bit();
+                        w.tamr().variant(tm4c123x::timer0::tamr::TAMR_A::CAP)
+                    });
+                    unsafe {
+                        timer.tailr.write(|w| w.bits(0xFFFF));
+                        timer.tamatchr.write(|w| w.bits(0xFFFF));
+                        timer.tapmr.write(|w| w.bits(0xFF));
+                        timer.tav.write(|w| w.bits(0));
+                        // Rising edges (TAEVENT = 0), then TAEN
+                        crate::bb::change_bit(&timer.ctl, 2, false);
+                        crate::bb::change_bit(&timer.ctl, 3, false);
+                        crate::bb::change_bit(&timer.ctl, 0, true);
+                    }
+
+                    // Timer B: a periodic 1 ms tick
+                    let tick = TickCalc::for_clock(clocks.sysclk, Width::Split16)
+                        .frequency_to_ticks(Hertz(1_000))
+                        .expect("system clock too fast for a 1 ms tick");
+                    timer.tbmr.write(|w| {
+                        w.tbmr().variant(tm4c123x::timer0::tbmr::TBMR_A::PERIOD)
+                    });
+                    unsafe {
+                        // The count includes zero
+                        timer.tbilr.write(|w| w.bits((tick.load - 1) as u32));
+                        timer.tbpr.write(|w| w.bits(u32::from(tick.prescale)));
+                    }
+
+                    let mut counter = FrequencyCounter {
+                        timer,
+                        mono,
+                        mode,
+                        gate_ms,
+                        last_cycle: 0,
+                        elapsed: 0,
+                        last: 0,
+                        edges: 0,
+                        running: false,
+                    };
+                    counter.start();
+                    counter
+                }
+            }
+
+            impl FrequencyCounter<tm4c123x::$TIMER> {
+                /// The NVIC interrupt timer B raises on each tick
+                pub const INTERRUPT: tm4c123x::Interrupt = tm4c123x::Interrupt::$INT;
+
+                /// Starts a new gate, throwing away any partial one
+                #[allow(unused_unsafe)]
+                pub fn start(&mut self) {
+                    let timer = &self.timer;
+                    unsafe {
+                        crate::bb::change_bit(&timer.ctl, 8, false);
+                        timer.tbv.write(|w| w.bits(timer.tbilr.read().bits()));
+                    }
+                    timer.icr.write(|w| w.tbtocint().set_bit());
+                    self.last = self.count();
+                    self.last_cycle = self.mono.now().ticks();
+                    self.edges = 0;
+                    self.elapsed = 0;
+                    self.running = true;
+                    unsafe {
+                        crate::bb::change_bit(&timer.ctl, 8, true);
+                    }
+                }
+
+                /// Stops taking readings, abandoning any partial gate. Edges
+                /// are still counted in hardware but ignored until `start`.
+                pub fn stop(&mut self) {
+                    unsafe {
+                        crate::bb::change_bit(&self.timer.ctl, 8, false);
+                    }
+                    self.running = false;
+                }
+
+                /// Changes the gate time and starts a new gate
+                pub fn set_gate_ms(&mut self, gate_ms: u32) {
+                    assert!(
+                        gate_ms >= MIN_GATE_MS && gate_ms <= MAX_GATE_MS,
+                        "gate time out of range"
+                    );
+                    self.gate_ms = gate_ms;
+                    self.start();
+                }
+
+                /// Deals with a pending tick, if there is one, and returns
+                /// the reading if that tick ended the gate
+                #[allow(unused_unsafe)]
+                pub fn poll(&mut self) -> Option<Reading> {
+                    if !self.running || self.timer.ris.read().tbtoris().bit_is_clear() {
+                        return None;
+                    }
+                    self.timer.icr.write(|w| w.tbtocint().set_bit());
+
+                    // The edge count and the time it was taken at, as close
+                    // together as they can be read
+                    let now = self.count();
+                    let cycle = self.mono.now().ticks();
+                    self.elapsed += u64::from(cycle.wrapping_sub(self.last_cycle));
+                    self.last_cycle = cycle;
+                    let since_last = now.wrapping_sub(self.last) & COUNT_MASK;
+                    self.edges = self.edges.wrapping_add(since_last);
+                    self.last = now;
+                    if now >= REWIND_AT {
+                        // Any edge landing between the read above and this
+                        // write is lost, which is one count in millions
+                        unsafe {
+                            self.timer.tav.write(|w| w.bits(0));
+                        }
+                        self.last = 0;
+                    }
+
+                    let gate = u64::from(self.gate_ms) * u64::from(self.mono.frequency().0) / 1_000;
+                    if self.elapsed < gate {
+                        return None;
+                    }
+                    let reading = reading(self.edges, self.elapsed, self.mono.frequency());
+                    match self.mode {
+                        Mode::Continuous => {
+                            self.edges = 0;
+                            self.elapsed = 0;
+                        }
+                        Mode::SingleShot => self.stop(),
+                    }
+                    Some(reading)
+                }
+
+                /// Waits out the current gate and returns its reading,
+                /// starting one first if the counter is stopped
+                pub fn measure(&mut self) -> Reading {
+                    if !self.running {
+                        self.start();
+                    }
+                    loop {
+                        if let Some(reading) = self.poll() {
+                            return reading;
+                        }
+                    }
+                }
+
+                /// Makes each tick raise `INTERRUPT`, so `poll` can run from
+                /// its handler
+                pub fn listen(&mut self) {
+                    self.timer.imr.modify(|_, w| w.tbtoim().set_bit());
+                }
+
+                /// Stops raising `INTERRUPT` on each tick
+                pub fn unlisten(&mut self) {
+                    self.timer.imr.modify(|_, w| w.tbtoim().clear_bit());
+                }
+
+                tm4c_hal::nvic_control!("the tick interrupt");
+
+                /// Stops both halves and releases the timer
+                pub fn free(self) -> tm4c123x::$TIMER {
+                    self.timer.imr.modify(|_, w| w.tbtoim().clear_bit());
+                    unsafe {
+                        crate::bb::change_bit(&self.timer.ctl, 0, false);
+                        crate::bb::change_bit(&self.timer.ctl, 8, false);
+                    }
+                    self.timer
+                }
+
+                fn count(&self) -> u32 {
+                    self.timer.tar.read().bits() & COUNT_MASK
+                }
+            }
+        )+
+    };
+}
+
+frequency_counter! {
+    TIMER0: (TIMER0B),
+    TIMER1: (TIMER1B),
+    TIMER2: (TIMER2B),
+    TIMER3: (TIMER3B),
+    TIMER4: (TIMER4B),
+    TIMER5: (TIMER5B),
+}
+
+/// The reading for `edges` counted over `cycles` of a `sysclk` clock
+fn reading(edges: u32, cycles: u64, sysclk: Hertz) -> Reading {
+    if edges == 0 {
+        return Reading::NoSignal;
+    }
+    let sysclk = u64::from(sysclk.0);
+    let frequency = (u64::from(edges) * sysclk / cycles) as u32;
+    // One count over the gate, rounded up
+    let resolution = ((sysclk + cycles - 1) / cycles) as u32;
+    Reading::Frequency {
+        frequency: Hertz(frequency),
+        resolution: Hertz(resolution),
+    }
+}
@@ -1,6 +1,20 @@
 //! A wrapper for the HIB (Hibernation) peripheral
 
-use crate::sysctl;
+use crate::{
+    sysctl::{self, ClockMeasurement, Clocks},
+    time::MonoTimer,
+};
+use core::ptr::{read_volatile, write_volatile};
+
+use tm4c_hal::poll::poll_until;
+
+pub use tm4c_hal::hib::{
+    trim_for_ppm, Hibernation, RtcStopped, TrimError, TrimOutOfRange, WriteTimeout, DATA_WORDS,
+    MEASURE_TICKS, MONOTONIC_WORDS, SLEW_DIVISOR, STEP_MS, TRIM_DEFAULT, WRITE_TIMEOUT_MS,
+};
+
+/// Offset of the first battery-backed HIBDATA word from the HIB base
+const HIBDATA_OFFSET: usize = 0x30;
 
 /// Which source to use for the HIB clock
 pub enum Source {
@@ -13,11 +27,23 @@ pub enum Source {
 /// A wrapper around the HIB (Hibernation) peripheral
 pub struct Hib {
     hib: tm4c123x::HIB,
+    /// Polls of WRC that make up `WRITE_TIMEOUT_MS` at the system clock
+    write_polls: u32,
 }
 
 impl Hib {
-    /// Initialize the HIB peripheral, using a clock from `source`
-    pub fn hib(hib: tm4c123x::HIB, source: Source, _pc: &sysctl::PowerControl) -> Self {
+    /// Initialize the HIB peripheral, using a clock from `source`. Waits up
+    /// to `WRITE_TIMEOUT_MS` for the 32.768 kHz clock to start, and fails
+    /// with `WriteTimeout` if it doesn't.
+    pub fn hib(
+        hib: tm4c123x::HIB,
+        source: Source,
+        _pc: &sysctl::PowerControl,
+        clocks: &Clocks,
+    ) -> Result<Self, WriteTimeout> {
+        clocks.assert_current();
+        let write_polls = tm4c_hal::hib::write_polls(clocks.sysclk);
+
         hib.ctl.write(|w| {
             match source {
                 Source::ExternalOscillator => w.oscbyp().set_bit(),
@@ -29,7 +55,7 @@ impl Hib {
             w
         });
 
-        while hib.ctl.read().wrc().bit_is_clear() {}
+        wait_for_write(&hib, write_polls)?;
         hib.ctl.write(|w| {
             match source {
                 Source::ExternalOscillator => w.oscbyp().set_bit(),
@@ -42,13 +68,15 @@ impl Hib {
 
             w
         });
-        while hib.ctl.read().wrc().bit_is_clear() {}
+        wait_for_write(&hib, write_polls)?;
 
-        Hib { hib }
+        Ok(Hib { hib, write_polls })
     }
 
     /// Get the current time, in units of (seconds, subseconds), where a
-    /// subsecond is 1/32768 seconds
+    /// subsecond is 1/32768 seconds. The seconds are read either side of the
+    /// sub-seconds and the read is retried if they differ, so the pair is
+    /// never torn by a rollover.
     pub fn get_time(&self) -> (u32, u16) {
         loop {
             let seconds = self.hib.rtcc.read().bits();
@@ -60,6 +88,57 @@ impl Hib {
         }
     }
 
+    /// Compensates for an RTC that runs `ppm` parts per million fast
+    /// (positive) or slow (negative), as reported by `measure_drift`. The
+    /// correction is applied once every 64 seconds, in steps of about 0.48
+    /// ppm. Zero removes any trim.
+    pub fn set_trim(&mut self, ppm: i32) -> Result<(), TrimError> {
+        let trim = trim_for_ppm(ppm)?;
+        self.wait_for_write()?;
+        self.hib.rtct.write(|w| unsafe { w.bits(u32::from(trim)) });
+        self.wait_for_write()?;
+        Ok(())
+    }
+
+    /// Times `seconds` RTC seconds with the core cycle counter and returns
+    /// how many ppm fast (positive) or slow (negative) the RTC runs relative
+    /// to the system clock, ready for `set_trim`; see
+    /// `tm4c_hal::hib::measure_drift`. The result is only as good as the
+    /// system clock, so run it from the PLL on a crystal. Measure with the
+    /// trim at zero and over a multiple of 64 seconds, since that is how
+    /// often the trim is applied.
+    pub fn measure_drift(&self, timer: MonoTimer, seconds: u32) -> Result<i32, RtcStopped> {
+        tm4c_hal::hib::measure_drift(self, || timer.now().ticks(), timer.frequency(), seconds)
+    }
+
+    /// Sets the RTC alarm for `seconds` and `subsec` 32768ths of a second
+    /// (less than 32768) and clears any earlier alarm. `alarm_pending`
+    /// reports when it has gone off.
+    pub fn set_alarm(&mut self, seconds: u32, subsec: u16) -> Result<(), WriteTimeout> {
+        assert!(subsec < 32_768);
+        self.wait_for_write()?;
+        self.hib.rtcm0.write(|w| unsafe { w.bits(seconds) });
+        self.wait_for_write()?;
+        self.hib
+            .rtcss
+            .write(|w| unsafe { w.bits(u32::from(subsec) << 16) });
+        self.wait_for_write()?;
+        self.clear_alarm()
+    }
+
+    /// True once the RTC has reached the time given to `set_alarm`
+    pub fn alarm_pending(&self) -> bool {
+        // RTCALT0
+        self.hib.ris.read().bits() & 1 != 0
+    }
+
+    /// Acknowledges the alarm
+    pub fn clear_alarm(&mut self) -> Result<(), WriteTimeout> {
+        self.wait_for_write()?;
+        self.hib.ic.write(|w| unsafe { w.bits(1) });
+        self.wait_for_write()
+    }
+
     /// Get the current time in milliseconds
     pub fn get_millis(&self) -> u64 {
         let (seconds, subsec) = self.get_time();
@@ -69,4 +148,83 @@ impl Hib {
         let millis: u64 = subsec * 1000 / 32_768;
         return seconds * 1000 + millis;
     }
+
+    /// Measures the system clock against the 32.768 kHz RTC, by counting
+    /// core cycles over 1/32 of a second; see
+    /// `tm4c_hal::hib::measure_sysclk`. Fails with `RtcStopped` rather than
+    /// hanging if the RTC isn't running.
+    pub fn measure_sysclk(
+        &self,
+        timer: MonoTimer,
+        clocks: &Clocks,
+    ) -> Result<ClockMeasurement, RtcStopped> {
+        tm4c_hal::hib::measure_sysclk(self, || timer.now().ticks(), clocks.sysclk)
+    }
+
+    /// Read battery-backed word `index` (less than `DATA_WORDS`). These words
+    /// survive hibernation and reset for as long as VBAT is present.
+    pub fn read_data(&self, index: usize) -> u32 {
+        assert!(index < DATA_WORDS);
+        unsafe { read_volatile(Self::data_ptr().add(index)) }
+    }
+
+    /// Write battery-backed word `index` (less than `DATA_WORDS`)
+    pub fn write_data(&mut self, index: usize, value: u32) -> Result<(), WriteTimeout> {
+        assert!(index < DATA_WORDS);
+        self.wait_for_write()?;
+        unsafe { write_volatile(Self::data_ptr().add(index), value) }
+        self.wait_for_write()
+    }
+
+    /// Releases the HIB peripheral
+    pub fn free(self) -> tm4c123x::HIB {
+        self.hib
+    }
+
+    fn wait_for_write(&self) -> Result<(), WriteTimeout> {
+        wait_for_write(&self.hib, self.write_polls)
+    }
+
+    fn data_ptr() -> *mut u32 {
+        (tm4c123x::HIB::ptr() as usize + HIBDATA_OFFSET) as *mut u32
+    }
+}
+
+impl Hibernation for Hib {
+    fn rtc_millis(&self) -> u64 {
+        self.get_millis()
+    }
+
+    fn rtc_seconds(&self) -> u32 {
+        self.hib.rtcc.read().bits()
+    }
+
+    fn rtc_subseconds(&self) -> u16 {
+        self.hib.rtcss.read().rtcssc().bits()
+    }
+
+    fn read_data(&self, index: usize) -> u32 {
+        Hib::read_data(self, index)
+    }
+
+    fn write_data(&mut self, index: usize, value: u32) -> Result<(), WriteTimeout> {
+        Hib::write_data(self, index, value)
+    }
 }
+
+/// Waits for WRC, so that the last write to a HIB register has landed
+/// and the next may go, polling `polls` times `WRITE_POLL_CYCLES` apart
+fn wait_for_write(hib: &tm4c123x::HIB, polls: u32) -> Result<(), WriteTimeout> {
+    poll_until(polls, || {
+        let done = hib.ctl.read().wrc().bit_is_set();
+        if !done {
+            cortex_m::asm::delay(tm4c_hal::hib::WRITE_POLL_CYCLES);
+        }
+        done
+    })
+    .map_err(|_| WriteTimeout)
+}
+
+/// Milliseconds that keep counting up across hibernation and reset; see
+/// `tm4c_hal::hib::MonotonicClock`
+pub type MonotonicClock = tm4c_hal::hib::MonotonicClock<Hib>;
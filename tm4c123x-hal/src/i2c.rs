@@ -7,11 +7,10 @@ use crate::{
     time::Hertz,
 };
 
-use cortex_m::asm::delay;
-use tm4c123x::{I2C0, I2C1, I2C2, I2C3};
+use tm4c123x::{Interrupt, I2C0, I2C1, I2C2, I2C3};
 
-pub use tm4c_hal::i2c::Error;
-pub use tm4c_hal::{i2c_busy_wait, i2c_hal, i2c_pins};
+pub use tm4c_hal::i2c::{Error, ReclockError, RegisterWidth, SclLowTimeout, SclPin, SdaPin};
+pub use tm4c_hal::{i2c_hal, i2c_pins};
 
 /// I2C peripheral operating in master mode
 pub struct I2c<I2C, PINS> {
@@ -21,13 +20,6 @@ pub struct I2c<I2C, PINS> {
     pub pins: PINS,
 }
 
-// FIXME these should be "closed" traits
-/// SCL pin -- DO NOT IMPLEMENT THIS TRAIT
-pub unsafe trait SclPin<I2C> {}
-
-/// SDA pin -- DO NOT IMPLEMENT THIS TRAIT
-pub unsafe trait SdaPin<I2C> {}
-
 i2c_pins!(I2C0, scl: [(gpiob::PB2, AF3)], sda: [(gpiob::PB3, AF3)],);
 i2c_pins!(I2C1, scl: [(gpioa::PA6, AF3)], sda: [(gpioa::PA7, AF3)],);
 i2c_pins!(I2C2, scl: [(gpioe::PE4, AF3)], sda: [(gpioe::PE5, AF3)],);
@@ -24,7 +24,13 @@
 #![no_std]
 
 pub use tm4c123x::{self, CorePeripherals, Peripherals};
-pub use tm4c_hal::{bb, delay, time};
+/// The peripheral access crate, under the name other HALs use for it
+pub use tm4c123x as pac;
+pub use tm4c_hal::{bb, cpu, debounce, delay, isr, parallel, post, pps, rt_budget, tick, time, trace};
+#[cfg(feature = "panic-safe-state")]
+pub use tm4c_hal::safe_state;
+#[cfg(feature = "soft-uart")]
+pub use tm4c_hal::soft_uart;
 
 // Enable use of interrupt macro
 #[cfg(feature = "rt")]
@@ -32,9 +38,12 @@ pub use crate::tm4c123x::interrupt;
 
 use embedded_hal as hal;
 
+pub mod diag;
+pub mod freq;
 pub mod gpio;
 pub mod hib;
 pub mod i2c;
+pub mod nmi;
 pub mod prelude;
 pub mod pwm;
 pub mod serial;
@@ -0,0 +1,34 @@
+//! Non-maskable interrupt causes
+//!
+//! The TM4C123 has no NMI cause register. An NMI comes from the NMI pin
+//! (or software), or from a watchdog whose second time-out was set to raise
+//! an NMI, so the watchdogs are checked and anything else is put down as
+//! external.
+
+pub use tm4c_hal::nmi::*;
+
+/// Reads which NMI sources have fired, without clearing them. Watchdog
+/// interrupts are left for the application to clear, as doing so reloads
+/// the watchdog.
+pub fn cause() -> NmiCause {
+    // This is safe as it's read only
+    let sysctl = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    let clocked = sysctl.rcgcwd.read().bits();
+    // An unclocked watchdog can't have fired, and reading it would fault
+    let watchdog0 = clocked & (1 << 0) != 0
+        && unsafe { &*tm4c123x::WATCHDOG0::ptr() }.ris.read().bits() & 1 != 0;
+    let watchdog1 = clocked & (1 << 1) != 0
+        && unsafe { &*tm4c123x::WATCHDOG1::ptr() }.ris.read().bits() & 1 != 0;
+    NmiCause {
+        external: !watchdog0 && !watchdog1,
+        watchdog0,
+        watchdog1,
+        ..NmiCause::default()
+    }
+}
+
+/// Reads the NMI cause and hands it to `dispatch`. Call this from the `NMI`
+/// exception handler.
+pub fn handle() {
+    dispatch(cause());
+}
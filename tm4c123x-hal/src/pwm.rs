@@ -1,10 +1,12 @@
 //! PWM abstractions
 
 use crate::gpio::{gpiob, gpioc, gpiof, AlternateFunction, PushPull, AF7};
+use crate::{sysctl::Clocks, time::Hertz};
+use tm4c_hal::timer::{spread_table, RangeError, TickCalc, Ticks, Width, SPREAD_STEPS};
 
 /// a timer
 pub struct Timer<T> {
-    timer: T,
+    pub(crate) timer: T,
 }
 
 /// Implemented for any pin that can be the Even CCP Pin (i.e. associated with timer A) of a timer
@@ -18,11 +20,119 @@ pub trait OddPin<T> {}
 /// PWM output from the even half (i.e. timer A) of a timer peripheral
 pub struct EvenPWM<T> {
     timer: T,
+    /// The `park_on_panic` registration, dropped with the output
+    #[cfg(feature = "panic-safe-state")]
+    hook: Option<crate::safe_state::HookId>,
 }
 
 /// PWM output from the odd half (i.e. timer B) of a timer peripheral
 pub struct OddPWM<T> {
     timer: T,
+    /// The `park_on_panic` registration, dropped with the output
+    #[cfg(feature = "panic-safe-state")]
+    hook: Option<crate::safe_state::HookId>,
+}
+
+/// Dither settings for a `SpreadSpectrum` output
+#[derive(Clone, Copy, Debug)]
+pub struct Modulation {
+    /// Peak deviation of the period from its nominal value, in tenths of a
+    /// percent (e.g. 20 for ±2%)
+    pub depth_permille: u16,
+    /// Number of PWM cycles each period is held before hopping to the next
+    pub cycles_per_step: u8,
+}
+
+/// The settings of one PWM output, as captured by `snapshot`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PwmState {
+    /// Interval load value (the period, in clock cycles)
+    pub period: u32,
+    /// Match value (the period minus the duty cycle)
+    pub compare: u32,
+    /// Prescaler, which extends the period
+    pub prescale: u32,
+    /// Prescaler match, which extends the compare value
+    pub prescale_match: u32,
+    /// Whether the output was running
+    pub enabled: bool,
+    /// Whether the output was inverted
+    pub inverted: bool,
+}
+
+/// Level `safe_state` parks an output at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SafeLevel {
+    /// Hold the pin low
+    Low,
+    /// Hold the pin high
+    High,
+}
+
+/// A PWM output whose period hops pseudo-randomly around its nominal value,
+/// with the compare value rescaled so the duty cycle stays put.
+///
+/// Every period/compare pair is worked out up front, so `on_interrupt` only
+/// has to copy the next pair into the timer. Call it from the timer's
+/// interrupt handler; the timer interrupt must be unmasked in the NVIC.
+pub struct SpreadSpectrum<PWM> {
+    pwm: PWM,
+    table: [(u32, u32); SPREAD_STEPS],
+    nominal: (u32, u32),
+    index: u8,
+    cycles_per_step: u8,
+    countdown: u8,
+    paused: bool,
+}
+
+/// A duty cycle of 100% for `FractionalDuty`
+pub const FULL_DUTY: u32 = 1 << 24;
+
+/// `FractionalDuty::set_brightness_8bit` levels: `(level / 255)^2.2` as a
+/// fraction of `FULL_DUTY`, so equal steps in level look like equal steps in
+/// brightness
+const GAMMA_2_2: [u32; 256] = [
+    0, 85, 391, 955, 1798, 2938, 4388, 6160, 8263, 10707, 13500, 16649, 20162, 24044, 28302, 32941,
+    37966, 43383, 49196, 55410, 62029, 69058, 76500, 84359, 92640, 101344, 110477, 120042, 130041,
+    140478, 151356, 162677, 174446, 186665, 199336, 212462, 226046, 240091, 254598, 269571, 285012,
+    300923, 317307, 334166, 351502, 369317, 387613, 406394, 425659, 445413, 465656, 486391, 507620,
+    529345, 551566, 574288, 597510, 621235, 645466, 670202, 695447, 721202, 747469, 774249, 801544,
+    829356, 857686, 886535, 915906, 945800, 976219, 1007163, 1038635, 1070636, 1103167, 1136230,
+    1169826, 1203957, 1238624, 1273829, 1309572, 1345856, 1382681, 1420049, 1457961, 1496419,
+    1535423, 1574976, 1615078, 1655730, 1696934, 1738692, 1781003, 1823870, 1867294, 1911276,
+    1955817, 2000918, 2046581, 2092806, 2139595, 2186948, 2234868, 2283355, 2332410, 2382034,
+    2432229, 2482995, 2534333, 2586246, 2638733, 2691795, 2745435, 2799652, 2854448, 2909824,
+    2965781, 3022320, 3079441, 3137147, 3195437, 3254313, 3313776, 3373826, 3434466, 3495695,
+    3557515, 3619926, 3682930, 3746527, 3810719, 3875505, 3940889, 4006869, 4073447, 4140624,
+    4208401, 4276778, 4345758, 4415339, 4485524, 4556313, 4627707, 4699707, 4772314, 4845528,
+    4919351, 4993783, 5068825, 5144478, 5220742, 5297620, 5375110, 5453215, 5531935, 5611271,
+    5691223, 5771793, 5852981, 5934787, 6017214, 6100261, 6183930, 6268220, 6353133, 6438670,
+    6524831, 6611618, 6699030, 6787069, 6875735, 6965029, 7054952, 7145505, 7236688, 7328502,
+    7420948, 7514026, 7607737, 7702083, 7797062, 7892678, 7988929, 8085817, 8183342, 8281506,
+    8380308, 8479750, 8579832, 8680555, 8781919, 8883925, 8986575, 9089868, 9193805, 9298388,
+    9403615, 9509489, 9616010, 9723179, 9830995, 9939461, 10048576, 10158341, 10268757, 10379824,
+    10491543, 10603916, 10716941, 10830620, 10944954, 11059943, 11175588, 11291890, 11408848,
+    11526464, 11644739, 11763672, 11883265, 12003518, 12124432, 12246007, 12368244, 12491144,
+    12614706, 12738933, 12863823, 12989379, 13115600, 13242487, 13370041, 13498262, 13627151,
+    13756708, 13886934, 14017830, 14149396, 14281633, 14414541, 14548120, 14682372, 14817297,
+    14952896, 15089168, 15226115, 15363738, 15502036, 15641010, 15780661, 15920990, 16061996,
+    16203681, 16346045, 16489088, 16632812, 16777216,
+];
+
+/// A PWM output with 24 bits of duty-cycle resolution, for dimming LEDs
+/// smoothly at the bottom of their range.
+///
+/// Each period the timer interrupt picks one of the two hardware duty values
+/// either side of the requested one, error-diffusion style, so that the
+/// average comes out right. Call `on_interrupt` from the timer's interrupt
+/// handler; the timer interrupt must be unmasked in the NVIC. That is one
+/// short interrupt per PWM period: at 25 kHz, around 1% of an 80 MHz CPU.
+pub struct FractionalDuty<PWM> {
+    pwm: PWM,
+    period: u32,
+    base: u32,
+    fraction: u32,
+    error: u32,
 }
 
 macro_rules! into_one_half {
@@ -38,15 +148,19 @@ macro_rules! into_one_half {
                 w.$ams().set_bit();
                 w.$mr().variant(<$mr_module>::PERIOD)
             });
-            $kind { timer: self.timer }
+            $kind {
+                timer: self.timer,
+                #[cfg(feature = "panic-safe-state")]
+                hook: None,
+            }
         }
     };
 }
 
 macro_rules! impl_for_timer {
-    ($Name:ident, $timer:path, $domain:expr, even: [$($($even_pins:ident)::+),+],
-            odd: [$($($odd_pins:ident)::+),+]) => {
-        impl_pwm!($timer);
+    ($Name:ident, $timer:path, $domain:expr, interrupts: ($inta:ident, $intb:ident),
+            even: [$($($even_pins:ident)::+),+], odd: [$($($odd_pins:ident)::+),+]) => {
+        impl_pwm!($timer, $inta, $intb);
 
         $(
             impl EvenPin<Timer<$timer>> for $($even_pins)::+<AlternateFunction<AF7, PushPull>> {}
@@ -65,7 +179,8 @@ macro_rules! impl_for_timer {
                     crate::sysctl::RunMode::Run,
                     crate::sysctl::PowerState::On,
                 );
-                crate::sysctl::reset(power_control, $domain);
+                crate::sysctl::reset(power_control, $domain)
+                    .expect("timer didn't come out of reset");
                 timer
                     .cfg
                     .modify(|_r, w| w.cfg().variant(tm4c123x::timer0::cfg::CFG_A::_16_BIT));
@@ -116,7 +231,300 @@ macro_rules! impl_for_timer {
 }
 
 macro_rules! pwm_half {
-    ($StructName:ident, $timer:path, $en_bit:expr, $ilr:ident, $matchr:ident) => {
+    ($StructName:ident, $timer:path, $int:ident, $en_bit:expr, $ilr:ident, $matchr:ident,
+            $mr:ident, $ild:ident, $pwmie:ident, $event_bit:expr, $pr:ident, $pmr:ident) => {
+        impl $StructName<$timer> {
+            /// The NVIC interrupt this half of the timer raises. Each half
+            /// has its own vector, so the even and odd outputs of one timer
+            /// need different handlers.
+            pub const INTERRUPT: tm4c123x::Interrupt = tm4c123x::Interrupt::$int;
+
+            tm4c_hal::nvic_control!("this half's interrupt");
+        }
+
+        impl $StructName<$timer> {
+            /// Captures the period, compare, prescaler, enable and invert
+            /// settings of this output
+            pub fn snapshot(&self) -> PwmState {
+                let timer = &self.timer;
+                PwmState {
+                    period: timer.$ilr.read().bits(),
+                    compare: timer.$matchr.read().bits(),
+                    prescale: timer.$pr.read().bits(),
+                    prescale_match: timer.$pmr.read().bits(),
+                    enabled: crate::bb::read_bit(&timer.ctl, $en_bit),
+                    // TnPWML sits six bits above TnEN
+                    inverted: crate::bb::read_bit(&timer.ctl, $en_bit + 6),
+                }
+            }
+
+            /// Sets the period to the nearest `frequency` gets to at the
+            /// system clock in `clocks`, and returns the ticks that came to.
+            /// The interval load register holds one less than that, as the
+            /// count includes zero. The duty cycle is reset to 0%, so set it
+            /// again afterwards. The output is left unchanged if `frequency`
+            /// is out of range.
+            pub fn set_frequency(
+                &mut self,
+                frequency: Hertz,
+                clocks: &Clocks,
+            ) -> Result<Ticks, RangeError> {
+                clocks.assert_current();
+                let calc = TickCalc::for_clock(clocks.sysclk, Width::Bits16);
+                let ticks = calc.frequency_to_ticks(frequency)?;
+                let load = (ticks.load - 1) as u32;
+                let timer = &self.timer;
+                unsafe {
+                    timer.$pr.write(|w| w.bits(0));
+                    timer.$ilr.write(|w| w.bits(load));
+                    // Matching at the load value keeps the output deasserted.
+                    // Clear any prescale match left by `restore` or `park`,
+                    // or the match would sit above the new load.
+                    timer.$pmr.write(|w| w.bits(0));
+                    timer.$matchr.write(|w| w.bits(load));
+                }
+                Ok(ticks)
+            }
+
+            /// Puts back settings captured by `snapshot`. The output is
+            /// stopped while the registers are written, so it restarts at
+            /// the beginning of a period.
+            pub fn restore(&mut self, state: PwmState) {
+                let timer = &self.timer;
+                unsafe {
+                    crate::bb::change_bit(&timer.ctl, $en_bit, false);
+                    timer.$pr.write(|w| w.bits(state.prescale));
+                    timer.$pmr.write(|w| w.bits(state.prescale_match));
+                    timer.$ilr.write(|w| w.bits(state.period));
+                    timer.$matchr.write(|w| w.bits(state.compare));
+                    crate::bb::change_bit(&timer.ctl, $en_bit + 6, state.inverted);
+                    crate::bb::change_bit(&timer.ctl, $en_bit, state.enabled);
+                }
+            }
+
+            /// Parks the output at `level` by pinning the duty cycle at 0% or
+            /// 100%, taking the invert setting into account. Take a
+            /// `snapshot` first to be able to `restore` the output later.
+            pub fn safe_state(&mut self, level: SafeLevel) {
+                Self::park(&self.timer, level);
+            }
+
+            /// Has `safe_state::panic_prelude` park this output at `level`,
+            /// replacing any earlier `park_on_panic` for it. The output
+            /// keeps the registration and gives it up when it is dropped
+            /// or `cancel_park_on_panic` is called. False means the hook
+            /// table is full.
+            #[cfg(feature = "panic-safe-state")]
+            pub fn park_on_panic(&mut self, level: SafeLevel) -> bool {
+                fn hook(level: usize) {
+                    let timer = unsafe { &*<$timer>::ptr() };
+                    let level = if level == SafeLevel::High as usize {
+                        SafeLevel::High
+                    } else {
+                        SafeLevel::Low
+                    };
+                    $StructName::<$timer>::park(timer, level);
+                }
+                self.cancel_park_on_panic();
+                self.hook = crate::safe_state::register(hook, level as usize);
+                self.hook.is_some()
+            }
+
+            /// Stops `safe_state::panic_prelude` parking this output, for
+            /// when it is handed over to code that manages its own safety
+            #[cfg(feature = "panic-safe-state")]
+            pub fn cancel_park_on_panic(&mut self) {
+                if let Some(id) = self.hook.take() {
+                    crate::safe_state::unregister(id);
+                }
+            }
+
+            fn park(timer: &tm4c123x::timer0::RegisterBlock, level: SafeLevel) {
+                let inverted = crate::bb::read_bit(&timer.ctl, $en_bit + 6);
+                // Matching at the load value keeps the output deasserted
+                let (prescale_match, compare) = if (level == SafeLevel::High) != inverted {
+                    (0, 0)
+                } else {
+                    (timer.$pr.read().bits(), timer.$ilr.read().bits())
+                };
+                unsafe {
+                    timer.$pmr.write(|w| w.bits(prescale_match));
+                    timer.$matchr.write(|w| w.bits(compare));
+                    crate::bb::change_bit(&timer.ctl, $en_bit, true);
+                }
+            }
+        }
+
+        /// A dropped output no longer gets parked on panic; another driver
+        /// may have the timer by then
+        #[cfg(feature = "panic-safe-state")]
+        impl Drop for $StructName<$timer> {
+            fn drop(&mut self) {
+                self.cancel_park_on_panic();
+            }
+        }
+
+        impl $StructName<$timer> {
+            /// Starts dithering the period of this output. The current period
+            /// and duty cycle are taken as the nominal values. Every period
+            /// must fit the 16-bit interval load register, so a depth that
+            /// would take it past 0xFFFF (or down to zero) is refused, and
+            /// the output handed back unchanged.
+            pub fn into_spread_spectrum(
+                self,
+                modulation: Modulation,
+            ) -> Result<SpreadSpectrum<Self>, (Self, RangeError)> {
+                use embedded_hal::Pwm;
+                let period = self.get_period();
+                let duty = self.get_duty(());
+                let table = match spread_table(period, duty, modulation.depth_permille, 0xFFFF) {
+                    Ok(table) => table,
+                    Err(e) => return Err((self, e)),
+                };
+                // Load new period/match values together at the next timeout
+                self.timer
+                    .$mr
+                    .modify(|_, w| w.$ild().set_bit().$pwmie().set_bit());
+                unsafe { crate::bb::change_bit(&self.timer.imr, $event_bit, true) }
+                Ok(SpreadSpectrum {
+                    pwm: self,
+                    table,
+                    nominal: (period, period - duty),
+                    index: 0,
+                    cycles_per_step: modulation.cycles_per_step.max(1),
+                    countdown: 1,
+                    paused: false,
+                })
+            }
+        }
+
+        impl $StructName<$timer> {
+            /// Switches to 24-bit duty control by dithering. The current duty
+            /// cycle carries over.
+            pub fn into_fractional_duty(self) -> FractionalDuty<Self> {
+                use embedded_hal::Pwm;
+                let period = self.get_period();
+                let duty = self.get_duty(());
+                // Load each new match value at the next timeout
+                self.timer
+                    .$mr
+                    .modify(|_, w| w.$ild().set_bit().$pwmie().set_bit());
+                unsafe { crate::bb::change_bit(&self.timer.imr, $event_bit, true) }
+                let mut fractional = FractionalDuty {
+                    pwm: self,
+                    period,
+                    base: 0,
+                    fraction: 0,
+                    error: 0,
+                };
+                let duty = u64::from(duty) * u64::from(FULL_DUTY) / u64::from(period.max(1));
+                fractional.set_duty_fraction(duty as u32);
+                fractional
+            }
+        }
+
+        impl FractionalDuty<$StructName<$timer>> {
+            /// Sets the duty cycle as a fraction of `FULL_DUTY`. Larger values
+            /// are treated as `FULL_DUTY`.
+            pub fn set_duty_fraction(&mut self, duty: u32) {
+                let cycles = u64::from(duty.min(FULL_DUTY)) * u64::from(self.period);
+                self.base = (cycles >> 24) as u32;
+                self.fraction = (cycles as u32) & (FULL_DUTY - 1);
+            }
+
+            /// Sets the brightness of an LED on this output, with gamma
+            /// correction so that the 256 levels look evenly spaced
+            pub fn set_brightness_8bit(&mut self, level: u8) {
+                self.set_duty_fraction(GAMMA_2_2[usize::from(level)]);
+            }
+
+            /// Acknowledges the timer event and queues the duty value for
+            /// the next period. Call this from the timer interrupt handler.
+            pub fn on_interrupt(&mut self) {
+                let timer = &self.pwm.timer;
+                timer.icr.write(|w| unsafe { w.bits(1 << $event_bit) });
+                self.error += self.fraction;
+                let duty = if self.error >= FULL_DUTY {
+                    self.error -= FULL_DUTY;
+                    self.base + 1
+                } else {
+                    self.base
+                };
+                timer
+                    .$matchr
+                    .write(|w| unsafe { w.bits(self.period - duty) });
+            }
+
+            /// Stops dithering and returns the plain PWM output, left at the
+            /// nearest hardware duty value
+            pub fn free(self) -> $StructName<$timer> {
+                let timer = &self.pwm.timer;
+                unsafe { crate::bb::change_bit(&timer.imr, $event_bit, false) }
+                timer
+                    .$mr
+                    .modify(|_, w| w.$ild().clear_bit().$pwmie().clear_bit());
+                timer.icr.write(|w| unsafe { w.bits(1 << $event_bit) });
+                let duty = self.base + u32::from(self.fraction >= FULL_DUTY / 2);
+                timer
+                    .$matchr
+                    .write(|w| unsafe { w.bits(self.period - duty) });
+                self.pwm
+            }
+        }
+
+        impl SpreadSpectrum<$StructName<$timer>> {
+            /// Acknowledges the timer event and queues the next period. Call
+            /// this from the timer interrupt handler.
+            pub fn on_interrupt(&mut self) {
+                let timer = &self.pwm.timer;
+                timer.icr.write(|w| unsafe { w.bits(1 << $event_bit) });
+                if self.paused {
+                    return;
+                }
+                self.countdown -= 1;
+                if self.countdown != 0 {
+                    return;
+                }
+                self.countdown = self.cycles_per_step;
+                let (period, thresh) = self.table[usize::from(self.index)];
+                self.index = if usize::from(self.index) + 1 == SPREAD_STEPS {
+                    0
+                } else {
+                    self.index + 1
+                };
+                timer.$ilr.write(|w| unsafe { w.bits(period) });
+                timer.$matchr.write(|w| unsafe { w.bits(thresh) });
+            }
+
+            /// Holds the output at its nominal period, e.g. while taking
+            /// measurements. Interrupts keep arriving but change nothing.
+            pub fn pause(&mut self) {
+                self.paused = true;
+                let timer = &self.pwm.timer;
+                timer.$ilr.write(|w| unsafe { w.bits(self.nominal.0) });
+                timer.$matchr.write(|w| unsafe { w.bits(self.nominal.1) });
+            }
+
+            /// Starts dithering again after `pause`
+            pub fn resume(&mut self) {
+                self.countdown = 1;
+                self.paused = false;
+            }
+
+            /// Stops dithering and returns the plain PWM output, left at its
+            /// nominal period and duty cycle
+            pub fn free(mut self) -> $StructName<$timer> {
+                self.pause();
+                let timer = &self.pwm.timer;
+                unsafe { crate::bb::change_bit(&timer.imr, $event_bit, false) }
+                timer
+                    .$mr
+                    .modify(|_, w| w.$ild().clear_bit().$pwmie().clear_bit());
+                timer.icr.write(|w| unsafe { w.bits(1 << $event_bit) });
+                self.pwm
+            }
+        }
+
         /// One half of a PWM timer
         impl embedded_hal::Pwm for $StructName<$timer> {
             type Channel = ();
@@ -159,9 +567,9 @@ macro_rules! pwm_half {
 }
 
 macro_rules! impl_pwm {
-    ($timer:path) => {
-        pwm_half!(EvenPWM, $timer, 0, tailr, tamatchr);
-        pwm_half!(OddPWM, $timer, 8, tbilr, tbmatchr);
+    ($timer:path, $inta:ident, $intb:ident) => {
+        pwm_half!(EvenPWM, $timer, $inta, 0, tailr, tamatchr, tamr, taild, tapwmie, 2, tapr, tapmr);
+        pwm_half!(OddPWM, $timer, $intb, 8, tbilr, tbmatchr, tbmr, tbild, tbpwmie, 10, tbpr, tbpmr);
     };
 }
 
@@ -169,6 +577,7 @@ impl_for_timer!(
     timer0,
     tm4c123x::TIMER0,
     crate::sysctl::Domain::Timer0,
+    interrupts: (TIMER0A, TIMER0B),
     even: [gpiob::PB6, gpiof::PF0],
     odd: [gpiob::PB7, gpiof::PF1]
 );
@@ -177,6 +586,7 @@ impl_for_timer!(
     timer1,
     tm4c123x::TIMER1,
     crate::sysctl::Domain::Timer1,
+    interrupts: (TIMER1A, TIMER1B),
     even: [gpiof::PF2, gpiob::PB4],
     odd: [gpiof::PF3, gpiob::PB5]
 );
@@ -185,6 +595,7 @@ impl_for_timer!(
     timer2,
     tm4c123x::TIMER2,
     crate::sysctl::Domain::Timer2,
+    interrupts: (TIMER2A, TIMER2B),
     even: [gpiof::PF4, gpiob::PB0],
     odd: [gpiob::PB1]
 );
@@ -193,6 +604,7 @@ impl_for_timer!(
     timer3,
     tm4c123x::TIMER3,
     crate::sysctl::Domain::Timer3,
+    interrupts: (TIMER3A, TIMER3B),
     even: [gpiob::PB2],
     odd: [gpiob::PB3]
 );
@@ -201,6 +613,7 @@ impl_for_timer!(
     timer4,
     tm4c123x::TIMER4,
     crate::sysctl::Domain::Timer4,
+    interrupts: (TIMER4A, TIMER4B),
     even: [gpioc::PC0],
     odd: [gpioc::PC1]
 );
@@ -209,6 +622,7 @@ impl_for_timer!(
     timer5,
     tm4c123x::TIMER5,
     crate::sysctl::Domain::Timer5,
+    interrupts: (TIMER5A, TIMER5B),
     even: [gpioc::PC2],
     odd: [gpioc::PC3]
 );
@@ -16,6 +16,7 @@ use crate::{
 };
 use core::{fmt, marker::PhantomData};
 use nb::{self, block};
+use tm4c123x::Interrupt;
 use void::Void;
 
 /// Serial abstraction
@@ -26,6 +27,7 @@ pub struct Serial<UART, TX, RX, RTS, CTS> {
     rts_pin: RTS,
     cts_pin: CTS,
     nl_mode: NewlineMode,
+    stats: Stats,
 }
 
 /// Serial receiver
@@ -33,6 +35,7 @@ pub struct Rx<UART, RX, CTS> {
     _uart: PhantomData<UART>,
     pin: RX,
     flow_pin: CTS,
+    stats: Stats,
 }
 
 /// Serial transmitter
@@ -41,6 +44,7 @@ pub struct Tx<UART, TX, RTS> {
     pin: TX,
     flow_pin: RTS,
     nl_mode: NewlineMode,
+    stats: Stats,
 }
 
 uart_pin_macro!(UART0,
@@ -28,6 +28,7 @@ use crate::{
     time::{Hertz, U32Ext},
 };
 use cortex_m::asm::nop;
+use tm4c_hal::poll::poll_until;
 
 /// Constrained SYSCTL peripheral.
 pub struct Sysctl {
@@ -51,8 +52,16 @@ pub struct ClockSetup {
     _0: (),
 }
 
+/// A complete clock configuration, as plain data, so that a table of them
+/// can live in a `static` and be picked from at run time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockConfig {
+    /// The system oscillator configuration
+    pub oscillator: Oscillator,
+}
+
 /// Selects the system oscillator source
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Oscillator {
     /// Use the main oscillator (with the given crystal), into the PLL or a clock divider
     Main(CrystalFrequency, SystemClock),
@@ -66,7 +75,7 @@ pub enum Oscillator {
 }
 
 /// Selects the source for the system clock
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SystemClock {
     /// Clock the system direct from the system oscillator
     UseOscillator(Divider),
@@ -75,140 +84,95 @@ pub enum SystemClock {
     UsePll(PllOutputFrequency),
 }
 
-/// Selects which crystal is fitted to the XOSC pins.
-#[derive(Clone, Copy)]
-pub enum CrystalFrequency {
-    /// 4 MHz
-    _4mhz,
-    /// 4.096 MHz
-    _4_09mhz,
-    /// 4.9152 MHz
-    _4_91mhz,
-    /// 5 MHz
-    _5mhz,
-    /// 5.12 MHz
-    _5_12mhz,
-    /// 6 MHz
-    _6mhz,
-    /// 6.144 MHz
-    _6_14mhz,
-    /// 7.3728 MHz
-    _7_37mhz,
-    /// 8 MHz
-    _8mhz,
-    /// 8.192 MHz
-    _8_19mhz,
-    /// 10 MHz
-    _10mhz,
-    /// 12 MHz
-    _12mhz,
-    /// 12.288 MHz
-    _12_2mhz,
-    /// 13.56 MHz
-    _13_5mhz,
-    /// 14.31818 MHz
-    _14_3mhz,
-    /// 16 MHz
-    _16mhz,
-    /// 16.384 MHz
-    _16_3mhz,
-    /// 18.0 MHz (USB)
-    _18mhz,
-    /// 20.0 MHz (USB)
-    _20mhz,
-    /// 24.0 MHz (USB)
-    _24mhz,
-    /// 25.0 MHz (USB)
-    _25mhz,
-}
-
-impl Into<Hertz> for CrystalFrequency {
-    fn into(self) -> Hertz {
-        Hertz(match self {
-            CrystalFrequency::_4mhz => 4_000_000,
-            CrystalFrequency::_4_09mhz => 4_090_000,
-            CrystalFrequency::_4_91mhz => 4_910_000,
-            CrystalFrequency::_5mhz => 5_000_000,
-            CrystalFrequency::_5_12mhz => 5_120_000,
-            CrystalFrequency::_6mhz => 6_000_000,
-            CrystalFrequency::_6_14mhz => 6_140_000,
-            CrystalFrequency::_7_37mhz => 7_370_000,
-            CrystalFrequency::_8mhz => 8_000_000,
-            CrystalFrequency::_8_19mhz => 8_190_000,
-            CrystalFrequency::_10mhz => 10_000_000,
-            CrystalFrequency::_12mhz => 12_000_000,
-            CrystalFrequency::_12_2mhz => 12_200_000,
-            CrystalFrequency::_13_5mhz => 13_500_000,
-            CrystalFrequency::_14_3mhz => 14_300_000,
-            CrystalFrequency::_16mhz => 16_000_000,
-            CrystalFrequency::_16_3mhz => 16_300_000,
-            CrystalFrequency::_18mhz => 18_000_000,
-            CrystalFrequency::_20mhz => 20_000_000,
-            CrystalFrequency::_24mhz => 24_000_000,
-            CrystalFrequency::_25mhz => 25_000_000,
-        })
+tm4c_hal::frequency_enum! {
+    /// Selects which crystal is fitted to the XOSC pins.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum CrystalFrequency {
+        /// 4 MHz
+        _4mhz => 4_000_000,
+        /// 4.096 MHz
+        _4_09mhz => 4_090_000,
+        /// 4.9152 MHz
+        _4_91mhz => 4_910_000,
+        /// 5 MHz
+        _5mhz => 5_000_000,
+        /// 5.12 MHz
+        _5_12mhz => 5_120_000,
+        /// 6 MHz
+        _6mhz => 6_000_000,
+        /// 6.144 MHz
+        _6_14mhz => 6_140_000,
+        /// 7.3728 MHz
+        _7_37mhz => 7_370_000,
+        /// 8 MHz
+        _8mhz => 8_000_000,
+        /// 8.192 MHz
+        _8_19mhz => 8_190_000,
+        /// 10 MHz
+        _10mhz => 10_000_000,
+        /// 12 MHz
+        _12mhz => 12_000_000,
+        /// 12.288 MHz
+        _12_2mhz => 12_200_000,
+        /// 13.56 MHz
+        _13_5mhz => 13_500_000,
+        /// 14.31818 MHz
+        _14_3mhz => 14_300_000,
+        /// 16 MHz
+        _16mhz => 16_000_000,
+        /// 16.384 MHz
+        _16_3mhz => 16_300_000,
+        /// 18.0 MHz (USB)
+        _18mhz => 18_000_000,
+        /// 20.0 MHz (USB)
+        _20mhz => 20_000_000,
+        /// 24.0 MHz (USB)
+        _24mhz => 24_000_000,
+        /// 25.0 MHz (USB)
+        _25mhz => 25_000_000,
     }
 }
 
-/// Selects what to divide the PLL's 400MHz down to.
-#[derive(Clone, Copy)]
-pub enum PllOutputFrequency {
-    /// 80.00 MHz
-    _80_00mhz = 0,
-    /// 66.67 MHz
-    _66_67mhz = 2,
-    /// 50 MHz
-    _50_00mhz = 3,
-    /// 40 MHz
-    _40_00mhz = 4,
-    /// 33.33 MHz
-    _33_33mhz = 5,
-    /// 28.57 MHz
-    _28_57mhz = 6,
-    /// 25 MHz
-    _25mhz = 7,
-    /// 22.22 MHz
-    _22_22mhz = 8,
-    /// 20 MHz
-    _20mhz = 9,
-    /// 18.18 MHz
-    _18_18mhz = 10,
-    /// 16.67 MHz
-    _16_67mhz = 11,
-    /// 15.38 MHz
-    _15_38mhz = 12,
-    /// 14.29 MHz
-    _14_29mhz = 13,
-    /// 13.33 MHz
-    _13_33mhz = 14,
-    /// 12.5 MHz
-    _12_5mhz = 15,
-}
-
-impl Into<Hertz> for PllOutputFrequency {
-    fn into(self) -> Hertz {
-        Hertz(match self {
-            PllOutputFrequency::_80_00mhz => 80_000_000,
-            PllOutputFrequency::_66_67mhz => 66_670_000,
-            PllOutputFrequency::_50_00mhz => 50_000_000,
-            PllOutputFrequency::_40_00mhz => 40_000_000,
-            PllOutputFrequency::_33_33mhz => 33_330_000,
-            PllOutputFrequency::_28_57mhz => 28_570_000,
-            PllOutputFrequency::_25mhz => 25_000_000,
-            PllOutputFrequency::_22_22mhz => 22_220_000,
-            PllOutputFrequency::_20mhz => 20_000_000,
-            PllOutputFrequency::_18_18mhz => 18_180_000,
-            PllOutputFrequency::_16_67mhz => 16_670_000,
-            PllOutputFrequency::_15_38mhz => 15_380_000,
-            PllOutputFrequency::_14_29mhz => 14_290_000,
-            PllOutputFrequency::_13_33mhz => 13_330_000,
-            PllOutputFrequency::_12_5mhz => 12_500_000,
-        })
+// tm4c_hal::calc's tests check each of these against pll_div400
+tm4c_hal::frequency_enum! {
+    /// Selects what to divide the PLL's 400MHz down to.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum PllOutputFrequency {
+        /// 80.00 MHz
+        _80_00mhz = 0 => 80_000_000,
+        /// 66.67 MHz
+        _66_67mhz = 2 => 66_670_000,
+        /// 50 MHz
+        _50_00mhz = 3 => 50_000_000,
+        /// 40 MHz
+        _40_00mhz = 4 => 40_000_000,
+        /// 33.33 MHz
+        _33_33mhz = 5 => 33_330_000,
+        /// 28.57 MHz
+        _28_57mhz = 6 => 28_570_000,
+        /// 25 MHz
+        _25mhz = 7 => 25_000_000,
+        /// 22.22 MHz
+        _22_22mhz = 8 => 22_220_000,
+        /// 20 MHz
+        _20mhz = 9 => 20_000_000,
+        /// 18.18 MHz
+        _18_18mhz = 10 => 18_180_000,
+        /// 16.67 MHz
+        _16_67mhz = 11 => 16_670_000,
+        /// 15.38 MHz
+        _15_38mhz = 12 => 15_380_000,
+        /// 14.29 MHz
+        _14_29mhz = 13 => 14_290_000,
+        /// 13.33 MHz
+        _13_33mhz = 14 => 13_330_000,
+        /// 12.5 MHz
+        _12_5mhz = 15 => 12_500_000,
     }
 }
 
 /// Selects how much to divide the system oscillator down.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Divider {
     /// Divide by 1
     _1 = 1,
@@ -341,198 +305,336 @@ pub enum Domain {
     Pwm1,
 }
 
-/// Reset a peripheral
-pub fn reset(_lock: &PowerControl, pd: Domain) {
+impl Domain {
+    /// Every domain `reset_peripheral_state` resets, in the order it resets
+    /// them, with the GPIO ports last. Left out are Hibernation, whose RTC
+    /// and memory are meant to outlast a reset, and GPIO port C, whose
+    /// PC0-PC3 carry JTAG/SWD and SWO, so the debugger stays attached.
+    pub const RESETTABLE: [Domain; 44] = [
+        Domain::Watchdog1,
+        Domain::Watchdog0,
+        Domain::Timer5,
+        Domain::Timer4,
+        Domain::Timer3,
+        Domain::Timer2,
+        Domain::Timer1,
+        Domain::Timer0,
+        Domain::MicroDma,
+        Domain::Uart7,
+        Domain::Uart6,
+        Domain::Uart5,
+        Domain::Uart4,
+        Domain::Uart3,
+        Domain::Uart2,
+        Domain::Uart1,
+        Domain::Uart0,
+        Domain::Ssi3,
+        Domain::Ssi2,
+        Domain::Ssi1,
+        Domain::Ssi0,
+        Domain::I2c3,
+        Domain::I2c2,
+        Domain::I2c1,
+        Domain::I2c0,
+        Domain::Usb,
+        Domain::Can,
+        Domain::Adc1,
+        Domain::Adc0,
+        Domain::AnalogComparator,
+        Domain::Eeprom,
+        Domain::WideTimer5,
+        Domain::WideTimer4,
+        Domain::WideTimer3,
+        Domain::WideTimer2,
+        Domain::WideTimer1,
+        Domain::WideTimer0,
+        Domain::Pwm0,
+        Domain::Pwm1,
+        Domain::GpioA,
+        Domain::GpioB,
+        Domain::GpioD,
+        Domain::GpioE,
+        Domain::GpioF,
+    ];
+}
+
+/// Reset a peripheral, which must be clocked in run mode. Fails with
+/// `ResetTimeout` if it doesn't report ready again within `RESET_POLLS`
+/// polls.
+pub fn reset(_lock: &PowerControl, pd: Domain) -> Result<(), ResetTimeout> {
     // We use bit-banding to make an atomic write, so this is safe
     let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
     match pd {
         Domain::Watchdog1 => unsafe {
             bb::toggle_bit(&p.srwd, 1);
-            bb::spin_bit(&p.prwd, 1);
+            wait_ready(&p.prwd, 1)
         },
         Domain::Watchdog0 => unsafe {
             bb::toggle_bit(&p.srwd, 0);
-            bb::spin_bit(&p.prwd, 0);
+            wait_ready(&p.prwd, 0)
         },
         Domain::Timer5 => unsafe {
             bb::toggle_bit(&p.srtimer, 5);
-            bb::spin_bit(&p.prtimer, 5);
+            wait_ready(&p.prtimer, 5)
         },
         Domain::Timer4 => unsafe {
             bb::toggle_bit(&p.srtimer, 4);
-            bb::spin_bit(&p.prtimer, 4);
+            wait_ready(&p.prtimer, 4)
         },
         Domain::Timer3 => unsafe {
             bb::toggle_bit(&p.srtimer, 3);
-            bb::spin_bit(&p.prtimer, 3);
+            wait_ready(&p.prtimer, 3)
         },
         Domain::Timer2 => unsafe {
             bb::toggle_bit(&p.srtimer, 2);
-            bb::spin_bit(&p.prtimer, 2);
+            wait_ready(&p.prtimer, 2)
         },
         Domain::Timer1 => unsafe {
             bb::toggle_bit(&p.srtimer, 1);
-            bb::spin_bit(&p.prtimer, 1);
+            wait_ready(&p.prtimer, 1)
         },
         Domain::Timer0 => unsafe {
             bb::toggle_bit(&p.srtimer, 0);
-            bb::spin_bit(&p.prtimer, 0);
+            wait_ready(&p.prtimer, 0)
         },
         Domain::GpioF => unsafe {
             bb::toggle_bit(&p.srgpio, 5);
-            bb::spin_bit(&p.prgpio, 5);
+            wait_ready(&p.prgpio, 5)
         },
         Domain::GpioE => unsafe {
             bb::toggle_bit(&p.srgpio, 4);
-            bb::spin_bit(&p.prgpio, 4);
+            wait_ready(&p.prgpio, 4)
         },
         Domain::GpioD => unsafe {
             bb::toggle_bit(&p.srgpio, 3);
-            bb::spin_bit(&p.prgpio, 3);
+            wait_ready(&p.prgpio, 3)
         },
         Domain::GpioC => unsafe {
             bb::toggle_bit(&p.srgpio, 2);
-            bb::spin_bit(&p.prgpio, 2);
+            wait_ready(&p.prgpio, 2)
         },
         Domain::GpioB => unsafe {
             bb::toggle_bit(&p.srgpio, 1);
-            bb::spin_bit(&p.prgpio, 1);
+            wait_ready(&p.prgpio, 1)
         },
         Domain::GpioA => unsafe {
             bb::toggle_bit(&p.srgpio, 0);
-            bb::spin_bit(&p.prgpio, 0);
+            wait_ready(&p.prgpio, 0)
         },
         Domain::MicroDma => unsafe {
             bb::toggle_bit(&p.srdma, 0);
-            bb::spin_bit(&p.prdma, 0);
+            wait_ready(&p.prdma, 0)
         },
         Domain::Hibernation => unsafe {
             bb::toggle_bit(&p.srhib, 0);
-            bb::spin_bit(&p.prhib, 0);
+            wait_ready(&p.prhib, 0)
         },
         Domain::Uart7 => unsafe {
             bb::toggle_bit(&p.sruart, 7);
-            bb::spin_bit(&p.pruart, 7);
+            wait_ready(&p.pruart, 7)
         },
         Domain::Uart6 => unsafe {
             bb::toggle_bit(&p.sruart, 6);
-            bb::spin_bit(&p.pruart, 6);
+            wait_ready(&p.pruart, 6)
         },
         Domain::Uart5 => unsafe {
             bb::toggle_bit(&p.sruart, 5);
-            bb::spin_bit(&p.pruart, 5);
+            wait_ready(&p.pruart, 5)
         },
         Domain::Uart4 => unsafe {
             bb::toggle_bit(&p.sruart, 4);
-            bb::spin_bit(&p.pruart, 4);
+            wait_ready(&p.pruart, 4)
         },
         Domain::Uart3 => unsafe {
             bb::toggle_bit(&p.sruart, 3);
-            bb::spin_bit(&p.pruart, 3);
+            wait_ready(&p.pruart, 3)
         },
         Domain::Uart2 => unsafe {
             bb::toggle_bit(&p.sruart, 2);
-            bb::spin_bit(&p.pruart, 2);
+            wait_ready(&p.pruart, 2)
         },
         Domain::Uart1 => unsafe {
             bb::toggle_bit(&p.sruart, 1);
-            bb::spin_bit(&p.pruart, 1);
+            wait_ready(&p.pruart, 1)
         },
         Domain::Uart0 => unsafe {
             bb::toggle_bit(&p.sruart, 0);
-            bb::spin_bit(&p.pruart, 0);
+            wait_ready(&p.pruart, 0)
         },
         Domain::Ssi3 => unsafe {
             bb::toggle_bit(&p.srssi, 3);
-            bb::spin_bit(&p.prssi, 3);
+            wait_ready(&p.prssi, 3)
         },
         Domain::Ssi2 => unsafe {
             bb::toggle_bit(&p.srssi, 2);
-            bb::spin_bit(&p.prssi, 2);
+            wait_ready(&p.prssi, 2)
         },
         Domain::Ssi1 => unsafe {
             bb::toggle_bit(&p.srssi, 1);
-            bb::spin_bit(&p.prssi, 1);
+            wait_ready(&p.prssi, 1)
         },
         Domain::Ssi0 => unsafe {
             bb::toggle_bit(&p.srssi, 0);
-            bb::spin_bit(&p.prssi, 0);
+            wait_ready(&p.prssi, 0)
         },
         Domain::I2c3 => unsafe {
             bb::toggle_bit(&p.sri2c, 3);
-            bb::spin_bit(&p.pri2c, 3);
+            wait_ready(&p.pri2c, 3)
         },
         Domain::I2c2 => unsafe {
             bb::toggle_bit(&p.sri2c, 2);
-            bb::spin_bit(&p.pri2c, 2);
+            wait_ready(&p.pri2c, 2)
         },
         Domain::I2c1 => unsafe {
             bb::toggle_bit(&p.sri2c, 1);
-            bb::spin_bit(&p.pri2c, 1);
+            wait_ready(&p.pri2c, 1)
         },
         Domain::I2c0 => unsafe {
             bb::toggle_bit(&p.sri2c, 0);
-            bb::spin_bit(&p.pri2c, 0);
+            wait_ready(&p.pri2c, 0)
         },
         Domain::Usb => unsafe {
             bb::toggle_bit(&p.srusb, 0);
-            bb::spin_bit(&p.prusb, 0);
+            wait_ready(&p.prusb, 0)
         },
         Domain::Can => unsafe {
             bb::toggle_bit(&p.srcan, 0);
-            bb::spin_bit(&p.prcan, 0);
+            wait_ready(&p.prcan, 0)
         },
         Domain::Adc1 => unsafe {
             bb::toggle_bit(&p.sradc, 1);
-            bb::spin_bit(&p.pradc, 1);
+            wait_ready(&p.pradc, 1)
         },
         Domain::Adc0 => unsafe {
             bb::toggle_bit(&p.sradc, 0);
-            bb::spin_bit(&p.pradc, 0);
+            wait_ready(&p.pradc, 0)
         },
         Domain::AnalogComparator => unsafe {
             bb::toggle_bit(&p.sracmp, 0);
-            bb::spin_bit(&p.pracmp, 0);
+            wait_ready(&p.pracmp, 0)
         },
         Domain::Eeprom => unsafe {
             bb::toggle_bit(&p.sreeprom, 0);
-            bb::spin_bit(&p.preeprom, 0);
+            wait_ready(&p.preeprom, 0)
         },
         Domain::WideTimer5 => unsafe {
             bb::toggle_bit(&p.srwtimer, 5);
-            bb::spin_bit(&p.prwtimer, 5);
+            wait_ready(&p.prwtimer, 5)
         },
         Domain::WideTimer4 => unsafe {
             bb::toggle_bit(&p.srwtimer, 4);
-            bb::spin_bit(&p.prwtimer, 4);
+            wait_ready(&p.prwtimer, 4)
         },
         Domain::WideTimer3 => unsafe {
             bb::toggle_bit(&p.srwtimer, 3);
-            bb::spin_bit(&p.prwtimer, 3);
+            wait_ready(&p.prwtimer, 3)
         },
         Domain::WideTimer2 => unsafe {
             bb::toggle_bit(&p.srwtimer, 2);
-            bb::spin_bit(&p.prwtimer, 2);
+            wait_ready(&p.prwtimer, 2)
         },
         Domain::WideTimer1 => unsafe {
             bb::toggle_bit(&p.srwtimer, 1);
-            bb::spin_bit(&p.prwtimer, 1);
+            wait_ready(&p.prwtimer, 1)
         },
         Domain::WideTimer0 => unsafe {
             bb::toggle_bit(&p.srwtimer, 0);
-            bb::spin_bit(&p.prwtimer, 0);
+            wait_ready(&p.prwtimer, 0)
         },
         Domain::Pwm0 => unsafe {
             bb::toggle_bit(&p.srpwm, 0);
-            bb::spin_bit(&p.prpwm, 0);
+            wait_ready(&p.prpwm, 0)
         },
         Domain::Pwm1 => unsafe {
             bb::toggle_bit(&p.srpwm, 1);
-            bb::spin_bit(&p.prpwm, 1);
+            wait_ready(&p.prpwm, 1)
         },
     }
 }
 
+/// Waits for `bit` of the peripheral-ready register at `address` to say the
+/// module is out of reset
+fn wait_ready<T>(address: *const T, bit: u8) -> Result<(), ResetTimeout> {
+    poll_until(RESET_POLLS, || bb::read_bit(address, bit)).map_err(|_| ResetTimeout)
+}
+
+/// Puts each of `domains` back the way a power-on reset leaves it: the
+/// module is reset, then its run, sleep and deep-sleep clocks are turned off.
+/// Domains this part doesn't have are skipped. `Domain::GpioC` is reset if
+/// it is given, debug pins and all; `Domain::RESETTABLE` leaves it out.
+/// Nothing may be using the modules any more; their drivers will find them
+/// unclocked and unconfigured.
+///
+/// A domain that doesn't come out of reset doesn't stop the rest; the
+/// result is `ResetTimeout` if any of them didn't.
+pub fn reset_domains(lock: &PowerControl, domains: &[Domain]) -> Result<(), ResetTimeout> {
+    let mut result = Ok(());
+    for &pd in domains.iter().filter(|&&pd| present(pd)) {
+        // The reset only completes while the module is clocked
+        control_power(lock, pd, RunMode::Run, PowerState::On);
+        if let Err(e) = reset(lock, pd) {
+            result = Err(e);
+        }
+        control_power(lock, pd, RunMode::Run, PowerState::Off);
+        control_power(lock, pd, RunMode::Sleep, PowerState::Off);
+        control_power(lock, pd, RunMode::DeepSleep, PowerState::Off);
+    }
+    result
+}
+
+/// Resets every peripheral in `Domain::RESETTABLE`, so that a soft reset
+/// from the debugger starts from the same peripheral state as a cold boot:
+/// no PWM left running, no DMA left armed. Call it first thing in `main`.
+///
+/// The GPIO ports go last, so pins keep driving whatever they were (a
+/// debug UART's TX line, say) until the very end. Port C is skipped, as it
+/// carries the debugger's JTAG/SWD and SWO pins; resetting it, or gating its
+/// clocks, would cut off a debug session or trace in progress. The clock
+/// setup in SYSCTL itself isn't touched.
+pub fn reset_peripheral_state(lock: &PowerControl) -> Result<(), ResetTimeout> {
+    reset_domains(lock, &Domain::RESETTABLE)
+}
+
+/// The causes of the resets since RESC was last cleared. The register
+/// survives every reset except power-on, so clear it once read to see only
+/// the next one.
+pub fn reset_cause(_lock: &PowerControl) -> ResetCause {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    ResetCause::from_bits(p.resc.read().bits())
+}
+
+/// Clears the record of reset causes in RESC
+pub fn clear_reset_cause(_lock: &PowerControl) {
+    // We own the SYSCTL through PowerControl, and nothing else writes RESC
+    let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    p.resc.write(|w| unsafe { w.bits(0) });
+}
+
+/// Resets the whole chip through the core's system reset request (APINT
+/// SYSRESREQ), the way the datasheet recommends for a software reset.
+/// Every peripheral goes back to its reset state, and `reset_cause` will
+/// report `software` afterwards. Interrupts are masked first, so nothing
+/// runs in the meantime. Safe to call from a panic handler.
+pub fn sys_reset(_lock: &PowerControl) -> ! {
+    cortex_m::interrupt::disable();
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// True if this part has the peripheral, from its PP (peripheral present)
+/// register. Smaller parts lack some of the UARTs, timers and so on, so
+/// firmware built for several boards can check before bringing one up.
+pub fn peripheral_present(_lock: &PowerControl, pd: Domain) -> bool {
+    present(pd)
+}
+
+fn present(pd: Domain) -> bool {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    let (index, bit) = class_bit(pd);
+    bb::read_bit((&p.ppwd as *const _ as *const u32).wrapping_add(index), bit)
+}
+
 /// Activate or De-Activate clocks and power to the given peripheral in the
 /// given run mode.
 ///
@@ -557,6 +659,102 @@ pub fn control_power(_lock: &PowerControl, pd: Domain, run_mode: RunMode, state:
     nop();
 }
 
+/// Whether `pd`'s clock is gated on in `run_mode`, read back from the
+/// RCGC, SCGC or DCGC register
+pub fn power_state(_lock: &PowerControl, pd: Domain, run_mode: RunMode) -> PowerState {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    let first: *const u32 = match run_mode {
+        RunMode::Run => &p.rcgcwd as *const _ as *const u32,
+        RunMode::Sleep => &p.scgcwd as *const _ as *const u32,
+        RunMode::DeepSleep => &p.dcgcwd as *const _ as *const u32,
+    };
+    let (index, bit) = class_bit(pd);
+    if bb::read_bit(first.wrapping_add(index), bit) {
+        PowerState::On
+    } else {
+        PowerState::Off
+    }
+}
+
+/// True once `pd` is clocked, out of reset and ready for its registers to
+/// be accessed, from the PR register
+pub fn is_peripheral_ready(_lock: &PowerControl, pd: Domain) -> bool {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+    let (index, bit) = class_bit(pd);
+    bb::read_bit((&p.prwd as *const _ as *const u32).wrapping_add(index), bit)
+}
+
+/// Where `pd`'s bit is in each bank of per-class registers (PP, SR, RCGC,
+/// SCGC, DCGC and PR), which all share one layout: the index of its
+/// register counting from the bank's watchdog register, and the bit
+fn class_bit(pd: Domain) -> (usize, u8) {
+    match pd {
+        Domain::Watchdog1 => (0, 1),
+        Domain::Watchdog0 => (0, 0),
+        Domain::Timer5 => (1, 5),
+        Domain::Timer4 => (1, 4),
+        Domain::Timer3 => (1, 3),
+        Domain::Timer2 => (1, 2),
+        Domain::Timer1 => (1, 1),
+        Domain::Timer0 => (1, 0),
+        Domain::GpioF => (2, 5),
+        Domain::GpioE => (2, 4),
+        Domain::GpioD => (2, 3),
+        Domain::GpioC => (2, 2),
+        Domain::GpioB => (2, 1),
+        Domain::GpioA => (2, 0),
+        Domain::MicroDma => (3, 0),
+        Domain::Hibernation => (5, 0),
+        Domain::Uart7 => (6, 7),
+        Domain::Uart6 => (6, 6),
+        Domain::Uart5 => (6, 5),
+        Domain::Uart4 => (6, 4),
+        Domain::Uart3 => (6, 3),
+        Domain::Uart2 => (6, 2),
+        Domain::Uart1 => (6, 1),
+        Domain::Uart0 => (6, 0),
+        Domain::Ssi3 => (7, 3),
+        Domain::Ssi2 => (7, 2),
+        Domain::Ssi1 => (7, 1),
+        Domain::Ssi0 => (7, 0),
+        Domain::I2c3 => (8, 3),
+        Domain::I2c2 => (8, 2),
+        Domain::I2c1 => (8, 1),
+        Domain::I2c0 => (8, 0),
+        Domain::Usb => (10, 0),
+        Domain::Can => (13, 0),
+        Domain::Adc1 => (14, 1),
+        Domain::Adc0 => (14, 0),
+        Domain::AnalogComparator => (15, 0),
+        Domain::Eeprom => (22, 0),
+        Domain::WideTimer5 => (23, 5),
+        Domain::WideTimer4 => (23, 4),
+        Domain::WideTimer3 => (23, 3),
+        Domain::WideTimer2 => (23, 2),
+        Domain::WideTimer1 => (23, 1),
+        Domain::WideTimer0 => (23, 0),
+        Domain::Pwm0 => (16, 0),
+        Domain::Pwm1 => (16, 1),
+    }
+}
+
+/// Like `control_power`, but refuses to touch the clock gates of a
+/// peripheral this part doesn't have, whose bits are reserved
+pub fn try_control_power(
+    lock: &PowerControl,
+    pd: Domain,
+    run_mode: RunMode,
+    state: PowerState,
+) -> Result<(), PeripheralNotPresent> {
+    if !present(pd) {
+        return Err(PeripheralNotPresent);
+    }
+    control_power(lock, pd, run_mode, state);
+    Ok(())
+}
+
 fn control_run_power(pd: Domain, on: bool) {
     // We use bit-banding to make an atomic write, so this is safe
     let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
@@ -735,15 +933,47 @@ impl SysctlExt for tm4c123x::SYSCTL {
 }
 
 impl ClockSetup {
+    /// Applies `config` and then does the same as `freeze`
+    pub fn freeze_with(mut self, config: &ClockConfig) -> Clocks {
+        self.oscillator = config.oscillator;
+        self.freeze()
+    }
+
     /// Fix the clock configuration and produce a record of the configuration
     /// so that other modules can calibrate themselves (e.g. the UARTs).
+    /// Panics if an oscillator or the PLL doesn't come up; see `try_freeze`.
     pub fn freeze(self) -> Clocks {
+        match self.try_freeze() {
+            Ok(clocks) => clocks,
+            Err(e) => panic!("clock setup failed: {:?}", e),
+        }
+    }
+
+    /// Like `freeze`, but reports an oscillator or PLL that never becomes
+    /// ready instead of panicking. The clocks are left part-way configured
+    /// in that case, still running from the internal oscillator.
+    pub fn try_freeze(self) -> Result<Clocks, ClockError> {
+        tm4c_hal::assert_can_block!("ClockSetup::freeze");
         // We own the SYSCTL at this point - no one else can be running.
         let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
 
         let mut osc = 0u32;
         let mut sysclk = 0u32;
 
+        // RCC2 overrides RCC whenever USERCC2 is set, and the boot ROM or
+        // earlier firmware may have left it so. Bypass the PLL in both, then
+        // drop back to RCC alone so the oscillator setup below takes effect.
+        // The PLL stage switches to RCC2 again.
+        p.rcc.modify(|_, w| w.bypass().set_bit());
+        p.rcc2.modify(|_, w| w.bypass2().set_bit());
+        p.rcc2.write(|w| {
+            w.usercc2().clear_bit();
+            w.bypass2().set_bit();
+            w.pwrdn2().set_bit();
+            w
+        });
+        tm4c_hal::trace_access!(p, rcc2, Write);
+
         match self.oscillator {
             Oscillator::Main(crystal_frequency, system_clock) => {
                 p.rcc.write(|w| {
@@ -860,6 +1090,7 @@ impl ClockSetup {
                     }
                     w
                 });
+                tm4c_hal::trace_access!(p, rcc, Write);
             }
             // The default
             Oscillator::PrecisionInternal(system_clock) => {
@@ -888,6 +1119,7 @@ impl ClockSetup {
                     }
                     w
                 });
+                tm4c_hal::trace_access!(p, rcc, Write);
             }
             Oscillator::PrecisionInternalDiv4(div) => {
                 osc = 4_000_000;
@@ -905,6 +1137,7 @@ impl ClockSetup {
                     sysclk = osc / (div as u32);
                     w
                 });
+                tm4c_hal::trace_access!(p, rcc, Write);
             }
             Oscillator::LowFrequencyInternal(div) => {
                 osc = 30_000;
@@ -922,64 +1155,225 @@ impl ClockSetup {
                     sysclk = osc / (div as u32);
                     w
                 });
+                tm4c_hal::trace_access!(p, rcc, Write);
             }
         }
 
         match self.oscillator {
             Oscillator::PrecisionInternal(SystemClock::UsePll(f))
             | Oscillator::Main(_, SystemClock::UsePll(f)) => {
-                // Configure 400MHz PLL with divider f
+                // Always RCC2 with DIV400, so every frequency takes the same
+                // path: sysclk = 400 MHz / (div + 1). RCC keeps the crystal
+                // setting and USESYSDIV.
+                let div = tm4c_hal::calc::pll_div400(Hertz::from(f).0);
+                // OSCSRC2 takes the same source RCC selected: 0 is the main
+                // oscillator, 1 the PIOSC
+                let oscsrc = match self.oscillator {
+                    Oscillator::Main(..) => 0,
+                    _ => 1,
+                };
 
                 // Set PLL bit in masked interrupt status to clear
                 // PLL lock status
                 p.misc.write(|w| w.plllmis().set_bit());
+                tm4c_hal::trace_access!(p, misc, Write);
 
-                // Enable the PLL
-                p.rcc.modify(|_, w| w.pwrdn().clear_bit());
-
-                while p.pllstat.read().lock().bit_is_clear() {
-                    nop();
-                }
-
-                match f {
-                    // We need to use RCC2 for this one
-                    PllOutputFrequency::_80_00mhz => {
-                        p.rcc2.write(|w| {
-                            w.usercc2().set_bit();
-                            // Divide 400 MHz not 200 MHz
-                            w.div400().set_bit();
-                            // div=2 with lsb=0 gives divide by 5, so 400 MHz => 80 MHz
-                            w.sysdiv2lsb().clear_bit();
-                            unsafe { w.sysdiv2().bits(2) };
-                            w.bypass2().clear_bit();
-                            w
-                        });
-                        sysclk = 400_000_000u32 / 5;
-                    }
-                    _ => {
-                        // All the other frequencies can be done with legacy registers
-                        p.rcc.modify(|_, w| {
-                            unsafe { w.sysdiv().bits(f as u8) };
-                            w.usesysdiv().set_bit();
-                            w.bypass().clear_bit();
-                            w
-                        });
-                        sysclk = 400_000_000u32 / (2 * ((f as u32) + 1));
+                p.rcc.modify(|_, w| w.usesysdiv().set_bit());
+                tm4c_hal::trace_access!(p, rcc, Write);
+
+                // Enable the PLL, still bypassed, with the divider in place
+                p.rcc2.write(|w| {
+                    w.usercc2().set_bit();
+                    w.div400().set_bit();
+                    unsafe {
+                        w.oscsrc2().bits(oscsrc);
+                        w.sysdiv2().bits(div >> 1);
                     }
-                }
+                    w.sysdiv2lsb().bit(div & 1 != 0);
+                    w.bypass2().set_bit();
+                    w.pwrdn2().clear_bit();
+                    w
+                });
+                tm4c_hal::trace_access!(p, rcc2, Write);
+
+                poll_until(CLOCK_READY_POLLS, || p.pllstat.read().lock().bit_is_set())
+                    .map_err(|_| ClockError::PllLock)?;
+
+                p.rcc2.modify(|_, w| w.bypass2().clear_bit());
+                tm4c_hal::trace_access!(p, rcc2, Write);
+
+                // Report what the hardware ended up with, not what was asked
+                let rcc2 = p.rcc2.read();
+                let div = (rcc2.sysdiv2().bits() << 1) | rcc2.sysdiv2lsb().bit() as u8;
+                sysclk = tm4c_hal::calc::pll_sysclk_div400(div);
             }
             _ => {}
         }
 
-        Clocks {
-            osc: osc.hz(),
-            sysclk: sysclk.hz(),
-        }
+        Ok(Clocks::configured(osc.hz(), sysclk.hz()))
     }
 }
 
+/// Changes the clock configuration after `freeze`, and returns a `Clocks`
+/// for the new one. `clocks` and every copy of it go stale, so drivers
+/// built with them must be given the new value (`Serial::change_baud_rate`,
+/// `Spi::reclock`, `I2c::reclock`) or made again. Peripherals keep running
+/// through the switch, at the wrong rate until then.
+///
+/// This goes through the same sequence as `freeze`, which bypasses the PLL
+/// before touching anything else, so the system runs straight from the
+/// oscillator while the PLL locks again. The flash on this part needs no
+/// wait state changes. On an error the PLL is left bypassed.
+pub fn reconfigure(
+    _pc: &mut PowerControl,
+    clocks: Clocks,
+    oscillator: Oscillator,
+) -> Result<Clocks, ClockError> {
+    tm4c_hal::assert_can_block!("sysctl::reconfigure");
+    clocks.assert_current();
+    ClockSetup { oscillator, _0: () }.try_freeze()
+}
+
 impl PowerControl {}
 
+/// Selects the oscillator that clocks the system in deep sleep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeepSleepOscillator {
+    /// The main oscillator, with this crystal. The PLL is always off in
+    /// deep sleep.
+    Main(CrystalFrequency),
+    /// The 16 MHz precision internal oscillator
+    PrecisionInternal,
+    /// The 30 kHz internal oscillator
+    LowFrequencyInternal,
+    /// The hibernation module's 32.768 kHz oscillator, which must be running
+    Hibernation,
+}
+
+/// The deep-sleep clock configuration, in DSLPCLKCFG. The run-mode clocks set by
+/// `ClockSetup::freeze` are left alone, so this can be applied at any time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeepSleepClockSetup {
+    /// Where the deep-sleep system clock comes from
+    pub oscillator: DeepSleepOscillator,
+    /// What to divide it by, from 1 to 64
+    pub divider: u16,
+    /// Power the PIOSC down in deep sleep. Ignored if deep sleep runs from
+    /// the PIOSC.
+    pub piosc_power_down: bool,
+}
+
+impl DeepSleepClockSetup {
+    /// Writes this configuration to DSLPCLKCFG and returns the frequencies deep
+    /// sleep will run at
+    pub fn apply(self, _pc: &PowerControl) -> DeepSleepClocks {
+        assert!(
+            self.divider >= 1 && self.divider <= 64,
+            "deep-sleep divider out of range"
+        );
+        let (dsoscsrc, osc) = match self.oscillator {
+            DeepSleepOscillator::Main(crystal) => (0x0, Hertz::from(crystal)),
+            DeepSleepOscillator::PrecisionInternal => (0x1, Hertz(16_000_000)),
+            DeepSleepOscillator::LowFrequencyInternal => (0x3, Hertz(30_000)),
+            DeepSleepOscillator::Hibernation => (0x7, Hertz(32_768)),
+        };
+        let piosc_power_down =
+            self.piosc_power_down && self.oscillator != DeepSleepOscillator::PrecisionInternal;
+
+        // DSDIVORIDE in bits 28:23, DSOSCSRC in bits 6:4, PIOSCPD is bit 1
+        let bits = (u32::from(self.divider - 1) << 23)
+            | (dsoscsrc << 4)
+            | if piosc_power_down { 1 << 1 } else { 0 };
+        // We own the SYSCTL through PowerControl, and DSLPCLKCFG only
+        // matters in deep sleep
+        let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+        p.dslpclkcfg.write(|w| unsafe { w.bits(bits) });
+        tm4c_hal::trace_access!(p, dslpclkcfg, Write);
+
+        DeepSleepClocks {
+            osc,
+            sysclk: Hertz(osc.0 / u32::from(self.divider)),
+        }
+    }
+}
+
+/// Idle-current settings. See `Builder` for the options.
+pub mod low_power {
+    pub use tm4c_hal::sysctl::low_power::*;
+
+    use super::{control_power, Domain, PowerControl, PowerState, RunMode};
+    use crate::bb;
+
+    /// Applies `config` and reports what changed. Fails without changing
+    /// anything if `gate_unused_gpio` lists a domain that isn't a GPIO port.
+    ///
+    /// This always sets RCC.ACG, so from now on sleep and deep sleep only
+    /// clock what `control_power` enabled for `RunMode::Sleep` and
+    /// `RunMode::DeepSleep`; enable those for anything that must keep
+    /// running (or wake the chip) while asleep. The PIOSC is only switched
+    /// off if the system clock does not run from it.
+    pub fn apply(pc: &PowerControl, config: &Builder<Domain>) -> Result<Report, NotGpio<Domain>> {
+        if let Some(&domain) = config.gate_unused_gpio.iter().find(|&&d| !is_gpio(d)) {
+            return Err(NotGpio(domain));
+        }
+
+        let p = unsafe { &*tm4c123x::SYSCTL::ptr() };
+        let mut report = Report::default();
+
+        // RCC.ACG
+        unsafe { bb::change_bit(&p.rcc, 27, true) };
+        report.auto_clock_gating = true;
+
+        if !config.keep_piosc {
+            // OSCSRC2 if USERCC2 is set, OSCSRC otherwise; 0 is the main oscillator
+            let rcc2 = p.rcc2.read().bits();
+            let oscsrc = if rcc2 & (1 << 31) != 0 {
+                (rcc2 >> 4) & 0x7
+            } else {
+                (p.rcc.read().bits() >> 4) & 0x3
+            };
+            if oscsrc == 0 {
+                // RCC.IOSCDIS
+                unsafe { bb::change_bit(&p.rcc, 1, true) };
+                report.piosc_off = true;
+            } else {
+                report.piosc_in_use = true;
+            }
+        }
+
+        // DSLPPWRCFG: FLASHPM in bits 5:4 (0x2 is low power), SRAMPM in bits 1:0
+        let flashpm = if config.flash_low_power { 0x2 } else { 0x0 };
+        p.dslppwrcfg.modify(|r, w| unsafe {
+            w.bits((r.bits() & !0x33) | (flashpm << 4) | config.sram_retention.bits())
+        });
+        if config.sram_retention != SramPower::Active {
+            report.sram_retention = Some(config.sram_retention);
+        }
+        report.flash_low_power = config.flash_low_power;
+
+        for &domain in config.gate_unused_gpio {
+            control_power(pc, domain, RunMode::Run, PowerState::Off);
+            control_power(pc, domain, RunMode::Sleep, PowerState::Off);
+            control_power(pc, domain, RunMode::DeepSleep, PowerState::Off);
+        }
+        report.gated_gpio = config.gate_unused_gpio.len();
+
+        Ok(report)
+    }
+
+    fn is_gpio(domain: Domain) -> bool {
+        match domain {
+            Domain::GpioA
+            | Domain::GpioB
+            | Domain::GpioC
+            | Domain::GpioD
+            | Domain::GpioE
+            | Domain::GpioF => true,
+            _ => false,
+        }
+    }
+}
+
 /// This module is all about identifying the physical chip we're running on.
 pub mod chip_id {
     pub use tm4c_hal::sysctl::chip_id::*;
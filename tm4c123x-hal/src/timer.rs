@@ -8,17 +8,36 @@ use nb;
 
 #[rustfmt::skip]
 use tm4c123x::{
+    Interrupt,
     TIMER0, TIMER1, TIMER2, TIMER3, TIMER4, TIMER5,
     WTIMER0, WTIMER1, WTIMER2, WTIMER3, WTIMER4, WTIMER5,
 };
+use tm4c_hal::tick::TickAccumulator;
 use tm4c_hal::time::Hertz;
 use void::Void;
 
+pub use tm4c_hal::tick::Every;
+pub use tm4c_hal::timer::{RangeError, TickCalc, Ticks, Width};
+
 /// Hardware timers
 pub struct Timer<TIM> {
     tim: TIM,
     clocks: Clocks,
     timeout: Hertz,
+    /// What the timer was last programmed with
+    ticks: Ticks,
+}
+
+/// A periodic tick with drift-free millisecond time, for schedulers that
+/// run off a timer. The timer is programmed as close to the requested rate
+/// as it gets, and `millis` accounts for the exact period that came to
+/// rather than assuming the requested one.
+///
+/// Call `poll` from the timer's interrupt (after `listen`ing for
+/// `Event::TimeOut`), or often enough that no tick is missed.
+pub struct TickSource<TIM> {
+    timer: Timer<TIM>,
+    accumulator: TickAccumulator,
 }
 
 /// Interrupt events
@@ -28,35 +47,25 @@ pub enum Event {
 }
 
 macro_rules! hal {
-    ($($TIM:ident: ($tim:ident, $powerDomain:ident),)+) => {
+    ($($TIM:ident: ($tim:ident, $powerDomain:ident, $INT:ident),)+) => {
         $(
             impl Periodic for Timer<$TIM> {}
 
             impl CountDown for Timer<$TIM> {
                 type Time = Hertz;
 
-                #[allow(unused_unsafe)]
+                /// Starts counting down at `timeout`, or as near to it as the
+                /// timer can get: a rate too slow for 32 bits runs at the
+                /// slowest rate there is, about 2^32 clocks a period. Use
+                /// `try_start` to be told instead.
                 fn start<T>(&mut self, timeout: T)
                 where
                     T: Into<Hertz>,
                 {
-                    // Disable timer
-                    self.tim.ctl.modify(|_, w|
-					w.taen().clear_bit()
-					.tben().clear_bit()
-                    );
                     self.timeout = timeout.into();
-
-                    let frequency = self.timeout.0;
-                    let ticks = self.clocks.sysclk.0 / frequency;
-
-                    self.tim.tav.write(|w| unsafe { w.bits(ticks) });
-                    self.tim.tailr.write(|w| unsafe { w.bits(ticks) });
-
-                    // // start counter
-                    self.tim.ctl.modify(|_, w|
-                        w.taen().set_bit()
-                    );
+                    let calc = self.calc();
+                    let ticks = calc.saturate(calc.frequency_to_ticks(self.timeout));
+                    self.program(ticks);
                 }
 
                 fn wait(&mut self) -> nb::Result<(), Void> {
@@ -81,11 +90,14 @@ macro_rules! hal {
                 where
                     T: Into<Hertz>,
                 {
+                    clocks.assert_current();
+
                     // power up
                     sysctl::control_power(
                         pc, sysctl::Domain::$powerDomain,
                         sysctl::RunMode::Run, sysctl::PowerState::On);
-                    sysctl::reset(pc, sysctl::Domain::$powerDomain);
+                    sysctl::reset(pc, sysctl::Domain::$powerDomain)
+                        .expect("timer didn't come out of reset");
 
                     // Stop Timers
                     tim.ctl.write(|w|
@@ -106,12 +118,69 @@ macro_rules! hal {
                         tim:tim,
                         clocks: *clocks,
                         timeout: Hertz(0),
+                        ticks: Ticks { load: 1, prescale: 0 },
                     };
                     timer.start(timeout);
 
                     timer
                 }
 
+                /// Starts counting down at `timeout`, returning the ticks the
+                /// period came to. If `timeout` can't be reached the timer is
+                /// left as it was.
+                pub fn try_start<T>(&mut self, timeout: T) -> Result<Ticks, RangeError>
+                where
+                    T: Into<Hertz>,
+                {
+                    let timeout = timeout.into();
+                    let ticks = self.calc().frequency_to_ticks(timeout)?;
+                    self.timeout = timeout;
+                    self.program(ticks);
+                    Ok(ticks)
+                }
+
+                fn calc(&self) -> TickCalc {
+                    // The timer keeps the Clocks it was made with
+                    self.clocks.assert_current();
+                    TickCalc::for_clock(self.clocks.sysclk, Width::Bits32)
+                }
+
+                #[allow(unused_unsafe)]
+                fn program(&mut self, ticks: Ticks) {
+                    // Disable timer
+                    self.tim.ctl.modify(|_, w|
+					w.taen().clear_bit()
+					.tben().clear_bit()
+                    );
+
+                    self.ticks = ticks;
+                    // The count includes zero
+                    let load = (ticks.load - 1) as u32;
+
+                    self.tim.tav.write(|w| unsafe { w.bits(load) });
+                    self.tim.tailr.write(|w| unsafe { w.bits(load) });
+
+                    // // start counter
+                    self.tim.ctl.modify(|_, w|
+                        w.taen().set_bit()
+                    );
+                }
+
+                /// `CountDown::wait`, for interrupt handlers; see `isr`.
+                /// Clears the time-out if it had happened.
+                pub fn wait_from_isr(
+                    &mut self,
+                    _isr: &tm4c_hal::isr::IsrContext,
+                ) -> nb::Result<(), Void> {
+                    self.wait()
+                }
+
+                /// The ticks the period came to when the timer was last
+                /// started, which may differ from the rate asked for
+                pub fn ticks(&self) -> Ticks {
+                    self.ticks
+                }
+
                 /// Starts listening for an `event`
                 pub fn listen(&mut self, event: Event) {
                     match event {
@@ -132,6 +201,19 @@ macro_rules! hal {
                     }
                 }
 
+                /// The underlying timer peripheral, for registers this driver
+                /// doesn't cover
+                pub fn inner(&self) -> &$TIM {
+                    &self.tim
+                }
+
+                /// Mutable access to the underlying timer peripheral. Leave
+                /// the registers the driver relies on (enable, mode, load
+                /// value) as you found them, or the driver will misbehave.
+                pub fn inner_mut(&mut self) -> &mut $TIM {
+                    &mut self.tim
+                }
+
                 /// Releases the TIM peripheral
                 pub fn free(self) -> $TIM {
                     // pause counter
@@ -140,23 +222,98 @@ macro_rules! hal {
                                   .tben().clear_bit());
                     self.tim
                 }
+
+                /// Like `free`, but also stops the timer's run-mode clock. The
+                /// next constructor call turns it back on.
+                pub fn power_down(self, pc: &sysctl::PowerControl) -> $TIM {
+                    let tim = self.free();
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::Off);
+                    tim
+                }
+            }
+
+            impl Timer<$TIM> {
+                /// The NVIC interrupt this timer raises. The halves are chained,
+                /// so this is timer A's.
+                pub const INTERRUPT: Interrupt = Interrupt::$INT;
+
+                tm4c_hal::nvic_control!("this timer's interrupt");
             }
         )+
     }
 }
 
+impl<TIM> TickSource<TIM>
+where
+    Timer<TIM>: CountDown<Time = Hertz>,
+{
+    /// Restarts `timer` at the nearest rate to `rate` it can manage
+    pub fn new<T>(mut timer: Timer<TIM>, rate: T) -> Self
+    where
+        T: Into<Hertz>,
+    {
+        timer.start(rate);
+        // The period it settled on, which is what each tick really lasts
+        let cycles = timer.ticks.cycles();
+        TickSource {
+            accumulator: TickAccumulator::new(cycles, timer.clocks.sysclk.0),
+            timer,
+        }
+    }
+
+    /// Counts a tick if one is due, returning whether there was
+    pub fn poll(&mut self) -> bool {
+        match self.timer.wait() {
+            Ok(()) => {
+                self.accumulator.tick();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Ticks counted so far
+    pub fn ticks(&self) -> u64 {
+        self.accumulator.ticks()
+    }
+
+    /// Milliseconds since the `TickSource` was made, from the true tick
+    /// period
+    pub fn millis(&self) -> u64 {
+        self.accumulator.millis()
+    }
+
+    /// A schedule firing every `ms` milliseconds from now; check it with
+    /// `Every::due(tick_source.millis())`. Panics if `ms` is zero.
+    pub fn every(&self, ms: u64) -> Every {
+        Every::new(self.millis(), ms)
+    }
+
+    /// The timer, for `listen` and the interrupt setup
+    pub fn timer_mut(&mut self) -> &mut Timer<TIM> {
+        &mut self.timer
+    }
+
+    /// Gives the timer back, still running
+    pub fn free(self) -> Timer<TIM> {
+        self.timer
+    }
+}
+
 hal! {
-    TIMER0: (timer0, Timer0),
-    TIMER1: (timer1, Timer1),
-    TIMER2: (timer2, Timer2),
-    TIMER3: (timer3, Timer3),
-    TIMER4: (timer4, Timer4),
-    TIMER5: (timer5, Timer5),
-
-    WTIMER0: (wtimer0, WideTimer0),
-    WTIMER1: (wtimer1, WideTimer1),
-    WTIMER2: (wtimer2, WideTimer2),
-    WTIMER3: (wtimer3, WideTimer3),
-    WTIMER4: (wtimer4, WideTimer4),
-    WTIMER5: (wtimer5, WideTimer5),
+    TIMER0: (timer0, Timer0, TIMER0A),
+    TIMER1: (timer1, Timer1, TIMER1A),
+    TIMER2: (timer2, Timer2, TIMER2A),
+    TIMER3: (timer3, Timer3, TIMER3A),
+    TIMER4: (timer4, Timer4, TIMER4A),
+    TIMER5: (timer5, Timer5, TIMER5A),
+
+    WTIMER0: (wtimer0, WideTimer0, WTIMER0A),
+    WTIMER1: (wtimer1, WideTimer1, WTIMER1A),
+    WTIMER2: (wtimer2, WideTimer2, WTIMER2A),
+    WTIMER3: (wtimer3, WideTimer3, WTIMER3A),
+    WTIMER4: (wtimer4, WideTimer4, WTIMER4A),
+    WTIMER5: (wtimer5, WideTimer5, WTIMER5A),
 }
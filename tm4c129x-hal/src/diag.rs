@@ -0,0 +1,95 @@
+//! Diagnostic snapshots
+//!
+//! See `tm4c_hal::diag` for the report format.
+
+pub use tm4c_hal::diag::*;
+
+use crate::sysctl::Clocks;
+
+/// Decodes an RSCLKCFG OSCSRC/PLLSRC value. The registers don't record the
+/// crystal frequency, so the main oscillator is taken to be `clocks.osc`.
+fn source(bits: u32, clocks: &Clocks) -> (ClockSource, u32) {
+    match bits {
+        0 => (ClockSource::PrecisionInternal, 16_000_000),
+        2 => (ClockSource::LowFrequencyInternal, 33_000),
+        3 => (ClockSource::MainOscillator, clocks.osc.0),
+        4 => (ClockSource::Hibernation, 32_768),
+        n => (ClockSource::Unknown(n as u8), 0),
+    }
+}
+
+/// Reads the clock configuration back out of RSCLKCFG and the PLL registers
+pub fn clock_tree(clocks: &Clocks) -> ClockTree {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let rsclkcfg = p.rsclkcfg.read().bits();
+
+    if rsclkcfg & (1 << 28) != 0 {
+        // USEPLL: fVCO = fIN * (MINT + MFRAC / 1024) / ((Q + 1) * (N + 1))
+        let (source, osc_hz) = source((rsclkcfg >> 24) & 0xF, clocks);
+        let pllfreq0 = p.pllfreq0.read().bits();
+        let pllfreq1 = p.pllfreq1.read().bits();
+        let mint = u64::from(pllfreq0 & 0x3FF);
+        let mfrac = u64::from((pllfreq0 >> 10) & 0x3FF);
+        let n = u64::from(pllfreq1 & 0x1F) + 1;
+        let q = u64::from((pllfreq1 >> 8) & 0x1F) + 1;
+        let vco_hz = u64::from(osc_hz) * (mint * 1024 + mfrac) / (1024 * q * n);
+        let divider = ((rsclkcfg >> 10) & 0x3FF) + 1;
+        ClockTree {
+            source,
+            osc_hz,
+            pll: true,
+            divider,
+            sysclk_hz: (vco_hz / u64::from(divider)) as u32,
+        }
+    } else {
+        let (source, osc_hz) = source((rsclkcfg >> 20) & 0xF, clocks);
+        let divider = (rsclkcfg & 0x3FF) + 1;
+        ClockTree {
+            source,
+            osc_hz,
+            pll: false,
+            divider,
+            sysclk_hz: osc_hz / divider,
+        }
+    }
+}
+
+/// Takes a snapshot of the chip's state. `clocks` is what the HAL was frozen
+/// with, to compare against what the registers now say; `drivers` are asked
+/// for their status when the report is printed.
+pub fn snapshot<'a>(clocks: &Clocks, drivers: &'a [&'a dyn Diagnose]) -> DiagReport<'a> {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let gates = [
+        ("wd", p.rcgcwd.read().bits()),
+        ("timer", p.rcgctimer.read().bits()),
+        ("gpio", p.rcgcgpio.read().bits()),
+        ("dma", p.rcgcdma.read().bits()),
+        ("hib", p.rcgchib.read().bits()),
+        ("uart", p.rcgcuart.read().bits()),
+        ("ssi", p.rcgcssi.read().bits()),
+        ("i2c", p.rcgci2c.read().bits()),
+        ("usb", p.rcgcusb.read().bits()),
+        ("ephy", p.rcgcephy.read().bits()),
+        ("can", p.rcgccan.read().bits()),
+        ("adc", p.rcgcadc.read().bits()),
+        ("acmp", p.rcgcacmp.read().bits()),
+        ("pwm", p.rcgcpwm.read().bits()),
+        ("eeprom", p.rcgceeprom.read().bits()),
+        ("emac", p.rcgcemac.read().bits()),
+    ];
+    let mut run_clock_gates = [None; CLOCK_GATE_SLOTS];
+    for (slot, &(name, bits)) in run_clock_gates.iter_mut().zip(gates.iter()) {
+        if bits != 0 {
+            *slot = Some((name, bits));
+        }
+    }
+    DiagReport::new(
+        clock_tree(clocks),
+        clocks.sysclk.0,
+        p.resc.read().bits(),
+        run_clock_gates,
+        drivers,
+    )
+}
@@ -0,0 +1,83 @@
+//! Ethernet MAC addresses
+//!
+//! TI programs a MAC address into the USER0 and USER1 flash user registers
+//! of parts sold with Ethernet, such as the Connected LaunchPad. The low
+//! three bytes of each register hold half the address, first byte lowest.
+//! Blank parts read all ones there, so `MacAddress::resolve` falls back to a
+//! locally administered address made from the chip's unique ID.
+
+/// Flash user registers 0 and 1. Addressed directly as they sit among the
+/// flash controller's registers in the System Control block.
+const USERREG0: *const u32 = 0x400F_E1E0 as *const u32;
+const USERREG1: *const u32 = 0x400F_E1E4 as *const u32;
+
+/// Unique identifier registers 0 to 3
+const UNIQUEID0: *const u32 = 0x400F_EF20 as *const u32;
+
+/// Which address the Ethernet MAC should use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacAddress {
+    /// The address programmed into USER0/USER1, or the fallback from
+    /// `fallback_mac` if there isn't one
+    Factory,
+    /// This address
+    Custom([u8; 6]),
+}
+
+impl MacAddress {
+    /// The six bytes this choice comes to on this chip
+    pub fn resolve(self) -> [u8; 6] {
+        match self {
+            MacAddress::Factory => factory_mac().unwrap_or_else(fallback_mac),
+            MacAddress::Custom(mac) => mac,
+        }
+    }
+}
+
+/// The MAC address programmed into USER0/USER1, or `None` if the registers
+/// are blank or don't hold a usable unicast address
+pub fn factory_mac() -> Option<[u8; 6]> {
+    // Read only, so this is safe
+    let (user0, user1) = unsafe {
+        (
+            core::ptr::read_volatile(USERREG0),
+            core::ptr::read_volatile(USERREG1),
+        )
+    };
+    if user0 == 0xFFFF_FFFF || user1 == 0xFFFF_FFFF {
+        return None;
+    }
+    let mac = [
+        user0 as u8,
+        (user0 >> 8) as u8,
+        (user0 >> 16) as u8,
+        user1 as u8,
+        (user1 >> 8) as u8,
+        (user1 >> 16) as u8,
+    ];
+    let multicast = mac[0] & 0x01 != 0;
+    if multicast || mac == [0; 6] {
+        None
+    } else {
+        Some(mac)
+    }
+}
+
+/// A locally administered unicast address derived from the chip's unique
+/// ID. It is the same on every boot, but another chip could in principle
+/// come up with the same one.
+pub fn fallback_mac() -> [u8; 6] {
+    // Fold the 128-bit ID down to 48 bits with FNV-1a
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for i in 0..4 {
+        // Read only, so this is safe
+        let word = unsafe { core::ptr::read_volatile(UNIQUEID0.add(i)) };
+        for byte in word.to_le_bytes().iter() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01B3);
+        }
+    }
+    let b = hash.to_le_bytes();
+    // Locally administered, unicast
+    [(b[0] | 0x02) & !0x01, b[1], b[2], b[3], b[4], b[5]]
+}
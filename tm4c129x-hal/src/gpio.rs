@@ -25,10 +25,12 @@
 //! let p = Peripherals::take().unwrap();
 //! let mut sc = p.SYSCTL.constrain();
 //! let mut portb = p.GPIO_PORTB.split(&sc.power_control);
-//! let timer_output_pin = portb.pb0.into_af_push_pull::<gpio::AF7>(&mut portb.control);
+//! let timer_output_pin = portb
+//!     .pb0
+//!     .into_alternate::<gpio::AF7, gpio::PushPull>(&mut portb.control);
 //! let uart_tx_pin = portb
 //!     .pb1
-//!     .into_af_open_drain::<gpio::AF1, gpio::PullUp>(&mut portb.control);
+//!     .into_alternate::<gpio::AF1, gpio::OpenDrain<gpio::PullUp>>(&mut portb.control);
 //! let blue_led = portb.pb2.into_push_pull_output();
 //! let button = portb.pb3.into_pull_up_input();
 //! # }
@@ -7,11 +7,10 @@ use crate::{
     time::Hertz,
 };
 
-use cortex_m::asm::delay;
-use tm4c129x::{I2C0, I2C1, I2C2, I2C3};
+use tm4c129x::{Interrupt, I2C0, I2C1, I2C2, I2C3};
 
-pub use tm4c_hal::i2c::Error;
-pub use tm4c_hal::{i2c_busy_wait, i2c_hal, i2c_pins};
+pub use tm4c_hal::i2c::{Error, ReclockError, RegisterWidth, SclLowTimeout, SclPin, SdaPin};
+pub use tm4c_hal::{i2c_hal, i2c_pins};
 
 /// I2C peripheral operating in master mode
 pub struct I2c<I2C, PINS> {
@@ -21,19 +20,13 @@ pub struct I2c<I2C, PINS> {
     pub pins: PINS,
 }
 
-// FIXME these should be "closed" traits
-/// SCL pin -- DO NOT IMPLEMENT THIS TRAIT
-pub unsafe trait SclPin<I2C> {}
-
-/// SDA pin -- DO NOT IMPLEMENT THIS TRAIT
-pub unsafe trait SdaPin<I2C> {}
-
 i2c_pins!(I2C0, scl: [(gpiob::PB2, AF2)], sda: [(gpiob::PB3, AF2)],);
 i2c_pins!(I2C1, scl: [(gpiog::PG0, AF2)], sda: [(gpiog::PG1, AF2)],);
 i2c_pins!(I2C2,
     scl: [(gpiol::PL1, AF2),(gpiop::PP5, AF2), (gpion::PN5, AF3)],
     sda: [(gpiol::PL0, AF2), (gpion::PN4, AF3)],
 );
+i2c_pins!(I2C3, scl: [(gpiok::PK4, AF2)], sda: [(gpiok::PK5, AF2)],);
 
 i2c_hal! {
     I2C0: (I2c0, i2c0),
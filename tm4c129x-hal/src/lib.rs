@@ -26,15 +26,24 @@
 #![allow(deprecated)]
 
 pub use tm4c129x::{self, CorePeripherals, Peripherals};
-pub use tm4c_hal::{bb, delay, time};
+/// The peripheral access crate, under the name other HALs use for it
+pub use tm4c129x as pac;
+pub use tm4c_hal::{bb, cpu, debounce, delay, isr, parallel, post, pps, rt_budget, tick, time, timer, trace};
+#[cfg(feature = "panic-safe-state")]
+pub use tm4c_hal::safe_state;
+#[cfg(feature = "soft-uart")]
+pub use tm4c_hal::soft_uart;
 
 // Enable use of interrupt macro
 #[cfg(feature = "rt")]
 pub use crate::tm4c129x::interrupt;
 
+pub mod diag;
+pub mod emac;
 pub mod gpio;
 pub mod hib;
 pub mod i2c;
+pub mod nmi;
 pub mod prelude;
 pub mod serial;
 // pub mod spi;
@@ -0,0 +1,41 @@
+//! Non-maskable interrupt causes
+
+pub use tm4c_hal::nmi::*;
+
+// NMIC bits
+const EXTERNAL: u32 = 1 << 0;
+const POWER: u32 = 1 << 2;
+const WDT0: u32 = 1 << 3;
+const WDT1: u32 = 1 << 5;
+const TAMPER: u32 = 1 << 9;
+const MOSCFAIL: u32 = 1 << 16;
+const KNOWN: u32 = EXTERNAL | POWER | WDT0 | WDT1 | TAMPER | MOSCFAIL;
+
+/// Reads which NMI sources have fired, without clearing them
+pub fn cause() -> NmiCause {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    cause_from_bits(p.nmic.read().bits())
+}
+
+/// Reads and clears the NMI cause, then hands it to `dispatch`. Call this
+/// from the `NMI` exception handler.
+pub fn handle() {
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let seen = p.nmic.read().bits() & KNOWN;
+    // The cause bits are cleared by writing zero; ones leave them alone, so
+    // a source that fires meanwhile isn't lost
+    p.nmic.write(|w| unsafe { w.bits(KNOWN & !seen) });
+    dispatch(cause_from_bits(seen));
+}
+
+fn cause_from_bits(bits: u32) -> NmiCause {
+    NmiCause {
+        external: bits & EXTERNAL != 0,
+        power: bits & POWER != 0,
+        watchdog0: bits & WDT0 != 0,
+        watchdog1: bits & WDT1 != 0,
+        tamper: bits & TAMPER != 0,
+        mosc_fail: bits & MOSCFAIL != 0,
+    }
+}
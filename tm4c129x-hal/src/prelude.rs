@@ -1,9 +1,12 @@
 //! Prelude
+//!
+//! `use tm4c129x_hal::prelude::*;` brings every extension trait into scope,
+//! along with the sysctl enums most programs need.
 
 #[rustfmt::skip]
 pub use crate::{
     gpio::GpioExt as _,
     hal::prelude::*,
-    sysctl::SysctlExt,
+    sysctl::{Domain, PowerState, RunMode, SysctlExt},
     time::U32Ext,
 };
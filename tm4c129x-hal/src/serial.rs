@@ -9,6 +9,7 @@ use crate::{
     time::Bps,
 };
 use nb::{self, block};
+use tm4c129x::Interrupt;
 use void::Void;
 
 pub use tm4c129x::{UART0, UART1, UART2, UART3, UART4, UART5, UART6, UART7};
@@ -22,6 +23,7 @@ pub struct Serial<UART, TX, RX, RTS, CTS> {
     rts_pin: RTS,
     cts_pin: CTS,
     nl_mode: NewlineMode,
+    stats: Stats,
 }
 
 /// Serial receiver
@@ -29,6 +31,7 @@ pub struct Rx<UART, RX, CTS> {
     _uart: PhantomData<UART>,
     pin: RX,
     flow_pin: CTS,
+    stats: Stats,
 }
 
 /// Serial transmitter
@@ -37,6 +40,7 @@ pub struct Tx<UART, TX, RTS> {
     pin: TX,
     flow_pin: RTS,
     nl_mode: NewlineMode,
+    stats: Stats,
 }
 
 uart_pin_macro!(UART0,
@@ -28,6 +28,7 @@ use crate::{
     time::{Hertz, U32Ext},
 };
 use cortex_m::asm::nop;
+use tm4c_hal::poll::poll_until;
 
 /// Constrained SYSCTL peripheral.
 pub struct Sysctl {
@@ -51,8 +52,16 @@ pub struct ClockSetup {
     _0: (),
 }
 
+/// A complete clock configuration, as plain data, so that a table of them
+/// can live in a `static` and be picked from at run time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockConfig {
+    /// The system oscillator configuration
+    pub oscillator: Oscillator,
+}
+
 /// Selects the system oscillator source
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Oscillator {
     /// Use the main oscillator (with the given crystal), into the PLL or a
     /// clock divider
@@ -60,12 +69,14 @@ pub enum Oscillator {
     /// Use the 16 MHz precision internal oscillator, into the PLL or a clock
     /// divider
     PrecisionInternal(SystemClock),
-    /// Use the 33 kHz internal oscillator, divided by the given value.
+    /// Use the 33 kHz internal oscillator, divided by the given value. Not
+    /// supported yet: `try_freeze` and `reconfigure` return
+    /// `ClockError::Unsupported`.
     LowFrequencyInternal(Divider),
 }
 
 /// Selects the source for the system clock
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SystemClock {
     /// Clock the system direct from the system oscillator
     UseOscillator(Divider),
@@ -74,116 +85,78 @@ pub enum SystemClock {
     UsePll(PllOutputFrequency),
 }
 
-/// Selects which crystal is fitted to the XOSC pins.
-#[derive(Clone, Copy)]
-pub enum CrystalFrequency {
-    /// 4 MHz
-    _4mhz,
-    /// 4.096 MHz
-    _4_09mhz,
-    /// 4.9152 MHz
-    _4_91mhz,
-    /// 5 MHz
-    _5mhz,
-    /// 5.12 MHz
-    _5_12mhz,
-    /// 6 MHz
-    _6mhz,
-    /// 6.144 MHz
-    _6_14mhz,
-    /// 7.3728 MHz
-    _7_37mhz,
-    /// 8 MHz
-    _8mhz,
-    /// 8.192 MHz
-    _8_19mhz,
-    /// 10 MHz
-    _10mhz,
-    /// 12 MHz
-    _12mhz,
-    /// 12.288 MHz
-    _12_2mhz,
-    /// 13.56 MHz
-    _13_5mhz,
-    /// 14.31818 MHz
-    _14_3mhz,
-    /// 16 MHz
-    _16mhz,
-    /// 16.384 MHz
-    _16_3mhz,
-    /// 18.0 MHz (USB)
-    _18mhz,
-    /// 20.0 MHz (USB)
-    _20mhz,
-    /// 24.0 MHz (USB)
-    _24mhz,
-    /// 25.0 MHz (USB)
-    _25mhz,
-}
-
-impl Into<Hertz> for CrystalFrequency {
-    fn into(self) -> Hertz {
-        Hertz(match self {
-            CrystalFrequency::_4mhz => 4_000_000,
-            CrystalFrequency::_4_09mhz => 4_090_000,
-            CrystalFrequency::_4_91mhz => 4_910_000,
-            CrystalFrequency::_5mhz => 5_000_000,
-            CrystalFrequency::_5_12mhz => 5_120_000,
-            CrystalFrequency::_6mhz => 6_000_000,
-            CrystalFrequency::_6_14mhz => 6_140_000,
-            CrystalFrequency::_7_37mhz => 7_370_000,
-            CrystalFrequency::_8mhz => 8_000_000,
-            CrystalFrequency::_8_19mhz => 8_190_000,
-            CrystalFrequency::_10mhz => 10_000_000,
-            CrystalFrequency::_12mhz => 12_000_000,
-            CrystalFrequency::_12_2mhz => 12_200_000,
-            CrystalFrequency::_13_5mhz => 13_500_000,
-            CrystalFrequency::_14_3mhz => 14_300_000,
-            CrystalFrequency::_16mhz => 16_000_000,
-            CrystalFrequency::_16_3mhz => 16_300_000,
-            CrystalFrequency::_18mhz => 18_000_000,
-            CrystalFrequency::_20mhz => 20_000_000,
-            CrystalFrequency::_24mhz => 24_000_000,
-            CrystalFrequency::_25mhz => 25_000_000,
-        })
+tm4c_hal::frequency_enum! {
+    /// Selects which crystal is fitted to the XOSC pins.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum CrystalFrequency {
+        /// 4 MHz
+        _4mhz => 4_000_000,
+        /// 4.096 MHz
+        _4_09mhz => 4_090_000,
+        /// 4.9152 MHz
+        _4_91mhz => 4_910_000,
+        /// 5 MHz
+        _5mhz => 5_000_000,
+        /// 5.12 MHz
+        _5_12mhz => 5_120_000,
+        /// 6 MHz
+        _6mhz => 6_000_000,
+        /// 6.144 MHz
+        _6_14mhz => 6_140_000,
+        /// 7.3728 MHz
+        _7_37mhz => 7_370_000,
+        /// 8 MHz
+        _8mhz => 8_000_000,
+        /// 8.192 MHz
+        _8_19mhz => 8_190_000,
+        /// 10 MHz
+        _10mhz => 10_000_000,
+        /// 12 MHz
+        _12mhz => 12_000_000,
+        /// 12.288 MHz
+        _12_2mhz => 12_200_000,
+        /// 13.56 MHz
+        _13_5mhz => 13_500_000,
+        /// 14.31818 MHz
+        _14_3mhz => 14_300_000,
+        /// 16 MHz
+        _16mhz => 16_000_000,
+        /// 16.384 MHz
+        _16_3mhz => 16_300_000,
+        /// 18.0 MHz (USB)
+        _18mhz => 18_000_000,
+        /// 20.0 MHz (USB)
+        _20mhz => 20_000_000,
+        /// 24.0 MHz (USB)
+        _24mhz => 24_000_000,
+        /// 25.0 MHz (USB)
+        _25mhz => 25_000_000,
     }
 }
 
-/// Selects what to divide the PLL's 400MHz down to.
-#[derive(Clone, Copy)]
-pub enum PllOutputFrequency {
-    /// 120 MHz
-    _120mhz,
-    /// 60 MHz
-    _60mhz,
-    /// 48 MHz
-    _48mhz,
-    /// 30 MHz
-    _30mhz,
-    /// 24 MHz
-    _24mhz,
-    /// 12 MHz
-    _12mhz,
-    /// 6 MHz
-    _6mhz,
-}
-
-impl Into<Hertz> for PllOutputFrequency {
-    fn into(self) -> Hertz {
-        Hertz(match self {
-            PllOutputFrequency::_120mhz => 120_000_000,
-            PllOutputFrequency::_60mhz => 60_000_000,
-            PllOutputFrequency::_48mhz => 48_000_000,
-            PllOutputFrequency::_30mhz => 30_000_000,
-            PllOutputFrequency::_24mhz => 24_000_000,
-            PllOutputFrequency::_12mhz => 12_000_000,
-            PllOutputFrequency::_6mhz => 6_000_000,
-        })
+tm4c_hal::frequency_enum! {
+    /// Selects what to divide the PLL's 400MHz down to.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum PllOutputFrequency {
+        /// 120 MHz
+        _120mhz => 120_000_000,
+        /// 60 MHz
+        _60mhz => 60_000_000,
+        /// 48 MHz
+        _48mhz => 48_000_000,
+        /// 30 MHz
+        _30mhz => 30_000_000,
+        /// 24 MHz
+        _24mhz => 24_000_000,
+        /// 12 MHz
+        _12mhz => 12_000_000,
+        /// 6 MHz
+        _6mhz => 6_000_000,
     }
 }
 
 /// Selects how much to divide the system oscillator down.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Divider {
     /// Divide by 1
     _1 = 1,
@@ -326,218 +299,361 @@ pub enum Domain {
     Ephy0,
 }
 
-/// Reset a peripheral
-pub fn reset(_lock: &PowerControl, pd: Domain) {
+impl Domain {
+    /// Every domain `reset_peripheral_state` resets, in the order it resets
+    /// them, with the GPIO ports last. Left out are Hibernation, whose RTC
+    /// and memory are meant to outlast a reset, and GPIO port C, whose
+    /// PC0-PC3 carry JTAG/SWD and SWO, so the debugger stays attached.
+    pub const RESETTABLE: [Domain; 49] = [
+        Domain::Watchdog1,
+        Domain::Watchdog0,
+        Domain::Timer5,
+        Domain::Timer4,
+        Domain::Timer3,
+        Domain::Timer2,
+        Domain::Timer1,
+        Domain::Timer0,
+        Domain::MicroDma,
+        Domain::Uart7,
+        Domain::Uart6,
+        Domain::Uart5,
+        Domain::Uart4,
+        Domain::Uart3,
+        Domain::Uart2,
+        Domain::Uart1,
+        Domain::Uart0,
+        Domain::Ssi3,
+        Domain::Ssi2,
+        Domain::Ssi1,
+        Domain::Ssi0,
+        Domain::I2c3,
+        Domain::I2c2,
+        Domain::I2c1,
+        Domain::I2c0,
+        Domain::Usb,
+        Domain::Can,
+        Domain::Adc1,
+        Domain::Adc0,
+        Domain::AnalogComparator,
+        Domain::Eeprom,
+        Domain::Pwm0,
+        Domain::Pwm1,
+        Domain::Emac0,
+        Domain::Ephy0,
+        Domain::GpioA,
+        Domain::GpioB,
+        Domain::GpioD,
+        Domain::GpioE,
+        Domain::GpioF,
+        Domain::GpioG,
+        Domain::GpioH,
+        Domain::GpioJ,
+        Domain::GpioK,
+        Domain::GpioL,
+        Domain::GpioM,
+        Domain::GpioN,
+        Domain::GpioP,
+        Domain::GpioQ,
+    ];
+}
+
+/// Reset a peripheral, which must be clocked in run mode. Fails with
+/// `ResetTimeout` if it doesn't report ready again within `RESET_POLLS`
+/// polls.
+pub fn reset(_lock: &PowerControl, pd: Domain) -> Result<(), ResetTimeout> {
     // We use bit-banding to make an atomic write, so this is safe
     let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
     match pd {
         Domain::Watchdog1 => unsafe {
             bb::toggle_bit(&p.srwd, 1);
-            bb::spin_bit(&p.prwd, 1);
+            wait_ready(&p.prwd, 1)
         },
         Domain::Watchdog0 => unsafe {
             bb::toggle_bit(&p.srwd, 0);
-            bb::spin_bit(&p.prwd, 0);
+            wait_ready(&p.prwd, 0)
         },
         Domain::Timer5 => unsafe {
             bb::toggle_bit(&p.srtimer, 5);
-            bb::spin_bit(&p.prtimer, 5);
+            wait_ready(&p.prtimer, 5)
         },
         Domain::Timer4 => unsafe {
             bb::toggle_bit(&p.srtimer, 4);
-            bb::spin_bit(&p.prtimer, 4);
+            wait_ready(&p.prtimer, 4)
         },
         Domain::Timer3 => unsafe {
             bb::toggle_bit(&p.srtimer, 3);
-            bb::spin_bit(&p.prtimer, 3);
+            wait_ready(&p.prtimer, 3)
         },
         Domain::Timer2 => unsafe {
             bb::toggle_bit(&p.srtimer, 2);
-            bb::spin_bit(&p.prtimer, 2);
+            wait_ready(&p.prtimer, 2)
         },
         Domain::Timer1 => unsafe {
             bb::toggle_bit(&p.srtimer, 1);
-            bb::spin_bit(&p.prtimer, 1);
+            wait_ready(&p.prtimer, 1)
         },
         Domain::Timer0 => unsafe {
             bb::toggle_bit(&p.srtimer, 0);
-            bb::spin_bit(&p.prtimer, 0);
+            wait_ready(&p.prtimer, 0)
         },
         Domain::GpioQ => unsafe {
             bb::toggle_bit(&p.srgpio, 14);
-            bb::spin_bit(&p.prgpio, 14);
+            wait_ready(&p.prgpio, 14)
         },
         Domain::GpioP => unsafe {
             bb::toggle_bit(&p.srgpio, 13);
-            bb::spin_bit(&p.prgpio, 13);
+            wait_ready(&p.prgpio, 13)
         },
         Domain::GpioN => unsafe {
             bb::toggle_bit(&p.srgpio, 12);
-            bb::spin_bit(&p.prgpio, 12);
+            wait_ready(&p.prgpio, 12)
         },
         Domain::GpioM => unsafe {
             bb::toggle_bit(&p.srgpio, 11);
-            bb::spin_bit(&p.prgpio, 11);
+            wait_ready(&p.prgpio, 11)
         },
         Domain::GpioL => unsafe {
             bb::toggle_bit(&p.srgpio, 10);
-            bb::spin_bit(&p.prgpio, 10);
+            wait_ready(&p.prgpio, 10)
         },
         Domain::GpioK => unsafe {
             bb::toggle_bit(&p.srgpio, 9);
-            bb::spin_bit(&p.prgpio, 9);
+            wait_ready(&p.prgpio, 9)
         },
         Domain::GpioJ => unsafe {
             bb::toggle_bit(&p.srgpio, 8);
-            bb::spin_bit(&p.prgpio, 8);
+            wait_ready(&p.prgpio, 8)
         },
         Domain::GpioH => unsafe {
             bb::toggle_bit(&p.srgpio, 7);
-            bb::spin_bit(&p.prgpio, 7);
+            wait_ready(&p.prgpio, 7)
         },
         Domain::GpioG => unsafe {
             bb::toggle_bit(&p.srgpio, 6);
-            bb::spin_bit(&p.prgpio, 6);
+            wait_ready(&p.prgpio, 6)
         },
         Domain::GpioF => unsafe {
             bb::toggle_bit(&p.srgpio, 5);
-            bb::spin_bit(&p.prgpio, 5);
+            wait_ready(&p.prgpio, 5)
         },
         Domain::GpioE => unsafe {
             bb::toggle_bit(&p.srgpio, 4);
-            bb::spin_bit(&p.prgpio, 4);
+            wait_ready(&p.prgpio, 4)
         },
         Domain::GpioD => unsafe {
             bb::toggle_bit(&p.srgpio, 3);
-            bb::spin_bit(&p.prgpio, 3);
+            wait_ready(&p.prgpio, 3)
         },
         Domain::GpioC => unsafe {
             bb::toggle_bit(&p.srgpio, 2);
-            bb::spin_bit(&p.prgpio, 2);
+            wait_ready(&p.prgpio, 2)
         },
         Domain::GpioB => unsafe {
             bb::toggle_bit(&p.srgpio, 1);
-            bb::spin_bit(&p.prgpio, 1);
+            wait_ready(&p.prgpio, 1)
         },
         Domain::GpioA => unsafe {
             bb::toggle_bit(&p.srgpio, 0);
-            bb::spin_bit(&p.prgpio, 0);
+            wait_ready(&p.prgpio, 0)
         },
         Domain::MicroDma => unsafe {
             bb::toggle_bit(&p.srdma, 0);
-            bb::spin_bit(&p.prdma, 0);
+            wait_ready(&p.prdma, 0)
         },
         Domain::Hibernation => unsafe {
             bb::toggle_bit(&p.srhib, 0);
-            bb::spin_bit(&p.prhib, 0);
+            wait_ready(&p.prhib, 0)
         },
         Domain::Uart7 => unsafe {
             bb::toggle_bit(&p.sruart, 7);
-            bb::spin_bit(&p.pruart, 7);
+            wait_ready(&p.pruart, 7)
         },
         Domain::Uart6 => unsafe {
             bb::toggle_bit(&p.sruart, 6);
-            bb::spin_bit(&p.pruart, 6);
+            wait_ready(&p.pruart, 6)
         },
         Domain::Uart5 => unsafe {
             bb::toggle_bit(&p.sruart, 5);
-            bb::spin_bit(&p.pruart, 5);
+            wait_ready(&p.pruart, 5)
         },
         Domain::Uart4 => unsafe {
             bb::toggle_bit(&p.sruart, 4);
-            bb::spin_bit(&p.pruart, 4);
+            wait_ready(&p.pruart, 4)
         },
         Domain::Uart3 => unsafe {
             bb::toggle_bit(&p.sruart, 3);
-            bb::spin_bit(&p.pruart, 3);
+            wait_ready(&p.pruart, 3)
         },
         Domain::Uart2 => unsafe {
             bb::toggle_bit(&p.sruart, 2);
-            bb::spin_bit(&p.pruart, 2);
+            wait_ready(&p.pruart, 2)
         },
         Domain::Uart1 => unsafe {
             bb::toggle_bit(&p.sruart, 1);
-            bb::spin_bit(&p.pruart, 1);
+            wait_ready(&p.pruart, 1)
         },
         Domain::Uart0 => unsafe {
             bb::toggle_bit(&p.sruart, 0);
-            bb::spin_bit(&p.pruart, 0);
+            wait_ready(&p.pruart, 0)
         },
         Domain::Ssi3 => unsafe {
             bb::toggle_bit(&p.srssi, 3);
-            bb::spin_bit(&p.prssi, 3);
+            wait_ready(&p.prssi, 3)
         },
         Domain::Ssi2 => unsafe {
             bb::toggle_bit(&p.srssi, 2);
-            bb::spin_bit(&p.prssi, 2);
+            wait_ready(&p.prssi, 2)
         },
         Domain::Ssi1 => unsafe {
             bb::toggle_bit(&p.srssi, 1);
-            bb::spin_bit(&p.prssi, 1);
+            wait_ready(&p.prssi, 1)
         },
         Domain::Ssi0 => unsafe {
             bb::toggle_bit(&p.srssi, 0);
-            bb::spin_bit(&p.prssi, 0);
+            wait_ready(&p.prssi, 0)
         },
         Domain::I2c3 => unsafe {
             bb::toggle_bit(&p.sri2c, 3);
-            bb::spin_bit(&p.pri2c, 3);
+            wait_ready(&p.pri2c, 3)
         },
         Domain::I2c2 => unsafe {
             bb::toggle_bit(&p.sri2c, 2);
-            bb::spin_bit(&p.pri2c, 2);
+            wait_ready(&p.pri2c, 2)
         },
         Domain::I2c1 => unsafe {
             bb::toggle_bit(&p.sri2c, 1);
-            bb::spin_bit(&p.pri2c, 1);
+            wait_ready(&p.pri2c, 1)
         },
         Domain::I2c0 => unsafe {
             bb::toggle_bit(&p.sri2c, 0);
-            bb::spin_bit(&p.pri2c, 0);
+            wait_ready(&p.pri2c, 0)
         },
         Domain::Usb => unsafe {
             bb::toggle_bit(&p.srusb, 0);
-            bb::spin_bit(&p.prusb, 0);
+            wait_ready(&p.prusb, 0)
         },
         Domain::Can => unsafe {
             bb::toggle_bit(&p.srcan, 0);
-            bb::spin_bit(&p.prcan, 0);
+            wait_ready(&p.prcan, 0)
         },
         Domain::Adc1 => unsafe {
             bb::toggle_bit(&p.sradc, 1);
-            bb::spin_bit(&p.pradc, 1);
+            wait_ready(&p.pradc, 1)
         },
         Domain::Adc0 => unsafe {
             bb::toggle_bit(&p.sradc, 0);
-            bb::spin_bit(&p.pradc, 0);
+            wait_ready(&p.pradc, 0)
         },
         Domain::AnalogComparator => unsafe {
             bb::toggle_bit(&p.sracmp, 0);
-            bb::spin_bit(&p.pracmp, 0);
+            wait_ready(&p.pracmp, 0)
         },
         Domain::Eeprom => unsafe {
             bb::toggle_bit(&p.sreeprom, 0);
-            bb::spin_bit(&p.preeprom, 0);
+            wait_ready(&p.preeprom, 0)
         },
         Domain::Pwm0 => unsafe {
             bb::toggle_bit(&p.srpwm, 0);
-            bb::spin_bit(&p.prpwm, 0);
+            wait_ready(&p.prpwm, 0)
         },
         Domain::Pwm1 => unsafe {
             bb::toggle_bit(&p.srpwm, 1);
-            bb::spin_bit(&p.prpwm, 1);
+            wait_ready(&p.prpwm, 1)
         },
         Domain::Emac0 => unsafe {
             bb::toggle_bit(&p.sremac, 0);
-            bb::spin_bit(&p.premac, 0);
+            wait_ready(&p.premac, 0)
         },
         Domain::Ephy0 => unsafe {
             bb::toggle_bit(&p.srephy, 0);
-            bb::spin_bit(&p.prephy, 0);
+            wait_ready(&p.prephy, 0)
         },
     }
 }
 
+/// Waits for `bit` of the peripheral-ready register at `address` to say the
+/// module is out of reset
+fn wait_ready<T>(address: *const T, bit: u8) -> Result<(), ResetTimeout> {
+    poll_until(RESET_POLLS, || bb::read_bit(address, bit)).map_err(|_| ResetTimeout)
+}
+
+/// Puts each of `domains` back the way a power-on reset leaves it: the
+/// module is reset, then its run, sleep and deep-sleep clocks are turned off.
+/// Domains this part doesn't have are skipped. `Domain::GpioC` is reset if
+/// it is given, debug pins and all; `Domain::RESETTABLE` leaves it out.
+/// Nothing may be using the modules any more; their drivers will find them
+/// unclocked and unconfigured.
+///
+/// A domain that doesn't come out of reset doesn't stop the rest; the
+/// result is `ResetTimeout` if any of them didn't.
+pub fn reset_domains(lock: &PowerControl, domains: &[Domain]) -> Result<(), ResetTimeout> {
+    let mut result = Ok(());
+    for &pd in domains.iter().filter(|&&pd| present(pd)) {
+        // The reset only completes while the module is clocked
+        control_power(lock, pd, RunMode::Run, PowerState::On);
+        if let Err(e) = reset(lock, pd) {
+            result = Err(e);
+        }
+        control_power(lock, pd, RunMode::Run, PowerState::Off);
+        control_power(lock, pd, RunMode::Sleep, PowerState::Off);
+        control_power(lock, pd, RunMode::DeepSleep, PowerState::Off);
+    }
+    result
+}
+
+/// Resets every peripheral in `Domain::RESETTABLE`, so that a soft reset
+/// from the debugger starts from the same peripheral state as a cold boot:
+/// no PWM left running, no DMA left armed. Call it first thing in `main`.
+///
+/// The GPIO ports go last, so pins keep driving whatever they were (a
+/// debug UART's TX line, say) until the very end. Port C is skipped, as it
+/// carries the debugger's JTAG/SWD and SWO pins; resetting it, or gating its
+/// clocks, would cut off a debug session or trace in progress. The clock
+/// setup in SYSCTL itself isn't touched.
+pub fn reset_peripheral_state(lock: &PowerControl) -> Result<(), ResetTimeout> {
+    reset_domains(lock, &Domain::RESETTABLE)
+}
+
+/// The causes of the resets since RESC was last cleared. The register
+/// survives every reset except power-on, so clear it once read to see only
+/// the next one.
+pub fn reset_cause(_lock: &PowerControl) -> ResetCause {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    ResetCause::from_bits(p.resc.read().bits())
+}
+
+/// Clears the record of reset causes in RESC
+pub fn clear_reset_cause(_lock: &PowerControl) {
+    // We own the SYSCTL through PowerControl, and nothing else writes RESC
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    p.resc.write(|w| unsafe { w.bits(0) });
+}
+
+/// Resets the whole chip through the core's system reset request (APINT
+/// SYSRESREQ), the way the datasheet recommends for a software reset.
+/// Every peripheral goes back to its reset state, and `reset_cause` will
+/// report `software` afterwards. Interrupts are masked first, so nothing
+/// runs in the meantime. Safe to call from a panic handler.
+pub fn sys_reset(_lock: &PowerControl) -> ! {
+    cortex_m::interrupt::disable();
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// True if this part has the peripheral, from its PP (peripheral present)
+/// register. Smaller parts lack some of the UARTs, timers and so on, so
+/// firmware built for several boards can check before bringing one up.
+pub fn peripheral_present(_lock: &PowerControl, pd: Domain) -> bool {
+    present(pd)
+}
+
+fn present(pd: Domain) -> bool {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let (index, bit) = class_bit(pd);
+    bb::read_bit((&p.ppwd as *const _ as *const u32).wrapping_add(index), bit)
+}
+
 /// Activate or De-Activate clocks and power to the given peripheral in the
 /// given run mode.
 ///
@@ -562,6 +678,107 @@ pub fn control_power(_lock: &PowerControl, pd: Domain, run_mode: RunMode, state:
     nop();
 }
 
+/// Whether `pd`'s clock is gated on in `run_mode`, read back from the
+/// RCGC, SCGC or DCGC register
+pub fn power_state(_lock: &PowerControl, pd: Domain, run_mode: RunMode) -> PowerState {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let first: *const u32 = match run_mode {
+        RunMode::Run => &p.rcgcwd as *const _ as *const u32,
+        RunMode::Sleep => &p.scgcwd as *const _ as *const u32,
+        RunMode::DeepSleep => &p.dcgcwd as *const _ as *const u32,
+    };
+    let (index, bit) = class_bit(pd);
+    if bb::read_bit(first.wrapping_add(index), bit) {
+        PowerState::On
+    } else {
+        PowerState::Off
+    }
+}
+
+/// True once `pd` is clocked, out of reset and ready for its registers to
+/// be accessed, from the PR register
+pub fn is_peripheral_ready(_lock: &PowerControl, pd: Domain) -> bool {
+    // This is safe as it's read only
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let (index, bit) = class_bit(pd);
+    bb::read_bit((&p.prwd as *const _ as *const u32).wrapping_add(index), bit)
+}
+
+/// Where `pd`'s bit is in each bank of per-class registers (PP, SR, RCGC,
+/// SCGC, DCGC and PR), which all share one layout: the index of its
+/// register counting from the bank's watchdog register, and the bit
+fn class_bit(pd: Domain) -> (usize, u8) {
+    match pd {
+        Domain::Watchdog1 => (0, 1),
+        Domain::Watchdog0 => (0, 0),
+        Domain::Timer5 => (1, 5),
+        Domain::Timer4 => (1, 4),
+        Domain::Timer3 => (1, 3),
+        Domain::Timer2 => (1, 2),
+        Domain::Timer1 => (1, 1),
+        Domain::Timer0 => (1, 0),
+        Domain::GpioQ => (2, 14),
+        Domain::GpioP => (2, 13),
+        Domain::GpioN => (2, 12),
+        Domain::GpioM => (2, 11),
+        Domain::GpioL => (2, 10),
+        Domain::GpioK => (2, 9),
+        Domain::GpioJ => (2, 8),
+        Domain::GpioH => (2, 7),
+        Domain::GpioG => (2, 6),
+        Domain::GpioF => (2, 5),
+        Domain::GpioE => (2, 4),
+        Domain::GpioD => (2, 3),
+        Domain::GpioC => (2, 2),
+        Domain::GpioB => (2, 1),
+        Domain::GpioA => (2, 0),
+        Domain::MicroDma => (3, 0),
+        Domain::Hibernation => (5, 0),
+        Domain::Uart7 => (6, 7),
+        Domain::Uart6 => (6, 6),
+        Domain::Uart5 => (6, 5),
+        Domain::Uart4 => (6, 4),
+        Domain::Uart3 => (6, 3),
+        Domain::Uart2 => (6, 2),
+        Domain::Uart1 => (6, 1),
+        Domain::Uart0 => (6, 0),
+        Domain::Ssi3 => (7, 3),
+        Domain::Ssi2 => (7, 2),
+        Domain::Ssi1 => (7, 1),
+        Domain::Ssi0 => (7, 0),
+        Domain::I2c3 => (8, 3),
+        Domain::I2c2 => (8, 2),
+        Domain::I2c1 => (8, 1),
+        Domain::I2c0 => (8, 0),
+        Domain::Usb => (10, 0),
+        Domain::Can => (13, 0),
+        Domain::Adc1 => (14, 1),
+        Domain::Adc0 => (14, 0),
+        Domain::AnalogComparator => (15, 0),
+        Domain::Eeprom => (22, 0),
+        Domain::Pwm0 => (16, 0),
+        Domain::Pwm1 => (16, 1),
+        Domain::Emac0 => (39, 0),
+        Domain::Ephy0 => (12, 0),
+    }
+}
+
+/// Like `control_power`, but refuses to touch the clock gates of a
+/// peripheral this part doesn't have, whose bits are reserved
+pub fn try_control_power(
+    lock: &PowerControl,
+    pd: Domain,
+    run_mode: RunMode,
+    state: PowerState,
+) -> Result<(), PeripheralNotPresent> {
+    if !present(pd) {
+        return Err(PeripheralNotPresent);
+    }
+    control_power(lock, pd, run_mode, state);
+    Ok(())
+}
+
 fn control_run_power(pd: Domain, on: bool) {
     // We use bit-banding to make an atomic write, so this is safe
     let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
@@ -756,9 +973,27 @@ impl SysctlExt for tm4c129x::SYSCTL {
 }
 
 impl ClockSetup {
+    /// Applies `config` and then does the same as `freeze`
+    pub fn freeze_with(mut self, config: &ClockConfig) -> Clocks {
+        self.oscillator = config.oscillator;
+        self.freeze()
+    }
+
     /// Fix the clock configuration and produce a record of the configuration
     /// so that other modules can calibrate themselves (e.g. the UARTs).
+    /// Panics if an oscillator or the PLL doesn't come up; see `try_freeze`.
     pub fn freeze(self) -> Clocks {
+        match self.try_freeze() {
+            Ok(clocks) => clocks,
+            Err(e) => panic!("clock setup failed: {:?}", e),
+        }
+    }
+
+    /// Like `freeze`, but reports an oscillator or PLL that never becomes
+    /// ready instead of panicking. The clocks are left part-way configured
+    /// in that case, still running from the internal oscillator.
+    pub fn try_freeze(self) -> Result<Clocks, ClockError> {
+        tm4c_hal::assert_can_block!("ClockSetup::freeze");
         // We own the SYSCTL at this point - no one else can be running.
         let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
 
@@ -772,11 +1007,35 @@ impl ClockSetup {
                 osc = 16_000_000.hz();
                 sysclk = (osc.0 / (div as u32)).hz();
 
+                let (xbcht, xbce, xws) =
+                    tm4c_hal::calc::memory_timing(sysclk.0).expect("system clock above 120 MHz");
+
+                // No faster than the PIOSC, so the new timing takes effect
+                // with the switch rather than before it
+                p.memtim0.modify(|_, w| {
+                    unsafe {
+                        w.fbcht().bits(xbcht);
+                        w.ebcht().bits(xbcht);
+
+                        w.fbce().bit(xbce);
+                        w.ebce().bit(xbce);
+
+                        w.fws().bits(xws);
+                        w.ews().bits(xws);
+                    }
+
+                    w
+                });
+                tm4c_hal::trace_access!(p, memtim0, Write);
+
                 p.rsclkcfg.modify(|_, w| {
+                    w.oscsrc().piosc();
+                    w.memtimu().set_bit();
                     w.osysdiv().bits(div as u16 - 1);
 
                     w
                 });
+                tm4c_hal::trace_access!(p, rsclkcfg, Write);
             }
             Oscillator::PrecisionInternal(SystemClock::UsePll(output_frequency)) => {
                 osc = 16_000_000.hz();
@@ -787,6 +1046,7 @@ impl ClockSetup {
                 // Crystal, MINT, MINT, N, Ref MHZ, Pll MHZ
 
                 p.rsclkcfg.modify(|_, w| w.pllsrc().piosc());
+                tm4c_hal::trace_access!(p, rsclkcfg, Write);
 
                 p.pllfreq0.modify(|_, w| {
                     w.pllpwr().set_bit();
@@ -796,6 +1056,7 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, pllfreq0, Write);
 
                 p.pllfreq1.modify(|_, w| {
                     w.q().bits(0);
@@ -803,18 +1064,13 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, pllfreq1, Write);
 
                 p.rsclkcfg.modify(|_, w| w.newfreq().set_bit());
+                tm4c_hal::trace_access!(p, rsclkcfg, Write);
 
-                let (xbcht, xbce, xws) = match sysclk.0 {
-                    f if f <= 16_000_000 => (0, true, 0),
-                    f if f <= 40_000_000 => (2, false, 1),
-                    f if f <= 60_000_000 => (3, false, 2),
-                    f if f <= 80_000_000 => (4, false, 3),
-                    f if f <= 100_000_000 => (5, false, 4),
-                    f if f <= 120_000_000 => (6, false, 5),
-                    _ => unreachable!(),
-                };
+                let (xbcht, xbce, xws) =
+                    tm4c_hal::calc::memory_timing(sysclk.0).expect("system clock above 120 MHz");
 
                 // 7. Write the MEMTIM0 register to correspond to the new system clock setting.
                 p.memtim0.modify(|_, w| {
@@ -831,24 +1087,25 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, memtim0, Write);
 
                 // 8. Wait for the PLLSTAT register to indicate the PLL has reached lock at the
                 // new operating point (or that a timeout period has passed and lock has failed,
                 // in which case an error condition exists and this sequence is abandoned and
                 // error processing is initiated).
-                while p.pllstat.read().lock().bit_is_clear() {
-                    cortex_m::asm::nop();
-                }
+                poll_until(CLOCK_READY_POLLS, || p.pllstat.read().lock().bit_is_set())
+                    .map_err(|_| ClockError::PllLock)?;
 
                 // 9. Write the RSCLKCFG register's PSYSDIV value, set the USEPLL bit to
                 // enabled, and MEMTIMU bit.
                 p.rsclkcfg.modify(|_, w| {
                     w.usepll().set_bit();
                     w.memtimu().set_bit();
-                    w.psysdiv().bits((480_000_000 / sysclk.0 - 1) as u16);
+                    w.psysdiv().bits(tm4c_hal::calc::psysdiv(sysclk.0));
 
                     w
                 });
+                tm4c_hal::trace_access!(p, rsclkcfg, Write);
             }
 
             Oscillator::Main(crystal_frequency, SystemClock::UseOscillator(div)) => {
@@ -864,12 +1121,10 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, moscctl, Write);
 
-                let (xbcht, xbce, xws) = match sysclk.0 {
-                    f if f < 16_000_000 => (0, true, 0),
-                    f if f < 40_000_000 => (2, false, 1),
-                    _ => unreachable!(),
-                };
+                let (xbcht, xbce, xws) =
+                    tm4c_hal::calc::memory_timing(sysclk.0).expect("system clock above 120 MHz");
 
                 // 7. Write the MEMTIM0 register to correspond to the new system clock
                 p.memtim0.modify(|_, w| {
@@ -886,13 +1141,13 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, memtim0, Write);
 
                 // If single-ended MOSC mode is required, the MOSC is ready to use. If crystal
                 // mode is required, clear the PWRDN bit and wait for the MOSCPUPRIS bit to be
                 // set in the Raw Interrupt Status (RIS), indicating MOSC crystal mode is ready.
-                while p.ris.read().moscpupris().bit_is_clear() {
-                    cortex_m::asm::nop();
-                }
+                poll_until(CLOCK_READY_POLLS, || p.ris.read().moscpupris().bit_is_set())
+                    .map_err(|_| ClockError::MainOscillator)?;
 
                 // 4. Set the OSCSRC field to 0x3 in the RSCLKCFG register at offset 0x0B0.
                 p.rsclkcfg.modify(|_, w| {
@@ -903,6 +1158,7 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, rsclkcfg, Write);
             }
 
             Oscillator::Main(crystal_frequency, SystemClock::UsePll(output_frequency)) => {
@@ -918,19 +1174,20 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, moscctl, Write);
 
                 // If single-ended MOSC mode is required, the MOSC is ready to use. If crystal
                 // mode is required, clear the PWRDN bit and wait for the MOSCPUPRIS bit to be
                 // set in the Raw Interrupt Status (RIS), indicating MOSC crystal mode is ready.
-                while p.ris.read().moscpupris().bit_is_clear() {
-                    cortex_m::asm::nop();
-                }
+                poll_until(CLOCK_READY_POLLS, || p.ris.read().moscpupris().bit_is_set())
+                    .map_err(|_| ClockError::MainOscillator)?;
 
                 // 6. Write the PLLFREQ0 and PLLFREQ1 registers with the values of Q, N, MINT,
                 // and MFRAC to the configure the desired VCO frequency setting.
                 // Crystal, MINT, MINT, N, Ref MHZ, Pll MHZ
 
                 p.rsclkcfg.modify(|_, w| w.pllsrc().mosc());
+                tm4c_hal::trace_access!(p, rsclkcfg, Write);
 
                 p.pllfreq1.modify(|_, w| {
                     w.q().bits(0);
@@ -938,6 +1195,7 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, pllfreq1, Write);
 
                 p.pllfreq0.modify(|_, w| {
                     w.mfrac().bits(0);
@@ -945,27 +1203,21 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, pllfreq0, Write);
 
                 p.pllfreq0.modify(|_, w| w.pllpwr().set_bit());
+                tm4c_hal::trace_access!(p, pllfreq0, Write);
 
                 // 8. Wait for the PLLSTAT register to indicate the PLL has reached lock at the
                 // new operating point (or that a timeout period has passed and lock has failed,
                 // in which case an error condition exists and this sequence is abandoned and
                 // error processing is initiated).
 
-                while p.pllstat.read().lock().bit_is_clear() {
-                    cortex_m::asm::nop();
-                }
+                poll_until(CLOCK_READY_POLLS, || p.pllstat.read().lock().bit_is_set())
+                    .map_err(|_| ClockError::PllLock)?;
 
-                let (xbcht, xbce, xws) = match sysclk.0 {
-                    f if f <= 16_000_000 => (0, true, 0),
-                    f if f <= 40_000_000 => (2, false, 1),
-                    f if f <= 60_000_000 => (3, false, 2),
-                    f if f <= 80_000_000 => (4, false, 3),
-                    f if f <= 100_000_000 => (5, false, 4),
-                    f if f <= 120_000_000 => (6, false, 5),
-                    _ => unreachable!(),
-                };
+                let (xbcht, xbce, xws) =
+                    tm4c_hal::calc::memory_timing(sysclk.0).expect("system clock above 120 MHz");
 
                 // 7. Write the MEMTIM0 register to correspond to the new system clock setting.
                 p.memtim0.modify(|_, w| {
@@ -982,27 +1234,214 @@ impl ClockSetup {
 
                     w
                 });
+                tm4c_hal::trace_access!(p, memtim0, Write);
 
                 // 9. Write the RSCLKCFG register's PSYSDIV value, set the USEPLL bit to
                 // enabled, and MEMTIMU bit.
                 p.rsclkcfg.modify(|_, w| {
                     w.usepll().set_bit();
                     w.memtimu().set_bit();
-                    w.psysdiv().bits((480_000_000 / sysclk.0 - 1) as u16);
+                    w.psysdiv().bits(tm4c_hal::calc::psysdiv(sysclk.0));
 
                     w
                 });
+                tm4c_hal::trace_access!(p, rsclkcfg, Write);
             }
 
-            Oscillator::LowFrequencyInternal(_div) => unimplemented!(),
+            // Not written yet; nothing has been touched by this point
+            Oscillator::LowFrequencyInternal(_div) => return Err(ClockError::Unsupported),
         }
 
-        Clocks { osc, sysclk }
+        Ok(Clocks::configured(osc, sysclk))
     }
 }
 
+/// Changes the clock configuration after `freeze`, and returns a `Clocks`
+/// for the new one. `clocks` and every copy of it go stale, so drivers
+/// built with them must be given the new value (`Serial::change_baud_rate`,
+/// `Spi::reclock`, `I2c::reclock`) or made again. Peripherals keep running
+/// through the switch, at the wrong rate until then.
+///
+/// The system first drops to the 16 MHz PIOSC with the PLL powered down,
+/// so the PLL can be set up again, then goes through the same sequence as
+/// `freeze`. The flash and EEPROM timing in MEMTIM0 is changed before the
+/// system clock goes up and after it comes down, so it is never too fast
+/// for the clock in use. On an error the system is left on the PIOSC,
+/// except for `ClockError::Unsupported` (the low-frequency oscillator),
+/// which is refused before anything changes.
+pub fn reconfigure(
+    _pc: &mut PowerControl,
+    clocks: Clocks,
+    oscillator: Oscillator,
+) -> Result<Clocks, ClockError> {
+    tm4c_hal::assert_can_block!("sysctl::reconfigure");
+    clocks.assert_current();
+    if let Oscillator::LowFrequencyInternal(_) = oscillator {
+        return Err(ClockError::Unsupported);
+    }
+    let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+
+    // Any clock this comes from is at least 16 MHz or runs with 16 MHz
+    // timing, so the MEMTIM0 in force stays good until `try_freeze` sets
+    // the timing for the new clock
+    p.rsclkcfg.modify(|_, w| {
+        w.usepll().clear_bit();
+        w.oscsrc().piosc();
+        w.osysdiv().bits(0);
+
+        w
+    });
+    tm4c_hal::trace_access!(p, rsclkcfg, Write);
+
+    // Power the PLL down, so a stale lock can't be mistaken for the new one
+    p.pllfreq0.modify(|_, w| w.pllpwr().clear_bit());
+    tm4c_hal::trace_access!(p, pllfreq0, Write);
+
+    ClockSetup { oscillator, _0: () }.try_freeze()
+}
+
 impl PowerControl {}
 
+/// Selects the oscillator that clocks the system in deep sleep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeepSleepOscillator {
+    /// The main oscillator, with this crystal. The PLL is always off in
+    /// deep sleep.
+    Main(CrystalFrequency),
+    /// The 16 MHz precision internal oscillator
+    PrecisionInternal,
+    /// The 33 kHz internal oscillator
+    LowFrequencyInternal,
+    /// The hibernation module's 32.768 kHz oscillator, which must be running
+    Hibernation,
+}
+
+/// The deep-sleep clock configuration, in DSCLKCFG. The run-mode clocks set by
+/// `ClockSetup::freeze` are left alone, so this can be applied at any time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeepSleepClockSetup {
+    /// Where the deep-sleep system clock comes from
+    pub oscillator: DeepSleepOscillator,
+    /// What to divide it by, from 1 to 1024
+    pub divider: u16,
+    /// Power the PIOSC down in deep sleep. Ignored if deep sleep runs from
+    /// the PIOSC.
+    pub piosc_power_down: bool,
+}
+
+impl DeepSleepClockSetup {
+    /// Writes this configuration to DSCLKCFG and returns the frequencies deep
+    /// sleep will run at
+    pub fn apply(self, _pc: &PowerControl) -> DeepSleepClocks {
+        assert!(
+            self.divider >= 1 && self.divider <= 1024,
+            "deep-sleep divider out of range"
+        );
+        let (dsoscsrc, osc) = match self.oscillator {
+            DeepSleepOscillator::Main(crystal) => (0x3, Hertz::from(crystal)),
+            DeepSleepOscillator::PrecisionInternal => (0x0, Hertz(16_000_000)),
+            DeepSleepOscillator::LowFrequencyInternal => (0x2, Hertz(33_000)),
+            DeepSleepOscillator::Hibernation => (0x4, Hertz(32_768)),
+        };
+        let piosc_power_down =
+            self.piosc_power_down && self.oscillator != DeepSleepOscillator::PrecisionInternal;
+
+        // DSCLKCFG: PIOSCPD is bit 31 (bit 30 is MOSCDPD), DSOSCSRC in bits
+        // 23:20, DSSYSDIV in bits 9:0
+        let bits = (dsoscsrc << 20)
+            | u32::from(self.divider - 1)
+            | if piosc_power_down { 1 << 31 } else { 0 };
+        // We own the SYSCTL through PowerControl, and DSCLKCFG only matters
+        // in deep sleep
+        let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+        p.dslpclkcfg.write(|w| unsafe { w.bits(bits) });
+        tm4c_hal::trace_access!(p, dslpclkcfg, Write);
+
+        DeepSleepClocks {
+            osc,
+            sysclk: Hertz(osc.0 / u32::from(self.divider)),
+        }
+    }
+}
+
+/// Idle-current settings. See `Builder` for the options.
+pub mod low_power {
+    pub use tm4c_hal::sysctl::low_power::*;
+
+    use super::{control_power, Domain, PowerControl, PowerState, RunMode};
+    use crate::bb;
+
+    /// Applies `config` and reports what changed. Fails without changing
+    /// anything if `gate_unused_gpio` lists a domain that isn't a GPIO port.
+    ///
+    /// The TM4C129x always clocks sleep and deep sleep from the SCGC/DCGC
+    /// registers, so there is no gating switch to flip here; enable
+    /// `RunMode::Sleep` and `RunMode::DeepSleep` with `control_power` for
+    /// anything that must keep running (or wake the chip) while asleep. The
+    /// PIOSC cannot be stopped in run mode on this part, so `keep_piosc:
+    /// false` powers it down in deep sleep only, and only when deep sleep is
+    /// not clocked from it.
+    pub fn apply(pc: &PowerControl, config: &Builder<Domain>) -> Result<Report, NotGpio<Domain>> {
+        if let Some(&domain) = config.gate_unused_gpio.iter().find(|&&d| !is_gpio(d)) {
+            return Err(NotGpio(domain));
+        }
+
+        let p = unsafe { &*tm4c129x::SYSCTL::ptr() };
+        let mut report = Report::default();
+
+        if !config.keep_piosc {
+            // DSCLKCFG.DSOSCSRC in bits 23:20; 0 is the PIOSC
+            if (p.dslpclkcfg.read().bits() >> 20) & 0xF != 0 {
+                // DSCLKCFG.PIOSCPD; bit 30 is MOSCDPD
+                unsafe { bb::change_bit(&p.dslpclkcfg, 31, true) };
+                report.piosc_off = true;
+            } else {
+                report.piosc_in_use = true;
+            }
+        }
+
+        // DSLPPWRCFG: FLASHPM in bits 5:4 (0x2 is low power), SRAMPM in bits 1:0
+        let flashpm = if config.flash_low_power { 0x2 } else { 0x0 };
+        p.dslppwrcfg.modify(|r, w| unsafe {
+            w.bits((r.bits() & !0x33) | (flashpm << 4) | config.sram_retention.bits())
+        });
+        if config.sram_retention != SramPower::Active {
+            report.sram_retention = Some(config.sram_retention);
+        }
+        report.flash_low_power = config.flash_low_power;
+
+        for &domain in config.gate_unused_gpio {
+            control_power(pc, domain, RunMode::Run, PowerState::Off);
+            control_power(pc, domain, RunMode::Sleep, PowerState::Off);
+            control_power(pc, domain, RunMode::DeepSleep, PowerState::Off);
+        }
+        report.gated_gpio = config.gate_unused_gpio.len();
+
+        Ok(report)
+    }
+
+    fn is_gpio(domain: Domain) -> bool {
+        match domain {
+            Domain::GpioA
+            | Domain::GpioB
+            | Domain::GpioC
+            | Domain::GpioD
+            | Domain::GpioE
+            | Domain::GpioF
+            | Domain::GpioG
+            | Domain::GpioH
+            | Domain::GpioJ
+            | Domain::GpioK
+            | Domain::GpioL
+            | Domain::GpioM
+            | Domain::GpioN
+            | Domain::GpioP
+            | Domain::GpioQ => true,
+            _ => false,
+        }
+    }
+}
+
 /// This module is all about identifying the physical chip we're running on.
 pub mod chip_id {
     pub use tm4c_hal::sysctl::chip_id::*;